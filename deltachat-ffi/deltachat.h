@@ -4683,6 +4683,28 @@ void dc_event_unref(dc_event_t* event);
 #define DC_EVENT_MSG_READ                 2015
 
 
+/**
+ * A single message was deleted locally because its ephemeral timer expired
+ * or because of the `delete_device_after` setting.
+ * The message is moved to the trash chat.
+ *
+ * The event is not emitted for messages deleted while the chatlist is loaded.
+ *
+ * @param data1 (int) chat_id the message was shown in
+ * @param data2 (int) msg_id
+ */
+#define DC_EVENT_MSG_DELETED              2016
+
+
+/**
+ * A message was moved to the mvbox folder on the server.
+ *
+ * @param data1 (int) msg_id
+ * @param data2 (char*) Name of the folder the message was moved from.
+ */
+#define DC_EVENT_MSG_MOVED_TO_MVBOX       2017
+
+
 /**
  * Chat changed.  The name or the image of a chat group was changed or members were added or removed.
  * Or the verify state of a chat has changed.
@@ -4730,6 +4752,16 @@ void dc_event_unref(dc_event_t* event);
 #define DC_EVENT_CONFIGURE_PROGRESS       2041
 
 
+/**
+ * The account became configured, e.g. by a successful dc_configure(),
+ * or lost its configuration.
+ *
+ * @param data1 (int) 1=the account is configured now, 0=the account is not configured anymore
+ * @param data2 0
+ */
+#define DC_EVENT_ACCOUNT_CONFIGURED_CHANGED 2042
+
+
 /**
  * Inform about the import/export progress started by dc_imex().
  *
@@ -4752,6 +4784,15 @@ void dc_event_unref(dc_event_t* event);
 #define DC_EVENT_IMEX_FILE_WRITTEN        2052
 
 
+/**
+ * Inform about the progress of exporting or importing all accounts at once.
+ *
+ * @param data1 (int) 0=error, 1-999=progress in permille, 1000=success and done
+ * @param data2 (int) ID of the account processed last.
+ */
+#define DC_EVENT_ACCOUNTS_IMEX_PROGRESS   2053
+
+
 /**
  * Progress information of a secure-join handshake from the view of the inviter
  * (Alice, the person who shows the QR code).
@@ -4783,13 +4824,22 @@ void dc_event_unref(dc_event_t* event);
  */
 #define DC_EVENT_SECUREJOIN_JOINER_PROGRESS       2061
 
+
+/**
+ * The connectivity to the IMAP server changed.
+ *
+ * @param data1 (int) The new connectivity, one of the DC_CONNECTIVITY_* constants.
+ * @param data2 0
+ */
+#define DC_EVENT_CONNECTIVITY_CHANGED             2100
+
 /**
  * @}
  */
 
 
 #define DC_EVENT_DATA1_IS_STRING(e)  0    // not used anymore 
-#define DC_EVENT_DATA2_IS_STRING(e)  ((e)==DC_EVENT_CONFIGURE_PROGRESS || (e)==DC_EVENT_IMEX_FILE_WRITTEN || ((e)>=100 && (e)<=499))
+#define DC_EVENT_DATA2_IS_STRING(e)  ((e)==DC_EVENT_CONFIGURE_PROGRESS || (e)==DC_EVENT_IMEX_FILE_WRITTEN || (e)==DC_EVENT_MSG_MOVED_TO_MVBOX || ((e)>=100 && (e)<=499))
 
 
 /*
@@ -4800,6 +4850,14 @@ void dc_event_unref(dc_event_t* event);
 #define DC_SHOW_EMAILS_ALL               2
 
 
+/*
+ * Values for data1 of #DC_EVENT_CONNECTIVITY_CHANGED
+ */
+#define DC_CONNECTIVITY_NOT_CONNECTED 1000
+#define DC_CONNECTIVITY_CONNECTING    2000
+#define DC_CONNECTIVITY_CONNECTED     4000
+
+
 /*
  * Values for dc_get|set_config("media_quality")
  */
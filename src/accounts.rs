@@ -1,17 +1,22 @@
 use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::time::{Duration, Instant};
 
 use async_std::fs;
+use async_std::io;
 use async_std::path::PathBuf;
 use async_std::prelude::*;
 use async_std::sync::{Arc, RwLock};
+use async_std::task;
 use uuid::Uuid;
 
-use anyhow::{ensure, Context as _};
+use anyhow::{bail, ensure, format_err, Context as _};
+use fs2::FileExt;
 use serde::{Deserialize, Serialize};
 
 use crate::context::Context;
 use crate::error::Result;
-use crate::events::Event;
+use crate::events::{Event, EventType};
 
 /// Account manager, that can handle multiple accounts in a single place.
 #[derive(Debug, Clone)]
@@ -19,6 +24,37 @@ pub struct Accounts {
     dir: PathBuf,
     config: Config,
     accounts: Arc<RwLock<BTreeMap<u32, Context>>>,
+    /// Lifecycle states of the accounts, see `AccountState`.
+    states: Arc<RwLock<BTreeMap<u32, AccountState>>>,
+    /// Lock on the directory, not taken for read-only instances.
+    lock: Option<Arc<AccountsLock>>,
+}
+
+/// Lifecycle state of an account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountState {
+    /// The account can be used.
+    Active,
+    /// The account is being removed, its data may be partially deleted.
+    Removing,
+    /// The account has been removed.
+    Removed,
+}
+
+/// Error importing a backup with `Accounts::import_account()`.
+#[derive(Debug, thiserror::Error)]
+pub enum ImportAccountError {
+    /// The backup could not be imported, the new account was removed.
+    #[error("Failed to import backup: {0:#}")]
+    Import(anyhow::Error),
+    /// The backup could not be imported and the new account could not
+    /// be removed.
+    #[error("Failed to import backup: {import:#}, failed to remove account {id}: {cleanup:#}")]
+    Cleanup {
+        id: u32,
+        import: anyhow::Error,
+        cleanup: anyhow::Error,
+    },
 }
 
 impl Accounts {
@@ -50,27 +86,113 @@ impl Accounts {
 
     /// Opens an existing accounts structure. Will error if the folder doesn't exist,
     /// no account exists and no config exists.
+    ///
+    /// Fails if another instance has the directory open.
     pub async fn open(dir: PathBuf) -> Result<Self> {
+        Accounts::open_with(dir, OpenMode::Normal).await
+    }
+
+    /// Opens an existing accounts structure, even if another instance
+    /// has the directory open.
+    ///
+    /// The lock is released when the process owning it exits, even if
+    /// it crashed, so this is not needed to recover from crashes.
+    /// Both instances share the accounts config file, but using the
+    /// same account from both leads to conflicting writes.
+    pub async fn force_open(dir: PathBuf) -> Result<Self> {
+        Accounts::open_with(dir, OpenMode::Force).await
+    }
+
+    /// Opens an existing accounts structure for inspection only.
+    ///
+    /// Account databases are opened read-only, and all methods which
+    /// would modify the accounts or their configuration return an
+    /// error.
+    pub async fn open_read_only(dir: PathBuf) -> Result<Self> {
+        Accounts::open_with(dir, OpenMode::ReadOnly).await
+    }
+
+    async fn open_with(dir: PathBuf, mode: OpenMode) -> Result<Self> {
         ensure!(dir.exists().await, "directory does not exist");
 
         let config_file = dir.join(CONFIG_NAME);
         ensure!(config_file.exists().await, "accounts.toml does not exist");
 
-        let config = Config::from_file(config_file).await?;
+        let lock = match mode {
+            OpenMode::Normal => Some(Arc::new(AccountsLock::acquire(&dir, false).await?)),
+            OpenMode::Force => Some(Arc::new(AccountsLock::acquire(&dir, true).await?)),
+            OpenMode::ReadOnly => None,
+        };
+
+        let mut config = Config::from_file(config_file).await?;
+        config.read_only = mode == OpenMode::ReadOnly;
         let accounts = config.load_accounts().await?;
+        let states = accounts
+            .keys()
+            .map(|id| (*id, AccountState::Active))
+            .collect();
 
         Ok(Self {
             dir,
             config,
             accounts: Arc::new(RwLock::new(accounts)),
+            states: Arc::new(RwLock::new(states)),
+            lock,
         })
     }
 
     /// Get an account by its `id`:
+    ///
+    /// Returns `None` for accounts which are being removed.
     pub async fn get_account(&self, id: u32) -> Option<Context> {
+        if self.get_account_state(id).await != Some(AccountState::Active) {
+            return None;
+        }
         self.accounts.read().await.get(&id).cloned()
     }
 
+    /// Get the lifecycle state of an account.
+    ///
+    /// Returns `None` if no account with this `id` was ever known.
+    pub async fn get_account_state(&self, id: u32) -> Option<AccountState> {
+        self.states.read().await.get(&id).copied()
+    }
+
+    /// Returns true if the accounts were opened with `Accounts::open_read_only()`.
+    pub fn is_read_only(&self) -> bool {
+        self.config.read_only
+    }
+
+    fn ensure_writable(&self) -> Result<()> {
+        ensure!(!self.is_read_only(), "accounts are opened read-only");
+        Ok(())
+    }
+
+    /// Start IO of a single account.
+    ///
+    /// Fails if the account is being removed or does not exist.
+    pub async fn start_account_io(&self, id: u32) -> Result<()> {
+        self.ensure_writable()?;
+        // Hold the lock while starting, so `remove_account()` waits
+        // and stops IO afterwards.
+        let states = self.states.read().await;
+        match states.get(&id) {
+            Some(AccountState::Active) => {}
+            Some(AccountState::Removing) => bail!("account {} is being removed", id),
+            Some(AccountState::Removed) => bail!("account {} has been removed", id),
+            None => bail!("no account with this id: {}", id),
+        }
+        let ctx = self
+            .accounts
+            .read()
+            .await
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| format_err!("no account with this id: {}", id))?;
+        ctx.start_io().await;
+        Ok(())
+    }
+
     /// Get the currently selected account.
     pub async fn get_selected_account(&self) -> Context {
         let id = self.config.get_selected_account().await;
@@ -84,6 +206,7 @@ impl Accounts {
 
     /// Select the given account.
     pub async fn select_account(&self, id: u32) -> Result<()> {
+        self.ensure_writable()?;
         self.config.select_account(id).await?;
 
         Ok(())
@@ -91,35 +214,87 @@ impl Accounts {
 
     /// Add a new account.
     pub async fn add_account(&self) -> Result<u32> {
+        self.ensure_writable()?;
         let os_name = self.config.os_name().await;
         let account_config = self.config.new_account(&self.dir).await?;
 
         let ctx = Context::new(os_name, account_config.dbfile().into(), account_config.id).await?;
         self.accounts.write().await.insert(account_config.id, ctx);
+        self.states
+            .write()
+            .await
+            .insert(account_config.id, AccountState::Active);
 
         Ok(account_config.id)
     }
 
     /// Remove an account.
+    ///
+    /// While the account is removed, `get_account()` returns `None` and
+    /// `start_account_io()` fails.
     pub async fn remove_account(&self, id: u32) -> Result<()> {
-        let ctx = self.accounts.write().await.remove(&id);
-        ensure!(ctx.is_some(), "no account with this id: {}", id);
-        let ctx = ctx.unwrap();
+        self.ensure_writable()?;
+        {
+            let states = &mut *self.states.write().await;
+            match states.get(&id) {
+                Some(AccountState::Active) => {}
+                Some(AccountState::Removing) => bail!("account {} is being removed", id),
+                Some(AccountState::Removed) | None => bail!("no account with this id: {}", id),
+            }
+            states.insert(id, AccountState::Removing);
+        }
+
+        let ctx = match self.accounts.write().await.remove(&id) {
+            Some(ctx) => ctx,
+            None => {
+                self.states.write().await.remove(&id);
+                bail!("no account with this id: {}", id);
+            }
+        };
+        let io_was_running = ctx.is_io_running().await;
         ctx.stop_io().await;
         drop(ctx);
 
         if let Some(cfg) = self.config.get_account(id).await {
-            fs::remove_dir_all(async_std::path::PathBuf::from(&cfg.dir))
-                .await
-                .context("failed to remove account data")?;
+            if let Err(err) = fs::remove_dir_all(async_std::path::PathBuf::from(&cfg.dir)).await {
+                let err = format_err!("failed to remove account data: {}", err);
+                return match self.restore_account(&cfg, io_was_running).await {
+                    Ok(()) => Err(err),
+                    Err(restore_err) => Err(err.context(restore_err)),
+                };
+            }
         }
         self.config.remove_account(id).await?;
+        self.states.write().await.insert(id, AccountState::Removed);
 
         Ok(())
     }
 
+    /// Reopens an account whose removal failed, so it can be used again.
+    async fn restore_account(&self, cfg: &AccountConfig, start_io: bool) -> Result<()> {
+        let os_name = self.config.os_name().await;
+        let ctx = match Context::new(os_name, cfg.dbfile().into(), cfg.id).await {
+            Ok(ctx) => ctx,
+            Err(err) => {
+                // The account is loaded again on the next start.
+                self.states.write().await.remove(&cfg.id);
+                return Err(err.context("failed to reopen account"));
+            }
+        };
+        if start_io {
+            ctx.start_io().await;
+        }
+        self.accounts.write().await.insert(cfg.id, ctx);
+        self.states
+            .write()
+            .await
+            .insert(cfg.id, AccountState::Active);
+        Ok(())
+    }
+
     /// Migrate an existing account into this structure.
     pub async fn migrate_account(&self, dbfile: PathBuf) -> Result<u32> {
+        self.ensure_writable()?;
         let blobdir = Context::derive_blobdir(&dbfile);
 
         ensure!(
@@ -158,6 +333,10 @@ impl Accounts {
                 )
                 .await?;
                 self.accounts.write().await.insert(account_config.id, ctx);
+                self.states
+                    .write()
+                    .await
+                    .insert(account_config.id, AccountState::Active);
                 Ok(account_config.id)
             }
             Err(err) => {
@@ -176,33 +355,324 @@ impl Accounts {
         }
     }
 
+    /// Get the stored configuration of an account by its `id`.
+    pub async fn get_account_config(&self, id: u32) -> Option<AccountConfig> {
+        self.config.get_account(id).await
+    }
+
+    /// Set the color used by UIs to represent the account.
+    ///
+    /// This is metadata for UIs only and is not used by the core.
+    pub async fn set_account_color(&self, id: u32, color: Option<u32>) -> Result<()> {
+        self.ensure_writable()?;
+        self.config
+            .update_account(id, |account| account.color = color)
+            .await
+    }
+
+    /// Set the avatar used by UIs to represent the account.
+    ///
+    /// This is metadata for UIs only and is not used by the core.
+    pub async fn set_account_avatar_path(
+        &self,
+        id: u32,
+        avatar_path: Option<std::path::PathBuf>,
+    ) -> Result<()> {
+        self.ensure_writable()?;
+        self.config
+            .update_account(id, |account| account.avatar_path = avatar_path)
+            .await
+    }
+
+    /// Set the label used by UIs to represent the account.
+    ///
+    /// This is metadata for UIs only and is not used by the core.
+    /// Empty names are stored as `None`.
+    pub async fn set_account_name(&self, id: u32, name: Option<String>) -> Result<()> {
+        self.ensure_writable()?;
+        let name = name.filter(|name| !name.trim().is_empty());
+        self.config
+            .update_account(id, |account| account.name = name)
+            .await
+    }
+
+    /// Get the label set with `set_account_name()`, if any.
+    pub async fn get_account_name(&self, id: u32) -> Option<String> {
+        self.config.get_account(id).await?.name
+    }
+
     /// Get a list of all account ids.
+    ///
+    /// The ids are in the order set with `set_account_order()`,
+    /// followed by the remaining accounts in creation order.
     pub async fn get_all(&self) -> Vec<u32> {
-        self.accounts.read().await.keys().copied().collect()
+        let mut ids: Vec<u32> = self.accounts.read().await.keys().copied().collect();
+        sort_accounts(&mut ids, &self.config.account_order().await);
+        ids
+    }
+
+    /// Sets the order of the accounts returned by `get_all()`,
+    /// e.g. after the user rearranged them.
+    ///
+    /// `ids` must contain each existing account exactly once.
+    pub async fn set_account_order(&self, ids: Vec<u32>) -> Result<()> {
+        self.ensure_writable()?;
+        self.config.set_account_order(ids).await
     }
 
     /// Import a backup using a new account and selects it.
+    ///
+    /// Returns the id of the new account. If the backup can not be
+    /// imported, the new account is removed and the previously selected
+    /// account is selected again; the error is then an
+    /// `ImportAccountError`.
     pub async fn import_account(&self, file: PathBuf) -> Result<u32> {
+        self.ensure_writable()?;
         let old_id = self.config.get_selected_account().await;
 
         let id = self.add_account().await?;
         let ctx = self.get_account(id).await.expect("just added");
 
-        match crate::imex::imex(&ctx, crate::imex::ImexMode::ImportBackup, &file).await {
+        let import = crate::imex::imex(&ctx, crate::imex::ImexMode::ImportBackup, &file).await;
+        drop(ctx);
+        match import {
             Ok(_) => Ok(id),
-            Err(err) => {
-                // remove temp account
-                self.remove_account(id).await?;
-                // set selection back
-                self.select_account(old_id).await?;
-                Err(err)
+            Err(import) => {
+                // remove temp account, but set selection back in any case
+                let removed = self.remove_account(id).await;
+                let restored = if self.config.get_account(old_id).await.is_some() {
+                    self.select_account(old_id).await
+                } else {
+                    Ok(())
+                };
+                match removed.and(restored) {
+                    Ok(()) => Err(ImportAccountError::Import(import).into()),
+                    Err(cleanup) => Err(ImportAccountError::Cleanup {
+                        id,
+                        import,
+                        cleanup,
+                    }
+                    .into()),
+                }
+            }
+        }
+    }
+
+    /// Exports backups of all configured accounts into a single archive
+    /// at `dest`, e.g. to move a whole installation to a new device.
+    ///
+    /// The archive contains a copy of the accounts config and one
+    /// `imex()` backup per account. If `passphrase` is given, the archive
+    /// is symmetrically encrypted with it. IO of each account is stopped
+    /// during its export and restarted afterwards if it was running.
+    ///
+    /// Unconfigured accounts are skipped. The progress across all
+    /// accounts is reported by `AccountsImexProgress` events.
+    pub async fn export_all(&self, dest: PathBuf, passphrase: Option<String>) -> Result<()> {
+        let tmp = self
+            .dir
+            .join(format!("export-{}", Uuid::new_v4().to_simple_ref()));
+        fs::create_dir_all(&tmp).await?;
+        let res = self.export_all_inner(&tmp, &dest, passphrase).await;
+        fs::remove_dir_all(&tmp).await.ok();
+        if res.is_err() {
+            self.emit_imex_progress(None, 0).await;
+        }
+        res
+    }
+
+    async fn export_all_inner(
+        &self,
+        tmp: &PathBuf,
+        dest: &PathBuf,
+        passphrase: Option<String>,
+    ) -> Result<()> {
+        let archive_path = tmp.join(EXPORT_ARCHIVE_NAME);
+        let mut builder = async_tar::Builder::new(fs::File::create(&archive_path).await?);
+        builder
+            .append_path_with_name(&self.config.file, CONFIG_NAME)
+            .await?;
+
+        let ids = self.get_all().await;
+        for (i, id) in ids.iter().enumerate() {
+            let ctx = match self.get_account(*id).await {
+                Some(ctx) => ctx,
+                None => continue,
+            };
+            if !ctx.is_configured().await {
+                warn!(ctx, "Account {} is not configured, not exporting it.", id);
+                continue;
+            }
+
+            let was_running = ctx.is_io_running().await;
+            ctx.stop_io().await;
+            let account_dir = tmp.join(id.to_string());
+            let res =
+                crate::imex::imex(&ctx, crate::imex::ImexMode::ExportBackup, &account_dir).await;
+            if was_running {
+                ctx.start_io().await;
+            }
+            res.with_context(|| format!("failed to export account {}", id))?;
+
+            let backup: PathBuf = crate::imex::has_backup(&ctx, &account_dir).await?.into();
+            let name = backup
+                .file_name()
+                .ok_or_else(|| format_err!("invalid backup name"))?;
+            builder
+                .append_path_with_name(&backup, PathBuf::from(id.to_string()).join(name))
+                .await?;
+            fs::remove_file(&backup).await?;
+
+            let progress = (i + 1) * 999 / ids.len();
+            self.emit_imex_progress(Some(&ctx), progress).await;
+        }
+        builder.into_inner().await?.flush().await?;
+
+        match passphrase {
+            Some(passphrase) => {
+                encrypt_file(&passphrase, &archive_path, dest, EXPORT_CHUNK_SIZE).await?;
+            }
+            None => {
+                fs::copy(&archive_path, dest).await?;
+            }
+        }
+        self.emit_imex_progress(None, 1000).await;
+        Ok(())
+    }
+
+    /// Imports an archive written by `export_all()`, adding a new account
+    /// for each account contained.
+    ///
+    /// Names and colors of the accounts are restored and the account
+    /// selected at the time of the export is selected again. Returns the
+    /// ids of the new accounts. If an account can not be imported, the
+    /// import stops; accounts imported before are kept.
+    pub async fn import_all(&self, file: PathBuf, passphrase: Option<String>) -> Result<Vec<u32>> {
+        self.ensure_writable()?;
+        let tmp = self
+            .dir
+            .join(format!("import-{}", Uuid::new_v4().to_simple_ref()));
+        fs::create_dir_all(&tmp).await?;
+        let res = self.import_all_inner(&tmp, &file, passphrase).await;
+        fs::remove_dir_all(&tmp).await.ok();
+        if res.is_err() {
+            self.emit_imex_progress(None, 0).await;
+        }
+        res
+    }
+
+    async fn import_all_inner(
+        &self,
+        tmp: &PathBuf,
+        file: &PathBuf,
+        passphrase: Option<String>,
+    ) -> Result<Vec<u32>> {
+        let archive_path = match passphrase {
+            Some(passphrase) => {
+                let archive_path = tmp.join(EXPORT_ARCHIVE_NAME);
+                decrypt_file(&passphrase, file, &archive_path).await?;
+                archive_path
+            }
+            None => file.clone(),
+        };
+        let unpacked = tmp.join("unpacked");
+        async_tar::Archive::new(fs::File::open(&archive_path).await?)
+            .unpack(&unpacked)
+            .await
+            .context("failed to unpack archive")?;
+
+        let bytes = fs::read(unpacked.join(CONFIG_NAME))
+            .await
+            .context("archive contains no accounts config")?;
+        let exported: InnerConfig = toml::from_slice(&bytes).context("failed to parse config")?;
+        let mut old_ids: Vec<u32> = exported.accounts.iter().map(|e| e.id).collect();
+        sort_accounts(&mut old_ids, &exported.order);
+
+        let mut backups = Vec::new();
+        for old_id in old_ids {
+            let account_dir = unpacked.join(old_id.to_string());
+            if let Ok(mut entries) = fs::read_dir(&account_dir).await {
+                if let Some(entry) = entries.next().await {
+                    backups.push((old_id, entry?.path()));
+                }
+            }
+        }
+
+        let mut imported = Vec::new();
+        let mut selected = None;
+        for (i, (old_id, backup)) in backups.iter().enumerate() {
+            let id = self.import_account(backup.clone()).await?;
+            imported.push(id);
+            if let Some(old) = exported.accounts.iter().find(|e| e.id == *old_id) {
+                let (color, name) = (old.color, old.name.clone());
+                self.config
+                    .update_account(id, |account| {
+                        account.color = color;
+                        account.name = name;
+                    })
+                    .await?;
+            }
+            if *old_id == exported.selected_account {
+                selected = Some(id);
+            }
+            let ctx = self.get_account(id).await;
+            self.emit_imex_progress(ctx.as_ref(), (i + 1) * 999 / backups.len())
+                .await;
+        }
+        if let Some(id) = selected {
+            self.select_account(id).await?;
+        }
+
+        self.emit_imex_progress(None, 1000).await;
+        Ok(imported)
+    }
+
+    /// Emits an `AccountsImexProgress` event on `ctx` or, if `None`, on
+    /// the selected account.
+    async fn emit_imex_progress(&self, ctx: Option<&Context>, progress: usize) {
+        let ctx = match ctx {
+            Some(ctx) => Some(ctx.clone()),
+            None => {
+                let id = self.config.get_selected_account().await;
+                self.accounts.read().await.get(&id).cloned()
             }
+        };
+        if let Some(ctx) = ctx {
+            ctx.emit_event(EventType::AccountsImexProgress {
+                progress,
+                account_id: ctx.get_id(),
+            });
         }
     }
 
+    /// Sets the delay between starting IO of two accounts in `start_io()`,
+    /// for providers limiting the rate of new connections.
+    ///
+    /// Defaults to 0, which starts all accounts at once.
+    pub async fn set_account_start_stagger_ms(&self, stagger_ms: u64) -> Result<()> {
+        self.ensure_writable()?;
+        self.config.set_account_start_stagger_ms(stagger_ms).await
+    }
+
+    /// Returns the delay set with `set_account_start_stagger_ms()`.
+    pub async fn get_account_start_stagger_ms(&self) -> u64 {
+        self.config.account_start_stagger_ms().await
+    }
+
+    /// Starts IO of all accounts.
+    ///
+    /// Does nothing if the accounts are opened read-only.
     pub async fn start_io(&self) {
+        if self.is_read_only() {
+            return;
+        }
+        let stagger = Duration::from_millis(self.config.account_start_stagger_ms().await);
         let accounts = &*self.accounts.read().await;
-        for account in accounts.values() {
+        for (i, account) in accounts.values().enumerate() {
+            let delay = account_start_delay(i, stagger);
+            if delay > Duration::from_millis(0) {
+                task::sleep(delay).await;
+            }
             account.start_io().await;
         }
     }
@@ -233,6 +703,41 @@ impl Accounts {
 
         EventEmitter(futures::stream::select_all(emitters))
     }
+
+    /// Event emitter without any account attached.
+    ///
+    /// Accounts are attached with `Accounts::add_account_to_emitter()`
+    /// and detached with `LazyEventEmitter::remove_account()`, so only
+    /// the accounts watched by the UI are merged.
+    pub fn get_lazy_event_emitter(&self) -> LazyEventEmitter {
+        LazyEventEmitter {
+            emitters: Vec::new(),
+            next_idx: 0,
+        }
+    }
+
+    /// Attaches the account with the given `id` to `emitter`.
+    ///
+    /// Does nothing if the account is already attached.
+    pub async fn add_account_to_emitter(
+        &self,
+        emitter: &mut LazyEventEmitter,
+        id: u32,
+    ) -> Result<()> {
+        if emitter
+            .emitters
+            .iter()
+            .any(|(account_id, _)| *account_id == id)
+        {
+            return Ok(());
+        }
+        let ctx = self
+            .get_account(id)
+            .await
+            .ok_or_else(|| format_err!("no account with this id: {}", id))?;
+        emitter.emitters.push((id, ctx.get_event_emitter()));
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -261,13 +766,288 @@ impl async_std::stream::Stream for EventEmitter {
     }
 }
 
+/// Event emitter merging the events of the attached accounts,
+/// see `Accounts::get_lazy_event_emitter()`.
+#[derive(Debug)]
+pub struct LazyEventEmitter {
+    emitters: Vec<(u32, crate::events::EventEmitter)>,
+    /// Index of the emitter polled first, so all accounts get a turn.
+    next_idx: usize,
+}
+
+impl LazyEventEmitter {
+    /// Detaches the account with the given `id`.
+    ///
+    /// Returns false if the account was not attached.
+    pub fn remove_account(&mut self, id: u32) -> bool {
+        let len = self.emitters.len();
+        self.emitters.retain(|(account_id, _)| *account_id != id);
+        self.emitters.len() != len
+    }
+
+    /// Returns the ids of the attached accounts.
+    pub fn account_ids(&self) -> Vec<u32> {
+        self.emitters.iter().map(|(id, _)| *id).collect()
+    }
+
+    /// Blocking recv of an event. Return `None` if no account is attached.
+    pub fn recv_sync(&mut self) -> Option<Event> {
+        async_std::task::block_on(self.recv())
+    }
+
+    /// Async recv of an event. Return `None` if no account is attached.
+    pub async fn recv(&mut self) -> Option<Event> {
+        self.next().await
+    }
+}
+
+impl async_std::stream::Stream for LazyEventEmitter {
+    type Item = Event;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let len = this.emitters.len();
+        let mut closed = Vec::new();
+        let mut res = std::task::Poll::Pending;
+        for i in 0..len {
+            let idx = (this.next_idx + i) % len;
+            let (id, emitter) = &mut this.emitters[idx];
+            match std::pin::Pin::new(emitter).poll_next(cx) {
+                std::task::Poll::Ready(Some(event)) => {
+                    this.next_idx = idx + 1;
+                    res = std::task::Poll::Ready(Some(event));
+                    break;
+                }
+                std::task::Poll::Ready(None) => closed.push(*id),
+                std::task::Poll::Pending => {}
+            }
+        }
+        this.emitters.retain(|(id, _)| !closed.contains(id));
+        if res.is_pending() && this.emitters.is_empty() {
+            return std::task::Poll::Ready(None);
+        }
+        res
+    }
+}
+
 pub const CONFIG_NAME: &str = "accounts.toml";
 pub const DB_NAME: &str = "dc.db";
+pub const LOCK_NAME: &str = "accounts.lock";
+/// Name of the archive built by `Accounts::export_all()` before encryption.
+const EXPORT_ARCHIVE_NAME: &str = "accounts-backup.tar";
+/// Lockfile held while modifying the accounts config, see `ConfigFileLock`.
+const CONFIG_LOCK_NAME: &str = "accounts.toml.lock";
+/// Number of archive bytes encrypted into one OpenPGP message by
+/// `Accounts::export_all()`, bounding the memory used for large archives.
+const EXPORT_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Length of the header prepended to the plaintext of each chunk written
+/// by `encrypt_file()`: a random file id, the chunk index and the number
+/// of chunks, the latter two as big-endian `u32`.
+const CHUNK_HEADER_LEN: usize = 16 + 4 + 4;
+
+/// Encrypts `src` to `dest` as a sequence of armored OpenPGP messages,
+/// each holding up to `chunk_size` bytes of `src`.
+///
+/// Every chunk starts with a header binding it to its position in the
+/// file, so that `decrypt_file()` detects chunks that were dropped,
+/// reordered or taken from another file.
+async fn encrypt_file(
+    passphrase: &str,
+    src: &PathBuf,
+    dest: &PathBuf,
+    chunk_size: usize,
+) -> Result<()> {
+    let total_len = fs::metadata(src).await?.len();
+    let count = std::cmp::max(1, (total_len + chunk_size as u64 - 1) / chunk_size as u64);
+    let count = u32::try_from(count).context("file has too many chunks")?;
+    let file_id = Uuid::new_v4();
+
+    let mut reader = fs::File::open(src).await?;
+    let mut writer = io::BufWriter::new(fs::File::create(dest).await?);
+    let mut chunk = vec![0; CHUNK_HEADER_LEN + chunk_size];
+    chunk[..16].copy_from_slice(file_id.as_bytes());
+    chunk[20..24].copy_from_slice(&count.to_be_bytes());
+    for index in 0..count {
+        chunk[16..20].copy_from_slice(&index.to_be_bytes());
+        let mut len = CHUNK_HEADER_LEN;
+        while len < chunk.len() {
+            match reader.read(&mut chunk[len..]).await? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        let encrypted = crate::pgp::symm_encrypt(passphrase, &chunk[..len]).await?;
+        writer.write_all(encrypted.as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+    }
+    ensure!(
+        reader.read(&mut [0; 1]).await? == 0,
+        "file grew while encrypting it"
+    );
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Decrypts a file written by `encrypt_file()` to `dest`, one message at a
+/// time.
+async fn decrypt_file(passphrase: &str, src: &PathBuf, dest: &PathBuf) -> Result<()> {
+    let mut lines = io::BufReader::new(fs::File::open(src).await?).lines();
+    let mut writer = io::BufWriter::new(fs::File::create(dest).await?);
+    let mut block = String::new();
+    let mut file_id = None;
+    let mut count = None;
+    let mut index: u32 = 0;
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        block.push_str(&line);
+        block.push('\n');
+        if line.starts_with("-----END PGP MESSAGE-----") {
+            let plain =
+                crate::pgp::symm_decrypt(passphrase, std::io::Cursor::new(block.as_bytes()))
+                    .await?;
+            ensure!(plain.len() >= CHUNK_HEADER_LEN, "chunk has no header");
+            let (header, content) = plain.split_at(CHUNK_HEADER_LEN);
+            let id: [u8; 16] = header[..16].try_into()?;
+            let chunk_index = u32::from_be_bytes(header[16..20].try_into()?);
+            let chunk_count = u32::from_be_bytes(header[20..24].try_into()?);
+            ensure!(
+                *file_id.get_or_insert(id) == id
+                    && *count.get_or_insert(chunk_count) == chunk_count,
+                "chunk {} belongs to another file",
+                index
+            );
+            ensure!(
+                chunk_index == index && index < chunk_count,
+                "expected chunk {}, got chunk {} of {}",
+                index,
+                chunk_index,
+                chunk_count
+            );
+            writer.write_all(content).await?;
+            index += 1;
+            block.clear();
+        }
+    }
+    ensure!(
+        block.trim().is_empty(),
+        "archive ends with an incomplete message"
+    );
+    ensure!(
+        count == Some(index),
+        "archive is truncated after chunk {}",
+        index
+    );
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Returns how long `Accounts::start_io()` waits before starting the
+/// account at `index`, given the configured `stagger`.
+fn account_start_delay(index: usize, stagger: Duration) -> Duration {
+    if index == 0 {
+        Duration::from_millis(0)
+    } else {
+        stagger
+    }
+}
+
+/// How long to wait for `CONFIG_LOCK_NAME` held by another process.
+const CONFIG_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OpenMode {
+    Normal,
+    Force,
+    ReadOnly,
+}
+
+/// Opens or creates the lockfile at `path` and tries to take an
+/// exclusive advisory lock on it.
+///
+/// The lock is held until the returned file is closed. It is released
+/// by the operating system also if the process crashes, so a lockfile
+/// left behind is never stale. Returns `None` if another instance holds
+/// the lock.
+fn try_lock_file(path: &std::path::Path) -> std::io::Result<Option<std::fs::File>> {
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(path)?;
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(Some(file)),
+        Err(err) if err.kind() == fs2::lock_contended_error().kind() => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Lock preventing two `Accounts` instances from using the same directory.
+///
+/// The lock on `LOCK_NAME` is released when the last clone of the
+/// `Accounts` is dropped. The file itself is kept, removing it could
+/// let two instances lock different files.
+#[derive(Debug)]
+struct AccountsLock {
+    /// The locked file, `None` if opened with `Accounts::force_open()`
+    /// while another instance holds the lock.
+    _file: Option<std::fs::File>,
+}
+
+impl AccountsLock {
+    async fn acquire(dir: &PathBuf, force: bool) -> Result<Self> {
+        let path: std::path::PathBuf = dir.join(LOCK_NAME).into();
+        let file = try_lock_file(&path).context("failed to lock accounts directory")?;
+        if file.is_none() && !force {
+            bail!("accounts directory is locked by another instance");
+        }
+        if let Some(mut file) = file.as_ref() {
+            // For debugging only, the content is not used for locking.
+            use std::io::Write as _;
+            file.set_len(0).ok();
+            write!(file, "{}", std::process::id()).ok();
+        }
+        Ok(AccountsLock { _file: file })
+    }
+}
+
+/// Short-lived lock serializing modifications of the accounts config,
+/// so processes sharing an accounts directory do not overwrite each
+/// other's changes or use the same account id.
+///
+/// The lock is released on drop.
+#[derive(Debug)]
+struct ConfigFileLock {
+    _file: std::fs::File,
+}
+
+impl ConfigFileLock {
+    async fn acquire(path: PathBuf) -> Result<Self> {
+        let path: std::path::PathBuf = path.into();
+        let start = Instant::now();
+        loop {
+            if let Some(file) = try_lock_file(&path).context("failed to lock config")? {
+                return Ok(ConfigFileLock { _file: file });
+            }
+            ensure!(
+                start.elapsed() < CONFIG_LOCK_TIMEOUT,
+                "timeout waiting for the lock on {}",
+                path.display()
+            );
+            task::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Config {
     file: PathBuf,
     inner: Arc<RwLock<InnerConfig>>,
+    /// If set, the configuration is never written to disk.
+    read_only: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -277,6 +1057,13 @@ struct InnerConfig {
     pub selected_account: u32,
     pub next_id: u32,
     pub accounts: Vec<AccountConfig>,
+    /// Delay between starting IO of two accounts, in milliseconds.
+    #[serde(default)]
+    pub account_start_stagger_ms: u64,
+    /// Order of the accounts set by the user, see
+    /// `Accounts::set_account_order()`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub order: Vec<u32>,
 }
 
 impl Config {
@@ -288,10 +1075,13 @@ impl Config {
                 accounts: Vec::new(),
                 selected_account: 0,
                 next_id: 1,
+                account_start_stagger_ms: 0,
+                order: Vec::new(),
             })),
+            read_only: false,
         };
 
-        cfg.sync().await?;
+        cfg.write(&*cfg.inner.read().await).await?;
 
         Ok(cfg)
     }
@@ -300,14 +1090,40 @@ impl Config {
         self.inner.read().await.os_name.clone()
     }
 
-    /// Sync the inmemory representation to disk.
-    async fn sync(&self) -> Result<()> {
-        fs::write(
-            &self.file,
-            toml::to_string_pretty(&*self.inner.read().await)?,
-        )
-        .await
-        .context("failed to write config")
+    /// Writes `inner` to disk.
+    ///
+    /// The file is replaced atomically, so processes reading it without
+    /// the lock never see a partially written config.
+    async fn write(&self, inner: &InnerConfig) -> Result<()> {
+        ensure!(!self.read_only, "accounts config is read-only");
+        let tmp = self.file.with_extension("toml.tmp");
+        fs::write(&tmp, toml::to_string_pretty(inner)?)
+            .await
+            .context("failed to write config")?;
+        fs::rename(&tmp, &self.file)
+            .await
+            .context("failed to write config")
+    }
+
+    /// Modifies the config with `f` and writes it to disk.
+    ///
+    /// The config file is locked and re-read first, so changes written
+    /// by other processes sharing the accounts directory, e.g. added or
+    /// removed accounts and the id counter, are not overwritten.
+    async fn update<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut InnerConfig) -> Result<R>,
+    {
+        ensure!(!self.read_only, "accounts config is read-only");
+        let _lock = ConfigFileLock::acquire(self.file.with_file_name(CONFIG_LOCK_NAME)).await?;
+        let inner = &mut *self.inner.write().await;
+        if self.file.exists().await {
+            let bytes = fs::read(&self.file).await.context("failed to read file")?;
+            *inner = toml::from_slice(&bytes).context("failed to parse config")?;
+        }
+        let res = f(inner)?;
+        self.write(inner).await?;
+        Ok(res)
     }
 
     /// Read a configuration from the given file into memory.
@@ -318,6 +1134,7 @@ impl Config {
         Ok(Config {
             file,
             inner: Arc::new(RwLock::new(inner)),
+            read_only: false,
         })
     }
 
@@ -325,12 +1142,12 @@ impl Config {
         let cfg = &*self.inner.read().await;
         let mut accounts = BTreeMap::new();
         for account_config in &cfg.accounts {
-            let ctx = Context::new(
-                cfg.os_name.clone(),
-                account_config.dbfile().into(),
-                account_config.id,
-            )
-            .await?;
+            let dbfile: PathBuf = account_config.dbfile().into();
+            let ctx = if self.read_only {
+                Context::new_read_only(cfg.os_name.clone(), dbfile, account_config.id).await?
+            } else {
+                Context::new(cfg.os_name.clone(), dbfile, account_config.id).await?
+            };
             accounts.insert(account_config.id, ctx);
         }
 
@@ -339,43 +1156,45 @@ impl Config {
 
     /// Create a new account in the given root directory.
     pub async fn new_account(&self, dir: &PathBuf) -> Result<AccountConfig> {
-        let id = {
-            let inner = &mut self.inner.write().await;
-            let id = inner.next_id;
-            let uuid = Uuid::new_v4();
-            let target_dir = dir.join(uuid.to_simple_ref().to_string());
-
-            inner.accounts.push(AccountConfig {
-                id,
-                dir: target_dir.into(),
-                uuid,
-            });
-            inner.next_id += 1;
-            id
-        };
-
-        self.sync().await?;
+        let id = self
+            .update(|inner| {
+                let id = inner.next_id;
+                let uuid = Uuid::new_v4();
+                let target_dir = dir.join(uuid.to_simple_ref().to_string());
+
+                inner.accounts.push(AccountConfig {
+                    id,
+                    dir: target_dir.into(),
+                    uuid,
+                    color: None,
+                    avatar_path: None,
+                    name: None,
+                });
+                inner.next_id += 1;
+                inner.selected_account = id;
+                Ok(id)
+            })
+            .await?;
 
-        self.select_account(id).await.expect("just added");
         let cfg = self.get_account(id).await.expect("just added");
         Ok(cfg)
     }
 
     /// Removes an existing acccount entirely.
     pub async fn remove_account(&self, id: u32) -> Result<()> {
-        {
-            let inner = &mut *self.inner.write().await;
+        self.update(|inner| {
             if let Some(idx) = inner.accounts.iter().position(|e| e.id == id) {
                 // remove account from the configs
                 inner.accounts.remove(idx);
             }
+            inner.order.retain(|e| *e != id);
             if inner.selected_account == id {
                 // reset selected account
                 inner.selected_account = inner.accounts.get(0).map(|e| e.id).unwrap_or_default();
             }
-        }
-
-        self.sync().await
+            Ok(())
+        })
+        .await
     }
 
     pub async fn get_account(&self, id: u32) -> Option<AccountConfig> {
@@ -388,13 +1207,63 @@ impl Config {
             .cloned()
     }
 
+    /// Modifies the configuration of an existing account and persists it.
+    async fn update_account<F>(&self, id: u32, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut AccountConfig),
+    {
+        self.update(|inner| {
+            let account = inner.accounts.iter_mut().find(|e| e.id == id);
+            ensure!(account.is_some(), "invalid account id: {}", id);
+            f(account.unwrap());
+            Ok(())
+        })
+        .await
+    }
+
     pub async fn get_selected_account(&self) -> u32 {
         self.inner.read().await.selected_account
     }
 
+    pub async fn account_start_stagger_ms(&self) -> u64 {
+        self.inner.read().await.account_start_stagger_ms
+    }
+
+    pub async fn set_account_start_stagger_ms(&self, stagger_ms: u64) -> Result<()> {
+        self.update(|inner| {
+            inner.account_start_stagger_ms = stagger_ms;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn account_order(&self) -> Vec<u32> {
+        self.inner.read().await.order.clone()
+    }
+
+    /// Sets the order of the accounts, `ids` must contain each account
+    /// exactly once.
+    pub async fn set_account_order(&self, ids: Vec<u32>) -> Result<()> {
+        self.update(|inner| {
+            let mut sorted = ids.clone();
+            sorted.sort_unstable();
+            let mut existing: Vec<u32> = inner.accounts.iter().map(|e| e.id).collect();
+            existing.sort_unstable();
+            ensure!(
+                sorted == existing,
+                "account order {:?} does not match the accounts {:?}",
+                ids,
+                existing
+            );
+
+            inner.order = ids;
+            Ok(())
+        })
+        .await
+    }
+
     pub async fn select_account(&self, id: u32) -> Result<()> {
-        {
-            let inner = &mut *self.inner.write().await;
+        self.update(|inner| {
             ensure!(
                 inner.accounts.iter().any(|e| e.id == id),
                 "invalid account id: {}",
@@ -402,13 +1271,23 @@ impl Config {
             );
 
             inner.selected_account = id;
-        }
-
-        self.sync().await?;
-        Ok(())
+            Ok(())
+        })
+        .await
     }
 }
 
+/// Sorts account `ids` by their position in `order`.
+///
+/// Accounts missing in `order`, e.g. added later or read from an older
+/// config, are put at the end, sorted by id.
+fn sort_accounts(ids: &mut Vec<u32>, order: &[u32]) {
+    ids.sort_by_key(|id| {
+        let pos = order.iter().position(|e| e == id).unwrap_or(order.len());
+        (pos, *id)
+    });
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct AccountConfig {
     /// Unique id.
@@ -416,6 +1295,16 @@ pub struct AccountConfig {
     /// Root directory for all data for this account.
     pub dir: std::path::PathBuf,
     pub uuid: Uuid,
+    /// Color used by UIs to represent the account.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<u32>,
+    /// Avatar used by UIs to represent the account.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_path: Option<std::path::PathBuf>,
+    /// Label used by UIs to represent the account, independent of
+    /// the configured address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
 }
 
 impl AccountConfig {
@@ -429,26 +1318,55 @@ impl AccountConfig {
 mod tests {
     use super::*;
 
+    use crate::dc_tools::time;
+
     #[async_std::test]
     async fn test_account_new_open() {
         let dir = tempfile::tempdir().unwrap();
         let p: PathBuf = dir.path().join("accounts1").into();
 
         let accounts1 = Accounts::new("my_os".into(), p.clone()).await.unwrap();
-        let accounts2 = Accounts::open(p).await.unwrap();
-
         assert_eq!(accounts1.accounts.read().await.len(), 1);
         assert_eq!(accounts1.config.get_selected_account().await, 1);
 
-        assert_eq!(accounts1.dir, accounts2.dir);
-        assert_eq!(
-            &*accounts1.config.inner.read().await,
-            &*accounts2.config.inner.read().await,
-        );
-        assert_eq!(
-            accounts1.accounts.read().await.len(),
-            accounts2.accounts.read().await.len()
-        );
+        let dir1 = accounts1.dir.clone();
+        let config1 = accounts1.config.inner.read().await.clone();
+        let len1 = accounts1.accounts.read().await.len();
+        drop(accounts1);
+
+        let accounts2 = Accounts::open(p).await.unwrap();
+        assert_eq!(dir1, accounts2.dir);
+        assert_eq!(config1, *accounts2.config.inner.read().await);
+        assert_eq!(len1, accounts2.accounts.read().await.len());
+    }
+
+    #[async_std::test]
+    async fn test_open_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let p: PathBuf = dir.path().join("accounts").into();
+
+        let accounts1 = Accounts::new("my_os".into(), p.clone()).await.unwrap();
+        let err = Accounts::open(p.clone()).await.unwrap_err();
+        assert!(err.to_string().contains("locked by another instance"));
+
+        // Clones share the lock.
+        let clone = accounts1.clone();
+        drop(accounts1);
+        assert!(Accounts::open(p.clone()).await.is_err());
+        drop(clone);
+
+        let accounts2 = Accounts::open(p.clone()).await.unwrap();
+
+        // Opening anyway does not release the lock of the other instance.
+        let accounts3 = Accounts::force_open(p.clone()).await.unwrap();
+        drop(accounts3);
+        assert!(Accounts::open(p.clone()).await.is_err());
+        drop(accounts2);
+
+        // A lockfile left behind by a crashed process does not block.
+        assert!(p.join(LOCK_NAME).exists().await);
+        fs::write(p.join(LOCK_NAME), "12345").await.unwrap();
+        Accounts::open(p).await.unwrap();
     }
 
     #[async_std::test]
@@ -474,6 +1392,91 @@ mod tests {
         assert_eq!(accounts.accounts.read().await.len(), 1);
     }
 
+    #[async_std::test]
+    async fn test_import_account_corrupt_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let p: PathBuf = dir.path().join("accounts").into();
+
+        let accounts = Accounts::new("my_os".into(), p.clone()).await.unwrap();
+        let id = accounts.add_account().await.unwrap();
+        accounts.select_account(1).await.unwrap();
+
+        let backup: PathBuf = dir.path().join("delta-chat-backup.tar").into();
+        fs::write(&backup, b"not a backup").await.unwrap();
+
+        let err = accounts.import_account(backup).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ImportAccountError>(),
+            Some(ImportAccountError::Import(_))
+        ));
+        assert_eq!(accounts.config.get_selected_account().await, 1);
+        assert_eq!(accounts.get_all().await, vec![1, id]);
+    }
+
+    #[async_std::test]
+    async fn test_lazy_event_emitter() {
+        use crate::events::EventType;
+
+        /// Receives events until all `msgs` arrived, returns the ids of
+        /// the contexts which emitted them.
+        async fn recv_infos(emitter: &mut LazyEventEmitter, msgs: &[&str]) -> Vec<u32> {
+            let mut ids = vec![0; msgs.len()];
+            while ids.contains(&0) {
+                let event = emitter.recv().await.unwrap();
+                if let EventType::Info(msg) = event.typ {
+                    if let Some(idx) = msgs.iter().position(|m| *m == msg) {
+                        ids[idx] = event.id;
+                    }
+                }
+            }
+            ids
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let p: PathBuf = dir.path().join("accounts").into();
+        let accounts = Accounts::new("my_os".into(), p).await.unwrap();
+        let id2 = accounts.add_account().await.unwrap();
+        let ctx1 = accounts.get_account(1).await.unwrap();
+        let ctx2 = accounts.get_account(id2).await.unwrap();
+
+        let mut emitter = accounts.get_lazy_event_emitter();
+        assert!(emitter.recv().await.is_none());
+        assert!(accounts
+            .add_account_to_emitter(&mut emitter, 42)
+            .await
+            .is_err());
+
+        accounts
+            .add_account_to_emitter(&mut emitter, 1)
+            .await
+            .unwrap();
+        ctx1.emit_event(EventType::Info("first".to_string()));
+        assert_eq!(
+            recv_infos(&mut emitter, &["first"]).await,
+            vec![ctx1.get_id()]
+        );
+
+        accounts
+            .add_account_to_emitter(&mut emitter, id2)
+            .await
+            .unwrap();
+        accounts
+            .add_account_to_emitter(&mut emitter, id2)
+            .await
+            .unwrap();
+        assert_eq!(emitter.account_ids(), vec![1, id2]);
+        ctx1.emit_event(EventType::Info("second".to_string()));
+        ctx2.emit_event(EventType::Info("third".to_string()));
+        assert_eq!(
+            recv_infos(&mut emitter, &["second", "third"]).await,
+            vec![ctx1.get_id(), ctx2.get_id()]
+        );
+
+        assert!(emitter.remove_account(1));
+        assert!(!emitter.remove_account(1));
+        assert_eq!(emitter.account_ids(), vec![id2]);
+    }
+
     #[async_std::test]
     async fn test_migrate_account() {
         let dir = tempfile::tempdir().unwrap();
@@ -525,4 +1528,450 @@ mod tests {
             assert_eq!(ids.get(i), Some(&expected_id));
         }
     }
+
+    #[async_std::test]
+    async fn test_concurrent_add_account() {
+        let dir = tempfile::tempdir().unwrap();
+        let p: PathBuf = dir.path().join("accounts").into();
+
+        let accounts1 = Accounts::new("my_os".into(), p.clone()).await.unwrap();
+        // Another instance using the same directory, e.g. in another process.
+        let accounts2 = Accounts::force_open(p.clone()).await.unwrap();
+
+        let adds = (0..10).map(|i| {
+            let accounts = if i % 2 == 0 {
+                accounts1.clone()
+            } else {
+                accounts2.clone()
+            };
+            task::spawn(async move { accounts.add_account().await.unwrap() })
+        });
+        let mut ids = futures::future::join_all(adds).await;
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 10);
+        assert!(!ids.contains(&1));
+        // The config lock is released.
+        let lock: std::path::PathBuf = p.join(CONFIG_LOCK_NAME).into();
+        assert!(try_lock_file(&lock).unwrap().is_some());
+
+        // All accounts are kept in the config file.
+        drop(accounts1);
+        drop(accounts2);
+        let accounts = Accounts::force_open(p).await.unwrap();
+        assert_eq!(accounts.get_all().await.len(), 11);
+    }
+
+    #[async_std::test]
+    async fn test_concurrent_config_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let p: PathBuf = dir.path().join("accounts").into();
+
+        let accounts1 = Accounts::new("my_os".into(), p.clone()).await.unwrap();
+        let accounts2 = Accounts::force_open(p.clone()).await.unwrap();
+        assert_eq!(accounts2.add_account().await.unwrap(), 2);
+
+        // Changes by the first instance keep the account added by the
+        // second one and do not reuse its id.
+        accounts1
+            .set_account_name(1, Some("Work".into()))
+            .await
+            .unwrap();
+        assert_eq!(accounts1.add_account().await.unwrap(), 3);
+        accounts2.remove_account(2).await.unwrap();
+        accounts1.select_account(1).await.unwrap();
+
+        drop(accounts1);
+        drop(accounts2);
+        let config = Config::from_file(p.join(CONFIG_NAME)).await.unwrap();
+        let inner = config.inner.read().await;
+        let ids: Vec<u32> = inner.accounts.iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![1, 3]);
+        assert_eq!(inner.next_id, 4);
+        assert_eq!(inner.accounts[0].name, Some("Work".to_string()));
+    }
+
+    #[async_std::test]
+    async fn test_staggered_ephemeral_task() {
+        let dir = tempfile::tempdir().unwrap();
+        let p: PathBuf = dir.path().join("accounts").into();
+
+        let accounts = Accounts::new("my_os".into(), p.clone()).await.unwrap();
+        for _ in 0..3 {
+            accounts.add_account().await.unwrap();
+        }
+
+        // The messages of all accounts expire at the same time.
+        let expires = time() + 3600;
+        let mut times = Vec::new();
+        for id in accounts.get_all().await {
+            let ctx = accounts.get_account(id).await.unwrap();
+            assert_eq!(ctx.next_ephemeral_task_time().await.unwrap(), None);
+            ctx.sql
+                .execute(
+                    "INSERT INTO msgs (chat_id, ephemeral_timestamp) VALUES (10, ?)",
+                    paramsv![expires],
+                )
+                .await
+                .unwrap();
+            let next = ctx.next_ephemeral_task_time().await.unwrap().unwrap();
+            assert!(next > expires && next <= expires + 61);
+            times.push(next);
+        }
+
+        times.sort_unstable();
+        for pair in times.windows(2) {
+            assert!(
+                pair[1] - pair[0] >= 10,
+                "ephemeral tasks not staggered: {:?}",
+                times
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn test_account_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let p: PathBuf = dir.path().join("accounts").into();
+
+        let accounts = Accounts::new("my_os".into(), p.clone()).await.unwrap();
+        for _ in 0..3 {
+            accounts.add_account().await.unwrap();
+        }
+        assert_eq!(accounts.get_all().await, vec![1, 2, 3, 4]);
+
+        assert!(accounts.set_account_order(vec![3, 1, 2]).await.is_err());
+        assert!(accounts.set_account_order(vec![3, 1, 1, 2]).await.is_err());
+        assert!(accounts
+            .set_account_order(vec![3, 1, 4, 2, 5])
+            .await
+            .is_err());
+        assert_eq!(accounts.get_all().await, vec![1, 2, 3, 4]);
+
+        accounts.set_account_order(vec![3, 1, 4, 2]).await.unwrap();
+        assert_eq!(accounts.get_all().await, vec![3, 1, 4, 2]);
+
+        // New accounts are appended, removed ones dropped from the order.
+        let id = accounts.add_account().await.unwrap();
+        accounts.remove_account(1).await.unwrap();
+        assert_eq!(accounts.get_all().await, vec![3, 4, 2, id]);
+        assert_eq!(accounts.config.account_order().await, vec![3, 4, 2]);
+
+        drop(accounts);
+        let accounts = Accounts::open(p).await.unwrap();
+        assert_eq!(accounts.get_all().await, vec![3, 4, 2, id]);
+
+        let mut ids = vec![5, 1, 2, 4];
+        sort_accounts(&mut ids, &[4, 7, 2]);
+        assert_eq!(ids, vec![4, 2, 1, 5]);
+    }
+
+    #[async_std::test]
+    async fn test_account_color() {
+        let dir = tempfile::tempdir().unwrap();
+        let p: PathBuf = dir.path().join("accounts").into();
+
+        let accounts = Accounts::new("my_os".into(), p.clone()).await.unwrap();
+        let config = accounts.get_account_config(1).await.unwrap();
+        assert_eq!(config.color, None);
+        assert_eq!(config.avatar_path, None);
+
+        accounts.set_account_color(1, Some(0xff0000)).await.unwrap();
+        accounts
+            .set_account_avatar_path(1, Some("avatar.png".into()))
+            .await
+            .unwrap();
+        assert!(accounts.set_account_color(42, Some(0)).await.is_err());
+        drop(accounts);
+
+        let accounts = Accounts::open(p).await.unwrap();
+        let config = accounts.get_account_config(1).await.unwrap();
+        assert_eq!(config.color, Some(0xff0000));
+        assert_eq!(config.avatar_path, Some("avatar.png".into()));
+    }
+
+    #[async_std::test]
+    async fn test_account_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let p: PathBuf = dir.path().join("accounts").into();
+
+        let accounts = Accounts::new("my_os".into(), p.clone()).await.unwrap();
+        assert_eq!(accounts.get_account_name(1).await, None);
+
+        accounts
+            .set_account_name(1, Some("Work".to_string()))
+            .await
+            .unwrap();
+        assert!(accounts
+            .set_account_name(42, Some("Home".to_string()))
+            .await
+            .is_err());
+        drop(accounts);
+
+        let accounts = Accounts::open(p).await.unwrap();
+        assert_eq!(accounts.get_account_name(1).await, Some("Work".to_string()));
+        accounts
+            .set_account_name(1, Some(" ".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(accounts.get_account_name(1).await, None);
+
+        // Configs written by older versions have no name.
+        let config: AccountConfig = toml::from_str(
+            "id = 1\n\
+             dir = \"/accounts/1\"\n\
+             uuid = \"7ea2f8e9-6de0-4a96-9a70-5bd0e5bd8e82\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.name, None);
+    }
+
+    #[async_std::test]
+    async fn test_remove_account_during_start_io() {
+        let dir = tempfile::tempdir().unwrap();
+        let p: PathBuf = dir.path().join("accounts").into();
+
+        let accounts = Accounts::new("my_os".into(), p.clone()).await.unwrap();
+        let id = accounts.add_account().await.unwrap();
+        assert_eq!(
+            accounts.get_account_state(id).await,
+            Some(AccountState::Active)
+        );
+
+        let accounts1 = accounts.clone();
+        let remove = async_std::task::spawn(async move { accounts1.remove_account(id).await });
+        // Depending on the order, IO is started before the removal or
+        // fails because the account is removed.
+        if let Err(err) = accounts.start_account_io(id).await {
+            assert!(err.to_string().contains("removed"));
+        }
+        remove.await.unwrap();
+
+        assert_eq!(
+            accounts.get_account_state(id).await,
+            Some(AccountState::Removed)
+        );
+        assert!(accounts.get_account(id).await.is_none());
+        let err = accounts.start_account_io(id).await.unwrap_err();
+        assert!(err.to_string().contains("has been removed"));
+        assert!(accounts.remove_account(id).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_open_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let p: PathBuf = dir.path().join("accounts").into();
+
+        let accounts = Accounts::new("my_os".into(), p.clone()).await.unwrap();
+        let ctx = accounts.get_selected_account().await;
+        ctx.set_config(crate::config::Config::Addr, Some("me@mail.com"))
+            .await
+            .unwrap();
+        drop(ctx);
+        drop(accounts);
+
+        let accounts = Accounts::open_read_only(p).await.unwrap();
+        assert!(accounts.is_read_only());
+        assert_eq!(accounts.get_all().await, vec![1]);
+        assert!(accounts.add_account().await.is_err());
+        assert!(accounts.select_account(1).await.is_err());
+        assert!(accounts.remove_account(1).await.is_err());
+        assert_eq!(accounts.get_all().await, vec![1]);
+
+        let ctx = accounts.get_selected_account().await;
+        assert_eq!(
+            ctx.get_config(crate::config::Config::Addr).await,
+            Some("me@mail.com".to_string())
+        );
+        assert!(ctx
+            .set_config(crate::config::Config::Addr, Some("other@mail.com"))
+            .await
+            .is_err());
+    }
+
+    #[async_std::test]
+    async fn test_account_start_stagger() {
+        let dir = tempfile::tempdir().unwrap();
+        let p: PathBuf = dir.path().join("accounts").into();
+
+        let accounts = Accounts::new("my_os".into(), p.clone()).await.unwrap();
+        accounts.add_account().await.unwrap();
+        accounts.add_account().await.unwrap();
+        assert_eq!(accounts.get_account_start_stagger_ms().await, 0);
+
+        accounts.set_account_start_stagger_ms(200).await.unwrap();
+        let stagger = Duration::from_millis(accounts.get_account_start_stagger_ms().await);
+        let delays: Vec<Duration> = (0..3).map(|i| account_start_delay(i, stagger)).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Duration::from_millis(0),
+                Duration::from_millis(200),
+                Duration::from_millis(200)
+            ]
+        );
+        assert_eq!(
+            account_start_delay(1, Duration::from_millis(0)),
+            Duration::from_millis(0)
+        );
+
+        drop(accounts);
+        let accounts = Accounts::open(p).await.unwrap();
+        assert_eq!(accounts.get_account_start_stagger_ms().await, 200);
+    }
+
+    #[async_std::test]
+    async fn test_export_import_all() {
+        use crate::config::Config;
+
+        let dir = tempfile::tempdir().unwrap();
+        let accounts = Accounts::new("my_os".into(), dir.path().join("accounts").into())
+            .await
+            .unwrap();
+        let unconfigured = accounts.add_account().await.unwrap();
+        let bob = accounts.add_account().await.unwrap();
+        for (id, addr) in &[(1, "alice@example.org"), (bob, "bob@example.net")] {
+            let ctx = accounts.get_account(*id).await.unwrap();
+            ctx.set_config(Config::ConfiguredAddr, Some(*addr))
+                .await
+                .unwrap();
+            ctx.set_config(Config::Configured, Some("1")).await.unwrap();
+        }
+        accounts
+            .set_account_name(bob, Some("Work".into()))
+            .await
+            .unwrap();
+        accounts
+            .set_account_color(bob, Some(0xff0000))
+            .await
+            .unwrap();
+        accounts.select_account(bob).await.unwrap();
+
+        let backup: PathBuf = dir.path().join("all.asc").into();
+        accounts
+            .export_all(backup.clone(), Some("secret".into()))
+            .await
+            .unwrap();
+        assert!(fs::read_to_string(&backup)
+            .await
+            .unwrap()
+            .starts_with("-----BEGIN PGP MESSAGE-----"));
+
+        let other = Accounts::new("my_os".into(), dir.path().join("other").into())
+            .await
+            .unwrap();
+        assert!(other
+            .import_all(backup.clone(), Some("wrong".into()))
+            .await
+            .is_err());
+        assert_eq!(other.get_all().await, vec![1]);
+
+        let imported = other
+            .import_all(backup, Some("secret".into()))
+            .await
+            .unwrap();
+        assert_eq!(imported.len(), 2);
+        assert!(!imported.contains(&unconfigured));
+        let mut addrs = Vec::new();
+        for id in &imported {
+            let ctx = other.get_account(*id).await.unwrap();
+            addrs.push(ctx.get_config(Config::ConfiguredAddr).await);
+        }
+        assert_eq!(
+            addrs,
+            vec![
+                Some("alice@example.org".to_string()),
+                Some("bob@example.net".to_string())
+            ]
+        );
+        assert_eq!(
+            other.get_account_name(imported[1]).await,
+            Some("Work".into())
+        );
+        assert_eq!(
+            other.get_account_config(imported[1]).await.unwrap().color,
+            Some(0xff0000)
+        );
+        assert_eq!(other.config.get_selected_account().await, imported[1]);
+    }
+
+    #[async_std::test]
+    async fn test_encrypt_file_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain: PathBuf = dir.path().join("plain").into();
+        let encrypted: PathBuf = dir.path().join("encrypted").into();
+        let decrypted: PathBuf = dir.path().join("decrypted").into();
+        let data: Vec<u8> = (0..25u8).collect();
+        fs::write(&plain, &data).await.unwrap();
+
+        encrypt_file("secret", &plain, &encrypted, 10)
+            .await
+            .unwrap();
+        let armored = fs::read_to_string(&encrypted).await.unwrap();
+        assert_eq!(armored.matches("-----END PGP MESSAGE-----").count(), 3);
+
+        decrypt_file("secret", &encrypted, &decrypted)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&decrypted).await.unwrap(), data);
+        assert!(decrypt_file("wrong", &encrypted, &decrypted).await.is_err());
+
+        fs::write(&encrypted, &armored[..armored.len() / 2])
+            .await
+            .unwrap();
+        assert!(decrypt_file("secret", &encrypted, &decrypted)
+            .await
+            .is_err());
+
+        // Dropping the last chunk or reordering chunks must be detected.
+        let split = |armored: &str| -> Vec<String> {
+            let end = "-----END PGP MESSAGE-----\n";
+            armored
+                .split(end)
+                .filter(|chunk| !chunk.is_empty())
+                .map(|chunk| format!("{}{}", chunk, end))
+                .collect()
+        };
+        let chunks = split(&armored);
+        assert_eq!(chunks.len(), 3);
+        for tampered in &[
+            chunks[..2].concat(),
+            [chunks[0].as_str(), chunks[2].as_str(), chunks[1].as_str()].concat(),
+        ] {
+            fs::write(&encrypted, tampered).await.unwrap();
+            assert!(decrypt_file("secret", &encrypted, &decrypted)
+                .await
+                .is_err());
+        }
+
+        // Chunks from another file with the same passphrase are rejected.
+        let other: PathBuf = dir.path().join("other").into();
+        encrypt_file("secret", &plain, &other, 10).await.unwrap();
+        let other = fs::read_to_string(&other).await.unwrap();
+        let other_chunks = split(&other);
+        fs::write(
+            &encrypted,
+            [
+                chunks[0].as_str(),
+                other_chunks[1].as_str(),
+                chunks[2].as_str(),
+            ]
+            .concat(),
+        )
+        .await
+        .unwrap();
+        assert!(decrypt_file("secret", &encrypted, &decrypted)
+            .await
+            .is_err());
+
+        // An empty file is written as a single chunk.
+        fs::write(&plain, b"").await.unwrap();
+        encrypt_file("secret", &plain, &encrypted, 10)
+            .await
+            .unwrap();
+        decrypt_file("secret", &encrypted, &decrypted)
+            .await
+            .unwrap();
+        assert!(fs::read(&decrypted).await.unwrap().is_empty());
+    }
 }
@@ -1035,12 +1035,14 @@ impl Chat {
 
         let ephemeral_timer = if msg.param.get_cmd() == SystemMessage::EphemeralTimerChanged {
             EphemeralTimer::Disabled
+        } else if let Some(timer) = msg.get_ephemeral_timer_override() {
+            timer
         } else {
             self.id.get_ephemeral_timer(context).await?
         };
         let ephemeral_timestamp = match ephemeral_timer {
             EphemeralTimer::Disabled => 0,
-            EphemeralTimer::Enabled { duration } => timestamp + i64::from(duration),
+            EphemeralTimer::Enabled { duration, .. } => timestamp + i64::from(duration),
         };
 
         // add message to the database
@@ -1531,6 +1533,21 @@ async fn prepare_msg_common(
     let mut chat = Chat::load_from_db(context, chat_id).await?;
     ensure!(chat.can_send(), "cannot send to {}", chat_id);
 
+    if let Some(timer) = msg.get_ephemeral_timer_override() {
+        EphemeralTimer::validated(timer.to_u32())?;
+        let chat_timer = chat_id.get_ephemeral_timer(context).await?;
+        let shorter = match (timer, chat_timer) {
+            (EphemeralTimer::Disabled, _) => false,
+            (_, EphemeralTimer::Disabled) => true,
+            (timer, chat_timer) => timer.to_u32() < chat_timer.to_u32(),
+        };
+        ensure!(
+            shorter,
+            "ephemeral timer of the message must be shorter than the timer of {}",
+            chat_id
+        );
+    }
+
     // The OutPreparing state is set by dc_prepare_msg() before it
     // calls this function and the message is left in the OutPreparing
     // state.  Otherwise we got called by send_msg() and we change the
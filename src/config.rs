@@ -8,10 +8,12 @@ use crate::chat::ChatId;
 use crate::constants::DC_VERSION_STR;
 use crate::context::Context;
 use crate::dc_tools::*;
+use crate::error::format_err;
 use crate::events::EventType;
 use crate::job;
 use crate::message::MsgId;
 use crate::mimefactory::RECOMMENDED_FILE_SIZE;
+use crate::scheduler::{InterruptInfo, InterruptReason};
 use crate::stock::StockMessage;
 
 /// The available configuration keys.
@@ -98,6 +100,135 @@ pub enum Config {
     #[strum(props(default = "0"))]
     DeleteDeviceAfter,
 
+    /// Number of newest messages in each chat which are never deleted
+    /// from the device by ephemeral timers or `DeleteDeviceAfter`.
+    ///
+    /// Equals to 0 by default, which means no messages are kept.
+    #[strum(props(default = "0"))]
+    KeepLastNPerChat,
+
+    /// If set to "1", messages are marked as seen on the server when
+    /// their ephemeral timer starts.
+    #[strum(props(default = "0"))]
+    SyncSeenToServer,
+
+    /// If set to "1", ephemeral timers of messages which could not be
+    /// decrypted are started like for other messages.
+    ///
+    /// By default they are not started, so the messages are kept until
+    /// they can be decrypted, e.g. after the key is restored.
+    #[strum(props(default = "0"))]
+    ExpireUndecryptable,
+
+    /// Minimum time in seconds between two IMAP fetches of a folder.
+    ///
+    /// Interrupts arriving earlier are coalesced into one deferred
+    /// fetch. Equals to 0 by default, which means fetches are not
+    /// throttled.
+    #[strum(props(default = "0"))]
+    MinFetchInterval,
+
+    /// Interval in seconds for checking that the connection is still
+    /// alive while waiting in IMAP IDLE.
+    ///
+    /// IDLE is ended and restarted after this interval, so a connection
+    /// silently dropped by the server is detected and reconnected
+    /// early. 0 disables the check.
+    #[strum(props(default = "60"))]
+    IdleLivenessInterval,
+
+    /// Number of seconds after which IMAP IDLE is refreshed with a new
+    /// IDLE command, so the server does not drop the connection.
+    ///
+    /// A shorter interval is used if the server advertises a lower limit
+    /// or dropped IDLE connections earlier before.
+    #[strum(props(default = "1380"))]
+    ImapIdleTimeout,
+
+    /// Path of a file to which IMAP traffic is written for debugging,
+    /// see `Client::capture()`.
+    ///
+    /// Each IMAP connection, e.g. the one watching the inbox and the one
+    /// watching the sentbox, writes to its own file named by appending
+    /// the watched folder to the path, e.g. `imap.capture.sentbox`. The
+    /// file is overwritten when the connection reconnects. Login
+    /// credentials are left out, but messages are contained in plain
+    /// text.
+    ImapCaptureFile,
+
+    /// IMAP connection executing IMAP jobs such as moving, deleting or
+    /// marking messages: "inbox", "mvbox" or "sentbox".
+    ///
+    /// Falls back to the inbox connection if the folder is not watched.
+    #[strum(props(default = "inbox"))]
+    JobImapConnection,
+
+    /// Number of IMAP jobs executed in a row before new messages are
+    /// fetched, between 1 and 200.
+    ///
+    /// Setting it to 1 interleaves fetches and jobs one-for-one.
+    #[strum(props(default = "20"))]
+    InboxJobBatchSize,
+
+    /// Number of times a failed SMTP job is retried before it is given
+    /// up. Messages which could not be sent are then marked as failed.
+    #[strum(props(default = "16"))]
+    SmtpMaxRetries,
+
+    /// Number of seconds after sending a message during which
+    /// connections without IDLE poll for new messages every 10 seconds
+    /// instead of backing off, so replies show up quickly.
+    /// Equals to 0 by default, which means polling is not boosted.
+    #[strum(props(default = "0"))]
+    BoostAfterSend,
+
+    /// Maximum number of seconds between polls of connections without
+    /// IDLE. Polling starts every 15 seconds after an interrupt or new
+    /// messages and backs off up to this interval while nothing arrives.
+    #[strum(props(default = "300"))]
+    FakeIdleMaxInterval,
+
+    /// Maximum number of simultaneous IMAP connections, for servers
+    /// limiting them.
+    ///
+    /// If fewer connections than watched folders are allowed, the
+    /// inbox connection fetches the remaining folders in turn.
+    /// Equals to 0 by default, which means there is no limit.
+    #[strum(props(default = "0"))]
+    MaxConnections,
+
+    /// If set to "1", IMAP traffic is compressed with DEFLATE if the
+    /// server supports it. Can be disabled for servers with a broken
+    /// implementation.
+    #[strum(props(default = "1"))]
+    ImapCompression,
+
+    /// Timeout in seconds for establishing an IMAP connection and for
+    /// reading the server greeting.
+    #[strum(props(default = "30"))]
+    ImapConnectTimeout,
+
+    /// Host of a SOCKS5 proxy used for IMAP and SMTP connections,
+    /// e.g. to connect through Tor. If empty, no proxy is used.
+    Socks5Host,
+
+    /// Port of the SOCKS5 proxy.
+    #[strum(props(default = "1080"))]
+    Socks5Port,
+
+    /// Username for the SOCKS5 proxy. If empty, the proxy is used
+    /// without authentication.
+    Socks5User,
+
+    /// Password for the SOCKS5 proxy.
+    Socks5Password,
+
+    /// If set to "1", new messages are fetched starting with the
+    /// highest UID, so after a long offline period the most recent
+    /// messages show up before the older backlog.
+    #[strum(props(default = "0"))]
+    FetchNewestFirst,
+
     SaveMimeHeaders,
     ConfiguredAddr,
     ConfiguredMailServer,
@@ -117,6 +248,7 @@ pub enum Config {
     ConfiguredInboxFolder,
     ConfiguredMvboxFolder,
     ConfiguredSentboxFolder,
+    ConfiguredTrashFolder,
     Configured,
 
     #[strum(serialize = "sys.version")]
@@ -139,6 +271,33 @@ pub enum Config {
     WebrtcInstance,
 }
 
+/// Logical IMAP folders, resolved to server-specific names by
+/// `Context::get_folder()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumString, AsRefStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum FolderKind {
+    Inbox,
+    Sentbox,
+    Mvbox,
+    Trash,
+}
+
+impl FolderKind {
+    /// Returns the key storing the folder name detected during configuration.
+    pub fn configured_key(self) -> Config {
+        match self {
+            FolderKind::Inbox => Config::ConfiguredInboxFolder,
+            FolderKind::Sentbox => Config::ConfiguredSentboxFolder,
+            FolderKind::Mvbox => Config::ConfiguredMvboxFolder,
+            FolderKind::Trash => Config::ConfiguredTrashFolder,
+        }
+    }
+
+    fn override_key(self) -> String {
+        format!("imap.folder_override.{}", self.as_ref())
+    }
+}
+
 impl Context {
     pub async fn config_exists(&self, key: Config) -> bool {
         self.sql.get_raw_config(self, key).await.is_some()
@@ -257,15 +416,80 @@ impl Context {
                 if self.get_config(Config::InboxWatch).await.as_deref() != value {
                     // If Inbox-watch is disabled and enabled again, do not fetch emails from in between.
                     // this avoids unexpected mass-downloads and -deletions (if delete_server_after is set)
-                    if let Some(inbox) = self.get_config(Config::ConfiguredInboxFolder).await {
+                    if let Some(inbox) = self.get_folder(FolderKind::Inbox).await {
                         crate::imap::set_config_last_seen_uid(self, inbox, 0, 0).await;
                     }
                 }
                 self.sql.set_raw_config(self, key, value).await
             }
+            Config::Configured => {
+                let was_configured = self.is_configured().await;
+                let ret = self.sql.set_raw_config(self, key, value).await;
+                self.emit_configured_changed(was_configured).await;
+                ret
+            }
+            Config::JobImapConnection => {
+                let ret = self.sql.set_raw_config(self, key, value).await;
+                self.interrupt_imap_jobs(
+                    InterruptInfo::new(false, None).with_reason(InterruptReason::ConfigChanged),
+                )
+                .await;
+                ret
+            }
+            Config::ImapConnectTimeout | Config::ImapIdleTimeout => {
+                if let Some(value) = value {
+                    if !value.parse::<i32>().map_or(false, |secs| secs >= 0) {
+                        return Err(format_err!("Invalid value for {}: {:?}", key, value).into());
+                    }
+                }
+                self.sql.set_raw_config(self, key, value).await
+            }
+            Config::Socks5Port => {
+                if let Some(value) = value {
+                    if !value.parse::<u16>().map_or(false, |port| port > 0) {
+                        return Err(format_err!("Invalid value for {}: {:?}", key, value).into());
+                    }
+                }
+                self.sql.set_raw_config(self, key, value).await
+            }
             _ => self.sql.set_raw_config(self, key, value).await,
         }
     }
+
+    /// Returns the server-specific name of a logical folder.
+    ///
+    /// A name set with `Context::set_folder_override()` takes precedence
+    /// over the name detected during configuration.
+    pub async fn get_folder(&self, kind: FolderKind) -> Option<String> {
+        if let Some(name) = self.get_folder_override(kind).await {
+            return Some(name);
+        }
+        self.get_config(kind.configured_key()).await
+    }
+
+    /// Returns the folder name set with `Context::set_folder_override()`.
+    pub async fn get_folder_override(&self, kind: FolderKind) -> Option<String> {
+        self.sql
+            .get_raw_config(self, kind.override_key())
+            .await
+            .filter(|name| !name.is_empty())
+    }
+
+    /// Overrides the server-specific name of a logical folder, e.g. for
+    /// servers using localized names such as "Gesendet".
+    ///
+    /// Passing `None` removes the override, so the folder detected
+    /// during configuration is used again.
+    pub async fn set_folder_override(
+        &self,
+        kind: FolderKind,
+        name: Option<&str>,
+    ) -> crate::sql::Result<()> {
+        let name = name.map(str::trim).filter(|name| !name.is_empty());
+        self.sql
+            .set_raw_config(self, kind.override_key(), name)
+            .await
+    }
 }
 
 /// Returns all available configuration keys concated together.
@@ -306,6 +530,98 @@ mod tests {
         );
     }
 
+    #[async_std::test]
+    async fn test_folder_override() {
+        let t = TestContext::new_alice().await;
+        t.ctx
+            .set_config(Config::ConfiguredSentboxFolder, Some("Sent"))
+            .await
+            .unwrap();
+        assert_eq!(
+            t.ctx.get_folder(FolderKind::Sentbox).await,
+            Some("Sent".to_string())
+        );
+
+        t.ctx
+            .set_folder_override(FolderKind::Sentbox, Some("Gesendet"))
+            .await
+            .unwrap();
+        assert_eq!(
+            t.ctx.get_folder(FolderKind::Sentbox).await,
+            Some("Gesendet".to_string())
+        );
+        assert!(t.ctx.is_sentbox("Gesendet").await);
+        assert!(!t.ctx.is_sentbox("Sent").await);
+
+        // Fetching from the localized folder tracks the Sent folder copy.
+        let contact_id = crate::contact::Contact::create(&t.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        let chat_id = crate::chat::create_by_contact_id(&t.ctx, contact_id)
+            .await
+            .unwrap();
+        let msg_id = crate::chat::send_text_msg(&t.ctx, chat_id, "hi".to_string())
+            .await
+            .unwrap();
+        let sent = t.pop_sent_msg().await;
+        for (folder, uid) in &[("INBOX", 1), ("Gesendet", 2)] {
+            crate::dc_receive_imf::dc_receive_imf(
+                &t.ctx,
+                sent.payload().as_bytes(),
+                folder,
+                *uid,
+                false,
+            )
+            .await
+            .unwrap();
+        }
+        let sentbox_uid: u32 = t
+            .ctx
+            .sql
+            .query_get_value_result("SELECT sentbox_uid FROM msgs WHERE id=?", paramsv![msg_id])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sentbox_uid, 2);
+
+        t.ctx
+            .set_folder_override(FolderKind::Sentbox, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            t.ctx.get_folder(FolderKind::Sentbox).await,
+            Some("Sent".to_string())
+        );
+    }
+
+    #[async_std::test]
+    async fn test_trash_folder_override() {
+        let t = TestContext::new_alice().await;
+        assert_eq!(t.ctx.get_folder(FolderKind::Trash).await, None);
+        assert!(!t.ctx.is_trash("Trash").await);
+
+        t.ctx
+            .set_config(Config::ConfiguredTrashFolder, Some("Trash"))
+            .await
+            .unwrap();
+        assert!(t.ctx.is_trash("Trash").await);
+
+        t.ctx
+            .set_folder_override(FolderKind::Trash, Some("Papierkorb"))
+            .await
+            .unwrap();
+        assert_eq!(
+            t.ctx.get_folder(FolderKind::Trash).await,
+            Some("Papierkorb".to_string())
+        );
+        assert!(t.ctx.is_trash("Papierkorb").await);
+        assert!(!t.ctx.is_trash("Trash").await);
+
+        // Deleted messages would reappear if the trash folder was fetched.
+        assert!(t.ctx.add_watched_folder("Papierkorb").await.is_err());
+        assert!(t.ctx.get_watched_folders().await.is_empty());
+    }
+
     #[test]
     fn test_default_prop() {
         assert_eq!(Config::ImapFolder.get_str("default"), Some("INBOX"));
@@ -409,4 +725,24 @@ mod tests {
         let media_quality = constants::MediaQuality::from_i32(media_quality).unwrap_or_default();
         assert_eq!(media_quality, constants::MediaQuality::Worse);
     }
+
+    #[async_std::test]
+    async fn test_set_timeout_config() {
+        let t = TestContext::new().await;
+        t.ctx
+            .set_config(Config::ImapIdleTimeout, Some("600"))
+            .await
+            .unwrap();
+        assert!(t
+            .ctx
+            .set_config(Config::ImapIdleTimeout, Some("-1"))
+            .await
+            .is_err());
+        assert!(t
+            .ctx
+            .set_config(Config::ImapConnectTimeout, Some("soon"))
+            .await
+            .is_err());
+        assert_eq!(t.ctx.get_config_int(Config::ImapIdleTimeout).await, 600);
+    }
 }
@@ -14,6 +14,7 @@ use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 
 use crate::config::Config;
 use crate::dc_tools::*;
+use crate::events::EventType;
 use crate::imap::Imap;
 use crate::login_param::{LoginParam, ServerLoginParam};
 use crate::message::Message;
@@ -51,6 +52,29 @@ impl Context {
         self.sql.get_raw_config_bool(self, "configured").await
     }
 
+    /// Removes the configuration of this account, so it has to be
+    /// configured again, e.g. with other login parameters.
+    ///
+    /// The entered login parameters, keys and messages are kept.
+    pub async fn reset_configuration(&self) -> Result<()> {
+        ensure!(
+            !self.scheduler.read().await.is_running(),
+            "cannot reset configuration, IO is running"
+        );
+        self.set_config(Config::Configured, None).await?;
+        Ok(())
+    }
+
+    /// Emits `AccountConfiguredChanged` if the configured state differs
+    /// from `was_configured`.
+    pub(crate) async fn emit_configured_changed(&self, was_configured: bool) {
+        let configured = self.is_configured().await;
+        if configured != was_configured {
+            info!(self, "account configured: {}", configured);
+            self.emit_event(EventType::AccountConfiguredChanged { configured });
+        }
+    }
+
     /// Configures this account with the currently set parameters.
     pub async fn configure(&self) -> Result<()> {
         use futures::future::FutureExt;
@@ -350,7 +374,9 @@ async fn configure(ctx: &Context, param: &mut LoginParam) -> Result<()> {
     // "configured_" prefix; also write the "configured"-flag */
     // the trailing underscore is correct
     param.save_to_database(ctx, "configured_").await?;
+    let was_configured = ctx.is_configured().await;
     ctx.sql.set_raw_config_bool(ctx, "configured", true).await?;
+    ctx.emit_configured_changed(was_configured).await;
 
     progress!(ctx, 920);
 
@@ -613,6 +639,44 @@ mod tests {
     use crate::config::*;
     use crate::test_utils::*;
 
+    #[async_std::test]
+    async fn test_account_configured_changed() {
+        use std::sync::{Arc, Mutex};
+
+        let t = TestContext::new().await;
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let changes1 = changes.clone();
+        t.ctx.on_event_type(
+            EventType::AccountConfiguredChanged { configured: true }.as_id(),
+            move |event| {
+                if let EventType::AccountConfiguredChanged { configured } = event.typ {
+                    changes1.lock().unwrap().push(configured);
+                }
+            },
+        );
+
+        t.configure_addr("alice@example.org").await;
+        assert_eq!(*changes.lock().unwrap(), vec![true]);
+
+        // Setting the flag again does not emit another event.
+        t.ctx
+            .set_config(Config::Configured, Some("1"))
+            .await
+            .unwrap();
+        assert_eq!(*changes.lock().unwrap(), vec![true]);
+
+        t.ctx.reset_configuration().await.unwrap();
+        assert!(!t.ctx.is_configured().await);
+        assert_eq!(*changes.lock().unwrap(), vec![true, false]);
+        assert_eq!(
+            t.ctx.get_config(Config::Addr).await,
+            Some("alice@example.org".to_string())
+        );
+
+        t.ctx.reset_configuration().await.unwrap();
+        assert_eq!(*changes.lock().unwrap(), vec![true, false]);
+    }
+
     #[async_std::test]
     async fn test_no_panic_on_bad_credentials() {
         let t = TestContext::new().await;
@@ -1,27 +1,31 @@
 //! Context module
 
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::ffi::OsString;
 use std::ops::Deref;
+use std::sync::atomic::AtomicBool;
 
 use async_std::path::{Path, PathBuf};
 use async_std::sync::{channel, Arc, Mutex, Receiver, RwLock, Sender};
 use async_std::task;
 
 use crate::chat::*;
-use crate::config::Config;
+use crate::config::{Config, FolderKind};
 use crate::constants::*;
 use crate::contact::*;
 use crate::dc_tools::duration_to_str;
+use crate::ephemeral::{DeletionAuditor, EphemeralTimerStockMapping};
 use crate::error::*;
-use crate::events::{Event, EventEmitter, EventType, Events};
+use crate::events::{Connectivity, Event, EventEmitter, EventOverflowPolicy, EventType, Events};
+use crate::job::MovedMsg;
 use crate::key::{DcKey, SignedPublicKey};
 use crate::login_param::LoginParam;
 use crate::message::{self, MsgId};
 use crate::scheduler::Scheduler;
 use crate::securejoin::Bob;
 use crate::sql::Sql;
-use std::time::SystemTime;
+use crate::storage::StorageBreakdown;
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(Clone, Debug)]
 pub struct Context {
@@ -58,6 +62,40 @@ pub struct InnerContext {
 
     pub(crate) scheduler: RwLock<Scheduler>,
     pub(crate) ephemeral_task: RwLock<Option<task::JoinHandle<()>>>,
+    /// Live spawned tasks by category, see `Context::debug_task_counts`.
+    pub(crate) task_counts: TaskCounts,
+    /// Callback awaited before automatic message deletion.
+    pub(crate) deletion_auditor: RwLock<Option<DeletionAuditor>>,
+    /// Mapping from ephemeral timer values to stock messages.
+    pub(crate) ephemeral_timer_stock_mapping: RwLock<Option<EphemeralTimerStockMapping>>,
+    /// Number of jobs which are performed right now.
+    pub(crate) running_jobs: RwLock<usize>,
+    /// SASL mechanisms of IMAP servers by server address, see
+    /// `Context::probe_auth_mechanisms()`.
+    pub(crate) auth_mechanisms: RwLock<HashMap<String, Vec<String>>>,
+    /// Whether the SMTP loop is performing a job, see `Context::smtp_is_sending`.
+    pub(crate) smtp_sending: AtomicBool,
+    /// Mutex to prevent two IMAP connections from executing IMAP jobs at the same time.
+    pub(crate) imap_jobs_mutex: Mutex<()>,
+    /// Result of the last IMAP connection attempt.
+    pub(crate) connectivity: RwLock<Connectivity>,
+    /// Whether the network connection is metered, see `Context::set_metered`.
+    pub(crate) metered: AtomicBool,
+    /// Whether IO is paused, see `Context::pause_io`.
+    pub(crate) io_paused: RwLock<bool>,
+    /// Latest moves to the mvbox, newest first.
+    pub(crate) recent_moves: RwLock<VecDeque<MovedMsg>>,
+    /// Current reconnect delays of failing IMAP connections by watched
+    /// folder, see `Context::get_reconnect_backoff`.
+    pub(crate) reconnect_backoff: RwLock<BTreeMap<String, Duration>>,
+    /// Until when fake-IDLE polls more often after sending a message,
+    /// see `Config::BoostAfterSend`.
+    pub(crate) fetch_boost_until: RwLock<Option<Instant>>,
+    /// Statistics about reused connections, see
+    /// `Context::connection_cache_stats`.
+    pub(crate) connection_cache: RwLock<ConnectionCacheStats>,
+    /// Last result of `Context::storage_breakdown` and when it was computed.
+    pub(crate) storage_breakdown: RwLock<Option<(Instant, StorageBreakdown)>>,
 
     /// Id for this context on the current device.
     pub(crate) id: u32,
@@ -65,6 +103,111 @@ pub struct InnerContext {
     creation_time: SystemTime,
 }
 
+/// State of the IO scheduler, see `Context::scheduler_state()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulerState {
+    /// IO is not started.
+    Stopped,
+    /// IO is started, but the loops are parked, see `Context::pause_io()`.
+    Paused,
+    /// IO is started and working.
+    Running,
+}
+
+/// State of a single connection of the IO scheduler,
+/// see `Context::scheduler_status()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    /// Not connected, e.g. because IO is not running or nothing
+    /// needed the connection yet.
+    NotConnected,
+    /// Connecting to the server.
+    Connecting,
+    /// Connected and working, e.g. executing jobs.
+    Connected,
+    /// Connected and waiting for new messages or jobs.
+    Idle,
+    /// Fetching new messages.
+    Fetching,
+    /// The last connection attempt or fetch failed, it is retried later.
+    Error(String),
+}
+
+impl Default for ConnectionStatus {
+    fn default() -> Self {
+        ConnectionStatus::NotConnected
+    }
+}
+
+/// Status of the connections of the IO scheduler,
+/// see `Context::scheduler_status()`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SchedulerStatus {
+    pub inbox: ConnectionStatus,
+    pub mvbox: ConnectionStatus,
+    pub sentbox: ConnectionStatus,
+    pub smtp: ConnectionStatus,
+}
+
+/// Statistics about reused IMAP and SMTP connections,
+/// see `Context::connection_cache_stats()`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionCacheStats {
+    /// Number of times an established connection was reused.
+    pub reused: u64,
+    /// Number of new connections which were established and logged in.
+    pub connected: u64,
+    /// Number of times the connections were dropped with
+    /// `Context::clear_connection_caches()`.
+    pub cleared: u64,
+}
+
+/// Number of live spawned tasks by category.
+///
+/// Tasks are only counted in debug builds, to detect leaked tasks in
+/// development and tests.
+#[derive(Debug, Default)]
+pub(crate) struct TaskCounts(TaskCountMap);
+
+type TaskCountMap = Arc<std::sync::Mutex<BTreeMap<&'static str, usize>>>;
+
+impl TaskCounts {
+    /// Counts a task of `category` until the returned guard is dropped.
+    fn track(&self, category: &'static str) -> TaskGuard {
+        if !cfg!(debug_assertions) {
+            return TaskGuard(None);
+        }
+        *self
+            .0
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .entry(category)
+            .or_default() += 1;
+        TaskGuard(Some((self.0.clone(), category)))
+    }
+
+    fn get(&self) -> BTreeMap<&'static str, usize> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner()).clone()
+    }
+}
+
+/// Guard living as long as a task counted by `TaskCounts`.
+struct TaskGuard(Option<(TaskCountMap, &'static str)>);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if let Some((counts, category)) = self.0.take() {
+            let counts = &mut *counts.lock().unwrap_or_else(|err| err.into_inner());
+            if let Some(count) = counts.get_mut(category) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.remove(category);
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct RunningState {
     pub ongoing_running: bool,
@@ -72,6 +215,22 @@ pub struct RunningState {
     cancel_sender: Option<Sender<()>>,
 }
 
+/// Summary of a housekeeping run, see `Context::housekeeping()`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HousekeepingReport {
+    /// Number of unreferenced files deleted from the blob directory.
+    pub deleted_files: usize,
+
+    /// Number of seen messages whose ephemeral timer has been started.
+    pub started_timers: usize,
+
+    /// Whether any expired message has been deleted.
+    pub deleted_expired_msgs: bool,
+
+    /// Number of trashed messages removed from the database.
+    pub pruned_msgs: usize,
+}
+
 /// Return some info about deltachat-core
 ///
 /// This contains information mostly about the library itself, the
@@ -102,11 +261,35 @@ impl Context {
         Context::with_blobdir(os_name, dbfile, blobdir, id).await
     }
 
+    /// Opens an existing context without write access to the database.
+    ///
+    /// Any attempt to modify the database fails. Migrations are not
+    /// run, so the database must already be created by `Context::new`.
+    pub async fn new_read_only(os_name: String, dbfile: PathBuf, id: u32) -> Result<Context> {
+        ensure!(
+            dbfile.exists().await,
+            "Database does not exist: {}",
+            dbfile.display()
+        );
+        let blobdir = Context::derive_blobdir(&dbfile);
+        Context::open_with_blobdir(os_name, dbfile, blobdir, id, true).await
+    }
+
     pub(crate) async fn with_blobdir(
         os_name: String,
         dbfile: PathBuf,
         blobdir: PathBuf,
         id: u32,
+    ) -> Result<Context> {
+        Context::open_with_blobdir(os_name, dbfile, blobdir, id, false).await
+    }
+
+    async fn open_with_blobdir(
+        os_name: String,
+        dbfile: PathBuf,
+        blobdir: PathBuf,
+        id: u32,
+        readonly: bool,
     ) -> Result<Context> {
         ensure!(
             blobdir.is_dir().await,
@@ -130,13 +313,28 @@ impl Context {
             events: Events::default(),
             scheduler: RwLock::new(Scheduler::Stopped),
             ephemeral_task: RwLock::new(None),
+            task_counts: TaskCounts::default(),
+            deletion_auditor: RwLock::new(None),
+            ephemeral_timer_stock_mapping: RwLock::new(None),
+            auth_mechanisms: RwLock::new(HashMap::new()),
+            running_jobs: RwLock::new(0),
+            smtp_sending: AtomicBool::new(false),
+            imap_jobs_mutex: Mutex::new(()),
+            connectivity: RwLock::new(Connectivity::default()),
+            metered: AtomicBool::new(false),
+            io_paused: RwLock::new(false),
+            recent_moves: RwLock::new(VecDeque::new()),
+            reconnect_backoff: RwLock::new(BTreeMap::new()),
+            fetch_boost_until: RwLock::new(None),
+            connection_cache: RwLock::new(ConnectionCacheStats::default()),
+            storage_breakdown: RwLock::new(None),
             creation_time: std::time::SystemTime::now(),
         };
 
         let ctx = Context {
             inner: Arc::new(inner),
         };
-        ctx.sql.open(&ctx, &ctx.dbfile, false).await?;
+        ctx.sql.open(&ctx, &ctx.dbfile, readonly).await?;
 
         Ok(ctx)
     }
@@ -161,10 +359,49 @@ impl Context {
     }
 
     /// Stops the IO scheduler.
+    ///
+    /// This also ends a pause, the next `start_io()` starts IO
+    /// running.
     pub async fn stop_io(&self) {
         info!(self, "stopping IO");
 
         self.inner.stop_io().await;
+        *self.io_paused.write().await = false;
+    }
+
+    /// Returns whether IO is stopped, paused or running.
+    pub async fn scheduler_state(&self) -> SchedulerState {
+        if !self.is_io_running().await {
+            SchedulerState::Stopped
+        } else if self.is_io_paused().await {
+            SchedulerState::Paused
+        } else {
+            SchedulerState::Running
+        }
+    }
+
+    /// Spawns a task counted as `category` until it finishes or is
+    /// cancelled, see `Context::debug_task_counts()`.
+    pub(crate) fn spawn_task<F, T>(&self, category: &'static str, fut: F) -> task::JoinHandle<T>
+    where
+        F: std::future::Future<Output = T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let guard = self.task_counts.track(category);
+        task::spawn(async move {
+            let _guard = guard;
+            fut.await
+        })
+    }
+
+    /// Returns the number of live tasks spawned by this context by
+    /// category, e.g. "inbox_loop" or "ephemeral".
+    ///
+    /// Categories without live tasks are omitted. Tasks are only
+    /// counted in debug builds, in release builds the result is always
+    /// empty.
+    pub fn debug_task_counts(&self) -> BTreeMap<&'static str, usize> {
+        self.task_counts.get()
     }
 
     /// Returns a reference to the underlying SQL instance.
@@ -198,6 +435,53 @@ impl Context {
         self.events.get_emitter()
     }
 
+    /// Sets what happens to events emitted while the event queue is full
+    /// because events are not received fast enough.
+    pub fn set_event_overflow_policy(&self, policy: EventOverflowPolicy) {
+        self.events.set_overflow_policy(policy);
+    }
+
+    /// Returns the number of events dropped because the event queue was full.
+    pub fn dropped_event_count(&self) -> usize {
+        self.events.dropped_count()
+    }
+
+    /// Registers a callback for events of one type.
+    ///
+    /// `event_id` is the id of the event type as returned by
+    /// `EventType::as_id()`. Multiple callbacks can be registered for
+    /// the same type. Events are still delivered to the `EventEmitter`.
+    pub fn on_event_type(&self, event_id: i32, handler: impl Fn(Event) + Send + Sync + 'static) {
+        self.events.on_event_type(event_id, handler);
+    }
+
+    /// Starts recording all emitted events to the file at `path`,
+    /// e.g. to attach a trace to a bug report.
+    ///
+    /// Each line of the file is a JSON object with a timestamp, the
+    /// event id and the event. Passwords are redacted.
+    pub async fn start_event_log(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut secrets = Vec::new();
+        for key in &[
+            Config::MailPw,
+            Config::SendPw,
+            Config::ConfiguredMailPw,
+            Config::ConfiguredSendPw,
+        ] {
+            if let Some(secret) = self.get_config(*key).await {
+                secrets.push(secret);
+            }
+        }
+        self.events.start_log(path.as_ref().as_ref(), secrets)?;
+        info!(self, "event log started");
+        Ok(())
+    }
+
+    /// Stops recording events started with `Context::start_event_log()`.
+    pub fn stop_event_log(&self) {
+        self.events.stop_log();
+    }
+
     /// Get the ID of this context.
     pub fn get_id(&self) -> u32 {
         self.id
@@ -310,11 +594,11 @@ impl Context {
             .unwrap_or_default();
 
         let configured_sentbox_folder = self
-            .get_config(Config::ConfiguredSentboxFolder)
+            .get_folder(FolderKind::Sentbox)
             .await
             .unwrap_or_else(|| "<unset>".to_string());
         let configured_mvbox_folder = self
-            .get_config(Config::ConfiguredMvboxFolder)
+            .get_folder(FolderKind::Mvbox)
             .await
             .unwrap_or_else(|| "<unset>".to_string());
 
@@ -357,6 +641,11 @@ impl Context {
         );
         res.insert("fingerprint", fingerprint_str);
 
+        res.insert(
+            "imap_reconnect_backoff",
+            duration_to_str(self.get_reconnect_backoff().await),
+        );
+
         let elapsed = self.creation_time.elapsed();
         res.insert("uptime", duration_to_str(elapsed.unwrap_or_default()));
 
@@ -451,18 +740,28 @@ impl Context {
     }
 
     pub async fn is_inbox(&self, folder_name: impl AsRef<str>) -> bool {
-        self.get_config(Config::ConfiguredInboxFolder).await
-            == Some(folder_name.as_ref().to_string())
+        self.get_folder(FolderKind::Inbox).await == Some(folder_name.as_ref().to_string())
     }
 
     pub async fn is_sentbox(&self, folder_name: impl AsRef<str>) -> bool {
-        self.get_config(Config::ConfiguredSentboxFolder).await
-            == Some(folder_name.as_ref().to_string())
+        self.get_folder(FolderKind::Sentbox).await == Some(folder_name.as_ref().to_string())
     }
 
     pub async fn is_mvbox(&self, folder_name: impl AsRef<str>) -> bool {
-        self.get_config(Config::ConfiguredMvboxFolder).await
-            == Some(folder_name.as_ref().to_string())
+        self.get_folder(FolderKind::Mvbox).await == Some(folder_name.as_ref().to_string())
+    }
+
+    pub async fn is_trash(&self, folder_name: impl AsRef<str>) -> bool {
+        self.get_folder(FolderKind::Trash).await == Some(folder_name.as_ref().to_string())
+    }
+
+    /// Runs housekeeping now instead of waiting for the scheduled job.
+    ///
+    /// Starts missing ephemeral timers, deletes expired messages,
+    /// reschedules the next ephemeral deletion and removes
+    /// unreferenced files from the blob directory.
+    pub async fn housekeeping(&self) -> HousekeepingReport {
+        crate::sql::housekeeping(self).await
     }
 
     pub fn derive_blobdir(dbfile: &PathBuf) -> PathBuf {
@@ -516,6 +815,20 @@ mod tests {
 
     use crate::test_utils::*;
 
+    #[async_std::test]
+    async fn test_debug_task_counts() {
+        let t = TestContext::new().await;
+        assert!(t.ctx.debug_task_counts().is_empty());
+
+        t.ctx.start_io().await;
+        let counts = t.ctx.debug_task_counts();
+        assert_eq!(counts.get("inbox_loop"), Some(&1));
+        assert_eq!(counts.get("smtp_loop"), Some(&1));
+
+        t.ctx.stop_io().await;
+        assert!(t.ctx.debug_task_counts().is_empty());
+    }
+
     #[async_std::test]
     async fn test_wrong_db() {
         let tmp = tempfile::tempdir().unwrap();
@@ -525,6 +838,81 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[async_std::test]
+    async fn test_on_event_type() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let t = TestContext::new().await;
+        let msgs_changed = Arc::new(AtomicUsize::new(0));
+        let chat_modified = Arc::new(AtomicUsize::new(0));
+
+        let msgs_changed_id = EventType::MsgsChanged {
+            chat_id: ChatId::new(0),
+            msg_id: MsgId::new(0),
+        }
+        .as_id();
+        for _ in 0..2 {
+            let counter = msgs_changed.clone();
+            t.ctx.on_event_type(msgs_changed_id, move |event| {
+                assert!(matches!(event.typ, EventType::MsgsChanged { .. }));
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        let counter = chat_modified.clone();
+        t.ctx
+            .on_event_type(EventType::ChatModified(ChatId::new(0)).as_id(), move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+
+        t.ctx.emit_event(EventType::MsgsChanged {
+            chat_id: ChatId::new(10),
+            msg_id: MsgId::new(11),
+        });
+        t.ctx.emit_event(EventType::Info("not handled".to_string()));
+
+        assert_eq!(msgs_changed.load(Ordering::SeqCst), 2);
+        assert_eq!(chat_modified.load(Ordering::SeqCst), 0);
+    }
+
+    #[async_std::test]
+    async fn test_event_log() {
+        let t = TestContext::new().await;
+        t.ctx
+            .set_config(Config::MailPw, Some("secret-pw"))
+            .await
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.log");
+
+        t.ctx
+            .start_event_log(PathBuf::from(path.clone()))
+            .await
+            .unwrap();
+        t.ctx
+            .emit_event(EventType::Info("login with secret-pw".to_string()));
+        t.ctx.emit_event(EventType::MsgsChanged {
+            chat_id: ChatId::new(0),
+            msg_id: MsgId::new(0),
+        });
+        t.ctx.stop_event_log();
+        t.ctx.emit_event(EventType::Info("not logged".to_string()));
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert!(!log.contains("secret-pw"));
+        assert!(!log.contains("not logged"));
+        let lines: Vec<serde_json::Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(lines
+            .iter()
+            .all(|line| line["timestamp"].as_i64().unwrap() > 0));
+        assert!(lines
+            .iter()
+            .any(|line| line["event"] == "Info(\"login with [REDACTED]\")"));
+        assert!(lines.iter().any(|line| line["event_id"] == 2000));
+    }
+
     #[async_std::test]
     async fn test_get_fresh_msgs() {
         let t = TestContext::new().await;
@@ -605,4 +993,41 @@ mod tests {
         assert!(info.get("database_dir").is_none());
         assert_eq!(info.get("level").unwrap(), "awesome");
     }
+
+    #[async_std::test]
+    async fn test_housekeeping() {
+        use crate::dc_tools::time;
+        use crate::message::{Message, MessageState};
+
+        let t = TestContext::new_alice().await;
+        let seen_id = receive_simple_msg_in(&t.ctx, "seen@example.com", "INBOX", 1).await;
+        let expired_id = receive_simple_msg_in(&t.ctx, "expired@example.com", "INBOX", 2).await;
+
+        // Seen message without a started timer.
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timer=60, state=? WHERE id=?",
+                paramsv![MessageState::InSeen, seen_id],
+            )
+            .await
+            .unwrap();
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timestamp=? WHERE id=?",
+                paramsv![time() - 1, expired_id],
+            )
+            .await
+            .unwrap();
+
+        let report = t.ctx.housekeeping().await;
+        assert_eq!(report.started_timers, 1);
+        assert!(report.deleted_expired_msgs);
+
+        let seen = Message::load_from_db(&t.ctx, seen_id).await.unwrap();
+        assert!(seen.get_ephemeral_timestamp() > time());
+        let expired = Message::load_from_db(&t.ctx, expired_id).await.unwrap();
+        assert!(expired.chat_id.is_trash());
+    }
 }
@@ -10,7 +10,9 @@ use crate::constants::*;
 use crate::contact::*;
 use crate::context::Context;
 use crate::dc_tools::*;
-use crate::ephemeral::{stock_ephemeral_timer_changed, Timer as EphemeralTimer};
+use crate::ephemeral::{
+    stock_ephemeral_timer_changed, Timer as EphemeralTimer, TimerChange, MAX_TIMER_DURATION,
+};
 use crate::error::{bail, ensure, format_err, Result};
 use crate::events::EventType;
 use crate::headerdef::HeaderDef;
@@ -362,8 +364,15 @@ async fn add_parts(
         message::rfc724_mid_exists(context, &rfc724_mid).await?
     {
         if old_server_folder != server_folder.as_ref() || old_server_uid != server_uid {
-            message::update_server_uid(context, &rfc724_mid, server_folder.as_ref(), server_uid)
-                .await;
+            message::add_server_copy(
+                context,
+                &rfc724_mid,
+                &old_server_folder,
+                old_server_uid,
+                server_folder.as_ref(),
+                server_uid,
+            )
+            .await;
         }
 
         warn!(context, "Message already in DB");
@@ -651,9 +660,23 @@ async fn add_parts(
         info!(context, "Existing non-decipherable message. (TRASH)");
     }
 
+    if mime_parser.is_system_message == SystemMessage::DeleteRequest {
+        if let Err(err) = handle_delete_request(context, mime_parser, from_id).await {
+            warn!(context, "Failed to handle delete request: {}", err);
+        }
+        // The request itself is not shown in any chat.
+        *chat_id = ChatId::new(DC_CHAT_ID_TRASH);
+        info!(context, "Delete request (TRASH)");
+    }
+
     // Extract ephemeral timer from the message.
     let mut ephemeral_timer = if let Some(value) = mime_parser.get(HeaderDef::EphemeralTimer) {
         match value.parse::<EphemeralTimer>() {
+            // Longer timers can not be stored, use the longest one.
+            Ok(timer) if timer.to_u32() > MAX_TIMER_DURATION => {
+                warn!(context, "ephemeral timer \"{}\" is too long", value);
+                EphemeralTimer::from_u32(MAX_TIMER_DURATION)
+            }
             Ok(timer) => timer,
             Err(err) => {
                 warn!(
@@ -675,28 +698,23 @@ async fn add_parts(
     // Only non-hidden timers are applied now. Timers from hidden
     // messages such as read receipts can be useful to detect
     // ephemeral timer support, but timer changes without visible
-    // received messages may be confusing to the user.
-    if !*hidden
-        && !location_kml_is
-        && !is_mdn
-        && (*chat_id).get_ephemeral_timer(context).await? != ephemeral_timer
-    {
-        if let Err(err) = (*chat_id)
-            .inner_set_ephemeral_timer(context, ephemeral_timer)
-            .await
-        {
-            warn!(
-                context,
-                "failed to modify timer for chat {}: {}", chat_id, err
-            );
-        } else if mime_parser.is_system_message != SystemMessage::EphemeralTimerChanged {
-            chat::add_info_msg(
-                context,
-                *chat_id,
-                stock_ephemeral_timer_changed(context, ephemeral_timer, from_id).await,
-            )
-            .await;
-        }
+    // received messages may be confusing to the user. Timers set for
+    // a single message are not applied to the chat either.
+    let is_timer_override = mime_parser
+        .get(HeaderDef::ChatEphemeralTimerOverride)
+        .is_some();
+    if !*hidden && !location_kml_is && !is_mdn && !is_timer_override {
+        apply_ephemeral_timer(
+            context,
+            *chat_id,
+            TimerChange {
+                timestamp: *sent_timestamp,
+                timer: ephemeral_timer,
+                from_id,
+            },
+            mime_parser.is_system_message,
+        )
+        .await;
     }
 
     if mime_parser.is_system_message == SystemMessage::EphemeralTimerChanged {
@@ -714,6 +732,11 @@ async fn add_parts(
         ephemeral_timer = EphemeralTimer::Disabled;
     }
 
+    // Do not let undecryptable messages expire, so they are not lost
+    // before the key is recovered, see `Config::ExpireUndecryptable`.
+    let keep_undecryptable = mime_parser.decrypting_failed
+        && !context.get_config_bool(Config::ExpireUndecryptable).await;
+
     // if a chat is protected, check additional properties
     if !chat_id.is_special() {
         let chat = Chat::load_from_db(context, *chat_id).await?;
@@ -841,12 +864,12 @@ async fn add_parts(
                     part.param.set_int(Param::Cmd, is_system_message as i32);
                 }
 
-                let ephemeral_timestamp = if in_fresh {
+                let ephemeral_timestamp = if in_fresh || keep_undecryptable {
                     0
                 } else {
                     match ephemeral_timer {
                         EphemeralTimer::Disabled => 0,
-                        EphemeralTimer::Enabled { duration } => rcvd_timestamp + i64::from(duration)
+                        EphemeralTimer::Enabled { duration, .. } => rcvd_timestamp + i64::from(duration)
                     }
                 };
 
@@ -1800,6 +1823,83 @@ async fn check_verified_properties(
     Ok(())
 }
 
+/// Applies the ephemeral timer of a received message to its chat.
+///
+/// Every sent message carries the timer of its chat, so members added
+/// to a group learn the timer from the member-added message or the
+/// first message they receive, without waiting for a timer change.
+///
+/// Changes older than the last applied one are ignored, so messages
+/// arriving out of order do not flip the timer back. Timers of members
+/// who missed our own last change are not applied either, instead the
+/// change is re-sent, see `ChatId::reconcile_ephemeral_timer`.
+async fn apply_ephemeral_timer(
+    context: &Context,
+    chat_id: ChatId,
+    change: TimerChange,
+    is_system_message: SystemMessage,
+) {
+    // A timer differing from our own last change may just be outdated.
+    if is_system_message != SystemMessage::EphemeralTimerChanged {
+        match chat_id.reconcile_ephemeral_timer(context, change).await {
+            Ok(true) => return,
+            Ok(false) => {}
+            Err(err) => {
+                warn!(
+                    context,
+                    "failed to reconcile timer for chat {}: {}", chat_id, err
+                );
+            }
+        }
+    }
+
+    match chat_id.apply_ephemeral_timer_change(context, change).await {
+        Ok(true) if is_system_message != SystemMessage::EphemeralTimerChanged => {
+            chat::add_info_msg(
+                context,
+                chat_id,
+                stock_ephemeral_timer_changed(context, change.timer, change.from_id).await,
+            )
+            .await;
+        }
+        Ok(_) => {}
+        Err(err) => {
+            warn!(
+                context,
+                "failed to modify timer for chat {}: {}", chat_id, err
+            );
+        }
+    }
+}
+
+/// Trashes the message referenced by a delete request.
+///
+/// The referenced message is only deleted if it was sent by the
+/// sender of the request, so nobody can delete other people's messages.
+async fn handle_delete_request(
+    context: &Context,
+    mime_parser: &MimeMessage,
+    from_id: u32,
+) -> Result<()> {
+    let rfc724_mid = match mime_parser.get(HeaderDef::ChatDeleteRequest) {
+        Some(value) => parse_message_id(value)?,
+        None => bail!("delete request without Message-ID"),
+    };
+
+    if let Some((_, _, msg_id)) = message::rfc724_mid_exists(context, &rfc724_mid).await? {
+        let msg = message::Message::load_from_db(context, msg_id).await?;
+        ensure!(
+            msg.from_id == from_id,
+            "delete request for {} not sent by its author",
+            msg_id
+        );
+        message::delete_msgs(context, &[msg_id]).await;
+    } else {
+        info!(context, "Delete request for unknown message {}", rfc724_mid);
+    }
+    Ok(())
+}
+
 fn set_better_msg(mime_parser: &mut MimeMessage, better_msg: impl AsRef<str>) {
     let msg = better_msg.as_ref();
     if !msg.is_empty() {
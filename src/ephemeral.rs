@@ -14,6 +14,12 @@
 //! all received messages, including BCC-self ones, to the chat. This
 //! way the setting is eventually synchronized among all participants.
 //!
+//! Messages may arrive out of order, and two participants may change
+//! the timer at the same time. To make all devices agree on the
+//! setting, the timestamp and sender of the last applied change are
+//! stored, and a received timer only replaces it if it wins according
+//! to `TimerChange::wins_over`.
+//!
 //! When user changes ephemeral timer setting for the chat, a system
 //! message is automatically sent to update the setting for all
 //! participants. This allows changing the setting for a chat like any
@@ -56,46 +62,284 @@
 //! the database entries which are expired either according to their
 //! ephemeral message timers or global `delete_server_after` setting.
 
-use crate::chat::{lookup_by_contact_id, send_msg, ChatId};
+use crate::chat::{lookup_by_contact_id, send_msg, Chat, ChatId};
+use crate::config::Config;
 use crate::constants::{
-    Viewtype, DC_CHAT_ID_LAST_SPECIAL, DC_CHAT_ID_TRASH, DC_CONTACT_ID_DEVICE, DC_CONTACT_ID_SELF,
+    Chattype, Viewtype, DC_CHAT_ID_LAST_SPECIAL, DC_CHAT_ID_TRASH, DC_CONTACT_ID_DEVICE,
+    DC_CONTACT_ID_SELF,
 };
+use crate::contact::Contact;
 use crate::context::Context;
-use crate::dc_tools::time;
+use crate::dc_tools::{dc_delete_file, time};
 use crate::error::{ensure, Error};
 use crate::events::EventType;
+use crate::job;
 use crate::message::{Message, MessageState, MsgId};
-use crate::mimeparser::SystemMessage;
+use crate::mimeparser::{SystemMessage, DECRYPTION_FAILED_ERROR};
+use crate::param::{Param, Params};
+use crate::scheduler::{InterruptInfo, InterruptReason};
 use crate::sql;
 use crate::stock::StockMessage;
 use async_std::task;
+use futures::future::BoxFuture;
+use futures::stream::{self, Stream};
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
+use std::cmp;
+use std::collections::HashSet;
 use std::convert::{TryFrom, TryInto};
+use std::fmt;
 use std::num::ParseIntError;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+/// Delay before retrying deletions the deletion auditor has failed to record.
+const AUDIT_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest delay added to the ephemeral task of an account, see
+/// `ephemeral_task_offset()`.
+const EPHEMERAL_TASK_SPREAD: Duration = Duration::from_secs(60);
+
+/// Longest ephemeral timer which can be set, one year.
+pub const MAX_TIMER_DURATION: u32 = 365 * 24 * 60 * 60;
+
+/// Seconds a burn-after-reading message is kept after it is seen.
+const BURN_AFTER_READING_DELAY: u32 = 1;
+
+/// Number of messages updated by one SQL statement, so the number of
+/// parameters stays below the SQLite limit.
+const UPDATE_BATCH_SIZE: usize = 500;
+
+/// Ephemeral message timer.
+///
+/// Timers are compared and sent by their duration in seconds only.
+/// The unit is kept so UIs can show the timer in the unit the user
+/// chose it in, the unit of the chat timer is stored in the chat
+/// params.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub enum Timer {
     Disabled,
-    Enabled { duration: u32 },
+    Enabled {
+        /// Duration in seconds.
+        duration: u32,
+
+        /// Unit the duration was chosen in.
+        #[serde(default)]
+        unit: TimerUnit,
+    },
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_u32() == other.to_u32()
+    }
+}
+
+impl Eq for Timer {}
+
+/// Unit of an ephemeral timer, see `Timer::unit()`.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+pub enum TimerUnit {
+    Seconds,
+    Minutes,
+    Hours,
+    Days,
+    Weeks,
+}
+
+impl Default for TimerUnit {
+    fn default() -> Self {
+        Self::Seconds
+    }
+}
+
+impl TimerUnit {
+    /// Returns the length of the unit in seconds.
+    pub fn to_seconds(self) -> u32 {
+        match self {
+            Self::Seconds => 1,
+            Self::Minutes => 60,
+            Self::Hours => 60 * 60,
+            Self::Days => 24 * 60 * 60,
+            Self::Weeks => 7 * 24 * 60 * 60,
+        }
+    }
+
+    /// Returns the unit of the given length in seconds, see
+    /// `TimerUnit::to_seconds()`.
+    pub fn from_seconds(seconds: u32) -> Option<Self> {
+        [
+            Self::Seconds,
+            Self::Minutes,
+            Self::Hours,
+            Self::Days,
+            Self::Weeks,
+        ]
+        .iter()
+        .copied()
+        .find(|unit| unit.to_seconds() == seconds)
+    }
+
+    /// Returns the largest unit `duration` is a whole multiple of,
+    /// e.g. `TimerUnit::Hours` for 7200 seconds.
+    pub fn natural(duration: u32) -> Self {
+        for unit in &[Self::Weeks, Self::Days, Self::Hours, Self::Minutes] {
+            if duration % unit.to_seconds() == 0 {
+                return *unit;
+            }
+        }
+        Self::Seconds
+    }
 }
 
 impl Timer {
     pub fn to_u32(self) -> u32 {
         match self {
             Self::Disabled => 0,
-            Self::Enabled { duration } => duration,
+            Self::Enabled { duration, .. } => duration,
         }
     }
 
+    /// Creates a timer from a duration in seconds, inferring the most
+    /// natural unit, see `TimerUnit::natural()`.
     pub fn from_u32(duration: u32) -> Self {
         if duration == 0 {
             Self::Disabled
         } else {
-            Self::Enabled { duration }
+            Self::Enabled {
+                duration,
+                unit: TimerUnit::natural(duration),
+            }
+        }
+    }
+
+    /// Creates a timer of `value` `unit`s, e.g. 1.5 hours.
+    ///
+    /// The duration is rounded to whole seconds. Unlike
+    /// `Timer::from_u32`, the unit is kept as given.
+    pub fn from_unit(value: f64, unit: TimerUnit) -> Self {
+        let duration = (value * f64::from(unit.to_seconds())).round();
+        if duration.is_nan() || duration < 1.0 {
+            Self::Disabled
+        } else if duration >= f64::from(u32::MAX) {
+            Self::Enabled {
+                duration: u32::MAX,
+                unit,
+            }
+        } else {
+            Self::Enabled {
+                duration: duration as u32,
+                unit,
+            }
+        }
+    }
+
+    /// Returns the unit the timer was chosen in, or inferred by
+    /// `Timer::from_u32`, or `None` if the timer is disabled.
+    pub fn unit(self) -> Option<TimerUnit> {
+        match self {
+            Self::Disabled => None,
+            Self::Enabled { unit, .. } => Some(unit),
+        }
+    }
+
+    /// Returns the duration in the unit of the timer, e.g. 1.5 for
+    /// 90 minutes chosen in hours.
+    pub fn value_in_unit(self) -> f64 {
+        match self {
+            Self::Disabled => 0.0,
+            Self::Enabled { duration, unit } => f64::from(duration) / f64::from(unit.to_seconds()),
+        }
+    }
+
+    /// Creates a timer, rejecting durations above `MAX_TIMER_DURATION`.
+    ///
+    /// Unlike `Timer::from_u32`, this should be used for timers set
+    /// by the user, so meaningless timers are not sent to other chat
+    /// members.
+    pub fn validated(duration: u32) -> Result<Self, TimerError> {
+        if duration > MAX_TIMER_DURATION {
+            Err(TimerError::TooLong(duration))
+        } else {
+            Ok(Self::from_u32(duration))
         }
     }
+
+    /// Creates a timer from a value stored in the database, rejecting
+    /// negative values and values not fitting into 32 bits.
+    ///
+    /// Durations above `MAX_TIMER_DURATION`, e.g. stored by older
+    /// versions, are clamped to `MAX_TIMER_DURATION`.
+    pub fn from_stored(value: i64) -> Result<Self, TimerError> {
+        let duration = u32::try_from(value).map_err(|_| TimerError::OutOfRange(value))?;
+        Ok(Self::from_u32(cmp::min(duration, MAX_TIMER_DURATION)))
+    }
+
+    /// Parses the values of the `Ephemeral-Timer` headers of a message.
+    ///
+    /// Malformed or forwarded messages may carry several headers. The
+    /// first valid value is used and the others are ignored, so the
+    /// result does not depend on headers appended later. Returns `None`
+    /// if no value is valid.
+    pub(crate) fn from_header_values<'a>(
+        values: impl IntoIterator<Item = &'a str>,
+    ) -> Option<Self> {
+        values
+            .into_iter()
+            .find_map(|value| value.trim().parse::<Timer>().ok())
+    }
+
+    /// Returns a builder creating a timer from weeks, days, hours,
+    /// minutes and seconds, e.g. `Timer::builder().hours(1).build()`.
+    pub fn builder() -> TimerBuilder {
+        TimerBuilder::default()
+    }
+}
+
+/// Builder summing up time units to a `Timer`, see `Timer::builder()`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TimerBuilder {
+    duration: u32,
+}
+
+impl TimerBuilder {
+    pub fn weeks(self, weeks: u32) -> Self {
+        self.seconds(weeks.saturating_mul(7 * 24 * 60 * 60))
+    }
+
+    pub fn days(self, days: u32) -> Self {
+        self.seconds(days.saturating_mul(24 * 60 * 60))
+    }
+
+    pub fn hours(self, hours: u32) -> Self {
+        self.seconds(hours.saturating_mul(60 * 60))
+    }
+
+    pub fn minutes(self, minutes: u32) -> Self {
+        self.seconds(minutes.saturating_mul(60))
+    }
+
+    pub fn seconds(mut self, seconds: u32) -> Self {
+        self.duration = self.duration.saturating_add(seconds);
+        self
+    }
+
+    /// Returns the timer, `Timer::Disabled` if all units are zero.
+    ///
+    /// The duration is not validated, see `Timer::validated()`.
+    pub fn build(self) -> Timer {
+        Timer::from_u32(self.duration)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum TimerError {
+    #[error("Ephemeral timer of {0} seconds is longer than one year")]
+    TooLong(u32),
+
+    #[error("Stored ephemeral timer {0} is out of range")]
+    OutOfRange(i64),
 }
 
 impl Default for Timer {
@@ -122,7 +366,7 @@ impl rusqlite::types::ToSql for Timer {
     fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput> {
         let val = rusqlite::types::Value::Integer(match self {
             Self::Disabled => 0,
-            Self::Enabled { duration } => i64::from(*duration),
+            Self::Enabled { duration, .. } => i64::from(*duration),
         });
         let out = rusqlite::types::ToSqlOutput::Owned(val);
         Ok(out)
@@ -132,28 +376,93 @@ impl rusqlite::types::ToSql for Timer {
 impl rusqlite::types::FromSql for Timer {
     fn column_result(value: rusqlite::types::ValueRef) -> rusqlite::types::FromSqlResult<Self> {
         i64::column_result(value).and_then(|value| {
-            if value == 0 {
-                Ok(Self::Disabled)
-            } else if let Ok(duration) = u32::try_from(value) {
-                Ok(Self::Enabled { duration })
-            } else {
-                Err(rusqlite::types::FromSqlError::OutOfRange(value))
-            }
+            Self::from_stored(value).map_err(|_| rusqlite::types::FromSqlError::OutOfRange(value))
         })
     }
 }
 
 impl ChatId {
     /// Get ephemeral message timer value in seconds.
+    ///
+    /// The timer has the unit it was set in, if the unit is stored.
     pub async fn get_ephemeral_timer(self, context: &Context) -> Result<Timer, Error> {
-        let timer = context
+        let row = context
             .sql
-            .query_get_value_result(
-                "SELECT ephemeral_timer FROM chats WHERE id=?;",
+            .query_row_optional(
+                "SELECT ephemeral_timer, param FROM chats WHERE id=?;",
+                paramsv![self],
+                |row| Ok((row.get::<_, Timer>(0)?, row.get::<_, Option<String>>(1)?)),
+            )
+            .await?;
+        let (timer, param) = match row {
+            Some(row) => row,
+            None => return Ok(Timer::default()),
+        };
+        let unit = param
+            .unwrap_or_default()
+            .parse::<Params>()
+            .unwrap_or_default()
+            .get_int(Param::EphemeralTimerUnit)
+            .and_then(|seconds| u32::try_from(seconds).ok())
+            .and_then(TimerUnit::from_seconds);
+        Ok(match (timer, unit) {
+            (Timer::Enabled { duration, .. }, Some(unit)) => Timer::Enabled { duration, unit },
+            (timer, _) => timer,
+        })
+    }
+
+    /// Returns the timestamp at which all messages of the chat will have
+    /// expired if no new messages arrive, or `None` if some message
+    /// does not expire, e.g. because its ephemeral timer is disabled or
+    /// not started yet.
+    ///
+    /// A chat which is empty already returns the current time.
+    pub async fn time_until_empty(self, context: &Context) -> Result<Option<i64>, Error> {
+        if context.get_config_int(Config::KeepLastNPerChat).await > 0 {
+            return Ok(None);
+        }
+        let (self_chat_id, device_chat_id) = retained_chat_ids(context).await;
+        let delete_device_after = if self == self_chat_id || self == device_chat_id {
+            None
+        } else {
+            context.get_config_delete_device_after().await
+        };
+
+        let msgs: Vec<(i64, i64)> = context
+            .sql
+            .query_map(
+                "SELECT timestamp, ephemeral_timestamp FROM msgs WHERE chat_id=?",
+                paramsv![self],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+                |rows| {
+                    rows.collect::<std::result::Result<Vec<_>, _>>()
+                        .map_err(Into::into)
+                },
+            )
+            .await?;
+
+        let mut empty_at = time();
+        for (timestamp, ephemeral_timestamp) in msgs {
+            match effective_expiry(timestamp, ephemeral_timestamp, delete_device_after) {
+                Some(expiry) => empty_at = cmp::max(empty_at, expiry),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(empty_at))
+    }
+
+    /// Returns the soonest timestamp at which a message of the chat
+    /// expires, or `None` if no ephemeral timer is running in the chat.
+    pub async fn next_ephemeral_expiry(self, context: &Context) -> Result<Option<i64>, Error> {
+        let expiry = context
+            .sql
+            .query_get_value_result::<Option<i64>>(
+                "SELECT MIN(ephemeral_timestamp) FROM msgs \
+                 WHERE chat_id=? AND ephemeral_timestamp!=0",
                 paramsv![self],
             )
             .await?;
-        Ok(timer.unwrap_or_default())
+        Ok(expiry.flatten())
     }
 
     /// Set ephemeral timer value without sending a message.
@@ -167,13 +476,20 @@ impl ChatId {
     ) -> Result<(), Error> {
         ensure!(!self.is_special(), "Invalid chat ID");
 
+        let mut chat = Chat::load_from_db(context, self).await?;
+        match timer.unit() {
+            Some(unit) => chat
+                .param
+                .set_int(Param::EphemeralTimerUnit, unit.to_seconds() as i32),
+            None => chat.param.remove(Param::EphemeralTimerUnit),
+        };
         context
             .sql
             .execute(
                 "UPDATE chats
-             SET ephemeral_timer=?
+             SET ephemeral_timer=?, param=?
              WHERE id=?;",
-                paramsv![timer, self],
+                paramsv![timer, chat.param.to_string(), self],
             )
             .await?;
 
@@ -184,14 +500,182 @@ impl ChatId {
         Ok(())
     }
 
+    /// Applies a received ephemeral timer change to the chat unless it
+    /// loses against the last applied change, see `TimerChange::wins_over`.
+    ///
+    /// Returns true if the timer of the chat was changed.
+    pub(crate) async fn apply_ephemeral_timer_change(
+        self,
+        context: &Context,
+        change: TimerChange,
+    ) -> Result<bool, Error> {
+        if self.is_special() {
+            return Ok(false);
+        }
+
+        let chat = Chat::load_from_db(context, self).await?;
+        let timer = self.get_ephemeral_timer(context).await?;
+        let timestamp = chat
+            .param
+            .get(Param::EphemeralTimerTimestamp)
+            .and_then(|timestamp| timestamp.parse().ok());
+        let from_id = chat.param.get_int(Param::EphemeralTimerSender);
+        if let (Some(timestamp), Some(from_id)) = (timestamp, from_id) {
+            let current = TimerChange {
+                timestamp,
+                timer,
+                from_id: from_id as u32,
+            };
+            if !change.wins_over(context, &current).await? {
+                info!(
+                    context,
+                    "Ignoring ephemeral timer change {:?} of {}, {:?} wins", change, self, current
+                );
+                return Ok(false);
+            }
+        }
+
+        self.store_ephemeral_timer_change(context, change).await?;
+        if change.timer == timer {
+            return Ok(false);
+        }
+        self.inner_set_ephemeral_timer(context, change.timer)
+            .await?;
+        Ok(true)
+    }
+
+    /// Re-sends the last ephemeral timer change made by ourselves if
+    /// `change`, carried by a received message, shows that its sender
+    /// never learned it, e.g. because the timer change message could not
+    /// be sent.
+    ///
+    /// The change is re-sent only once, so members not supporting
+    /// ephemeral messages do not cause a loop.
+    ///
+    /// Returns true if the timer of `change` is outdated and must not
+    /// be applied.
+    pub(crate) async fn reconcile_ephemeral_timer(
+        self,
+        context: &Context,
+        change: TimerChange,
+    ) -> Result<bool, Error> {
+        if self.is_special() || change.from_id == DC_CONTACT_ID_SELF {
+            return Ok(false);
+        }
+
+        let mut chat = Chat::load_from_db(context, self).await?;
+        if chat.param.get_int(Param::EphemeralTimerSender) != Some(DC_CONTACT_ID_SELF as i32) {
+            return Ok(false);
+        }
+        let timestamp: i64 = match chat
+            .param
+            .get(Param::EphemeralTimerTimestamp)
+            .and_then(|timestamp| timestamp.parse().ok())
+        {
+            Some(timestamp) => timestamp,
+            None => return Ok(false),
+        };
+        let timer = self.get_ephemeral_timer(context).await?;
+        if change.timer == timer || change.timestamp < timestamp {
+            return Ok(false);
+        }
+
+        // Only a contact still using the timer our change replaced
+        // missed it, other timers are changes of their own.
+        let previous = chat
+            .param
+            .get(Param::EphemeralTimerPrevious)
+            .and_then(|previous| previous.parse().ok())
+            .map(Timer::from_u32);
+        if previous != Some(change.timer) {
+            return Ok(false);
+        }
+
+        let resent = chat
+            .param
+            .get(Param::EphemeralTimerResent)
+            .and_then(|resent| resent.parse::<i64>().ok());
+        if resent == Some(timestamp) {
+            info!(
+                context,
+                "Contact {} still does not know ephemeral timer of {}, already re-sent",
+                change.from_id,
+                self
+            );
+            return Ok(true);
+        }
+
+        info!(
+            context,
+            "Contact {} does not know ephemeral timer of {}, re-sending it", change.from_id, self
+        );
+        let resent = TimerChange {
+            timestamp: time(),
+            timer,
+            from_id: DC_CONTACT_ID_SELF,
+        };
+        chat.param
+            .set(Param::EphemeralTimerResent, resent.timestamp.to_string());
+        chat.update_param(context).await?;
+        self.store_ephemeral_timer_change(context, resent).await?;
+
+        let mut msg = Message::new(Viewtype::Text);
+        msg.text = Some(stock_ephemeral_timer_changed(context, timer, DC_CONTACT_ID_SELF).await);
+        msg.param.set_cmd(SystemMessage::EphemeralTimerChanged);
+        send_msg(context, self, &mut msg).await?;
+        Ok(true)
+    }
+
+    /// Stores the timestamp and sender of the last applied ephemeral
+    /// timer change.
+    async fn store_ephemeral_timer_change(
+        self,
+        context: &Context,
+        change: TimerChange,
+    ) -> Result<(), Error> {
+        let mut chat = Chat::load_from_db(context, self).await?;
+        chat.param
+            .set(Param::EphemeralTimerTimestamp, change.timestamp.to_string())
+            .set_int(Param::EphemeralTimerSender, change.from_id as i32);
+        chat.update_param(context).await?;
+        Ok(())
+    }
+
     /// Set ephemeral message timer value in seconds.
     ///
     /// If timer value is 0, disable ephemeral message timer.
+    ///
+    /// A message informing the other chat members is sent, except for
+    /// the self and device chats, which have no other members.
     pub async fn set_ephemeral_timer(self, context: &Context, timer: Timer) -> Result<(), Error> {
-        if timer == self.get_ephemeral_timer(context).await? {
+        Timer::validated(timer.to_u32())?;
+        let old_timer = self.get_ephemeral_timer(context).await?;
+        if timer == old_timer {
+            // Only the unit changed, there is nothing to tell the
+            // other members.
+            if timer.unit() != old_timer.unit() {
+                self.inner_set_ephemeral_timer(context, timer).await?;
+            }
             return Ok(());
         }
         self.inner_set_ephemeral_timer(context, timer).await?;
+        let chat = Chat::load_from_db(context, self).await?;
+        if chat.is_self_talk() || chat.is_device_talk() {
+            return Ok(());
+        }
+        let change = TimerChange {
+            timestamp: time(),
+            timer,
+            from_id: DC_CONTACT_ID_SELF,
+        };
+        self.store_ephemeral_timer_change(context, change).await?;
+        let mut chat = Chat::load_from_db(context, self).await?;
+        chat.param.set(
+            Param::EphemeralTimerPrevious,
+            old_timer.to_u32().to_string(),
+        );
+        chat.update_param(context).await?;
+
         let mut msg = Message::new(Viewtype::Text);
         msg.text = Some(stock_ephemeral_timer_changed(context, timer, DC_CONTACT_ID_SELF).await);
         msg.param.set_cmd(SystemMessage::EphemeralTimerChanged);
@@ -205,15 +689,54 @@ impl ChatId {
     }
 }
 
-/// Returns a stock message saying that ephemeral timer is changed to `timer` by `from_id`.
-pub(crate) async fn stock_ephemeral_timer_changed(
-    context: &Context,
-    timer: Timer,
-    from_id: u32,
-) -> String {
+/// Ephemeral timer change carried by a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TimerChange {
+    /// Sent timestamp of the message.
+    pub timestamp: i64,
+    pub timer: Timer,
+    pub from_id: u32,
+}
+
+impl TimerChange {
+    /// Returns true if this change takes precedence over `other`.
+    ///
+    /// The later change wins. If both were sent at the same time, the
+    /// stricter timer wins, i.e. the shorter one, and if the timers are
+    /// the same, the change from the sender with the lower address wins.
+    /// Contact ids differ between devices, addresses do not, so all
+    /// devices agree on the result regardless of the order changes
+    /// arrive in.
+    pub async fn wins_over(&self, context: &Context, other: &TimerChange) -> Result<bool, Error> {
+        let addr = Contact::load_from_db(context, self.from_id)
+            .await?
+            .get_addr()
+            .to_lowercase();
+        let other_addr = Contact::load_from_db(context, other.from_id)
+            .await?
+            .get_addr()
+            .to_lowercase();
+        Ok(self.precedence(&addr) > other.precedence(&other_addr))
+    }
+
+    fn precedence<'a>(&self, addr: &'a str) -> (i64, u64, cmp::Reverse<&'a str>) {
+        let strictness = match self.timer {
+            Timer::Disabled => 0,
+            Timer::Enabled { duration, .. } => u64::from(u32::MAX) - u64::from(duration) + 1,
+        };
+        (self.timestamp, strictness, cmp::Reverse(addr))
+    }
+}
+
+/// Returns the stock message used to announce that the ephemeral
+/// timer is changed to `timer` and the value inserted into it.
+///
+/// Presets have their own stock messages, other durations are shown in
+/// seconds using `StockMessage::MsgEphemeralTimerEnabled`.
+pub fn default_ephemeral_timer_stock(timer: Timer) -> (StockMessage, String) {
     let stock_message = match timer {
         Timer::Disabled => StockMessage::MsgEphemeralTimerDisabled,
-        Timer::Enabled { duration } => match duration {
+        Timer::Enabled { duration, .. } => match duration {
             60 => StockMessage::MsgEphemeralTimerMinute,
             3600 => StockMessage::MsgEphemeralTimerHour,
             86400 => StockMessage::MsgEphemeralTimerDay,
@@ -222,35 +745,143 @@ pub(crate) async fn stock_ephemeral_timer_changed(
             _ => StockMessage::MsgEphemeralTimerEnabled,
         },
     };
+    (stock_message, timer.to_string())
+}
+
+/// Mapping from timer values to stock messages set with
+/// `Context::set_ephemeral_timer_stock_mapping()`.
+pub(crate) struct EphemeralTimerStockMapping(
+    Box<dyn Fn(Timer) -> (StockMessage, String) + Send + Sync>,
+);
+
+impl fmt::Debug for EphemeralTimerStockMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EphemeralTimerStockMapping")
+    }
+}
+
+impl Context {
+    /// Sets the function choosing the stock message which announces
+    /// ephemeral timer changes, replacing `default_ephemeral_timer_stock()`.
+    ///
+    /// The function returns the stock message and the value inserted for
+    /// its `%1$s` placeholder. This allows to render durations in the
+    /// units preferred by a locale, e.g. by mapping one hour to
+    /// `StockMessage::MsgEphemeralTimerEnabled` with the value "60" and
+    /// translating it to say minutes.
+    pub async fn set_ephemeral_timer_stock_mapping<F>(&self, mapping: F)
+    where
+        F: Fn(Timer) -> (StockMessage, String) + Send + Sync + 'static,
+    {
+        *self.ephemeral_timer_stock_mapping.write().await =
+            Some(EphemeralTimerStockMapping(Box::new(mapping)));
+    }
+}
+
+/// Returns the timestamp at which a message sent at `timestamp` is
+/// deleted from the device, or `None` if it is kept.
+///
+/// `ephemeral_timestamp` is 0 if no ephemeral timer is running for the
+/// message, `delete_device_after` is `None` if the setting does not
+/// apply to the message.
+pub(crate) fn effective_expiry(
+    timestamp: i64,
+    ephemeral_timestamp: i64,
+    delete_device_after: Option<i64>,
+) -> Option<i64> {
+    let ephemeral_expiry = Some(ephemeral_timestamp).filter(|t| *t != 0);
+    let device_expiry = delete_device_after.map(|after| timestamp + after);
+    match (ephemeral_expiry, device_expiry) {
+        (Some(a), Some(b)) => Some(cmp::min(a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Returns a stock message saying that ephemeral timer is changed to `timer` by `from_id`.
+pub(crate) async fn stock_ephemeral_timer_changed(
+    context: &Context,
+    timer: Timer,
+    from_id: u32,
+) -> String {
+    let (stock_message, value) = match &*context.ephemeral_timer_stock_mapping.read().await {
+        Some(EphemeralTimerStockMapping(mapping)) => mapping(timer),
+        None => default_ephemeral_timer_stock(timer),
+    };
 
     context
-        .stock_system_msg(stock_message, timer.to_string(), "", from_id)
+        .stock_system_msg(stock_message, value, "", from_id)
         .await
 }
 
 impl MsgId {
     /// Returns ephemeral message timer value for the message.
+    ///
+    /// A corrupt stored value is logged and treated as
+    /// `Timer::Disabled`, so it is not used for scheduling.
     pub(crate) async fn ephemeral_timer(self, context: &Context) -> crate::sql::Result<Timer> {
-        let res = match context
+        let value: Option<i64> = context
             .sql
             .query_get_value_result(
                 "SELECT ephemeral_timer FROM msgs WHERE id=?",
                 paramsv![self],
             )
-            .await?
-        {
-            None | Some(0) => Timer::Disabled,
-            Some(duration) => Timer::Enabled { duration },
+            .await?;
+        let res = match Timer::from_stored(value.unwrap_or_default()) {
+            Ok(timer) => timer,
+            Err(err) => {
+                warn!(
+                    context,
+                    "Ignoring ephemeral timer of message {}: {}", self, err
+                );
+                Timer::Disabled
+            }
         };
         Ok(res)
     }
 
     /// Starts ephemeral message timer for the message if it is not started yet.
+    ///
+    /// If `Config::SyncSeenToServer` is enabled, the message is also
+    /// queued to be marked as seen on the server.
     pub(crate) async fn start_ephemeral_timer(self, context: &Context) -> crate::sql::Result<()> {
-        if let Timer::Enabled { duration } = self.ephemeral_timer(context).await? {
-            let ephemeral_timestamp = time() + i64::from(duration);
+        self.start_ephemeral_timer_at(context, time()).await
+    }
+
+    /// Same as `start_ephemeral_timer`, but the timer starts at `seen_at`
+    /// instead of now, e.g. when processing messages which were
+    /// displayed some time ago.
+    ///
+    /// An already running timer is only moved to expire earlier. A
+    /// `seen_at` in the future is treated as now, so a wrong clock does
+    /// not extend the timer.
+    pub(crate) async fn start_ephemeral_timer_at(
+        self,
+        context: &Context,
+        seen_at: i64,
+    ) -> crate::sql::Result<()> {
+        let param = self.load_param(context).await?;
+        if param.exists(Param::EphemeralRemaining) {
+            // The timer is already started, but paused.
+            return Ok(());
+        }
+        if self.is_undecryptable(context).await?
+            && !context.get_config_bool(Config::ExpireUndecryptable).await
+        {
+            info!(
+                context,
+                "Not starting ephemeral timer of undecryptable message {}", self
+            );
+            return Ok(());
+        }
+        let timer = if param.get_bool(Param::BurnAfterReading).unwrap_or_default() {
+            Timer::from_u32(BURN_AFTER_READING_DELAY)
+        } else {
+            self.ephemeral_timer(context).await?
+        };
+        if let Timer::Enabled { duration, .. } = timer {
+            let ephemeral_timestamp = cmp::min(seen_at, time()) + i64::from(duration);
 
-            context
+            let started = context
                 .sql
                 .execute(
                     "UPDATE msgs SET ephemeral_timestamp = ? \
@@ -258,76 +889,549 @@ impl MsgId {
                 AND id = ?",
                     paramsv![ephemeral_timestamp, ephemeral_timestamp, self],
                 )
-                .await?;
+                .await?
+                > 0;
             schedule_ephemeral_task(context).await;
+
+            if started && context.get_config_bool(Config::SyncSeenToServer).await {
+                job::add(
+                    context,
+                    job::Job::new(
+                        job::Action::MarkseenEphemeralOnImap,
+                        self.to_u32(),
+                        Params::new(),
+                        0,
+                    ),
+                )
+                .await;
+            }
         }
         Ok(())
     }
-}
-
-/// Deletes messages which are expired according to
-/// `delete_device_after` setting or `ephemeral_timestamp` column.
-///
-/// Returns true if any message is deleted, so caller can emit
-/// MsgsChanged event. If nothing has been deleted, returns
-/// false. This function does not emit the MsgsChanged event itself,
-/// because it is also called when chatlist is reloaded, and emitting
-/// MsgsChanged there will cause infinite reload loop.
-pub(crate) async fn delete_expired_messages(context: &Context) -> Result<bool, Error> {
-    let mut updated = context
-        .sql
-        .execute(
-            "UPDATE msgs \
-             SET txt = 'DELETED', chat_id = ? \
-             WHERE \
-             ephemeral_timestamp != 0 \
-             AND ephemeral_timestamp < ? \
-             AND chat_id != ?",
-            paramsv![DC_CHAT_ID_TRASH, time(), DC_CHAT_ID_TRASH],
-        )
-        .await?
-        > 0;
 
-    if let Some(delete_device_after) = context.get_config_delete_device_after().await {
-        let self_chat_id = lookup_by_contact_id(context, DC_CONTACT_ID_SELF)
-            .await
-            .unwrap_or_default()
-            .0;
-        let device_chat_id = lookup_by_contact_id(context, DC_CONTACT_ID_DEVICE)
+    /// Returns true if the message could not be decrypted.
+    async fn is_undecryptable(self, context: &Context) -> crate::sql::Result<bool> {
+        context
+            .sql
+            .exists(
+                "SELECT id FROM msgs WHERE id=? AND error=?;",
+                paramsv![self, DECRYPTION_FAILED_ERROR],
+            )
             .await
-            .unwrap_or_default()
-            .0;
-
-        let threshold_timestamp = time() - delete_device_after;
+    }
 
-        // Delete expired messages
-        //
-        // Only update the rows that have to be updated, to avoid emitting
-        // unnecessary "chat modified" events.
-        let rows_modified = context
-            .sql
-            .execute(
-                "UPDATE msgs \
-             SET txt = 'DELETED', chat_id = ? \
-             WHERE timestamp < ? \
-             AND chat_id > ? \
-             AND chat_id != ? \
-             AND chat_id != ?",
-                paramsv![
-                    DC_CHAT_ID_TRASH,
+    /// Marks the message to be deleted right after it is seen,
+    /// regardless of the ephemeral timer of the chat.
+    ///
+    /// The flag is local, the message is not deleted on other devices
+    /// or for other chat members.
+    pub async fn set_burn_after_reading(
+        self,
+        context: &Context,
+        burn: bool,
+    ) -> crate::sql::Result<()> {
+        let mut param = self.load_param(context).await?;
+        if burn {
+            param.set_int(Param::BurnAfterReading, 1);
+        } else {
+            param.remove(Param::BurnAfterReading);
+        }
+        context
+            .sql
+            .execute(
+                "UPDATE msgs SET param=? WHERE id=?",
+                paramsv![param.to_string(), self],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn load_param(self, context: &Context) -> crate::sql::Result<Params> {
+        let param: Option<String> = context
+            .sql
+            .query_get_value_result("SELECT param FROM msgs WHERE id=?", paramsv![self])
+            .await?;
+        Ok(param.unwrap_or_default().parse().unwrap_or_default())
+    }
+
+    /// Pauses the running ephemeral timer of the message, e.g. while
+    /// the message is displayed, so it does not disappear mid-read.
+    ///
+    /// The remaining time is stashed until
+    /// `MsgId::resume_ephemeral_timer()` is called.
+    pub async fn pause_ephemeral_timer(self, context: &Context) -> Result<(), Error> {
+        let msg = Message::load_from_db(context, self).await?;
+        let mut param = msg.param;
+        if msg.ephemeral_timestamp == 0 || param.exists(Param::EphemeralRemaining) {
+            return Ok(());
+        }
+
+        let remaining = cmp::max(msg.ephemeral_timestamp - time(), 0);
+        param.set(Param::EphemeralRemaining, remaining.to_string());
+        context
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timestamp=0, param=? WHERE id=?",
+                paramsv![param.to_string(), self],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Resumes an ephemeral timer paused with
+    /// `MsgId::pause_ephemeral_timer()` with the stashed remaining time.
+    ///
+    /// A timer started while paused is only moved to expire earlier.
+    pub async fn resume_ephemeral_timer(self, context: &Context) -> Result<(), Error> {
+        let mut param = self.load_param(context).await?;
+        let remaining: i64 = match param
+            .get(Param::EphemeralRemaining)
+            .and_then(|remaining| remaining.parse().ok())
+        {
+            Some(remaining) => remaining,
+            None => return Ok(()),
+        };
+
+        param.remove(Param::EphemeralRemaining);
+        context
+            .sql
+            .execute(
+                "UPDATE msgs \
+                 SET ephemeral_timestamp = CASE \
+                 WHEN ephemeral_timestamp > 0 AND ephemeral_timestamp < ?1 \
+                 THEN ephemeral_timestamp ELSE ?1 END, \
+                 param=?2 \
+                 WHERE id=?3",
+                paramsv![time() + remaining, param.to_string(), self],
+            )
+            .await?;
+        schedule_ephemeral_task(context).await;
+        Ok(())
+    }
+
+    /// Deletes the message from the server as soon as possible,
+    /// regardless of ephemeral timers and `delete_server_after`.
+    ///
+    /// The message is handled like a message with an expired ephemeral
+    /// timer, so the local copy is moved to the trash chat as well.
+    pub async fn delete_from_server_now(self, context: &Context) -> Result<(), Error> {
+        context
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timestamp=? WHERE id=?",
+                paramsv![time() - 1, self],
+            )
+            .await?;
+
+        if delete_expired_messages(context).await? {
+            context.emit_event(EventType::MsgsChanged {
+                chat_id: ChatId::new(0),
+                msg_id: MsgId::new(0),
+            });
+        }
+        context
+            .interrupt_imap_jobs(InterruptInfo::new(false, None).with_reason(InterruptReason::Job))
+            .await;
+        Ok(())
+    }
+
+    /// Returns the timestamp at which the message will be deleted locally
+    /// because of its ephemeral timer, or `None` if no timer is running.
+    pub async fn ephemeral_timestamp(self, context: &Context) -> Result<Option<i64>, Error> {
+        let ephemeral_timestamp = context
+            .sql
+            .query_get_value_result::<i64>(
+                "SELECT ephemeral_timestamp FROM msgs WHERE id=? AND chat_id!=?",
+                paramsv![self, DC_CHAT_ID_TRASH],
+            )
+            .await?;
+        Ok(ephemeral_timestamp.filter(|timestamp| *timestamp != 0))
+    }
+
+    /// Returns a stream of seconds remaining until the message expires.
+    ///
+    /// A value is yielded once per second, the last one is zero. The
+    /// stream ends early if the message has no running ephemeral timer
+    /// or is deleted.
+    pub fn countdown_stream(self, context: &Context) -> impl Stream<Item = i64> {
+        let context = context.clone();
+        stream::unfold(Some(true), move |state| {
+            let context = context.clone();
+            async move {
+                let first = state?;
+                if !first {
+                    task::sleep(Duration::from_secs(1)).await;
+                }
+                let ephemeral_timestamp = context
+                    .sql
+                    .query_get_value_result::<i64>(
+                        "SELECT ephemeral_timestamp FROM msgs WHERE id=? AND chat_id!=?",
+                        paramsv![self, DC_CHAT_ID_TRASH],
+                    )
+                    .await
+                    .ok()
+                    .flatten()
+                    .filter(|timestamp| *timestamp != 0)?;
+
+                let remaining = cmp::max(ephemeral_timestamp - time(), 0);
+                let next_state = if remaining > 0 { Some(false) } else { None };
+                Some((remaining, next_state))
+            }
+        })
+    }
+
+    /// Returns true if the content of the message was already removed
+    /// by ephemeral deletion, see `delete_expired_messages`.
+    ///
+    /// UIs holding an old message id can use this to show the message
+    /// as deleted instead of stale cached content.
+    pub async fn is_scrubbed(self, context: &Context) -> bool {
+        context
+            .sql
+            .exists(
+                "SELECT id FROM msgs WHERE id=? AND chat_id=? AND txt='DELETED'",
+                paramsv![self, DC_CHAT_ID_TRASH],
+            )
+            .await
+            .unwrap_or_else(|err| {
+                warn!(context, "failed to check if {} is scrubbed: {}", self, err);
+                false
+            })
+    }
+}
+
+/// Deletes messages which are expired according to
+/// `delete_device_after` setting or `ephemeral_timestamp` column.
+///
+/// Emits `MsgDeleted` for each deleted message.
+///
+/// Returns true if any message is deleted, so caller can emit
+/// MsgsChanged event. If nothing has been deleted, returns
+/// false. This function does not emit the MsgsChanged event itself,
+/// because it is also called when chatlist is reloaded, and emitting
+/// MsgsChanged there will cause infinite reload loop.
+pub(crate) async fn delete_expired_messages(context: &Context) -> Result<bool, Error> {
+    sweep_expired_messages(context, true).await
+}
+
+/// Same as `delete_expired_messages`, but does not emit `MsgDeleted`
+/// events, so it is safe to call while the chatlist is reloaded.
+pub(crate) async fn hide_expired_messages(context: &Context) -> Result<bool, Error> {
+    sweep_expired_messages(context, false).await
+}
+
+async fn sweep_expired_messages(context: &Context, emit_events: bool) -> Result<bool, Error> {
+    let auditor = context.deletion_auditor.read().await.clone();
+    let deleted = if let Some(auditor) = auditor {
+        audit_and_delete_expired_messages(context, &auditor).await?
+    } else {
+        let expired = load_expired_messages(context).await?;
+        let deleted = trash_messages(context, expired).await?;
+        schedule_ephemeral_task(context).await;
+        deleted
+    };
+
+    if deleted.is_empty() {
+        return Ok(false);
+    }
+    info!(context, "Deleted {} expired messages", deleted.len());
+    if emit_events {
+        for record in &deleted {
+            context.emit_event(EventType::MsgDeleted {
+                chat_id: record.chat_id,
+                msg_id: record.msg_id,
+            });
+        }
+    }
+    Ok(true)
+}
+
+/// Deletes the attachment of the trashed message `msg` unless another
+/// message which is not trashed uses the same blob.
+///
+/// Returns true if the file was deleted.
+async fn remove_unreferenced_file(context: &Context, msg: &Message) -> Result<bool, Error> {
+    let file = match msg.param.get(Param::File) {
+        Some(file) => file,
+        None => return Ok(false),
+    };
+    let in_use = context
+        .sql
+        .exists(
+            "SELECT id FROM msgs \
+             WHERE chat_id != ? \
+             AND instr(char(10) || param || char(10), ?) > 0",
+            paramsv![DC_CHAT_ID_TRASH, format!("\nf={}\n", file)],
+        )
+        .await?;
+    if in_use {
+        return Ok(false);
+    }
+    Ok(dc_delete_file(context, file).await)
+}
+
+/// Moves the messages to the trash chat.
+///
+/// Returns the records of the messages which were moved, messages
+/// already in the trash chat are skipped.
+async fn trash_messages(
+    context: &Context,
+    records: Vec<AuditRecord>,
+) -> Result<Vec<AuditRecord>, Error> {
+    if records.is_empty() {
+        return Ok(records);
+    }
+
+    let msg_ids: Vec<MsgId> = records.iter().map(|record| record.msg_id).collect();
+    let trashed_ids = context
+        .sql
+        .with_conn(move |mut conn| {
+            let tx = conn.transaction()?;
+            let mut trashed_ids = HashSet::new();
+            for chunk in msg_ids.chunks(UPDATE_BATCH_SIZE) {
+                let id_list = chunk.iter().map(|_| "?").join(",");
+                let mut params: Vec<&dyn crate::ToSql> = vec![&DC_CHAT_ID_TRASH];
+                params.extend(chunk.iter().map(|msg_id| msg_id as &dyn crate::ToSql));
+
+                let mut stmt = tx.prepare(&format!(
+                    "SELECT id FROM msgs WHERE chat_id != ?1 AND id IN ({})",
+                    id_list
+                ))?;
+                let ids = stmt
+                    .query_map(&params, |row| row.get::<_, MsgId>(0))?
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                drop(stmt);
+
+                tx.execute(
+                    &format!(
+                        "UPDATE msgs SET txt = 'DELETED', chat_id = ?1 \
+                         WHERE chat_id != ?1 AND id IN ({})",
+                        id_list
+                    ),
+                    &params,
+                )?;
+                trashed_ids.extend(ids);
+            }
+            tx.commit()?;
+            Ok(trashed_ids)
+        })
+        .await?;
+
+    Ok(records
+        .into_iter()
+        .filter(|record| trashed_ids.contains(&record.msg_id))
+        .collect())
+}
+
+/// Moves messages older than `delete_device_after` seconds to the
+/// trash chat, except for messages in the self-talk and device chats.
+///
+/// Returns the number of deleted messages.
+async fn delete_device_expired_messages(
+    context: &Context,
+    delete_device_after: i64,
+) -> Result<usize, Error> {
+    let (self_chat_id, device_chat_id) = retained_chat_ids(context).await;
+
+    let threshold_timestamp = time() - delete_device_after;
+    let kept_msgs = kept_msgs_condition(context).await;
+
+    // Delete expired messages
+    //
+    // Only update the rows that have to be updated, to avoid emitting
+    // unnecessary "chat modified" events.
+    let rows_modified = context
+        .sql
+        .execute(
+            format!(
+                "UPDATE msgs \
+                 SET txt = 'DELETED', chat_id = ? \
+                 WHERE timestamp < ? \
+                 AND chat_id > ? \
+                 AND chat_id != ? \
+                 AND chat_id != ?{}",
+                kept_msgs
+            ),
+            paramsv![
+                DC_CHAT_ID_TRASH,
+                threshold_timestamp,
+                DC_CHAT_ID_LAST_SPECIAL,
+                self_chat_id,
+                device_chat_id
+            ],
+        )
+        .await?;
+    Ok(rows_modified)
+}
+
+/// Returns an SQL condition on the `id` column excluding the newest
+/// messages of each chat according to `Config::KeepLastNPerChat`.
+///
+/// The condition is empty if no messages are kept, so the windowed
+/// subquery is only evaluated when needed.
+async fn kept_msgs_condition(context: &Context) -> String {
+    let keep_last = context.get_config_int(Config::KeepLastNPerChat).await;
+    if keep_last <= 0 {
+        return String::new();
+    }
+    format!(
+        " AND id NOT IN ( \
+         SELECT id FROM ( \
+         SELECT id, ROW_NUMBER() OVER ( \
+         PARTITION BY chat_id ORDER BY timestamp DESC, id DESC) AS pos \
+         FROM msgs WHERE chat_id != {}) \
+         WHERE pos <= {})",
+        DC_CHAT_ID_TRASH, keep_last
+    )
+}
+
+/// Returns self-talk and device chat IDs, which are not affected by
+/// `delete_device_after` setting.
+async fn retained_chat_ids(context: &Context) -> (ChatId, ChatId) {
+    let self_chat_id = lookup_by_contact_id(context, DC_CONTACT_ID_SELF)
+        .await
+        .unwrap_or_default()
+        .0;
+    let device_chat_id = lookup_by_contact_id(context, DC_CONTACT_ID_DEVICE)
+        .await
+        .unwrap_or_default()
+        .0;
+    (self_chat_id, device_chat_id)
+}
+
+/// Variant of `delete_expired_messages` used when a deletion auditor
+/// is set.
+///
+/// Messages are trashed one by one, each only after the auditor has
+/// accepted its record. Messages for which auditing failed stay
+/// where they are and are retried after `AUDIT_RETRY_INTERVAL`.
+///
+/// Returns the records of the deleted messages.
+async fn audit_and_delete_expired_messages(
+    context: &Context,
+    auditor: &DeletionAuditor,
+) -> Result<Vec<AuditRecord>, Error> {
+    let mut deleted = Vec::new();
+    let mut deferred = false;
+
+    for record in load_expired_messages(context).await? {
+        if let Err(err) = (auditor.0)(record.clone()).await {
+            warn!(
+                context,
+                "Deletion audit for {} failed, deferring deletion: {}", record.msg_id, err
+            );
+            deferred = true;
+            continue;
+        }
+
+        deleted.extend(trash_messages(context, vec![record]).await?);
+    }
+
+    if deferred {
+        schedule_audit_retry(context).await;
+    } else {
+        schedule_ephemeral_task(context).await;
+    }
+    Ok(deleted)
+}
+
+/// Returns audit records for all messages `delete_expired_messages`
+/// would move to the trash chat now.
+async fn load_expired_messages(context: &Context) -> Result<Vec<AuditRecord>, Error> {
+    let kept_msgs = kept_msgs_condition(context).await;
+    let mut records = context
+        .sql
+        .query_map(
+            format!(
+                "SELECT id, chat_id \
+                 FROM msgs \
+                 WHERE ephemeral_timestamp != 0 \
+                 AND ephemeral_timestamp < ? \
+                 AND chat_id != ?{}",
+                kept_msgs
+            ),
+            paramsv![time(), DC_CHAT_ID_TRASH],
+            |row| {
+                Ok(AuditRecord {
+                    msg_id: row.get(0)?,
+                    chat_id: row.get(1)?,
+                    reason: DeletionReason::EphemeralTimer,
+                })
+            },
+            |rows| {
+                rows.collect::<std::result::Result<Vec<_>, _>>()
+                    .map_err(Into::into)
+            },
+        )
+        .await?;
+
+    if let Some(delete_device_after) = context.get_config_delete_device_after().await {
+        let (self_chat_id, device_chat_id) = retained_chat_ids(context).await;
+        let threshold_timestamp = time() - delete_device_after;
+
+        let expired: Vec<AuditRecord> = context
+            .sql
+            .query_map(
+                format!(
+                    "SELECT id, chat_id \
+                     FROM msgs \
+                     WHERE timestamp < ? \
+                     AND chat_id > ? \
+                     AND chat_id != ? \
+                     AND chat_id != ?{}",
+                    kept_msgs
+                ),
+                paramsv![
                     threshold_timestamp,
                     DC_CHAT_ID_LAST_SPECIAL,
                     self_chat_id,
                     device_chat_id
                 ],
+                |row| {
+                    Ok(AuditRecord {
+                        msg_id: row.get(0)?,
+                        chat_id: row.get(1)?,
+                        reason: DeletionReason::DeleteDeviceAfter,
+                    })
+                },
+                |rows| {
+                    rows.collect::<std::result::Result<Vec<_>, _>>()
+                        .map_err(Into::into)
+                },
             )
             .await?;
+        for record in expired {
+            if !records.iter().any(|r| r.msg_id == record.msg_id) {
+                records.push(record);
+            }
+        }
+    }
+
+    Ok(records)
+}
 
-        updated |= rows_modified > 0;
+/// Schedules a MsgsChanged event after `AUDIT_RETRY_INTERVAL`, so
+/// the UI reloads and deletion is retried.
+///
+/// Unlike `schedule_ephemeral_task`, this never emits the event
+/// immediately, to avoid a reload loop while the auditor is failing.
+async fn schedule_audit_retry(context: &Context) {
+    if let Some(ephemeral_task) = context.ephemeral_task.write().await.take() {
+        ephemeral_task.cancel().await;
     }
 
-    schedule_ephemeral_task(context).await;
-    Ok(updated)
+    let context1 = context.clone();
+    let ephemeral_task = context.spawn_task("ephemeral", async move {
+        async_std::task::sleep(AUDIT_RETRY_INTERVAL).await;
+        emit_event!(
+            context1,
+            EventType::MsgsChanged {
+                chat_id: ChatId::new(0),
+                msg_id: MsgId::new(0)
+            }
+        );
+    });
+    *context.ephemeral_task.write().await = Some(ephemeral_task);
 }
 
 /// Schedule a task to emit MsgsChanged event when the next local
@@ -341,24 +1445,12 @@ pub(crate) async fn delete_expired_messages(context: &Context) -> Result<bool, E
 /// timeouts are at least one hour long and deletion is triggered often enough
 /// by user actions.
 pub async fn schedule_ephemeral_task(context: &Context) {
-    let ephemeral_timestamp: Option<i64> = match context
-        .sql
-        .query_get_value_result(
-            "SELECT ephemeral_timestamp \
-         FROM msgs \
-         WHERE ephemeral_timestamp != 0 \
-           AND chat_id != ? \
-         ORDER BY ephemeral_timestamp ASC \
-         LIMIT 1",
-            paramsv![DC_CHAT_ID_TRASH], // Trash contains already deleted messages, skip them
-        )
-        .await
-    {
+    let task_time = match next_ephemeral_task_time(context).await {
         Err(err) => {
             warn!(context, "Can't calculate next ephemeral timeout: {}", err);
             return;
         }
-        Ok(ephemeral_timestamp) => ephemeral_timestamp,
+        Ok(task_time) => task_time,
     };
 
     // Cancel existing task, if any
@@ -366,16 +1458,14 @@ pub async fn schedule_ephemeral_task(context: &Context) {
         ephemeral_task.cancel().await;
     }
 
-    if let Some(ephemeral_timestamp) = ephemeral_timestamp {
+    if let Some(task_time) = task_time {
         let now = SystemTime::now();
-        let until = UNIX_EPOCH
-            + Duration::from_secs(ephemeral_timestamp.try_into().unwrap_or(u64::MAX))
-            + Duration::from_secs(1);
+        let until = UNIX_EPOCH + Duration::from_secs(task_time.try_into().unwrap_or(u64::MAX));
 
         if let Ok(duration) = until.duration_since(now) {
             // Schedule a task, ephemeral_timestamp is in the future
             let context1 = context.clone();
-            let ephemeral_task = task::spawn(async move {
+            let ephemeral_task = context.spawn_task("ephemeral", async move {
                 async_std::task::sleep(duration).await;
                 emit_event!(
                     context1,
@@ -399,11 +1489,61 @@ pub async fn schedule_ephemeral_task(context: &Context) {
     }
 }
 
+/// Returns the unix timestamp at which the ephemeral task emits
+/// MsgsChanged next, see `Context::next_ephemeral_task_time()`.
+async fn next_ephemeral_task_time(context: &Context) -> sql::Result<Option<i64>> {
+    // Kept messages are skipped, otherwise their past timestamps would
+    // trigger reloads over and over.
+    let kept_msgs = kept_msgs_condition(context).await;
+    let ephemeral_timestamp: Option<i64> = context
+        .sql
+        .query_get_value_result(
+            &format!(
+                "SELECT ephemeral_timestamp \
+                 FROM msgs \
+                 WHERE ephemeral_timestamp != 0 \
+                 AND chat_id != ?{} \
+                 ORDER BY ephemeral_timestamp ASC \
+                 LIMIT 1",
+                kept_msgs
+            ),
+            paramsv![DC_CHAT_ID_TRASH], // Trash contains already deleted messages, skip them
+        )
+        .await?;
+    let offset = ephemeral_task_offset(context.get_id()).as_secs() as i64;
+    Ok(ephemeral_timestamp.map(|timestamp| timestamp.saturating_add(1 + offset)))
+}
+
+/// Returns the delay added to the ephemeral task of the account with
+/// the id `account_id`.
+///
+/// The delays are spread over `EPHEMERAL_TASK_SPREAD` with Fibonacci
+/// hashing of the account id. This way the accounts of a multi-account
+/// setup do not all reload and delete messages at the same time if
+/// their messages expire together, e.g. in a group shared by them.
+fn ephemeral_task_offset(account_id: u32) -> Duration {
+    let hash = u64::from(account_id.wrapping_mul(0x9e37_79b9));
+    Duration::from_secs((hash * EPHEMERAL_TASK_SPREAD.as_secs()) >> 32)
+}
+
 /// Returns ID of any expired message that should be deleted from the server.
 ///
 /// It looks up the trash chat too, to find messages that are already
-/// deleted locally, but not deleted on the server.
+/// deleted locally, but not deleted on the server. Messages which
+/// only have a copy in the Sent folder left are returned as well.
 pub(crate) async fn load_imap_deletion_msgid(context: &Context) -> sql::Result<Option<MsgId>> {
+    Ok(load_imap_deletion_msgids(context, 1).await?.pop())
+}
+
+/// Returns IDs of up to `limit` expired messages that should be
+/// deleted from the server, see `load_imap_deletion_msgid()`.
+///
+/// The IDs are ordered by IMAP folder, so messages in the same folder
+/// follow each other and can be deleted with a single command.
+pub(crate) async fn load_imap_deletion_msgids(
+    context: &Context,
+    limit: usize,
+) -> sql::Result<Vec<MsgId>> {
     let now = time();
 
     let threshold_timestamp = match context.get_config_delete_server_after().await {
@@ -413,20 +1553,95 @@ pub(crate) async fn load_imap_deletion_msgid(context: &Context) -> sql::Result<O
 
     context
         .sql
-        .query_row_optional(
+        .query_map(
             "SELECT id FROM msgs \
          WHERE ( \
          timestamp < ? \
          OR (ephemeral_timestamp != 0 AND ephemeral_timestamp < ?) \
          ) \
-         AND server_uid != 0 \
-         LIMIT 1",
-            paramsv![threshold_timestamp, now],
+         AND (server_uid != 0 OR sentbox_uid != 0) \
+         ORDER BY server_folder, server_uid \
+         LIMIT ?",
+            paramsv![threshold_timestamp, now, limit as i64],
             |row| row.get::<_, MsgId>(0),
+            |rows| {
+                rows.collect::<std::result::Result<Vec<_>, _>>()
+                    .map_err(Into::into)
+            },
+        )
+        .await
+}
+
+/// Returns the server UIDs of messages in `folder` which should be
+/// deleted on the server, see `load_imap_deletion_msgids()`.
+pub(crate) async fn load_imap_deletion_uids(
+    context: &Context,
+    folder: &str,
+    limit: usize,
+) -> sql::Result<Vec<u32>> {
+    let now = time();
+
+    let threshold_timestamp = match context.get_config_delete_server_after().await {
+        None => 0,
+        Some(delete_server_after) => now - delete_server_after,
+    };
+
+    context
+        .sql
+        .query_map(
+            "SELECT DISTINCT server_uid FROM msgs \
+         WHERE ( \
+         timestamp < ? \
+         OR (ephemeral_timestamp != 0 AND ephemeral_timestamp < ?) \
+         ) \
+         AND server_folder = ? AND server_uid != 0 \
+         ORDER BY server_uid \
+         LIMIT ?",
+            paramsv![threshold_timestamp, now, folder, limit as i64],
+            |row| row.get::<_, u32>(0),
+            |rows| {
+                rows.collect::<std::result::Result<Vec<_>, _>>()
+                    .map_err(Into::into)
+            },
         )
         .await
 }
 
+/// Forgets the server UIDs of messages in `folder` which are gone from
+/// the server, so they are not deleted there again.
+pub(crate) async fn forget_server_uids(
+    context: &Context,
+    folder: &str,
+    uids: &[u32],
+) -> sql::Result<()> {
+    if uids.is_empty() {
+        return Ok(());
+    }
+
+    let folder = folder.to_string();
+    let uids = uids.to_vec();
+    context
+        .sql
+        .with_conn(move |mut conn| {
+            let tx = conn.transaction()?;
+            for chunk in uids.chunks(UPDATE_BATCH_SIZE) {
+                let mut params: Vec<&dyn crate::ToSql> = vec![&folder];
+                params.extend(chunk.iter().map(|uid| uid as &dyn crate::ToSql));
+                tx.execute(
+                    &format!(
+                        "UPDATE msgs SET server_uid=0 \
+                         WHERE server_folder=? AND server_uid IN ({})",
+                        chunk.iter().map(|_| "?").join(",")
+                    ),
+                    &params,
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+}
+
 /// Start ephemeral timers for seen messages if they are not started
 /// yet.
 ///
@@ -436,93 +1651,2732 @@ pub(crate) async fn load_imap_deletion_msgid(context: &Context) -> sql::Result<O
 ///
 /// This function is supposed to be called in the background,
 /// e.g. from housekeeping task.
-pub(crate) async fn start_ephemeral_timers(context: &Context) -> sql::Result<()> {
-    context
+///
+/// Timers paused with `MsgId::pause_ephemeral_timer()` are not started.
+///
+/// Returns the number of messages whose timer has been started.
+pub(crate) async fn start_ephemeral_timers(context: &Context) -> sql::Result<usize> {
+    let expire_undecryptable = context.get_config_bool(Config::ExpireUndecryptable).await;
+    // Params are stored as `key=value` lines.
+    let paused = format!("{}=*", Param::EphemeralRemaining as u8 as char);
+    let started = context
         .sql
         .execute(
             "UPDATE msgs \
     SET ephemeral_timestamp = ? + ephemeral_timer \
     WHERE ephemeral_timer > 0 \
     AND ephemeral_timestamp = 0 \
-    AND state NOT IN (?, ?, ?)",
+    AND param NOT GLOB ? \
+    AND param NOT GLOB ('*' || char(10) || ?) \
+    AND state NOT IN (?, ?, ?) \
+    AND (? OR error != ?)",
             paramsv![
                 time(),
+                paused,
+                paused,
                 MessageState::InFresh,
                 MessageState::InNoticed,
-                MessageState::OutDraft
+                MessageState::OutDraft,
+                expire_undecryptable,
+                DECRYPTION_FAILED_ERROR
             ],
         )
         .await?;
 
-    Ok(())
+    Ok(started)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::test_utils::*;
+/// Selects trashed and hidden messages which are neither on the server
+/// nor referenced by a pending job, so no trace of them is left.
+const PRUNABLE_MSGS: &str = "(chat_id=? OR hidden) \
+     AND server_uid=0 \
+     AND sentbox_uid=0 \
+     AND id NOT IN (SELECT foreign_id FROM jobs WHERE action IN (?, ?, ?, ?))";
 
-    #[async_std::test]
-    async fn test_stock_ephemeral_messages() {
-        let context = TestContext::new().await.ctx;
+/// Removes messages from the database which are both moved to the
+/// trash chat (or hidden) and deleted from the server.
+///
+/// This function is supposed to be called in the background,
+/// e.g. from housekeeping task.
+///
+/// Returns the number of removed messages.
+pub(crate) async fn prune_trashed_messages(context: &Context) -> sql::Result<usize> {
+    context
+        .sql
+        .execute(
+            format!(
+                "DELETE FROM msgs_mdns WHERE msg_id IN (SELECT id FROM msgs WHERE {})",
+                PRUNABLE_MSGS
+            ),
+            prunable_msgs_params(),
+        )
+        .await?;
+    let pruned = context
+        .sql
+        .execute(
+            format!("DELETE FROM msgs WHERE {}", PRUNABLE_MSGS),
+            prunable_msgs_params(),
+        )
+        .await?;
 
-        assert_eq!(
-            stock_ephemeral_timer_changed(&context, Timer::Disabled, DC_CONTACT_ID_SELF).await,
-            "Message deletion timer is disabled by me."
-        );
+    Ok(pruned)
+}
 
-        assert_eq!(
-            stock_ephemeral_timer_changed(&context, Timer::Disabled, 0).await,
-            "Message deletion timer is disabled."
-        );
-        assert_eq!(
-            stock_ephemeral_timer_changed(&context, Timer::Enabled { duration: 1 }, 0).await,
-            "Message deletion timer is set to 1 s."
+fn prunable_msgs_params() -> Vec<&'static dyn crate::ToSql> {
+    paramsv![
+        DC_CHAT_ID_TRASH,
+        job::Action::MarkseenMsgOnImap,
+        job::Action::MoveMsg,
+        job::Action::DeleteMsgOnImap,
+        job::Action::SendMsgToSmtp
+    ]
+}
+
+/// Parameters of `PRUNABLE_MSGS` limited to the trash chat, so hidden
+/// messages in other chats are left to housekeeping.
+fn orphaned_trash_params() -> Vec<&'static dyn crate::ToSql> {
+    let mut params = paramsv![DC_CHAT_ID_TRASH];
+    params.extend(prunable_msgs_params());
+    params
+}
+
+/// Diagnostic report on the state of ephemeral message deletion.
+///
+/// Returned by `Context::ephemeral_self_check()`. A non-zero
+/// `overdue_msgs` count that persists for a long time indicates that
+/// local deletion is stuck.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EphemeralHealth {
+    /// Whether a wakeup task for the next local deletion is scheduled.
+    pub task_scheduled: bool,
+
+    /// Timestamp of the next local deletion, if any message has a
+    /// running ephemeral timer.
+    pub next_wakeup: Option<i64>,
+
+    /// Number of messages whose ephemeral timer has expired, but
+    /// which are not moved to the trash chat yet.
+    pub overdue_msgs: usize,
+
+    /// Number of trash chat entries still referencing a message on
+    /// the server.
+    pub lingering_server_uids: usize,
+}
+
+impl EphemeralHealth {
+    /// Returns true if no expired messages are waiting for deletion.
+    pub fn is_healthy(&self) -> bool {
+        self.overdue_msgs == 0
+    }
+}
+
+/// Why a message is deleted locally.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeletionReason {
+    /// Ephemeral timer of the message has expired.
+    EphemeralTimer,
+
+    /// Message is older than `delete_device_after` setting.
+    DeleteDeviceAfter,
+}
+
+/// Order in which `Context::delete_expired_messages_prioritized()`
+/// deletes expired messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeletionStrategy {
+    /// Delete the oldest messages first.
+    OldestFirst,
+    /// Delete the messages with the largest attachments first.
+    LargestFirst,
+}
+
+/// Result of `Context::delete_expired_messages_prioritized()`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReclaimedSpace {
+    /// Number of deleted messages.
+    pub count: usize,
+    /// Size of the removed attachments in bytes.
+    pub bytes: u64,
+}
+
+/// Record passed to the deletion auditor before a message is deleted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub msg_id: MsgId,
+    pub chat_id: ChatId,
+    pub reason: DeletionReason,
+}
+
+/// Inconsistent combination of retention settings, returned by
+/// `Context::validate_retention_settings()`.
+///
+/// The settings still work as configured, but may not do what the
+/// user expects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RetentionWarning {
+    /// Messages are deleted from the server before they are deleted
+    /// from the device, so other devices may never see them.
+    ServerBeforeDevice {
+        delete_server_after: i64,
+        delete_device_after: i64,
+    },
+
+    /// Messages of the chat are deleted from the server before their
+    /// ephemeral timer expires, so other devices may never see them.
+    ServerBeforeEphemeralTimer {
+        chat_id: ChatId,
+        delete_server_after: i64,
+        timer: Timer,
+    },
+
+    /// Messages of the chat are deleted from the device before their
+    /// ephemeral timer expires, so the timer has no effect.
+    DeviceBeforeEphemeralTimer {
+        chat_id: ChatId,
+        delete_device_after: i64,
+        timer: Timer,
+    },
+}
+
+/// Ephemeral timer setting of a chat, as exported by
+/// `Context::export_ephemeral_settings_json()`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EphemeralSetting {
+    pub chat_name: String,
+    pub chat_id: u32,
+    /// Address of the contact of a 1:1 chat.
+    #[serde(default)]
+    pub contact_addr: Option<String>,
+    pub timer_seconds: u32,
+}
+
+/// Callback which is awaited before each automatic message deletion.
+///
+/// Cloned out of `Context::deletion_auditor` before it is called, so
+/// the lock is not held while the callback runs.
+#[derive(Clone)]
+pub(crate) struct DeletionAuditor(
+    Arc<dyn Fn(AuditRecord) -> BoxFuture<'static, Result<(), Error>> + Send + Sync>,
+);
+
+impl fmt::Debug for DeletionAuditor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DeletionAuditor")
+    }
+}
+
+impl Context {
+    /// Sets a callback which is awaited before each message is
+    /// deleted because of an ephemeral timer or `delete_device_after`.
+    ///
+    /// If the returned future fails, the message is not deleted and
+    /// auditing is retried later, so no deletion is ever left
+    /// unaudited.
+    pub async fn set_deletion_auditor<F>(&self, auditor: F)
+    where
+        F: Fn(AuditRecord) -> BoxFuture<'static, Result<(), Error>> + Send + Sync + 'static,
+    {
+        *self.deletion_auditor.write().await = Some(DeletionAuditor(Arc::new(auditor)));
+    }
+
+    /// Returns the unix timestamp at which the expired messages of this
+    /// account are deleted next, or `None` if no ephemeral timer is
+    /// running.
+    ///
+    /// Deletion is delayed a little after the next message expires by
+    /// an offset derived from the account id, so the accounts of a
+    /// multi-account setup do not all run it at the same time.
+    pub async fn next_ephemeral_task_time(&self) -> Result<Option<i64>, Error> {
+        Ok(next_ephemeral_task_time(self).await?)
+    }
+
+    /// Checks whether ephemeral message deletion is progressing.
+    ///
+    /// This is intended for support and debugging, see
+    /// `EphemeralHealth` for the reported values.
+    pub async fn ephemeral_self_check(&self) -> Result<EphemeralHealth, Error> {
+        let task_scheduled = self.ephemeral_task.read().await.is_some();
+        let kept_msgs = kept_msgs_condition(self).await;
+
+        let next_wakeup = self
+            .sql
+            .query_get_value_result::<Option<i64>>(
+                &format!(
+                    "SELECT MIN(ephemeral_timestamp) \
+                     FROM msgs \
+                     WHERE ephemeral_timestamp != 0 \
+                     AND chat_id != ?{}",
+                    kept_msgs
+                ),
+                paramsv![DC_CHAT_ID_TRASH],
+            )
+            .await?
+            .flatten();
+
+        let overdue_msgs = self
+            .sql
+            .query_row(
+                format!(
+                    "SELECT COUNT(*) \
+                     FROM msgs \
+                     WHERE ephemeral_timestamp != 0 \
+                     AND ephemeral_timestamp < ? \
+                     AND chat_id != ?{}",
+                    kept_msgs
+                ),
+                paramsv![time(), DC_CHAT_ID_TRASH],
+                |row| row.get::<_, isize>(0),
+            )
+            .await? as usize;
+
+        let lingering_server_uids = self
+            .sql
+            .query_row(
+                "SELECT COUNT(*) FROM msgs WHERE chat_id = ? AND server_uid != 0",
+                paramsv![DC_CHAT_ID_TRASH],
+                |row| row.get::<_, isize>(0),
+            )
+            .await? as usize;
+
+        Ok(EphemeralHealth {
+            task_scheduled,
+            next_wakeup,
+            overdue_msgs,
+            lingering_server_uids,
+        })
+    }
+
+    /// Checks `delete_server_after`, `delete_device_after` and the
+    /// ephemeral timers of chats for inconsistent combinations.
+    ///
+    /// This is advisory only, the settings are not changed.
+    pub async fn validate_retention_settings(&self) -> Result<Vec<RetentionWarning>, Error> {
+        let delete_server_after = self.get_config_delete_server_after().await;
+        let delete_device_after = self.get_config_delete_device_after().await;
+        let mut warnings = Vec::new();
+
+        if let (Some(delete_server_after), Some(delete_device_after)) =
+            (delete_server_after, delete_device_after)
+        {
+            if delete_server_after < delete_device_after {
+                warnings.push(RetentionWarning::ServerBeforeDevice {
+                    delete_server_after,
+                    delete_device_after,
+                });
+            }
+        }
+
+        let timers: Vec<(ChatId, Timer)> = self
+            .sql
+            .query_map(
+                "SELECT id, ephemeral_timer \
+                 FROM chats \
+                 WHERE id>? \
+                 AND ephemeral_timer!=0 \
+                 ORDER BY id",
+                paramsv![DC_CHAT_ID_LAST_SPECIAL],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+                |rows| {
+                    rows.collect::<std::result::Result<Vec<_>, _>>()
+                        .map_err(Into::into)
+                },
+            )
+            .await?;
+        for (chat_id, timer) in timers {
+            let duration = i64::from(timer.to_u32());
+            if let Some(delete_server_after) = delete_server_after {
+                if delete_server_after < duration {
+                    warnings.push(RetentionWarning::ServerBeforeEphemeralTimer {
+                        chat_id,
+                        delete_server_after,
+                        timer,
+                    });
+                }
+            }
+            if let Some(delete_device_after) = delete_device_after {
+                if delete_device_after < duration {
+                    warnings.push(RetentionWarning::DeviceBeforeEphemeralTimer {
+                        chat_id,
+                        delete_device_after,
+                        timer,
+                    });
+                }
+            }
+        }
+
+        Ok(warnings)
+    }
+
+    /// Applies `delete_device_after` to existing messages right away,
+    /// instead of waiting until the chatlist or a chat is loaded.
+    ///
+    /// Emits `MsgsChanged` if messages were deleted and returns their
+    /// number. If a deletion auditor is set, all expired messages are
+    /// deleted through it, including ones with expired ephemeral timers.
+    pub async fn apply_device_retention_now(&self) -> Result<u64, Error> {
+        let auditor = self.deletion_auditor.read().await.clone();
+        let deleted = if let Some(auditor) = auditor {
+            audit_and_delete_expired_messages(self, &auditor)
+                .await?
+                .len()
+        } else if let Some(delete_device_after) = self.get_config_delete_device_after().await {
+            delete_device_expired_messages(self, delete_device_after).await?
+        } else {
+            0
+        };
+
+        if deleted > 0 {
+            info!(
+                self,
+                "Deleted {} messages according to delete_device_after", deleted
+            );
+            self.emit_event(EventType::MsgsChanged {
+                chat_id: ChatId::new(0),
+                msg_id: MsgId::new(0),
+            });
+        }
+        Ok(deleted as u64)
+    }
+
+    /// Deletes up to `limit` expired messages in the order given by
+    /// `strategy` and removes their attachments right away, e.g. to free
+    /// space quickly when the device is low on storage.
+    ///
+    /// Only messages which are already expired according to their
+    /// ephemeral timer or `delete_device_after` are deleted. If a
+    /// deletion auditor is set, each deletion is audited first.
+    pub async fn delete_expired_messages_prioritized(
+        &self,
+        strategy: DeletionStrategy,
+        limit: Option<usize>,
+    ) -> Result<ReclaimedSpace, Error> {
+        let mut candidates = Vec::new();
+        for record in load_expired_messages(self).await? {
+            match Message::load_from_db(self, record.msg_id).await {
+                Ok(msg) => {
+                    let bytes = msg.get_filebytes(self).await;
+                    candidates.push((record, msg, bytes));
+                }
+                Err(err) => warn!(self, "Cannot load expired message: {}", err),
+            }
+        }
+        match strategy {
+            DeletionStrategy::OldestFirst => {
+                candidates.sort_by_key(|(record, msg, _)| (msg.timestamp_sort, record.msg_id))
+            }
+            DeletionStrategy::LargestFirst => {
+                candidates.sort_by_key(|(record, msg, bytes)| {
+                    (cmp::Reverse(*bytes), msg.timestamp_sort, record.msg_id)
+                });
+            }
+        }
+        if let Some(limit) = limit {
+            candidates.truncate(limit);
+        }
+
+        let mut reclaimed = ReclaimedSpace::default();
+        let mut deferred = false;
+        let auditor = self.deletion_auditor.read().await.clone();
+        for (record, msg, bytes) in candidates {
+            if let Some(ref auditor) = auditor {
+                if let Err(err) = (auditor.0)(record.clone()).await {
+                    warn!(
+                        self,
+                        "Deletion audit for {} failed, deferring deletion: {}", record.msg_id, err
+                    );
+                    deferred = true;
+                    continue;
+                }
+            }
+            for record in trash_messages(self, vec![record]).await? {
+                if remove_unreferenced_file(self, &msg).await? {
+                    reclaimed.bytes += bytes;
+                }
+                reclaimed.count += 1;
+                self.emit_event(EventType::MsgDeleted {
+                    chat_id: record.chat_id,
+                    msg_id: record.msg_id,
+                });
+            }
+        }
+
+        if deferred {
+            schedule_audit_retry(self).await;
+        } else {
+            schedule_ephemeral_task(self).await;
+        }
+        if reclaimed.count > 0 {
+            info!(
+                self,
+                "Deleted {} expired messages, reclaimed {} bytes", reclaimed.count, reclaimed.bytes
+            );
+            self.emit_event(EventType::MsgsChanged {
+                chat_id: ChatId::new(0),
+                msg_id: MsgId::new(0),
+            });
+        }
+        Ok(reclaimed)
+    }
+
+    /// Deletes expired messages right away and removes them from the
+    /// database unless they still have to be deleted from the server
+    /// or a job still refers to them, see
+    /// `Context::cleanup_orphaned_trash()`.
+    ///
+    /// Usually local deletion only happens when the chatlist or a chat
+    /// is loaded. This is meant to be called e.g. when the app goes to
+    /// the background, so expired messages do not stay on the device.
+    ///
+    /// Emits `MsgDeleted` for each deleted message and `MsgsChanged` if
+    /// any message was deleted. Returns true if any message was deleted.
+    pub async fn delete_expired_messages_now(&self) -> Result<bool, Error> {
+        let deleted = delete_expired_messages(self).await?;
+        if deleted {
+            self.emit_event(EventType::MsgsChanged {
+                chat_id: ChatId::new(0),
+                msg_id: MsgId::new(0),
+            });
+        }
+        self.cleanup_orphaned_trash().await?;
+        Ok(deleted)
+    }
+
+    /// Returns the number of messages `Context::cleanup_orphaned_trash()`
+    /// would remove.
+    pub async fn count_orphaned_trash(&self) -> Result<u64, Error> {
+        let count = self
+            .sql
+            .query_row(
+                format!(
+                    "SELECT COUNT(*) FROM msgs WHERE chat_id=? AND {}",
+                    PRUNABLE_MSGS
+                ),
+                orphaned_trash_params(),
+                |row| row.get::<_, isize>(0),
+            )
+            .await?;
+        Ok(count as u64)
+    }
+
+    /// Removes messages from the database which are deleted both
+    /// locally and from the server, but were left behind, e.g. because
+    /// housekeeping did not run.
+    ///
+    /// Returns the number of removed messages.
+    pub async fn cleanup_orphaned_trash(&self) -> Result<u64, Error> {
+        self.sql
+            .execute(
+                format!(
+                    "DELETE FROM msgs_mdns WHERE msg_id IN \
+                     (SELECT id FROM msgs WHERE chat_id=? AND {})",
+                    PRUNABLE_MSGS
+                ),
+                orphaned_trash_params(),
+            )
+            .await?;
+        let removed = self
+            .sql
+            .execute(
+                format!("DELETE FROM msgs WHERE chat_id=? AND {}", PRUNABLE_MSGS),
+                orphaned_trash_params(),
+            )
+            .await?;
+        if removed > 0 {
+            info!(self, "Removed {} orphaned trash entries", removed);
+        }
+        Ok(removed as u64)
+    }
+
+    /// Returns the contacts of 1:1 chats with an enabled ephemeral
+    /// timer, together with the timer.
+    ///
+    /// Group chats are returned by `Context::chats_with_ephemeral_timers()`.
+    pub async fn contacts_with_ephemeral_timers(&self) -> Result<Vec<(u32, Timer)>, Error> {
+        self.sql
+            .query_map(
+                "SELECT cc.contact_id, c.ephemeral_timer \
+                 FROM chats c \
+                 INNER JOIN chats_contacts cc ON c.id=cc.chat_id \
+                 WHERE c.type=? \
+                 AND c.id>? \
+                 AND c.blocked=0 \
+                 AND c.ephemeral_timer!=0 \
+                 ORDER BY cc.contact_id",
+                paramsv![Chattype::Single, DC_CHAT_ID_LAST_SPECIAL],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+                |rows| {
+                    rows.collect::<std::result::Result<Vec<_>, _>>()
+                        .map_err(Into::into)
+                },
+            )
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Returns the group chats with an enabled ephemeral timer,
+    /// together with the timer.
+    pub async fn chats_with_ephemeral_timers(&self) -> Result<Vec<(ChatId, Timer)>, Error> {
+        self.sql
+            .query_map(
+                "SELECT id, ephemeral_timer \
+                 FROM chats \
+                 WHERE type=? \
+                 AND id>? \
+                 AND blocked=0 \
+                 AND ephemeral_timer!=0 \
+                 ORDER BY id",
+                paramsv![Chattype::Group, DC_CHAT_ID_LAST_SPECIAL],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+                |rows| {
+                    rows.collect::<std::result::Result<Vec<_>, _>>()
+                        .map_err(Into::into)
+                },
+            )
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Exports the ephemeral timers of all chats as a JSON list of
+    /// `EphemeralSetting`, e.g. for auditing retention.
+    pub async fn export_ephemeral_settings_json(&self) -> Result<String, Error> {
+        let settings = self
+            .sql
+            .query_map(
+                "SELECT c.id, c.name, c.ephemeral_timer, \
+                 (SELECT ct.addr FROM chats_contacts cc \
+                  INNER JOIN contacts ct ON ct.id=cc.contact_id \
+                  WHERE cc.chat_id=c.id AND c.type=? AND ct.addr!='') \
+                 FROM chats c \
+                 WHERE c.id>? \
+                 AND c.blocked=0 \
+                 ORDER BY c.id",
+                paramsv![Chattype::Single, DC_CHAT_ID_LAST_SPECIAL],
+                |row| {
+                    Ok(EphemeralSetting {
+                        chat_id: row.get(0)?,
+                        chat_name: row.get(1)?,
+                        timer_seconds: row.get(2)?,
+                        contact_addr: row.get(3)?,
+                    })
+                },
+                |rows| {
+                    rows.collect::<std::result::Result<Vec<_>, _>>()
+                        .map_err(Into::into)
+                },
+            )
+            .await?;
+        Ok(serde_json::to_string(&settings)?)
+    }
+
+    /// Applies ephemeral timers exported by
+    /// `Context::export_ephemeral_settings_json()`.
+    ///
+    /// 1:1 chats are matched by the address of the contact, other chats
+    /// by name. Chat ids are local to the device, so the id is only used
+    /// to choose between several chats with the same name. Blocked chats
+    /// are skipped as on export. Timers are changed without sending a
+    /// message to the chat. Settings which could not be applied, e.g.
+    /// because no chat matches, are returned.
+    pub async fn import_ephemeral_settings_json(
+        &self,
+        json: &str,
+    ) -> Result<Vec<EphemeralSetting>, Error> {
+        let settings: Vec<EphemeralSetting> = serde_json::from_str(json)?;
+        let mut unmatched = Vec::new();
+        for setting in settings {
+            let chat_id: Option<ChatId> = match setting.contact_addr {
+                Some(ref addr) => {
+                    self.sql
+                        .query_get_value_result(
+                            "SELECT c.id FROM chats c \
+                             INNER JOIN chats_contacts cc ON cc.chat_id=c.id \
+                             INNER JOIN contacts ct ON ct.id=cc.contact_id \
+                             WHERE c.id>? AND c.type=? AND c.blocked=0 \
+                             AND ct.addr=? COLLATE NOCASE \
+                             ORDER BY c.id=? DESC, c.id \
+                             LIMIT 1",
+                            paramsv![
+                                DC_CHAT_ID_LAST_SPECIAL,
+                                Chattype::Single,
+                                addr,
+                                setting.chat_id
+                            ],
+                        )
+                        .await?
+                }
+                None => {
+                    self.sql
+                        .query_get_value_result(
+                            "SELECT id FROM chats \
+                             WHERE id>? AND blocked=0 AND name=? \
+                             ORDER BY id=? DESC, id \
+                             LIMIT 1",
+                            paramsv![DC_CHAT_ID_LAST_SPECIAL, setting.chat_name, setting.chat_id],
+                        )
+                        .await?
+                }
+            };
+            let timer = Timer::validated(setting.timer_seconds);
+            match (chat_id, timer) {
+                (Some(chat_id), Ok(timer)) => {
+                    chat_id.inner_set_ephemeral_timer(self, timer).await?;
+                }
+                _ => {
+                    warn!(self, "Can not apply ephemeral setting {:?}", setting);
+                    unmatched.push(setting);
+                }
+            }
+        }
+        Ok(unmatched)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::{self, ProtectionStatus};
+    use crate::dc_receive_imf::dc_receive_imf;
+    use crate::headerdef::HeaderDef;
+    use crate::message;
+    use crate::test_utils::*;
+
+    #[async_std::test]
+    async fn test_stock_ephemeral_messages() {
+        let context = TestContext::new().await.ctx;
+
+        assert_eq!(
+            stock_ephemeral_timer_changed(&context, Timer::Disabled, DC_CONTACT_ID_SELF).await,
+            "Message deletion timer is disabled by me."
+        );
+
+        assert_eq!(
+            stock_ephemeral_timer_changed(&context, Timer::Disabled, 0).await,
+            "Message deletion timer is disabled."
+        );
+        assert_eq!(
+            stock_ephemeral_timer_changed(&context, Timer::from_u32(1), 0).await,
+            "Message deletion timer is set to 1 s."
         );
         assert_eq!(
-            stock_ephemeral_timer_changed(&context, Timer::Enabled { duration: 30 }, 0).await,
+            stock_ephemeral_timer_changed(&context, Timer::from_u32(30), 0).await,
             "Message deletion timer is set to 30 s."
         );
         assert_eq!(
-            stock_ephemeral_timer_changed(&context, Timer::Enabled { duration: 60 }, 0).await,
+            stock_ephemeral_timer_changed(&context, Timer::from_u32(60), 0).await,
             "Message deletion timer is set to 1 minute."
         );
         assert_eq!(
-            stock_ephemeral_timer_changed(&context, Timer::Enabled { duration: 60 * 60 }, 0).await,
+            stock_ephemeral_timer_changed(&context, Timer::from_u32(60 * 60), 0).await,
             "Message deletion timer is set to 1 hour."
         );
         assert_eq!(
-            stock_ephemeral_timer_changed(
-                &context,
-                Timer::Enabled {
-                    duration: 24 * 60 * 60
-                },
-                0
-            )
-            .await,
+            stock_ephemeral_timer_changed(&context, Timer::from_u32(24 * 60 * 60), 0).await,
             "Message deletion timer is set to 1 day."
         );
         assert_eq!(
-            stock_ephemeral_timer_changed(
-                &context,
-                Timer::Enabled {
-                    duration: 7 * 24 * 60 * 60
-                },
-                0
-            )
-            .await,
+            stock_ephemeral_timer_changed(&context, Timer::from_u32(7 * 24 * 60 * 60), 0).await,
             "Message deletion timer is set to 1 week."
         );
         assert_eq!(
-            stock_ephemeral_timer_changed(
-                &context,
-                Timer::Enabled {
-                    duration: 4 * 7 * 24 * 60 * 60
-                },
-                0
-            )
-            .await,
+            stock_ephemeral_timer_changed(&context, Timer::from_u32(4 * 7 * 24 * 60 * 60), 0).await,
             "Message deletion timer is set to 4 weeks."
         );
     }
+
+    #[test]
+    fn test_timer_validated() {
+        for duration in &[0, 60, 3600, 86400, 604_800, 2_419_200, MAX_TIMER_DURATION] {
+            assert_eq!(
+                Timer::validated(*duration).unwrap(),
+                Timer::from_u32(*duration)
+            );
+        }
+        let ten_years = 10 * MAX_TIMER_DURATION;
+        assert_eq!(
+            Timer::validated(ten_years),
+            Err(TimerError::TooLong(ten_years))
+        );
+    }
+
+    #[test]
+    fn test_timer_from_header_values() {
+        assert_eq!(Timer::from_header_values(vec![]), None);
+        assert_eq!(Timer::from_header_values(vec!["foo", "-1"]), None);
+        assert_eq!(
+            Timer::from_header_values(vec!["60"]),
+            Some(Timer::from_u32(60))
+        );
+        assert_eq!(
+            Timer::from_header_values(vec!["foo", " 60 ", "3600"]),
+            Some(Timer::from_u32(60))
+        );
+        assert_eq!(
+            Timer::from_header_values(vec!["0", "3600"]),
+            Some(Timer::Disabled)
+        );
+    }
+
+    #[test]
+    fn test_timer_builder() {
+        assert_eq!(Timer::builder().hours(1).build(), Timer::from_u32(3600));
+        assert_eq!(
+            Timer::builder()
+                .weeks(1)
+                .days(1)
+                .hours(1)
+                .minutes(1)
+                .seconds(1)
+                .build(),
+            Timer::from_u32(604_800 + 86400 + 3600 + 60 + 1)
+        );
+        assert_eq!(Timer::builder().build(), Timer::Disabled);
+        assert_eq!(Timer::builder().days(0).seconds(0).build(), Timer::Disabled);
+        assert_eq!(
+            Timer::builder().weeks(u32::MAX).seconds(1).build(),
+            Timer::from_u32(u32::MAX)
+        );
+    }
+
+    #[test]
+    fn test_timer_unit() {
+        assert_eq!(Timer::from_u32(60).unit(), Some(TimerUnit::Minutes));
+        assert_eq!(Timer::from_u32(7200).unit(), Some(TimerUnit::Hours));
+        assert_eq!(Timer::from_u32(90).unit(), Some(TimerUnit::Seconds));
+        assert_eq!(Timer::from_u32(1_209_600).unit(), Some(TimerUnit::Weeks));
+        assert_eq!(Timer::Disabled.unit(), None);
+
+        // The chosen unit is kept, but not compared.
+        let timer = Timer::from_unit(120.0, TimerUnit::Minutes);
+        assert_eq!(timer.unit(), Some(TimerUnit::Minutes));
+        assert!((timer.value_in_unit() - 120.0).abs() < f64::EPSILON);
+        assert_eq!(timer.to_u32(), 7200);
+        assert_eq!(timer, Timer::from_u32(7200));
+
+        let timer = Timer::from_unit(1.5, TimerUnit::Hours);
+        assert_eq!(timer.to_u32(), 5400);
+        assert!((timer.value_in_unit() - 1.5).abs() < f64::EPSILON);
+
+        assert_eq!(Timer::from_unit(0.0, TimerUnit::Days), Timer::Disabled);
+        assert_eq!(Timer::from_unit(1e12, TimerUnit::Weeks).to_u32(), u32::MAX);
+    }
+
+    #[async_std::test]
+    async fn test_timer_unit_stored() {
+        let t = TestContext::new_alice().await;
+        let chat_id = chat::create_group_chat(&t.ctx, ProtectionStatus::Unprotected, "foo")
+            .await
+            .unwrap();
+
+        chat_id
+            .set_ephemeral_timer(&t.ctx, Timer::from_unit(120.0, TimerUnit::Minutes))
+            .await
+            .unwrap();
+        let timer = chat_id.get_ephemeral_timer(&t.ctx).await.unwrap();
+        assert_eq!(timer.to_u32(), 7200);
+        assert_eq!(timer.unit(), Some(TimerUnit::Minutes));
+        assert!((timer.value_in_unit() - 120.0).abs() < f64::EPSILON);
+
+        // Changing only the unit is stored as well.
+        chat_id
+            .set_ephemeral_timer(&t.ctx, Timer::from_unit(2.0, TimerUnit::Hours))
+            .await
+            .unwrap();
+        let timer = chat_id.get_ephemeral_timer(&t.ctx).await.unwrap();
+        assert_eq!(timer.unit(), Some(TimerUnit::Hours));
+
+        // Timers without a stored unit infer it.
+        chat_id
+            .set_ephemeral_timer(&t.ctx, Timer::Disabled)
+            .await
+            .unwrap();
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE chats SET ephemeral_timer=90 WHERE id=?",
+                paramsv![chat_id],
+            )
+            .await
+            .unwrap();
+        let timer = chat_id.get_ephemeral_timer(&t.ctx).await.unwrap();
+        assert_eq!(timer.unit(), Some(TimerUnit::Seconds));
+    }
+
+    #[async_std::test]
+    async fn test_set_ephemeral_timer_self_chat() {
+        use std::sync::{Arc, Mutex};
+
+        let t = TestContext::new_alice().await;
+        let chat_id = chat::create_by_contact_id(&t.ctx, DC_CONTACT_ID_SELF)
+            .await
+            .unwrap();
+        let modified = Arc::new(Mutex::new(Vec::new()));
+        let modified1 = modified.clone();
+        t.ctx.on_event_type(
+            EventType::ChatEphemeralTimerModified {
+                chat_id,
+                timer: Timer::Disabled,
+            }
+            .as_id(),
+            move |event| {
+                if let EventType::ChatEphemeralTimerModified { chat_id, timer } = event.typ {
+                    modified1.lock().unwrap().push((chat_id, timer));
+                }
+            },
+        );
+
+        let timer = Timer::from_u32(60);
+        chat_id.set_ephemeral_timer(&t.ctx, timer).await.unwrap();
+        chat_id
+            .set_ephemeral_timer(&t.ctx, Timer::Disabled)
+            .await
+            .unwrap();
+        assert_eq!(
+            *modified.lock().unwrap(),
+            vec![(chat_id, timer), (chat_id, Timer::Disabled)]
+        );
+
+        let msgs: i32 = t
+            .ctx
+            .sql
+            .query_get_value_result(
+                "SELECT COUNT(*) FROM msgs WHERE chat_id=?",
+                paramsv![chat_id],
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msgs, 0);
+    }
+
+    #[async_std::test]
+    async fn test_set_ephemeral_timer_too_long() {
+        let t = TestContext::new_alice().await;
+        let chat_id = chat::create_by_contact_id(&t.ctx, DC_CONTACT_ID_SELF)
+            .await
+            .unwrap();
+        let timer = Timer::from_u32(10 * MAX_TIMER_DURATION);
+        assert!(chat_id.set_ephemeral_timer(&t.ctx, timer).await.is_err());
+        assert_eq!(
+            chat_id.get_ephemeral_timer(&t.ctx).await.unwrap(),
+            Timer::Disabled
+        );
+    }
+
+    #[async_std::test]
+    async fn test_ephemeral_self_check() {
+        let t = TestContext::new().await;
+
+        let health = t.ctx.ephemeral_self_check().await.unwrap();
+        assert!(health.is_healthy());
+        assert_eq!(health.next_wakeup, None);
+
+        let mut msg = Message::new(Viewtype::Text);
+        msg.text = Some("expired message".to_string());
+        let msg_id = chat::add_device_msg(&t.ctx, None, Some(&mut msg))
+            .await
+            .unwrap();
+
+        // Simulate a timer that expired without any wakeup task scheduled.
+        let expired_at = time() - 100;
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timer=60, ephemeral_timestamp=? WHERE id=?",
+                paramsv![expired_at, msg_id],
+            )
+            .await
+            .unwrap();
+
+        let health = t.ctx.ephemeral_self_check().await.unwrap();
+        assert!(!health.is_healthy());
+        assert!(!health.task_scheduled);
+        assert_eq!(health.overdue_msgs, 1);
+        assert_eq!(health.next_wakeup, Some(expired_at));
+        assert_eq!(health.lingering_server_uids, 0);
+
+        // Deleting the message moves it to trash and fixes the report.
+        delete_expired_messages(&t.ctx).await.unwrap();
+        let health = t.ctx.ephemeral_self_check().await.unwrap();
+        assert!(health.is_healthy());
+        assert_eq!(health.next_wakeup, None);
+    }
+
+    #[async_std::test]
+    async fn test_delete_from_server_now() {
+        let t = TestContext::new_alice().await;
+
+        let msg_id = receive_simple_msg(&t.ctx, "delete-now@example.com").await;
+        assert_eq!(load_imap_deletion_msgid(&t.ctx).await.unwrap(), None);
+
+        msg_id.delete_from_server_now(&t.ctx).await.unwrap();
+        assert_eq!(
+            load_imap_deletion_msgid(&t.ctx).await.unwrap(),
+            Some(msg_id)
+        );
+
+        // Local copy is moved to trash.
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert_eq!(msg.chat_id, ChatId::new(DC_CHAT_ID_TRASH));
+    }
+
+    #[async_std::test]
+    async fn test_load_imap_deletion_msgids() {
+        let t = TestContext::new_alice().await;
+        let mut msg_ids = Vec::new();
+        for (i, (folder, uid)) in [("INBOX", 1), ("Archive", 2), ("INBOX", 3)]
+            .iter()
+            .enumerate()
+        {
+            let rfc724_mid = format!("batch{}@example.com", i);
+            let msg_id = receive_simple_msg_in(&t.ctx, &rfc724_mid, folder, *uid).await;
+            msg_ids.push(msg_id);
+        }
+        assert!(load_imap_deletion_msgids(&t.ctx, 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timestamp=? WHERE server_uid!=0",
+                paramsv![time() - 1],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            load_imap_deletion_msgids(&t.ctx, 10).await.unwrap(),
+            vec![msg_ids[1], msg_ids[0], msg_ids[2]]
+        );
+        assert_eq!(
+            load_imap_deletion_msgids(&t.ctx, 2).await.unwrap(),
+            vec![msg_ids[1], msg_ids[0]]
+        );
+        assert_eq!(
+            load_imap_deletion_msgid(&t.ctx).await.unwrap(),
+            Some(msg_ids[1])
+        );
+    }
+
+    #[async_std::test]
+    async fn test_corrupt_ephemeral_timer() {
+        use std::sync::{Arc, Mutex};
+
+        let t = TestContext::new_alice().await;
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let warnings1 = warnings.clone();
+        t.ctx
+            .on_event_type(EventType::Warning(String::new()).as_id(), move |event| {
+                if let EventType::Warning(msg) = event.typ {
+                    warnings1.lock().unwrap().push(msg);
+                }
+            });
+
+        let msg_id = receive_simple_msg(&t.ctx, "corrupt@example.com").await;
+
+        for corrupt in &[-60i64, 1 << 40] {
+            t.ctx
+                .sql
+                .execute(
+                    "UPDATE msgs SET ephemeral_timer=? WHERE id=?",
+                    paramsv![*corrupt, msg_id],
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                msg_id.ephemeral_timer(&t.ctx).await.unwrap(),
+                Timer::Disabled
+            );
+        }
+        assert_eq!(warnings.lock().unwrap().len(), 2);
+        assert!(warnings.lock().unwrap()[1].contains("out of range"));
+
+        assert_eq!(Timer::from_stored(0), Ok(Timer::Disabled));
+        assert_eq!(Timer::from_stored(60), Ok(Timer::from_u32(60)));
+        assert_eq!(Timer::from_stored(-1), Err(TimerError::OutOfRange(-1)));
+        assert_eq!(
+            Timer::from_stored(i64::from(MAX_TIMER_DURATION)),
+            Ok(Timer::from_u32(MAX_TIMER_DURATION))
+        );
+        assert_eq!(
+            Timer::from_stored(i64::from(MAX_TIMER_DURATION) + 1),
+            Ok(Timer::from_u32(MAX_TIMER_DURATION))
+        );
+    }
+
+    #[async_std::test]
+    async fn test_load_chat_with_legacy_timer() {
+        let t = TestContext::new_alice().await;
+        let chat_id = chat::create_group_chat(&t.ctx, ProtectionStatus::Unprotected, "foo")
+            .await
+            .unwrap();
+        let two_years = 2 * MAX_TIMER_DURATION;
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE chats SET ephemeral_timer=? WHERE id=?",
+                paramsv![two_years, chat_id],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            chat_id.get_ephemeral_timer(&t.ctx).await.unwrap(),
+            Timer::from_u32(MAX_TIMER_DURATION)
+        );
+        let info = Chat::load_from_db(&t.ctx, chat_id)
+            .await
+            .unwrap()
+            .get_info(&t.ctx)
+            .await
+            .unwrap();
+        assert_eq!(info.ephemeral_timer, Timer::from_u32(MAX_TIMER_DURATION));
+        chat::send_text_msg(&t.ctx, chat_id, "hi".to_string())
+            .await
+            .unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_forget_vanished_uids() {
+        let t = TestContext::new_alice().await;
+        let mut msg_ids = Vec::new();
+        for (i, (folder, uid)) in [("INBOX", 1), ("Archive", 2), ("INBOX", 3)]
+            .iter()
+            .enumerate()
+        {
+            let rfc724_mid = format!("vanished{}@example.com", i);
+            let msg_id = receive_simple_msg_in(&t.ctx, &rfc724_mid, folder, *uid).await;
+            msg_ids.push(msg_id);
+        }
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timestamp=? WHERE server_uid!=0",
+                paramsv![time() - 1],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            load_imap_deletion_uids(&t.ctx, "INBOX", 10).await.unwrap(),
+            vec![1, 3]
+        );
+
+        // UID 1 was deleted in another client, the server only knows UID 3.
+        forget_server_uids(&t.ctx, "INBOX", &[1]).await.unwrap();
+        assert_eq!(
+            load_imap_deletion_uids(&t.ctx, "INBOX", 10).await.unwrap(),
+            vec![3]
+        );
+        assert_eq!(
+            load_imap_deletion_msgids(&t.ctx, 10).await.unwrap(),
+            vec![msg_ids[1], msg_ids[2]]
+        );
+        let msg = Message::load_from_db(&t.ctx, msg_ids[0]).await.unwrap();
+        assert_eq!(msg.server_uid, 0);
+    }
+
+    #[async_std::test]
+    async fn test_delete_sentbox_copy() {
+        let t = TestContext::new_alice().await;
+        t.ctx
+            .set_config(Config::ConfiguredSentboxFolder, Some("Sent"))
+            .await
+            .unwrap();
+        let contact_id = Contact::create(&t.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        let chat_id = chat::create_by_contact_id(&t.ctx, contact_id)
+            .await
+            .unwrap();
+        chat_id
+            .set_ephemeral_timer(&t.ctx, Timer::from_u32(60))
+            .await
+            .unwrap();
+        t.pop_sent_msg().await;
+
+        let msg_id = chat::send_text_msg(&t.ctx, chat_id, "hi".to_string())
+            .await
+            .unwrap();
+        let sent = t.pop_sent_msg().await;
+
+        // BCC-self copy in the inbox and a copy in the Sent folder.
+        dc_receive_imf(&t.ctx, sent.payload().as_bytes(), "INBOX", 1, false)
+            .await
+            .unwrap();
+        dc_receive_imf(&t.ctx, sent.payload().as_bytes(), "Sent", 2, false)
+            .await
+            .unwrap();
+
+        // Expire the message.
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timestamp=? WHERE id=?",
+                paramsv![time() - 1, msg_id],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            load_imap_deletion_msgid(&t.ctx).await.unwrap(),
+            Some(msg_id)
+        );
+        let locations = t
+            .ctx
+            .sql
+            .query_row(
+                "SELECT server_folder, server_uid, sentbox_uid FROM msgs WHERE id=?",
+                paramsv![msg_id],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, u32>(1)?,
+                        row.get::<_, u32>(2)?,
+                    ))
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(locations, ("INBOX".to_string(), 1, 2));
+
+        // Sent folder copy is still deleted after the inbox copy is gone.
+        t.ctx
+            .sql
+            .execute("UPDATE msgs SET server_uid=0 WHERE id=?", paramsv![msg_id])
+            .await
+            .unwrap();
+        assert_eq!(
+            load_imap_deletion_msgid(&t.ctx).await.unwrap(),
+            Some(msg_id)
+        );
+    }
+
+    #[async_std::test]
+    async fn test_delete_expired_messages_now_keeps_sentbox_copy() {
+        let t = TestContext::new_alice().await;
+        t.ctx
+            .set_config(Config::ConfiguredSentboxFolder, Some("Sent"))
+            .await
+            .unwrap();
+        let contact_id = Contact::create(&t.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        let chat_id = chat::create_by_contact_id(&t.ctx, contact_id)
+            .await
+            .unwrap();
+        let msg_id = chat::send_text_msg(&t.ctx, chat_id, "hi".to_string())
+            .await
+            .unwrap();
+        let sent = t.pop_sent_msg().await;
+
+        // The inbox copy is gone, only the copy in the Sent folder is
+        // left on the server.
+        dc_receive_imf(&t.ctx, sent.payload().as_bytes(), "INBOX", 1, false)
+            .await
+            .unwrap();
+        dc_receive_imf(&t.ctx, sent.payload().as_bytes(), "Sent", 2, false)
+            .await
+            .unwrap();
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET server_uid=0, ephemeral_timestamp=? WHERE id=?",
+                paramsv![time() - 1, msg_id],
+            )
+            .await
+            .unwrap();
+
+        assert!(t.ctx.delete_expired_messages_now().await.unwrap());
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert_eq!(msg.chat_id, ChatId::new(DC_CHAT_ID_TRASH));
+        assert_eq!(
+            load_imap_deletion_msgid(&t.ctx).await.unwrap(),
+            Some(msg_id)
+        );
+    }
+
+    #[async_std::test]
+    async fn test_ephemeral_timer_stock_mapping() {
+        let context = TestContext::new().await.ctx;
+
+        // Show one hour as "60 minutes" instead of using the preset.
+        context
+            .set_ephemeral_timer_stock_mapping(|timer| match timer.to_u32() {
+                3600 => (StockMessage::MsgEphemeralTimerEnabled, "60".to_string()),
+                _ => default_ephemeral_timer_stock(timer),
+            })
+            .await;
+        context
+            .set_stock_translation(
+                StockMessage::MsgEphemeralTimerEnabled,
+                "Message deletion timer is set to %1$s minutes.".to_string(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            stock_ephemeral_timer_changed(&context, Timer::from_u32(3600), 0).await,
+            "Message deletion timer is set to 60 minutes."
+        );
+        assert_eq!(
+            stock_ephemeral_timer_changed(&context, Timer::from_u32(60), 0).await,
+            "Message deletion timer is set to 1 minute."
+        );
+        assert_eq!(
+            stock_ephemeral_timer_changed(&context, Timer::Disabled, 0).await,
+            "Message deletion timer is disabled."
+        );
+    }
+
+    #[async_std::test]
+    async fn test_deletion_auditor() {
+        use futures::FutureExt;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let t = TestContext::new_alice().await;
+        let msg_id = receive_simple_msg(&t.ctx, "audited@example.com").await;
+
+        // The auditor fails on the first call and succeeds afterwards.
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls1 = calls.clone();
+        t.ctx
+            .set_deletion_auditor(move |record| {
+                assert_eq!(record.reason, DeletionReason::EphemeralTimer);
+                let call = calls1.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    ensure!(call > 0, "audit log unavailable");
+                    Ok(())
+                }
+                .boxed()
+            })
+            .await;
+
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timestamp=? WHERE id=?",
+                paramsv![time() - 1, msg_id],
+            )
+            .await
+            .unwrap();
+
+        // Deletion is deferred while auditing fails.
+        assert!(!delete_expired_messages(&t.ctx).await.unwrap());
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert_ne!(msg.chat_id, ChatId::new(DC_CHAT_ID_TRASH));
+
+        assert!(delete_expired_messages(&t.ctx).await.unwrap());
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert_eq!(msg.chat_id, ChatId::new(DC_CHAT_ID_TRASH));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[async_std::test]
+    async fn test_ephemeral_timestamp() {
+        let t = TestContext::new_alice().await;
+        let mut msg_ids = Vec::new();
+        for i in 0..2 {
+            let rfc724_mid = format!("expiry{}@example.com", i);
+            let msg_id = receive_simple_msg_in(&t.ctx, &rfc724_mid, "INBOX", i + 1).await;
+            msg_ids.push(msg_id);
+        }
+        let chat_id = Message::load_from_db(&t.ctx, msg_ids[0])
+            .await
+            .unwrap()
+            .chat_id;
+
+        assert_eq!(msg_ids[0].ephemeral_timestamp(&t.ctx).await.unwrap(), None);
+        assert_eq!(chat_id.next_ephemeral_expiry(&t.ctx).await.unwrap(), None);
+
+        let now = time();
+        for (msg_id, timestamp) in msg_ids.iter().zip(&[now + 60, now + 30]) {
+            t.ctx
+                .sql
+                .execute(
+                    "UPDATE msgs SET ephemeral_timestamp=? WHERE id=?",
+                    paramsv![*timestamp, *msg_id],
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            msg_ids[0].ephemeral_timestamp(&t.ctx).await.unwrap(),
+            Some(now + 60)
+        );
+        assert_eq!(
+            chat_id.next_ephemeral_expiry(&t.ctx).await.unwrap(),
+            Some(now + 30)
+        );
+
+        // Deleted messages do not expire anymore.
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET chat_id=? WHERE id=?",
+                paramsv![DC_CHAT_ID_TRASH, msg_ids[1]],
+            )
+            .await
+            .unwrap();
+        assert_eq!(msg_ids[1].ephemeral_timestamp(&t.ctx).await.unwrap(), None);
+        assert_eq!(
+            chat_id.next_ephemeral_expiry(&t.ctx).await.unwrap(),
+            Some(now + 60)
+        );
+    }
+
+    #[async_std::test]
+    async fn test_countdown_stream() {
+        use futures::StreamExt;
+
+        let t = TestContext::new_alice().await;
+        let msg_id = receive_simple_msg(&t.ctx, "countdown@example.com").await;
+
+        // No timer is running.
+        let values: Vec<i64> = msg_id.countdown_stream(&t.ctx).collect().await;
+        assert!(values.is_empty());
+
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timestamp=? WHERE id=?",
+                paramsv![time() + 2, msg_id],
+            )
+            .await
+            .unwrap();
+        let values: Vec<i64> = async_std::future::timeout(
+            Duration::from_secs(10),
+            msg_id.countdown_stream(&t.ctx).collect(),
+        )
+        .await
+        .unwrap();
+        assert!(values.len() >= 2);
+        assert!(values[0] <= 2);
+        assert_eq!(values.last(), Some(&0));
+        assert!(values.windows(2).all(|w| w[0] > w[1]));
+    }
+
+    #[async_std::test]
+    async fn test_contacts_with_ephemeral_timers() {
+        let t = TestContext::new_alice().await;
+        let contact_id = Contact::create(&t.ctx, "Bob", "bob@example.org")
+            .await
+            .unwrap();
+        let chat_id = chat::create_by_contact_id(&t.ctx, contact_id)
+            .await
+            .unwrap();
+        let group_id = chat::create_group_chat(&t.ctx, ProtectionStatus::Unprotected, "Group")
+            .await
+            .unwrap();
+        assert!(t
+            .ctx
+            .contacts_with_ephemeral_timers()
+            .await
+            .unwrap()
+            .is_empty());
+
+        let timer = Timer::from_u32(60);
+        chat_id.set_ephemeral_timer(&t.ctx, timer).await.unwrap();
+        group_id.set_ephemeral_timer(&t.ctx, timer).await.unwrap();
+        assert_eq!(
+            t.ctx.contacts_with_ephemeral_timers().await.unwrap(),
+            vec![(contact_id, timer)]
+        );
+        assert_eq!(
+            t.ctx.chats_with_ephemeral_timers().await.unwrap(),
+            vec![(group_id, timer)]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_pause_ephemeral_timer() {
+        let t = TestContext::new_alice().await;
+        let chat_id = chat::create_by_contact_id(&t.ctx, DC_CONTACT_ID_SELF)
+            .await
+            .unwrap();
+        let msg_id = chat::send_text_msg(&t.ctx, chat_id, "hello".to_string())
+            .await
+            .unwrap();
+        let ephemeral_timestamp = time() + 30;
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timer=60, ephemeral_timestamp=? WHERE id=?",
+                paramsv![ephemeral_timestamp, msg_id],
+            )
+            .await
+            .unwrap();
+
+        msg_id.pause_ephemeral_timer(&t.ctx).await.unwrap();
+        let paused_at = time();
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert_eq!(msg.ephemeral_timestamp, 0);
+        let remaining: i64 = msg.param.get_int(Param::EphemeralRemaining).unwrap().into();
+        assert!(remaining <= 30);
+        assert!(remaining >= ephemeral_timestamp - paused_at);
+
+        // Marking the message as seen does not restart the timer.
+        msg_id.start_ephemeral_timer(&t.ctx).await.unwrap();
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert_eq!(msg.ephemeral_timestamp, 0);
+
+        // Neither does housekeeping.
+        assert_eq!(start_ephemeral_timers(&t.ctx).await.unwrap(), 0);
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert_eq!(msg.ephemeral_timestamp, 0);
+
+        // The remaining time is added to the time of resuming, not to
+        // the time of pausing. Pretend a lot of time was remaining
+        // instead of waiting.
+        let mut param = msg.param.clone();
+        param.set_int(Param::EphemeralRemaining, 3600);
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET param=? WHERE id=?",
+                paramsv![param.to_string(), msg_id],
+            )
+            .await
+            .unwrap();
+        let resumed_at = time();
+        msg_id.resume_ephemeral_timer(&t.ctx).await.unwrap();
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert!(!msg.param.exists(Param::EphemeralRemaining));
+        assert!(msg.ephemeral_timestamp >= resumed_at + 3600);
+        assert!(msg.ephemeral_timestamp <= time() + 3600);
+
+        // Resuming does not postpone a timer which expires earlier.
+        msg_id.pause_ephemeral_timer(&t.ctx).await.unwrap();
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timestamp=? WHERE id=?",
+                paramsv![time() + 5, msg_id],
+            )
+            .await
+            .unwrap();
+        msg_id.resume_ephemeral_timer(&t.ctx).await.unwrap();
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert!(msg.ephemeral_timestamp <= time() + 5);
+    }
+
+    #[test]
+    fn test_effective_expiry() {
+        assert_eq!(effective_expiry(1000, 0, None), None);
+        assert_eq!(effective_expiry(1000, 5000, None), Some(5000));
+        assert_eq!(effective_expiry(1000, 0, Some(100)), Some(1100));
+        assert_eq!(effective_expiry(1000, 5000, Some(100)), Some(1100));
+        assert_eq!(effective_expiry(1000, 1050, Some(100)), Some(1050));
+    }
+
+    #[async_std::test]
+    async fn test_time_until_empty() {
+        let t = TestContext::new_alice().await;
+        let contact_id = Contact::create(&t.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        let chat_id = chat::create_by_contact_id(&t.ctx, contact_id)
+            .await
+            .unwrap();
+        // An empty chat is empty already.
+        let empty_at = chat_id.time_until_empty(&t.ctx).await.unwrap().unwrap();
+        assert!(empty_at <= time());
+
+        let now = time();
+        let mut msg_ids = Vec::new();
+        for text in &["first", "second", "third"] {
+            msg_ids.push(
+                chat::send_text_msg(&t.ctx, chat_id, text.to_string())
+                    .await
+                    .unwrap(),
+            );
+        }
+        assert_eq!(chat_id.time_until_empty(&t.ctx).await.unwrap(), None);
+
+        for (msg_id, ephemeral_timestamp) in msg_ids.iter().zip(&[now + 300, now + 900, now + 600])
+        {
+            t.ctx
+                .sql
+                .execute(
+                    "UPDATE msgs SET ephemeral_timestamp=? WHERE id=?",
+                    paramsv![*ephemeral_timestamp, *msg_id],
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            chat_id.time_until_empty(&t.ctx).await.unwrap(),
+            Some(now + 900)
+        );
+
+        // Messages expire earlier according to `delete_device_after`.
+        t.ctx
+            .set_config(Config::DeleteDeviceAfter, Some("600"))
+            .await
+            .unwrap();
+        let empty_at = chat_id.time_until_empty(&t.ctx).await.unwrap().unwrap();
+        // Sent messages may have timestamps a few seconds in the future.
+        assert!(empty_at >= now + 600 && empty_at <= time() + 610);
+
+        // Kept messages never expire.
+        t.ctx
+            .set_config(Config::KeepLastNPerChat, Some("1"))
+            .await
+            .unwrap();
+        assert_eq!(chat_id.time_until_empty(&t.ctx).await.unwrap(), None);
+    }
+
+    #[async_std::test]
+    async fn test_start_ephemeral_timer_at() {
+        let t = TestContext::new_alice().await;
+        let chat_id = chat::create_by_contact_id(&t.ctx, DC_CONTACT_ID_SELF)
+            .await
+            .unwrap();
+        let msg_id = chat::send_text_msg(&t.ctx, chat_id, "hello".to_string())
+            .await
+            .unwrap();
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timer=3600, ephemeral_timestamp=0 WHERE id=?",
+                paramsv![msg_id],
+            )
+            .await
+            .unwrap();
+
+        // The timer starts when the message was seen.
+        let seen_at = time() - 600;
+        msg_id
+            .start_ephemeral_timer_at(&t.ctx, seen_at)
+            .await
+            .unwrap();
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert_eq!(msg.ephemeral_timestamp, seen_at + 3600);
+
+        // Deletion is never postponed.
+        msg_id.start_ephemeral_timer(&t.ctx).await.unwrap();
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert_eq!(msg.ephemeral_timestamp, seen_at + 3600);
+
+        msg_id
+            .start_ephemeral_timer_at(&t.ctx, seen_at - 600)
+            .await
+            .unwrap();
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert_eq!(msg.ephemeral_timestamp, seen_at + 3000);
+
+        // A time in the future does not extend the timer.
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timestamp=0 WHERE id=?",
+                paramsv![msg_id],
+            )
+            .await
+            .unwrap();
+        msg_id
+            .start_ephemeral_timer_at(&t.ctx, time() + 86400)
+            .await
+            .unwrap();
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert!(msg.ephemeral_timestamp <= time() + 3600);
+    }
+
+    #[async_std::test]
+    async fn test_burn_after_reading() {
+        let t = TestContext::new_alice().await;
+        let chat_id = chat::create_by_contact_id(&t.ctx, DC_CONTACT_ID_SELF)
+            .await
+            .unwrap();
+        let msg_id = chat::send_text_msg(&t.ctx, chat_id, "hello".to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            chat_id.get_ephemeral_timer(&t.ctx).await.unwrap(),
+            Timer::Disabled
+        );
+
+        msg_id.set_burn_after_reading(&t.ctx, true).await.unwrap();
+        msg_id.start_ephemeral_timer(&t.ctx).await.unwrap();
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert!(msg.ephemeral_timestamp > 0);
+        assert!(msg.ephemeral_timestamp <= time() + i64::from(BURN_AFTER_READING_DELAY));
+
+        // The timer runs out.
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timestamp=? WHERE id=?",
+                paramsv![time() - 1, msg_id],
+            )
+            .await
+            .unwrap();
+        delete_expired_messages(&t.ctx).await.unwrap();
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert_eq!(msg.chat_id, ChatId::new(DC_CHAT_ID_TRASH));
+    }
+
+    #[async_std::test]
+    async fn test_msg_deleted_event() {
+        use std::sync::{Arc, Mutex};
+
+        let t = TestContext::new_alice().await;
+        let chat_id = chat::create_by_contact_id(&t.ctx, DC_CONTACT_ID_SELF)
+            .await
+            .unwrap();
+        let deleted_events = Arc::new(Mutex::new(Vec::new()));
+        let events = deleted_events.clone();
+        let event_id = EventType::MsgDeleted {
+            chat_id: ChatId::new(0),
+            msg_id: MsgId::new(0),
+        }
+        .as_id();
+        t.ctx.on_event_type(event_id, move |event| {
+            if let EventType::MsgDeleted { chat_id, msg_id } = event.typ {
+                events.lock().unwrap().push((chat_id, msg_id));
+            }
+        });
+
+        let mut msg_ids = Vec::new();
+        for text in &["hidden", "deleted"] {
+            let msg_id = chat::send_text_msg(&t.ctx, chat_id, text.to_string())
+                .await
+                .unwrap();
+            msg_ids.push(msg_id);
+        }
+        async fn expire(t: &TestContext, msg_id: MsgId) {
+            t.ctx
+                .sql
+                .execute(
+                    "UPDATE msgs SET ephemeral_timestamp=? WHERE id=?",
+                    paramsv![time() - 1, msg_id],
+                )
+                .await
+                .unwrap();
+        }
+
+        // No events while the chatlist is reloaded.
+        expire(&t, msg_ids[0]).await;
+        assert!(hide_expired_messages(&t.ctx).await.unwrap());
+        assert!(deleted_events.lock().unwrap().is_empty());
+
+        expire(&t, msg_ids[1]).await;
+        assert!(delete_expired_messages(&t.ctx).await.unwrap());
+        assert_eq!(*deleted_events.lock().unwrap(), vec![(chat_id, msg_ids[1])]);
+
+        // Messages already in the trash chat are not reported again.
+        expire(&t, msg_ids[0]).await;
+        assert!(!delete_expired_messages(&t.ctx).await.unwrap());
+        assert_eq!(deleted_events.lock().unwrap().len(), 1);
+    }
+
+    #[async_std::test]
+    async fn test_ephemeral_settings_json() {
+        let t = TestContext::new_alice().await;
+        let chat_id1 = chat::create_group_chat(&t.ctx, ProtectionStatus::Unprotected, "foo")
+            .await
+            .unwrap();
+        let chat_id2 = chat::create_group_chat(&t.ctx, ProtectionStatus::Unprotected, "bar")
+            .await
+            .unwrap();
+        chat_id1
+            .inner_set_ephemeral_timer(&t.ctx, Timer::from_u32(60))
+            .await
+            .unwrap();
+        chat_id2
+            .inner_set_ephemeral_timer(&t.ctx, Timer::from_u32(3600))
+            .await
+            .unwrap();
+
+        let json = t.ctx.export_ephemeral_settings_json().await.unwrap();
+        let settings: Vec<EphemeralSetting> = serde_json::from_str(&json).unwrap();
+        assert!(settings.contains(&EphemeralSetting {
+            chat_name: "foo".to_string(),
+            chat_id: chat_id1.to_u32(),
+            contact_addr: None,
+            timer_seconds: 60,
+        }));
+
+        for chat_id in &[chat_id1, chat_id2] {
+            chat_id
+                .inner_set_ephemeral_timer(&t.ctx, Timer::Disabled)
+                .await
+                .unwrap();
+        }
+
+        let unmatched = t.ctx.import_ephemeral_settings_json(&json).await.unwrap();
+        assert!(unmatched.is_empty());
+        assert_eq!(
+            chat_id1.get_ephemeral_timer(&t.ctx).await.unwrap(),
+            Timer::from_u32(60)
+        );
+        assert_eq!(
+            chat_id2.get_ephemeral_timer(&t.ctx).await.unwrap(),
+            Timer::from_u32(3600)
+        );
+
+        // Chats are matched by name if the id is unknown, and unknown
+        // chats are reported.
+        let json = r#"[
+            {"chat_name": "bar", "chat_id": 9999, "timer_seconds": 0},
+            {"chat_name": "unknown", "chat_id": 9998, "timer_seconds": 60}
+        ]"#;
+        let unmatched = t.ctx.import_ephemeral_settings_json(json).await.unwrap();
+        assert_eq!(unmatched.len(), 1);
+        assert_eq!(unmatched[0].chat_name, "unknown");
+        assert_eq!(
+            chat_id2.get_ephemeral_timer(&t.ctx).await.unwrap(),
+            Timer::Disabled
+        );
+
+        // The name takes precedence over the device-local id.
+        let json = format!(
+            r#"[{{"chat_name": "bar", "chat_id": {}, "timer_seconds": 120}}]"#,
+            chat_id1.to_u32()
+        );
+        let unmatched = t.ctx.import_ephemeral_settings_json(&json).await.unwrap();
+        assert!(unmatched.is_empty());
+        assert_eq!(
+            chat_id1.get_ephemeral_timer(&t.ctx).await.unwrap(),
+            Timer::from_u32(60)
+        );
+        assert_eq!(
+            chat_id2.get_ephemeral_timer(&t.ctx).await.unwrap(),
+            Timer::from_u32(120)
+        );
+
+        // 1:1 chats are matched by the address of the contact.
+        let contact_id = Contact::create(&t.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        let bob_chat_id = chat::create_by_contact_id(&t.ctx, contact_id)
+            .await
+            .unwrap();
+        bob_chat_id
+            .inner_set_ephemeral_timer(&t.ctx, Timer::from_u32(60))
+            .await
+            .unwrap();
+        let json = t.ctx.export_ephemeral_settings_json().await.unwrap();
+        let settings: Vec<EphemeralSetting> = serde_json::from_str(&json).unwrap();
+        assert!(settings.contains(&EphemeralSetting {
+            chat_name: "Bob".to_string(),
+            chat_id: bob_chat_id.to_u32(),
+            contact_addr: Some("bob@example.net".to_string()),
+            timer_seconds: 60,
+        }));
+        let json = r#"[{"chat_name": "Robert", "chat_id": 9999,
+                        "contact_addr": "BOB@example.net", "timer_seconds": 300}]"#;
+        let unmatched = t.ctx.import_ephemeral_settings_json(json).await.unwrap();
+        assert!(unmatched.is_empty());
+        assert_eq!(
+            bob_chat_id.get_ephemeral_timer(&t.ctx).await.unwrap(),
+            Timer::from_u32(300)
+        );
+
+        // Blocked chats are not exported and not imported either.
+        t.ctx
+            .sql
+            .execute("UPDATE chats SET blocked=1 WHERE id=?", paramsv![chat_id1])
+            .await
+            .unwrap();
+        let json = t.ctx.export_ephemeral_settings_json().await.unwrap();
+        assert!(!json.contains("\"foo\""));
+        let json = r#"[{"chat_name": "foo", "chat_id": 9999, "timer_seconds": 0}]"#;
+        let unmatched = t.ctx.import_ephemeral_settings_json(json).await.unwrap();
+        assert_eq!(unmatched.len(), 1);
+        assert_eq!(
+            chat_id1.get_ephemeral_timer(&t.ctx).await.unwrap(),
+            Timer::from_u32(60)
+        );
+    }
+
+    #[async_std::test]
+    async fn test_cleanup_orphaned_trash() {
+        let t = TestContext::new_alice().await;
+        let chat_id = chat::create_by_contact_id(&t.ctx, DC_CONTACT_ID_SELF)
+            .await
+            .unwrap();
+        let orphan_id = chat::send_text_msg(&t.ctx, chat_id, "orphan".to_string())
+            .await
+            .unwrap();
+        let on_server_id = chat::send_text_msg(&t.ctx, chat_id, "on server".to_string())
+            .await
+            .unwrap();
+        orphan_id.trash(&t.ctx).await.unwrap();
+        on_server_id.trash(&t.ctx).await.unwrap();
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET server_uid=1 WHERE id=?",
+                paramsv![on_server_id],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(t.ctx.count_orphaned_trash().await.unwrap(), 1);
+        assert_eq!(t.ctx.cleanup_orphaned_trash().await.unwrap(), 1);
+        assert!(Message::load_from_db(&t.ctx, orphan_id).await.is_err());
+
+        // Messages still to be deleted from the server are kept.
+        assert!(Message::load_from_db(&t.ctx, on_server_id).await.is_ok());
+        assert_eq!(t.ctx.count_orphaned_trash().await.unwrap(), 0);
+    }
+
+    #[async_std::test]
+    async fn test_cleanup_orphaned_trash_keeps_pending() {
+        let t = TestContext::new_alice().await;
+        let chat_id = chat::create_by_contact_id(&t.ctx, DC_CONTACT_ID_SELF)
+            .await
+            .unwrap();
+        let job_id = chat::send_text_msg(&t.ctx, chat_id, "job".to_string())
+            .await
+            .unwrap();
+        let sentbox_id = chat::send_text_msg(&t.ctx, chat_id, "sentbox".to_string())
+            .await
+            .unwrap();
+        job_id.trash(&t.ctx).await.unwrap();
+        sentbox_id.trash(&t.ctx).await.unwrap();
+        job::Job::new(
+            job::Action::DeleteMsgOnImap,
+            job_id.to_u32(),
+            Params::new(),
+            0,
+        )
+        .save(&t.ctx)
+        .await
+        .unwrap();
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET sentbox_uid=3 WHERE id=?",
+                paramsv![sentbox_id],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(t.ctx.count_orphaned_trash().await.unwrap(), 0);
+        assert_eq!(t.ctx.cleanup_orphaned_trash().await.unwrap(), 0);
+        assert!(Message::load_from_db(&t.ctx, job_id).await.is_ok());
+        assert!(Message::load_from_db(&t.ctx, sentbox_id).await.is_ok());
+    }
+
+    async fn add_expired_file_msg(t: &TestContext, chat_id: ChatId, name: &str, bytes: usize) {
+        let msg_id = add_blob_msg(&t.ctx, chat_id, Viewtype::File, name, bytes).await;
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET timestamp=?, ephemeral_timestamp=? WHERE id=?;",
+                paramsv![time() - 1000 + bytes as i64 / 1000, time() - 1, msg_id],
+            )
+            .await
+            .unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_trash_messages() {
+        let t = TestContext::new_alice().await;
+        let chat_id = chat::create_by_contact_id(&t.ctx, DC_CONTACT_ID_SELF)
+            .await
+            .unwrap();
+        let mut records = Vec::new();
+        for _ in 0..UPDATE_BATCH_SIZE + 1 {
+            t.ctx
+                .sql
+                .execute(
+                    "INSERT INTO msgs (chat_id, txt) VALUES (?, 'hi')",
+                    paramsv![chat_id],
+                )
+                .await
+                .unwrap();
+            let msg_id: u32 = t
+                .ctx
+                .sql
+                .query_get_value_result("SELECT MAX(id) FROM msgs", paramsv![])
+                .await
+                .unwrap()
+                .unwrap();
+            records.push(AuditRecord {
+                msg_id: MsgId::new(msg_id),
+                chat_id,
+                reason: DeletionReason::EphemeralTimer,
+            });
+        }
+        let last_id = records.last().unwrap().msg_id;
+        last_id.trash(&t.ctx).await.unwrap();
+
+        // Messages already in the trash chat are skipped.
+        let trashed = trash_messages(&t.ctx, records).await.unwrap();
+        assert_eq!(trashed.len(), UPDATE_BATCH_SIZE);
+        assert!(trashed.iter().all(|record| record.msg_id != last_id));
+        assert!(trashed[0].msg_id.is_scrubbed(&t.ctx).await);
+        assert!(!last_id.is_scrubbed(&t.ctx).await);
+    }
+
+    #[async_std::test]
+    async fn test_delete_expired_messages_prioritized() {
+        let mut reclaimed = Vec::new();
+        for strategy in &[
+            DeletionStrategy::OldestFirst,
+            DeletionStrategy::LargestFirst,
+        ] {
+            let t = TestContext::new_alice().await;
+            let chat_id = chat::create_by_contact_id(&t.ctx, DC_CONTACT_ID_SELF)
+                .await
+                .unwrap();
+            add_expired_file_msg(&t, chat_id, "small.txt", 1000).await;
+            add_expired_file_msg(&t, chat_id, "medium.txt", 2000).await;
+            add_expired_file_msg(&t, chat_id, "large.bin", 50_000).await;
+
+            let res = t
+                .ctx
+                .delete_expired_messages_prioritized(*strategy, Some(1))
+                .await
+                .unwrap();
+            assert_eq!(res.count, 1);
+            reclaimed.push(res.bytes);
+
+            // The remaining expired messages are deleted without a limit.
+            let res = t
+                .ctx
+                .delete_expired_messages_prioritized(*strategy, None)
+                .await
+                .unwrap();
+            assert_eq!(res.count, 2);
+            assert_eq!(reclaimed.last().unwrap() + res.bytes, 53_000);
+        }
+
+        assert_eq!(reclaimed, vec![1000, 50_000]);
+    }
+
+    #[async_std::test]
+    async fn test_prune_trashed_messages() {
+        let t = TestContext::new_alice().await;
+        let chat_id = chat::create_by_contact_id(&t.ctx, DC_CONTACT_ID_SELF)
+            .await
+            .unwrap();
+        let no_uid_id = chat::send_text_msg(&t.ctx, chat_id, "no uid".to_string())
+            .await
+            .unwrap();
+        let uid_id = chat::send_text_msg(&t.ctx, chat_id, "uid".to_string())
+            .await
+            .unwrap();
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET chat_id=?, server_uid=0 WHERE id=?",
+                paramsv![DC_CHAT_ID_TRASH, no_uid_id],
+            )
+            .await
+            .unwrap();
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET chat_id=?, server_uid=7 WHERE id=?",
+                paramsv![DC_CHAT_ID_TRASH, uid_id],
+            )
+            .await
+            .unwrap();
+
+        // Copies in the Sent folder and pending jobs keep the row.
+        let sentbox_id = chat::send_text_msg(&t.ctx, chat_id, "sentbox".to_string())
+            .await
+            .unwrap();
+        let job_id = chat::send_text_msg(&t.ctx, chat_id, "job".to_string())
+            .await
+            .unwrap();
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET chat_id=?, server_uid=0, sentbox_uid=(id=?) \
+                 WHERE id=? OR id=?",
+                paramsv![DC_CHAT_ID_TRASH, sentbox_id, sentbox_id, job_id],
+            )
+            .await
+            .unwrap();
+        job::Job::new(
+            job::Action::DeleteMsgOnImap,
+            job_id.to_u32(),
+            Params::new(),
+            0,
+        )
+        .save(&t.ctx)
+        .await
+        .unwrap();
+
+        // Hidden messages without a server UID are pruned as well.
+        let hidden_id = chat::send_text_msg(&t.ctx, chat_id, "hidden".to_string())
+            .await
+            .unwrap();
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET hidden=1, server_uid=0 WHERE id=?",
+                paramsv![hidden_id],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(prune_trashed_messages(&t.ctx).await.unwrap(), 2);
+        assert!(Message::load_from_db(&t.ctx, no_uid_id).await.is_err());
+        assert!(Message::load_from_db(&t.ctx, hidden_id).await.is_err());
+
+        // The message still has to be deleted from the server.
+        let msg = Message::load_from_db(&t.ctx, uid_id).await.unwrap();
+        assert_eq!(msg.chat_id, ChatId::new(DC_CHAT_ID_TRASH));
+        assert_eq!(msg.server_uid, 7);
+        assert!(Message::load_from_db(&t.ctx, sentbox_id).await.is_ok());
+        assert!(Message::load_from_db(&t.ctx, job_id).await.is_ok());
+    }
+
+    #[async_std::test]
+    async fn test_delete_expired_messages_now() {
+        let t = TestContext::new_alice().await;
+        let contact_id = Contact::create(&t.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        let chat_id = chat::create_by_contact_id(&t.ctx, contact_id)
+            .await
+            .unwrap();
+        let mut ids = Vec::new();
+        for text in &["expired", "on server", "kept", "pending"] {
+            ids.push(
+                chat::send_text_msg(&t.ctx, chat_id, text.to_string())
+                    .await
+                    .unwrap(),
+            );
+            if *text != "pending" {
+                t.pop_sent_msg().await;
+            }
+        }
+        let (expired_id, on_server_id, kept_id, pending_id) = (ids[0], ids[1], ids[2], ids[3]);
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timestamp=? WHERE id=? OR id=? OR id=?",
+                paramsv![time() - 1, expired_id, on_server_id, pending_id],
+            )
+            .await
+            .unwrap();
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET server_uid=1 WHERE id=?",
+                paramsv![on_server_id],
+            )
+            .await
+            .unwrap();
+
+        assert!(t.ctx.delete_expired_messages_now().await.unwrap());
+
+        // The row of a message not on the server is removed at once.
+        assert!(Message::load_from_db(&t.ctx, expired_id).await.is_err());
+
+        // A message still to be deleted from the server is trashed.
+        let msg = Message::load_from_db(&t.ctx, on_server_id).await.unwrap();
+        assert_eq!(msg.chat_id, ChatId::new(DC_CHAT_ID_TRASH));
+
+        // A message still to be sent is trashed, but its row is kept.
+        let msg = Message::load_from_db(&t.ctx, pending_id).await.unwrap();
+        assert_eq!(msg.chat_id, ChatId::new(DC_CHAT_ID_TRASH));
+        assert_eq!(t.ctx.count_orphaned_trash().await.unwrap(), 0);
+
+        let msg = Message::load_from_db(&t.ctx, kept_id).await.unwrap();
+        assert_eq!(msg.chat_id, chat_id);
+
+        // Once the SMTP job is done, the row is removed.
+        t.pop_sent_msg().await;
+        assert!(!t.ctx.delete_expired_messages_now().await.unwrap());
+        assert!(Message::load_from_db(&t.ctx, pending_id).await.is_err());
+        assert!(Message::load_from_db(&t.ctx, on_server_id).await.is_ok());
+    }
+
+    #[async_std::test]
+    async fn test_validate_retention_settings() {
+        let t = TestContext::new_alice().await;
+        assert!(t
+            .ctx
+            .validate_retention_settings()
+            .await
+            .unwrap()
+            .is_empty());
+
+        t.ctx
+            .set_config(Config::DeleteServerAfter, Some("3600"))
+            .await
+            .unwrap();
+        t.ctx
+            .set_config(Config::DeleteDeviceAfter, Some("86400"))
+            .await
+            .unwrap();
+        let chat_id = chat::create_group_chat(&t.ctx, ProtectionStatus::Unprotected, "foo")
+            .await
+            .unwrap();
+        let timer = Timer::from_u32(604_800);
+        chat_id
+            .inner_set_ephemeral_timer(&t.ctx, timer)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            t.ctx.validate_retention_settings().await.unwrap(),
+            vec![
+                RetentionWarning::ServerBeforeDevice {
+                    delete_server_after: 3600,
+                    delete_device_after: 86400,
+                },
+                RetentionWarning::ServerBeforeEphemeralTimer {
+                    chat_id,
+                    delete_server_after: 3600,
+                    timer,
+                },
+                RetentionWarning::DeviceBeforeEphemeralTimer {
+                    chat_id,
+                    delete_device_after: 86400,
+                    timer,
+                },
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_apply_device_retention_now() {
+        let t = TestContext::new_alice().await;
+        let bob_id = Contact::create(&t.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        let chat_id = chat::create_by_contact_id(&t.ctx, bob_id).await.unwrap();
+        let old_id = chat::send_text_msg(&t.ctx, chat_id, "old".to_string())
+            .await
+            .unwrap();
+        let new_id = chat::send_text_msg(&t.ctx, chat_id, "new".to_string())
+            .await
+            .unwrap();
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET timestamp=? WHERE id=?",
+                paramsv![time() - 2 * 86400, old_id],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(t.ctx.apply_device_retention_now().await.unwrap(), 0);
+
+        t.ctx
+            .set_config(Config::DeleteDeviceAfter, Some("86400"))
+            .await
+            .unwrap();
+        assert_eq!(t.ctx.apply_device_retention_now().await.unwrap(), 1);
+        assert!(Message::load_from_db(&t.ctx, old_id)
+            .await
+            .unwrap()
+            .chat_id
+            .is_trash());
+        assert_eq!(
+            Message::load_from_db(&t.ctx, new_id).await.unwrap().chat_id,
+            chat_id
+        );
+    }
+
+    #[async_std::test]
+    async fn test_keep_last_n_per_chat() {
+        let t = TestContext::new_alice().await;
+        t.ctx
+            .set_config(Config::KeepLastNPerChat, Some("2"))
+            .await
+            .unwrap();
+        t.ctx
+            .set_config(Config::DeleteDeviceAfter, Some("86400"))
+            .await
+            .unwrap();
+        let bob_id = Contact::create(&t.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        let chat_id = chat::create_by_contact_id(&t.ctx, bob_id).await.unwrap();
+
+        let mut msg_ids = Vec::new();
+        for i in 0..4 {
+            let msg_id = chat::send_text_msg(&t.ctx, chat_id, format!("message {}", i))
+                .await
+                .unwrap();
+            t.ctx
+                .sql
+                .execute(
+                    "UPDATE msgs SET timestamp=? WHERE id=?",
+                    paramsv![time() - 3 * 86400 + i, msg_id],
+                )
+                .await
+                .unwrap();
+            msg_ids.push(msg_id);
+        }
+        // The newest message has an expired ephemeral timer as well.
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timer=60, ephemeral_timestamp=? WHERE id=?",
+                paramsv![time() - 100, msg_ids[3]],
+            )
+            .await
+            .unwrap();
+
+        assert!(delete_expired_messages(&t.ctx).await.unwrap());
+        for (i, msg_id) in msg_ids.iter().enumerate() {
+            let msg = Message::load_from_db(&t.ctx, *msg_id).await.unwrap();
+            assert_eq!(msg.chat_id.is_trash(), i < 2, "message {}", i);
+        }
+        assert!(!delete_expired_messages(&t.ctx).await.unwrap());
+
+        // Without keeping, the remaining messages expire as well.
+        t.ctx
+            .set_config(Config::KeepLastNPerChat, None)
+            .await
+            .unwrap();
+        assert!(delete_expired_messages(&t.ctx).await.unwrap());
+        for msg_id in msg_ids {
+            let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+            assert!(msg.chat_id.is_trash());
+        }
+    }
+
+    #[async_std::test]
+    async fn test_message_timer_override() {
+        let alice = TestContext::new_alice().await;
+        let bob = TestContext::new_bob().await;
+        let bob_id = Contact::create(&alice.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        let alice_chat_id = chat::create_by_contact_id(&alice.ctx, bob_id)
+            .await
+            .unwrap();
+        let alice_id = Contact::create(&bob.ctx, "Alice", "alice@example.com")
+            .await
+            .unwrap();
+        let bob_chat_id = chat::create_by_contact_id(&bob.ctx, alice_id)
+            .await
+            .unwrap();
+
+        let mut msg = Message::new(Viewtype::Text);
+        msg.set_text(Some("hello".to_string()));
+        msg.set_ephemeral_timer(Timer::from_u32(60));
+        assert_eq!(msg.get_ephemeral_timer(), 60);
+        let msg_id = chat::send_msg(&alice.ctx, alice_chat_id, &mut msg)
+            .await
+            .unwrap();
+        let sent = alice.pop_sent_msg().await;
+        let parsed = bob.parse_msg(&sent).await;
+        assert_eq!(
+            parsed.get(HeaderDef::EphemeralTimer),
+            Some(&"60".to_string())
+        );
+        assert!(parsed.get(HeaderDef::ChatEphemeralTimerOverride).is_some());
+
+        // The override is used for the message only.
+        let msg = Message::load_from_db(&alice.ctx, msg_id).await.unwrap();
+        assert_eq!(msg.get_ephemeral_timer(), 60);
+        assert_eq!(
+            alice_chat_id.get_ephemeral_timer(&alice.ctx).await.unwrap(),
+            Timer::Disabled
+        );
+
+        bob.recv_msg(&sent).await;
+        let msg = bob.get_last_msg(bob_chat_id).await;
+        assert_eq!(msg.get_text(), Some("hello".to_string()));
+        assert_eq!(msg.get_ephemeral_timer(), 60);
+        assert_eq!(
+            bob_chat_id.get_ephemeral_timer(&bob.ctx).await.unwrap(),
+            Timer::Disabled
+        );
+
+        // Other messages use the timer of the chat.
+        let msg_id = chat::send_text_msg(&alice.ctx, alice_chat_id, "hi".to_string())
+            .await
+            .unwrap();
+        let msg = Message::load_from_db(&alice.ctx, msg_id).await.unwrap();
+        assert_eq!(msg.get_ephemeral_timer(), 0);
+
+        // The override must not exceed the maximum or the timer of the chat.
+        alice_chat_id
+            .inner_set_ephemeral_timer(&alice.ctx, Timer::from_u32(3600))
+            .await
+            .unwrap();
+        for timer in &[
+            Timer::Disabled,
+            Timer::from_u32(3600),
+            Timer::from_u32(7200),
+            Timer::from_u32(MAX_TIMER_DURATION + 1),
+        ] {
+            let mut msg = Message::new(Viewtype::Text);
+            msg.set_text(Some("hello".to_string()));
+            msg.set_ephemeral_timer(*timer);
+            assert!(chat::send_msg(&alice.ctx, alice_chat_id, &mut msg)
+                .await
+                .is_err());
+        }
+        let mut msg = Message::new(Viewtype::Text);
+        msg.set_text(Some("hello".to_string()));
+        msg.set_ephemeral_timer(Timer::from_u32(60));
+        chat::send_msg(&alice.ctx, alice_chat_id, &mut msg)
+            .await
+            .unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_is_scrubbed() {
+        let t = TestContext::new_alice().await;
+        let chat_id = chat::create_by_contact_id(&t.ctx, DC_CONTACT_ID_SELF)
+            .await
+            .unwrap();
+        let msg_id = chat::send_text_msg(&t.ctx, chat_id, "hello".to_string())
+            .await
+            .unwrap();
+        assert!(!msg_id.is_scrubbed(&t.ctx).await);
+
+        t.ctx
+            .sql
+            .execute(
+                "UPDATE msgs SET ephemeral_timer=60, ephemeral_timestamp=? WHERE id=?",
+                paramsv![time() - 1, msg_id],
+            )
+            .await
+            .unwrap();
+        assert!(delete_expired_messages(&t.ctx).await.unwrap());
+        assert!(msg_id.is_scrubbed(&t.ctx).await);
+    }
+
+    #[async_std::test]
+    async fn test_timer_change_wins_over() {
+        let t = TestContext::new_alice().await;
+        let bob_id = Contact::create(&t.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        let claire_id = Contact::create(&t.ctx, "Claire", "claire@example.org")
+            .await
+            .unwrap();
+        let change = |timestamp, duration, from_id| TimerChange {
+            timestamp,
+            timer: Timer::from_u32(duration),
+            from_id,
+        };
+        let ctx = &t.ctx;
+        let wins = move |a: TimerChange, b: TimerChange| async move {
+            a.wins_over(ctx, &b).await.unwrap()
+        };
+
+        assert!(wins(change(2, 3600, claire_id), change(1, 60, bob_id)).await);
+        assert!(wins(change(1, 60, claire_id), change(1, 3600, bob_id)).await);
+        assert!(wins(change(1, 60, claire_id), change(1, 0, bob_id)).await);
+        assert!(wins(change(1, 60, bob_id), change(1, 60, claire_id)).await);
+        assert!(!wins(change(1, 60, claire_id), change(1, 60, bob_id)).await);
+        assert!(!wins(change(1, 60, bob_id), change(1, 60, bob_id)).await);
+    }
+
+    #[async_std::test]
+    async fn test_timer_change_tie_independent_of_contact_ids() {
+        // Contacts are created in different orders, so their ids differ
+        // between the devices, but both devices keep the change of the
+        // sender with the lower address.
+        for claire_first in &[false, true] {
+            let t = TestContext::new_alice().await;
+            let (bob_id, claire_id) = if *claire_first {
+                let claire_id = Contact::create(&t.ctx, "Claire", "claire@example.org")
+                    .await
+                    .unwrap();
+                let bob_id = Contact::create(&t.ctx, "Bob", "bob@example.net")
+                    .await
+                    .unwrap();
+                (bob_id, claire_id)
+            } else {
+                let bob_id = Contact::create(&t.ctx, "Bob", "bob@example.net")
+                    .await
+                    .unwrap();
+                let claire_id = Contact::create(&t.ctx, "Claire", "claire@example.org")
+                    .await
+                    .unwrap();
+                (bob_id, claire_id)
+            };
+            let chat_id = chat::create_group_chat(&t.ctx, ProtectionStatus::Unprotected, "Group")
+                .await
+                .unwrap();
+
+            for from_id in &[claire_id, bob_id] {
+                chat_id
+                    .apply_ephemeral_timer_change(
+                        &t.ctx,
+                        TimerChange {
+                            timestamp: 1000,
+                            timer: Timer::from_u32(60),
+                            from_id: *from_id,
+                        },
+                    )
+                    .await
+                    .unwrap();
+            }
+            let chat = Chat::load_from_db(&t.ctx, chat_id).await.unwrap();
+            assert_eq!(
+                chat.param.get_int(Param::EphemeralTimerSender),
+                Some(bob_id as i32)
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn test_undecryptable_ephemeral_msg() {
+        let t = TestContext::new_alice().await;
+        let bob_id = Contact::create(&t.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        chat::create_by_contact_id(&t.ctx, bob_id).await.unwrap();
+
+        // The message is old enough to be expired already, but is kept.
+        dc_receive_imf(
+            &t.ctx,
+            b"From: Bob <bob@example.net>\n\
+                 To: alice@example.com\n\
+                 Subject: foo\n\
+                 Message-ID: <undecryptable@example.net>\n\
+                 Chat-Version: 1.0\n\
+                 Ephemeral-Timer: 60\n\
+                 Date: Sun, 22 Mar 2020 22:37:57 +0000\n\
+                 Content-Type: multipart/encrypted; protocol=\"application/pgp-encrypted\";\n\
+                 \tboundary=\"boundary\"\n\
+                 \n\
+                 --boundary\n\
+                 Content-Type: application/pgp-encrypted\n\
+                 \n\
+                 Version: 1\n\
+                 \n\
+                 --boundary\n\
+                 Content-Type: application/octet-stream\n\
+                 \n\
+                 -----BEGIN PGP MESSAGE-----\n\
+                 \n\
+                 garbage\n\
+                 -----END PGP MESSAGE-----\n\
+                 \n\
+                 --boundary--\n",
+            "INBOX",
+            1,
+            false,
+        )
+        .await
+        .unwrap();
+
+        let (_, _, msg_id) = message::rfc724_mid_exists(&t.ctx, "undecryptable@example.net")
+            .await
+            .unwrap()
+            .unwrap();
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert!(!msg.chat_id.is_trash());
+        assert_eq!(msg.error(), Some(DECRYPTION_FAILED_ERROR.to_string()));
+
+        // Neither seeing the message nor housekeeping starts the timer.
+        message::markseen_msgs(&t.ctx, vec![msg_id]).await;
+        assert_eq!(start_ephemeral_timers(&t.ctx).await.unwrap(), 0);
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert_eq!(msg.ephemeral_timestamp, 0);
+
+        t.ctx
+            .set_config(Config::ExpireUndecryptable, Some("1"))
+            .await
+            .unwrap();
+        msg_id.start_ephemeral_timer(&t.ctx).await.unwrap();
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert!(msg.ephemeral_timestamp > 0);
+    }
+
+    #[async_std::test]
+    async fn test_conflicting_timer_changes() {
+        let msg = |id: &str, date: &str, timer: u32| {
+            format!(
+                "From: Bob <bob@example.net>\n\
+                 To: alice@example.com\n\
+                 Subject: foo\n\
+                 Message-ID: <{}@example.net>\n\
+                 Chat-Version: 1.0\n\
+                 Ephemeral-Timer: {}\n\
+                 Date: {}\n\
+                 \n\
+                 hello\n",
+                id, timer, date
+            )
+        };
+        let week = msg("week", "Sun, 22 Mar 2020 22:37:57 +0000", 604_800);
+        let day = msg("day", "Sun, 22 Mar 2020 22:37:57 +0000", 86400);
+        let later = msg("later", "Mon, 23 Mar 2020 22:37:57 +0000", 3600);
+
+        for order in &[[&week, &day, &later], [&day, &week, &later]] {
+            let t = TestContext::new_alice().await;
+            let bob_id = Contact::create(&t.ctx, "Bob", "bob@example.net")
+                .await
+                .unwrap();
+            let chat_id = chat::create_by_contact_id(&t.ctx, bob_id).await.unwrap();
+
+            // Of two changes sent at the same time, the stricter one wins.
+            for (uid, raw) in order[..2].iter().enumerate() {
+                dc_receive_imf(&t.ctx, raw.as_bytes(), "INBOX", uid as u32 + 1, false)
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(
+                chat_id.get_ephemeral_timer(&t.ctx).await.unwrap(),
+                Timer::from_u32(86400)
+            );
+
+            dc_receive_imf(&t.ctx, order[2].as_bytes(), "INBOX", 3, false)
+                .await
+                .unwrap();
+            assert_eq!(
+                chat_id.get_ephemeral_timer(&t.ctx).await.unwrap(),
+                Timer::from_u32(3600)
+            );
+        }
+
+        // Older changes arriving late do not flip the timer back.
+        let t = TestContext::new_alice().await;
+        let bob_id = Contact::create(&t.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        let chat_id = chat::create_by_contact_id(&t.ctx, bob_id).await.unwrap();
+        for (uid, raw) in [&later, &day, &week].iter().enumerate() {
+            dc_receive_imf(&t.ctx, raw.as_bytes(), "INBOX", uid as u32 + 1, false)
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            chat_id.get_ephemeral_timer(&t.ctx).await.unwrap(),
+            Timer::from_u32(3600)
+        );
+    }
+
+    #[async_std::test]
+    async fn test_reconcile_lost_timer_change() {
+        let alice = TestContext::new_alice().await;
+        let bob = TestContext::new_bob().await;
+        let bob_id = Contact::create(&alice.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        let alice_chat_id = chat::create_by_contact_id(&alice.ctx, bob_id)
+            .await
+            .unwrap();
+        let alice_id = Contact::create(&bob.ctx, "Alice", "alice@example.com")
+            .await
+            .unwrap();
+        let bob_chat_id = chat::create_by_contact_id(&bob.ctx, alice_id)
+            .await
+            .unwrap();
+
+        // The timer change message is lost.
+        let timer = Timer::from_u32(60);
+        alice_chat_id
+            .set_ephemeral_timer(&alice.ctx, timer)
+            .await
+            .unwrap();
+        alice.pop_sent_msg().await;
+
+        // Bob's next message still has no timer, so Alice re-sends
+        // the change instead of disabling the timer.
+        chat::send_text_msg(&bob.ctx, bob_chat_id, "hi".to_string())
+            .await
+            .unwrap();
+        let sent = bob.pop_sent_msg().await;
+        alice.recv_msg(&sent).await;
+        assert_eq!(
+            alice_chat_id.get_ephemeral_timer(&alice.ctx).await.unwrap(),
+            timer
+        );
+        let resent = alice.pop_sent_msg().await;
+        assert_eq!(
+            alice.parse_msg(&resent).await.is_system_message,
+            SystemMessage::EphemeralTimerChanged
+        );
+
+        // The change is re-sent only once.
+        chat::send_text_msg(&bob.ctx, bob_chat_id, "hi again".to_string())
+            .await
+            .unwrap();
+        alice.recv_msg(&bob.pop_sent_msg().await).await;
+        assert_eq!(
+            alice_chat_id.get_ephemeral_timer(&alice.ctx).await.unwrap(),
+            timer
+        );
+        assert!(!job::action_exists(&alice.ctx, job::Action::SendMsgToSmtp).await);
+
+        bob.recv_msg(&resent).await;
+        assert_eq!(
+            bob_chat_id.get_ephemeral_timer(&bob.ctx).await.unwrap(),
+            timer
+        );
+    }
+
+    #[async_std::test]
+    async fn test_reconcile_other_timer() {
+        let alice = TestContext::new_alice().await;
+        let bob = TestContext::new_bob().await;
+        let bob_id = Contact::create(&alice.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        let alice_chat_id = chat::create_by_contact_id(&alice.ctx, bob_id)
+            .await
+            .unwrap();
+        let alice_id = Contact::create(&bob.ctx, "Alice", "alice@example.com")
+            .await
+            .unwrap();
+        let bob_chat_id = chat::create_by_contact_id(&bob.ctx, alice_id)
+            .await
+            .unwrap();
+
+        alice_chat_id
+            .set_ephemeral_timer(&alice.ctx, Timer::from_u32(60))
+            .await
+            .unwrap();
+        alice.pop_sent_msg().await;
+
+        // Bob uses a timer which is neither Alice's new nor her
+        // previous one, so he did not miss her change.
+        bob_chat_id
+            .inner_set_ephemeral_timer(&bob.ctx, Timer::from_u32(3600))
+            .await
+            .unwrap();
+        chat::send_text_msg(&bob.ctx, bob_chat_id, "hi".to_string())
+            .await
+            .unwrap();
+        alice.recv_msg(&bob.pop_sent_msg().await).await;
+        assert!(!job::action_exists(&alice.ctx, job::Action::SendMsgToSmtp).await);
+    }
+
+    #[async_std::test]
+    async fn test_ephemeral_timer_for_new_member() {
+        let alice = TestContext::new_alice().await;
+        let bob = TestContext::new_bob().await;
+        let claire_id = Contact::create(&alice.ctx, "Claire", "claire@example.org")
+            .await
+            .unwrap();
+        let bob_id = Contact::create(&alice.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        let group_id = chat::create_group_chat(&alice.ctx, ProtectionStatus::Unprotected, "Group")
+            .await
+            .unwrap();
+        chat::add_contact_to_chat(&alice.ctx, group_id, claire_id).await;
+
+        let timer = Timer::from_u32(60);
+        group_id
+            .set_ephemeral_timer(&alice.ctx, timer)
+            .await
+            .unwrap();
+        alice.pop_sent_msg().await;
+
+        // Bob learns the timer from the member-added message.
+        chat::add_contact_to_chat(&alice.ctx, group_id, bob_id).await;
+        bob.recv_msg(&alice.pop_sent_msg().await).await;
+
+        let grpid = chat::Chat::load_from_db(&alice.ctx, group_id)
+            .await
+            .unwrap()
+            .grpid;
+        let (bob_group_id, _, _) = chat::get_chat_id_by_grpid(&bob.ctx, grpid).await.unwrap();
+        assert_eq!(
+            bob_group_id.get_ephemeral_timer(&bob.ctx).await.unwrap(),
+            timer
+        );
+    }
 }
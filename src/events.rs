@@ -1,50 +1,223 @@
 //! # Events specification
 
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
 use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
 
 use async_std::path::PathBuf;
 use async_std::sync::{channel, Receiver, Sender, TrySendError};
 use strum::EnumProperty;
 
 use crate::chat::ChatId;
+use crate::dc_tools::time;
 use crate::ephemeral::Timer as EphemeralTimer;
 use crate::message::MsgId;
 
-#[derive(Debug)]
+/// Callback registered with `Events::on_event_type()`.
+type EventHandler = Box<dyn Fn(Event) + Send + Sync + 'static>;
+
+/// Replacement for secrets in the event log.
+const REDACTED: &str = "[REDACTED]";
+
+/// File receiving a copy of all emitted events, see `Events::start_log()`.
+struct EventLog {
+    file: File,
+    /// Strings replaced by `REDACTED` before writing an event.
+    secrets: Vec<String>,
+}
+
+impl EventLog {
+    /// Writes `event` as a single line of JSON.
+    fn write(&mut self, event: &Event) -> io::Result<()> {
+        let mut text = format!("{:?}", event.typ);
+        for secret in &self.secrets {
+            text = text.replace(secret, REDACTED);
+        }
+        let line = serde_json::json!({
+            "timestamp": time(),
+            "context_id": event.id,
+            "event_id": event.as_id(),
+            "event": text,
+        });
+        writeln!(self.file, "{}", line)
+    }
+}
+
+/// Number of events queued for the `EventEmitter` before the
+/// `EventOverflowPolicy` applies.
+const EVENT_QUEUE_CAPACITY: usize = 1_000;
+
+/// What happens to events emitted while the event queue is full,
+/// e.g. because the UI stalls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventOverflowPolicy {
+    /// Drop the oldest queued event to make room for the new one.
+    DropOldest,
+    /// Drop the new event, keeping the queued ones.
+    DropNewest,
+}
+
+impl Default for EventOverflowPolicy {
+    fn default() -> Self {
+        EventOverflowPolicy::DropOldest
+    }
+}
+
 pub struct Events {
     receiver: Receiver<Event>,
     sender: Sender<Event>,
+    /// Handlers keyed by event id, see `EventType::as_id()`.
+    handlers: RwLock<HashMap<i32, Vec<EventHandler>>>,
+    log: Mutex<Option<EventLog>>,
+    overflow_policy: RwLock<EventOverflowPolicy>,
+    /// Number of events dropped because the queue was full.
+    dropped: AtomicUsize,
+}
+
+impl fmt::Debug for Events {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Events")
+            .field("receiver", &self.receiver)
+            .field("sender", &self.sender)
+            .field(
+                "log",
+                &self
+                    .log
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner())
+                    .is_some(),
+            )
+            .field("dropped", &self.dropped)
+            .finish()
+    }
 }
 
 impl Default for Events {
     fn default() -> Self {
-        let (sender, receiver) = channel(1_000);
-
-        Self { receiver, sender }
+        let (sender, receiver) = channel(EVENT_QUEUE_CAPACITY);
+
+        Self {
+            receiver,
+            sender,
+            handlers: Default::default(),
+            log: Default::default(),
+            overflow_policy: Default::default(),
+            dropped: AtomicUsize::new(0),
+        }
     }
 }
 
 impl Events {
     pub fn emit(&self, event: Event) {
-        match self.sender.try_send(event) {
-            Ok(()) => {}
-            Err(TrySendError::Full(event)) => {
-                // when we are full, we pop remove the oldest event and push on the new one
-                let _ = self.receiver.try_recv();
+        if let Some(log) = self
+            .log
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .as_mut()
+        {
+            // Logging must not fail emitting, the log is for debugging only.
+            log.write(&event).ok();
+        }
 
-                // try again
-                self.emit(event);
+        if let Some(handlers) = self
+            .handlers
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .get(&event.as_id())
+        {
+            for handler in handlers {
+                handler(event.clone());
             }
+        }
+
+        self.send(event);
+    }
+
+    fn send(&self, event: Event) {
+        match self.sender.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(event)) => match self.overflow_policy() {
+                EventOverflowPolicy::DropOldest => {
+                    // when we are full, we pop remove the oldest event and push on the new one
+                    if self.receiver.try_recv().is_ok() {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+
+                    // try again
+                    self.send(event);
+                }
+                EventOverflowPolicy::DropNewest => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            },
             Err(TrySendError::Disconnected(_)) => {
                 unreachable!("unable to emit event, channel disconnected");
             }
         }
     }
 
+    pub fn overflow_policy(&self) -> EventOverflowPolicy {
+        *self
+            .overflow_policy
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub fn set_overflow_policy(&self, policy: EventOverflowPolicy) {
+        *self
+            .overflow_policy
+            .write()
+            .unwrap_or_else(|err| err.into_inner()) = policy;
+    }
+
+    /// Returns the number of events dropped because no consumer
+    /// received them fast enough.
+    pub fn dropped_count(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
     /// Retrieve the event emitter.
     pub fn get_emitter(&self) -> EventEmitter {
         EventEmitter(self.receiver.clone())
     }
+
+    /// Registers a handler called for each event with the given id.
+    ///
+    /// Handlers are called synchronously when the event is emitted,
+    /// in addition to the event being queued for the `EventEmitter`.
+    /// They should return quickly and must not register other handlers.
+    pub fn on_event_type(&self, id: i32, handler: impl Fn(Event) + Send + Sync + 'static) {
+        self.handlers
+            .write()
+            .unwrap_or_else(|err| err.into_inner())
+            .entry(id)
+            .or_default()
+            .push(Box::new(handler));
+    }
+
+    /// Starts writing all emitted events to the file at `path` as
+    /// newline-delimited JSON, replacing any previous log.
+    ///
+    /// Occurrences of `secrets` are redacted.
+    pub fn start_log(&self, path: &std::path::Path, secrets: Vec<String>) -> io::Result<()> {
+        let file = File::create(path)?;
+        let secrets = secrets.into_iter().filter(|s| !s.is_empty()).collect();
+        let log = EventLog { file, secrets };
+        *self.log.lock().unwrap_or_else(|err| err.into_inner()) = Some(log);
+        Ok(())
+    }
+
+    /// Stops writing events started with `Events::start_log()`.
+    pub fn stop_log(&self) {
+        self.log
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .take();
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -212,6 +385,18 @@ pub enum EventType {
     #[strum(props(id = "2015"))]
     MsgRead { chat_id: ChatId, msg_id: MsgId },
 
+    /// A single message was deleted locally because its ephemeral timer
+    /// expired or because of the `delete_device_after` setting.
+    /// The message is moved to the trash chat, chat_id is the chat
+    /// the message was shown in.
+    #[strum(props(id = "2016"))]
+    MsgDeleted { chat_id: ChatId, msg_id: MsgId },
+
+    /// A message was moved to the mvbox folder on the server.
+    /// from_folder is the folder the message was moved from.
+    #[strum(props(id = "2017"))]
+    MsgMovedToMvbox { msg_id: MsgId, from_folder: String },
+
     /// Chat changed.  The name or the image of a chat group was changed or members were added or removed.
     /// Or the verify state of a chat has changed.
     /// See dc_set_chat_name(), dc_set_chat_profile_image(), dc_add_contact_to_chat()
@@ -255,6 +440,14 @@ pub enum EventType {
         comment: Option<String>,
     },
 
+    /// The account became configured, e.g. by a successful configure(),
+    /// or lost its configuration, e.g. by reset_configuration().
+    #[strum(props(id = "2042"))]
+    AccountConfiguredChanged {
+        /// Whether the account is configured now.
+        configured: bool,
+    },
+
     /// Inform about the import/export progress started by imex().
     ///
     /// @param data1 (usize) 0=error, 1-999=progress in permille, 1000=success and done
@@ -272,6 +465,17 @@ pub enum EventType {
     #[strum(props(id = "2052"))]
     ImexFileWritten(PathBuf),
 
+    /// Inform about the progress of `Accounts::export_all()` and
+    /// `Accounts::import_all()` across all accounts.
+    #[strum(props(id = "2053"))]
+    AccountsImexProgress {
+        /// 0=error, 1-999=progress in permille, 1000=success and done
+        progress: usize,
+
+        /// The account processed last.
+        account_id: u32,
+    },
+
     /// Progress information of a secure-join handshake from the view of the inviter
     /// (Alice, the person who shows the QR code).
     ///
@@ -297,4 +501,67 @@ pub enum EventType {
     ///     (Bob has verified alice and waits until Alice does the same for him)
     #[strum(props(id = "2061"))]
     SecurejoinJoinerProgress { contact_id: u32, progress: usize },
+
+    /// Connectivity to the IMAP server changed, see `Context::get_connectivity()`.
+    ///
+    /// @param data1 (int) New connectivity as DC_CONNECTIVITY_*.
+    #[strum(props(id = "2100"))]
+    ConnectivityChanged(Connectivity),
+}
+
+/// Connectivity of the IO scheduler to the IMAP server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum Connectivity {
+    /// Connecting to the server failed, IO waits for the network to come back.
+    NotConnected = 1000,
+
+    /// No connection attempt has finished yet.
+    Connecting = 2000,
+
+    /// Connected to the server, idle if there is nothing to do.
+    Connected = 4000,
+}
+
+impl Default for Connectivity {
+    fn default() -> Self {
+        Connectivity::Connecting
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(text: impl ToString) -> Event {
+        Event {
+            id: 0,
+            typ: EventType::Info(text.to_string()),
+        }
+    }
+
+    #[async_std::test]
+    async fn test_event_overflow() {
+        // Nobody receives events, like a stalled UI.
+        let events = Events::default();
+        for i in 0..EVENT_QUEUE_CAPACITY + 100 {
+            events.emit(info(i));
+        }
+        assert_eq!(events.dropped_count(), 100);
+
+        events.set_overflow_policy(EventOverflowPolicy::DropNewest);
+        for i in 0..10 {
+            events.emit(info(i));
+        }
+        assert_eq!(events.dropped_count(), 110);
+
+        // The oldest events were dropped first, later the newest.
+        let emitter = events.get_emitter();
+        assert_eq!(emitter.recv().await, Some(info(100)));
+        let mut received = 1;
+        while emitter.0.try_recv().is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, EVENT_QUEUE_CAPACITY);
+    }
 }
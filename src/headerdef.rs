@@ -35,6 +35,10 @@ pub enum HeaderDef {
     ChatDuration,
     ChatDispositionNotificationTo,
     ChatWebrtcRoom,
+
+    /// Message-ID of the message a delete request refers to
+    ChatDeleteRequest,
+
     Autocrypt,
     AutocryptSetupMessage,
     SecureJoin,
@@ -43,6 +47,10 @@ pub enum HeaderDef {
     SecureJoinInvitenumber,
     SecureJoinAuth,
     EphemeralTimer,
+
+    /// Marks `Ephemeral-Timer` as applying to the message only, not to the chat
+    ChatEphemeralTimerOverride,
+
     _TestHeader,
 }
 
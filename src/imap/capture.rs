@@ -0,0 +1,432 @@
+//! # Capturing and replaying IMAP traffic.
+//!
+//! Parsing bugs are often hard to reproduce, because they depend on the
+//! exact responses of a particular server. `CaptureStream` records all
+//! data sent and received on a connection to a file, and `ReplayStream`
+//! feeds the recorded responses back, so fetch logic can be re-run
+//! offline with `Client::replay()`.
+//!
+//! The file consists of records, each starting with a header line `C
+//! <length>` for data sent by the client or `S <length>` for data sent
+//! by the server, followed by the raw data. Client data containing
+//! credentials is recorded as a header line `R <length>` without data.
+
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_std::fs::File;
+use async_std::io::{self, Read, Write};
+
+use super::session::SessionStream;
+
+/// Direction of a captured record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Client,
+    Server,
+    /// Data sent by the client which is left out of the capture.
+    Redacted,
+}
+
+impl Direction {
+    fn tag(self) -> char {
+        match self {
+            Direction::Client => 'C',
+            Direction::Server => 'S',
+            Direction::Redacted => 'R',
+        }
+    }
+}
+
+/// Stream which records all data sent and received to a file.
+///
+/// Records are buffered in memory and written to the file whenever the
+/// connection is flushed, so reading and writing never block on the
+/// file. Responses read after the last flush are not written.
+#[derive(Debug)]
+pub(crate) struct CaptureStream {
+    inner: Box<dyn SessionStream>,
+    file: File,
+    /// Records not written to `file` yet.
+    pending: Vec<u8>,
+    /// Tag of an AUTHENTICATE command in progress. Client data is
+    /// redacted until the server completes the command.
+    authenticating: Option<String>,
+}
+
+impl CaptureStream {
+    pub fn new(inner: Box<dyn SessionStream>, file: File) -> Self {
+        CaptureStream {
+            inner,
+            file,
+            pending: Vec::new(),
+            authenticating: None,
+        }
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let direction = match direction {
+            Direction::Client if self.is_secret(data) => Direction::Redacted,
+            Direction::Server => {
+                if let Some(ref tag) = self.authenticating {
+                    let completed = String::from_utf8_lossy(data)
+                        .lines()
+                        .any(|line| line.starts_with(&format!("{} ", tag)));
+                    if completed {
+                        self.authenticating = None;
+                    }
+                }
+                direction
+            }
+            direction => direction,
+        };
+        self.pending
+            .extend_from_slice(format!("{} {}\n", direction.tag(), data.len()).as_bytes());
+        if direction != Direction::Redacted {
+            self.pending.extend_from_slice(data);
+        }
+    }
+
+    /// Returns whether client `data` contains credentials, i.e. is a
+    /// LOGIN command or belongs to an AUTHENTICATE exchange.
+    fn is_secret(&mut self, data: &[u8]) -> bool {
+        if self.authenticating.is_some() {
+            return true;
+        }
+        let line = String::from_utf8_lossy(data);
+        let mut words = line.split_whitespace();
+        let tag = words.next().unwrap_or_default();
+        match words.next().map(|command| command.to_ascii_uppercase()) {
+            Some(command) if command == "LOGIN" => true,
+            Some(command) if command == "AUTHENTICATE" => {
+                self.authenticating = Some(tag.to_string());
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Writes the pending records to the file.
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            match Pin::new(&mut self.file).poll_write(cx, &self.pending) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => {
+                    self.pending.drain(..n);
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Pin::new(&mut self.file).poll_flush(cx)
+    }
+}
+
+impl Read for CaptureStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(n)) => {
+                this.record(Direction::Server, &buf[..n]);
+                Poll::Ready(Ok(n))
+            }
+            res => res,
+        }
+    }
+}
+
+impl Write for CaptureStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(Ok(n)) => {
+                this.record(Direction::Client, &buf[..n]);
+                Poll::Ready(Ok(n))
+            }
+            res => res,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        match this.poll_write_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            res => res,
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        match this.poll_write_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_close(cx),
+            res => res,
+        }
+    }
+}
+
+impl SessionStream for CaptureStream {}
+
+/// Stream which plays back the server side of a captured connection.
+///
+/// Data written by the client is compared to the capture, so replay
+/// fails with an error as soon as the client behaves differently.
+/// Redacted data matches any data of the same length.
+#[derive(Debug)]
+pub(crate) struct ReplayStream {
+    /// Data sent by the client, concatenated.
+    client: Vec<u8>,
+    /// Whether the byte of `client` at the same position was redacted.
+    redacted: Vec<bool>,
+    client_pos: usize,
+    /// Data sent by the server, concatenated.
+    server: Vec<u8>,
+    server_pos: usize,
+}
+
+impl ReplayStream {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::parse(&std::fs::read(path)?)
+    }
+
+    fn parse(mut data: &[u8]) -> io::Result<Self> {
+        let mut stream = ReplayStream {
+            client: Vec::new(),
+            redacted: Vec::new(),
+            client_pos: 0,
+            server: Vec::new(),
+            server_pos: 0,
+        };
+        while !data.is_empty() {
+            let header_end = data
+                .iter()
+                .position(|b| *b == b'\n')
+                .ok_or_else(|| invalid_capture("truncated header"))?;
+            let header = std::str::from_utf8(&data[..header_end])
+                .map_err(|_| invalid_capture("invalid header"))?;
+            let (direction, len) = match header.split_at(header.find(' ').unwrap_or(0)) {
+                ("C", len) => (Direction::Client, len),
+                ("S", len) => (Direction::Server, len),
+                ("R", len) => (Direction::Redacted, len),
+                _ => return Err(invalid_capture("invalid header")),
+            };
+            let len: usize = len
+                .trim()
+                .parse()
+                .map_err(|_| invalid_capture("invalid length"))?;
+            if direction == Direction::Redacted {
+                stream.client.resize(stream.client.len() + len, 0);
+                stream.redacted.resize(stream.client.len(), true);
+                data = &data[header_end + 1..];
+                continue;
+            }
+            let record = data
+                .get(header_end + 1..header_end + 1 + len)
+                .ok_or_else(|| invalid_capture("truncated record"))?;
+            match direction {
+                Direction::Client => {
+                    stream.client.extend_from_slice(record);
+                    stream.redacted.resize(stream.client.len(), false);
+                }
+                _ => stream.server.extend_from_slice(record),
+            }
+            data = &data[header_end + 1 + len..];
+        }
+        Ok(stream)
+    }
+}
+
+fn invalid_capture(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("IMAP capture: {}", msg))
+}
+
+impl Read for ReplayStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let remaining = &this.server[this.server_pos..];
+        let n = std::cmp::min(remaining.len(), buf.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+        this.server_pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl Write for ReplayStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let range = this.client_pos..this.client_pos + buf.len();
+        let matches = match (this.client.get(range.clone()), this.redacted.get(range)) {
+            (Some(expected), Some(redacted)) => expected
+                .iter()
+                .zip(redacted)
+                .zip(buf)
+                .all(|((expected, redacted), actual)| *redacted || expected == actual),
+            _ => false,
+        };
+        if !matches {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "IMAP replay diverged at byte {}: {:?}",
+                    this.client_pos,
+                    String::from_utf8_lossy(buf)
+                ),
+            )));
+        }
+        this.client_pos += buf.len();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl SessionStream for ReplayStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_std::prelude::*;
+    use std::time::Duration;
+
+    use crate::imap::client::Client;
+    use crate::imap::session::Session;
+    use crate::test_utils::fake_imap_server;
+
+    /// Fetches all messages and returns their UIDs, flags and bodies.
+    async fn fetch_all(session: &mut Session) -> Vec<(Option<u32>, String, Vec<u8>)> {
+        session.select("INBOX").await.unwrap();
+        let mut fetched = Vec::new();
+        let mut list = session
+            .uid_fetch("1:*", "(FLAGS BODY.PEEK[])")
+            .await
+            .unwrap();
+        while let Some(fetch) = list.next().await {
+            let fetch = fetch.unwrap();
+            fetched.push((
+                fetch.uid,
+                format!("{:?}", fetch.flags().collect::<Vec<_>>()),
+                fetch.body().unwrap_or_default().to_vec(),
+            ));
+        }
+        fetched
+    }
+
+    #[async_std::test]
+    async fn test_capture_and_replay() {
+        let (port, server) = fake_imap_server(|line| {
+            if line.contains(" SELECT ") {
+                "* 1 EXISTS\r\n\
+                 * OK [UIDVALIDITY 7] UIDs valid\r\n"
+                    .to_string()
+            } else if line.contains(" UID FETCH ") {
+                "* 1 FETCH (UID 3 FLAGS (\\Seen) BODY[] {16}\r\n\
+                 Subject: hi\r\n\r\nx)\r\n"
+                    .to_string()
+            } else {
+                String::new()
+            }
+        })
+        .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("imap.capture");
+
+        let client = Client::connect_insecure("127.0.0.1", port, None, Duration::from_secs(10))
+            .await
+            .unwrap()
+            .capture(File::create(&path).await.unwrap());
+        let mut session = client
+            .login("alice", "secret")
+            .await
+            .map_err(|(err, _)| err)
+            .unwrap();
+        let captured = fetch_all(&mut session).await;
+        assert_eq!(captured.len(), 1);
+        assert_eq!(captured[0].0, Some(3));
+        session.logout().await.unwrap();
+        drop(session);
+
+        // The password is not captured.
+        let capture = std::fs::read(&path).unwrap();
+        assert!(!String::from_utf8_lossy(&capture).contains("secret"));
+        server.cancel().await;
+
+        // No server is needed for replaying.
+        let client = Client::replay(&path).unwrap();
+        let mut session = client
+            .login("alice", "secret")
+            .await
+            .map_err(|(err, _)| err)
+            .unwrap();
+        assert_eq!(fetch_all(&mut session).await, captured);
+    }
+
+    #[async_std::test]
+    async fn test_replay_diverged() {
+        let mut stream = ReplayStream::parse(b"S 22\n* OK IMAP4rev1 ready\r\nC 5\nA0001").unwrap();
+        let mut greeting = vec![0u8; 22];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, b"* OK IMAP4rev1 ready\r\n");
+        assert!(stream.write_all(b"B0001").await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_replay_redacted() {
+        let data = b"S 22\n* OK IMAP4rev1 ready\r\nR 24\nC 2\n\r\n";
+        let mut stream = ReplayStream::parse(data).unwrap();
+        stream.write_all(b"A0001 LOGIN alice secret").await.unwrap();
+        assert!(stream.write_all(b"\n\n").await.is_err());
+
+        let mut stream = ReplayStream::parse(data).unwrap();
+        stream.write_all(b"A0001 LOGIN alice other!").await.unwrap();
+        stream.write_all(b"\r\n").await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_capture_redacts_authenticate() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = File::create(dir.path().join("imap.capture")).await.unwrap();
+        let inner = Box::new(ReplayStream::parse(b"").unwrap());
+        let mut stream = CaptureStream::new(inner, file);
+
+        stream.record(Direction::Client, b"A0001 AUTHENTICATE XOAUTH2\r\n");
+        stream.record(Direction::Server, b"+ \r\n");
+        stream.record(Direction::Client, b"dXNlcj1hbGljZQ==\r\n");
+        stream.record(Direction::Server, b"A0001 OK done\r\n");
+        stream.record(Direction::Client, b"A0002 SELECT INBOX\r\n");
+        assert_eq!(
+            String::from_utf8_lossy(&stream.pending),
+            "C 28\nA0001 AUTHENTICATE XOAUTH2\r\n\
+             S 4\n+ \r\n\
+             R 18\n\
+             S 15\nA0001 OK done\r\n\
+             C 20\nA0002 SELECT INBOX\r\n"
+        );
+    }
+}
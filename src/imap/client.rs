@@ -1,16 +1,57 @@
+use std::future::Future;
 use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::time::Duration;
 
 use async_imap::{
     error::{Error as ImapError, Result as ImapResult},
+    imap_proto::types::{Capability, Response, Status},
     Client as ImapClient,
 };
-use async_std::net::{self, TcpStream};
+use async_std::fs::File;
+use async_std::future;
 
+use super::capture::{CaptureStream, ReplayStream};
+use super::compress::{CompressHandle, CompressStream};
 use super::session::Session;
 use crate::login_param::dc_build_tls;
+use crate::provider::Socket;
+use crate::socks::{connect_tcp, Socks5Config};
 
 use super::session::SessionStream;
 
+/// Error authenticating with `Client::login_oauth2_with_refresh()`.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum OAuth2Error {
+    #[error("IMAP could not get OAuth2 access token")]
+    NoToken,
+
+    /// The server rejected the access token, even after refreshing it.
+    #[error("IMAP OAuth2 access token rejected: {0}")]
+    TokenExpired(#[source] ImapError),
+
+    #[error("IMAP OAuth2 authentication failed: {0}")]
+    Imap(#[source] ImapError),
+}
+
+/// SASL XOAUTH2 authenticator.
+#[derive(Debug)]
+struct OAuth2 {
+    user: String,
+    access_token: String,
+}
+
+impl async_imap::Authenticator for OAuth2 {
+    type Response = String;
+
+    fn process(&mut self, _data: &[u8]) -> Self::Response {
+        format!(
+            "user={}\x01auth=Bearer {}\x01\x01",
+            self.user, self.access_token
+        )
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct Client {
     is_secure: bool,
@@ -75,21 +116,173 @@ impl Client {
         Ok(Session { inner: session })
     }
 
-    pub async fn connect_secure<A: net::ToSocketAddrs, S: AsRef<str>>(
-        addr: A,
-        domain: S,
+    /// Wraps the connection, so DEFLATE compression can be enabled
+    /// after login with `Session::compress()`.
+    pub fn compressible(self) -> (Self, CompressHandle) {
+        let Client { inner, is_secure } = self;
+        let (stream, handle) = CompressStream::new(inner.into_inner());
+        let stream: Box<dyn SessionStream> = Box::new(stream);
+        let client = Client {
+            is_secure,
+            inner: ImapClient::new(stream),
+        };
+        (client, handle)
+    }
+
+    /// Returns the SASL mechanisms advertised by the server with `AUTH=`
+    /// capabilities, e.g. `PLAIN`, `CRAM-MD5` or `XOAUTH2`.
+    ///
+    /// This sends a `CAPABILITY` command, so it should be called after
+    /// STARTTLS, as servers may advertise different mechanisms before.
+    pub async fn auth_mechanisms(&mut self) -> ImapResult<Vec<String>> {
+        self.inner.run_command("CAPABILITY").await?;
+
+        let mut mechanisms = Vec::new();
+        loop {
+            let response = self
+                .inner
+                .read_response()
+                .await
+                .ok_or_else(|| ImapError::Bad("connection closed".to_string()))??;
+            match response.parsed() {
+                Response::Capabilities(capabilities) => {
+                    for capability in capabilities {
+                        let mechanism = match capability {
+                            Capability::Auth(mechanism) => mechanism.to_uppercase(),
+                            _ => continue,
+                        };
+                        if !mechanisms.contains(&mechanism) {
+                            mechanisms.push(mechanism);
+                        }
+                    }
+                }
+                Response::Done {
+                    status,
+                    information,
+                    ..
+                } => {
+                    if *status != Status::Ok {
+                        return Err(ImapError::Bad(format!(
+                            "CAPABILITY failed: {:?}",
+                            information
+                        )));
+                    }
+                    break;
+                }
+                _ => {}
+            }
+        }
+        Ok(mechanisms)
+    }
+
+    /// Records all further traffic of the connection to `file`, so it
+    /// can be replayed with `Client::replay()`.
+    ///
+    /// Credentials sent with LOGIN or AUTHENTICATE are left out, but the
+    /// capture contains messages in plain text, so it must only be used
+    /// for debugging.
+    pub fn capture(self, file: File) -> Self {
+        let Client { inner, is_secure } = self;
+        let stream: Box<dyn SessionStream> = Box::new(CaptureStream::new(inner.into_inner(), file));
+        Client {
+            is_secure,
+            inner: ImapClient::new(stream),
+        }
+    }
+
+    /// Creates a client replaying the server responses captured with
+    /// `Client::capture()`, so a session can be reproduced offline.
+    ///
+    /// The greeting is read before capturing starts, so it is not
+    /// replayed. The client has to send exactly the same commands as
+    /// the captured one, otherwise writing fails.
+    pub fn replay(path: &Path) -> ImapResult<Self> {
+        let stream: Box<dyn SessionStream> = Box::new(ReplayStream::open(path)?);
+        Ok(Client {
+            is_secure: true,
+            inner: ImapClient::new(stream),
+        })
+    }
+
+    /// Authenticates with SASL XOAUTH2 using `access_token`.
+    pub async fn login_oauth2<U: AsRef<str>>(
+        self,
+        username: U,
+        access_token: String,
+    ) -> std::result::Result<Session, (ImapError, Self)> {
+        let auth = OAuth2 {
+            user: username.as_ref().to_string(),
+            access_token,
+        };
+        self.authenticate("XOAUTH2", auth).await
+    }
+
+    /// Authenticates with SASL XOAUTH2 using an access token returned
+    /// by `get_token`.
+    ///
+    /// The token is requested with `get_token(refresh)`. If a cached
+    /// token is rejected by the server, it is refreshed with
+    /// `get_token(true)` and authentication is retried once. A token
+    /// which was refreshed already is not refreshed again.
+    pub async fn login_oauth2_with_refresh<U, F, Fut>(
+        self,
+        username: U,
+        refresh: bool,
+        mut get_token: F,
+    ) -> std::result::Result<Session, (OAuth2Error, Self)>
+    where
+        U: AsRef<str>,
+        F: FnMut(bool) -> Fut,
+        Fut: Future<Output = Option<String>>,
+    {
+        let access_token = match get_token(refresh).await {
+            Some(access_token) => access_token,
+            None => return Err((OAuth2Error::NoToken, self)),
+        };
+        let client = match self.login_oauth2(&username, access_token).await {
+            Ok(session) => return Ok(session),
+            Err((err, client)) => match err {
+                ImapError::No(_) | ImapError::Bad(_) if !refresh => client,
+                ImapError::No(_) | ImapError::Bad(_) => {
+                    return Err((OAuth2Error::TokenExpired(err), client))
+                }
+                err => return Err((OAuth2Error::Imap(err), client)),
+            },
+        };
+
+        let access_token = match get_token(true).await {
+            Some(access_token) => access_token,
+            None => return Err((OAuth2Error::NoToken, client)),
+        };
+        client
+            .login_oauth2(&username, access_token)
+            .await
+            .map_err(|(err, client)| match err {
+                ImapError::No(_) | ImapError::Bad(_) => (OAuth2Error::TokenExpired(err), client),
+                err => (OAuth2Error::Imap(err), client),
+            })
+    }
+
+    /// Connects with implicit TLS and reads the server greeting.
+    ///
+    /// If `socks5` is set, the connection is made through the proxy,
+    /// while TLS is still negotiated with and verified for `host`.
+    ///
+    /// Connecting, including the TLS handshake, and reading the greeting
+    /// each fail if they take longer than `timeout`.
+    pub async fn connect_secure(
+        host: &str,
+        port: u16,
         strict_tls: bool,
+        socks5: Option<&Socks5Config>,
+        timeout: Duration,
     ) -> ImapResult<Self> {
-        let stream = TcpStream::connect(addr).await?;
-        let tls = dc_build_tls(strict_tls);
-        let tls_stream: Box<dyn SessionStream> =
-            Box::new(tls.connect(domain.as_ref(), stream).await?);
-        let mut client = ImapClient::new(tls_stream);
-
-        let _greeting = client
-            .read_response()
+        let connect = connect_tls(host, port, strict_tls, socks5);
+        let tls_stream = future::timeout(timeout, connect)
             .await
-            .ok_or_else(|| ImapError::Bad("failed to read greeting".to_string()))?;
+            .map_err(|_| ImapError::Bad("connect timed out".to_string()))??;
+        let mut client = ImapClient::new(tls_stream);
+        read_greeting(&mut client, timeout).await?;
 
         Ok(Client {
             is_secure: true,
@@ -97,14 +290,21 @@ impl Client {
         })
     }
 
-    pub async fn connect_insecure<A: net::ToSocketAddrs>(addr: A) -> ImapResult<Self> {
-        let stream: Box<dyn SessionStream> = Box::new(TcpStream::connect(addr).await?);
+    /// Connects without TLS and reads the server greeting, see
+    /// `Client::connect_secure()` for `socks5` and `timeout`.
+    pub async fn connect_insecure(
+        host: &str,
+        port: u16,
+        socks5: Option<&Socks5Config>,
+        timeout: Duration,
+    ) -> ImapResult<Self> {
+        let stream = future::timeout(timeout, connect_tcp(host, port, socks5))
+            .await
+            .map_err(|_| ImapError::Bad("connect timed out".to_string()))??;
+        let stream: Box<dyn SessionStream> = Box::new(stream);
 
         let mut client = ImapClient::new(stream);
-        let _greeting = client
-            .read_response()
-            .await
-            .ok_or_else(|| ImapError::Bad("failed to read greeting".to_string()))?;
+        read_greeting(&mut client, timeout).await?;
 
         Ok(Client {
             is_secure: false,
@@ -112,16 +312,31 @@ impl Client {
         })
     }
 
-    pub async fn secure<S: AsRef<str>>(self, domain: S, strict_tls: bool) -> ImapResult<Client> {
+    /// Upgrades the connection with STARTTLS.
+    ///
+    /// Fails if the STARTTLS command and the TLS handshake take longer
+    /// than `timeout`.
+    pub async fn secure<S: AsRef<str>>(
+        self,
+        domain: S,
+        strict_tls: bool,
+        timeout: Duration,
+    ) -> ImapResult<Client> {
         if self.is_secure {
             Ok(self)
         } else {
             let Client { mut inner, .. } = self;
             let tls = dc_build_tls(strict_tls);
-            inner.run_command_and_check_ok("STARTTLS", None).await?;
+            let starttls = async move {
+                inner.run_command_and_check_ok("STARTTLS", None).await?;
 
-            let stream = inner.into_inner();
-            let ssl_stream = tls.connect(domain.as_ref(), stream).await?;
+                let stream = inner.into_inner();
+                let ssl_stream = tls.connect(domain.as_ref(), stream).await?;
+                Ok::<_, ImapError>(ssl_stream)
+            };
+            let ssl_stream = future::timeout(timeout, starttls)
+                .await
+                .map_err(|_| ImapError::Bad("STARTTLS timed out".to_string()))??;
             let boxed: Box<dyn SessionStream> = Box::new(ssl_stream);
 
             Ok(Client {
@@ -130,4 +345,307 @@ impl Client {
             })
         }
     }
+
+    /// Finds a working connection to `host` by probing `ports` in order.
+    ///
+    /// Each port is tried with implicit TLS first and STARTTLS second.
+    /// A combination is accepted when the server sends a greeting and
+    /// answers the `CAPABILITY` command over the secured connection.
+    pub async fn autoconfigure(
+        host: &str,
+        ports: &[u16],
+        strict_tls: bool,
+        socks5: Option<&Socks5Config>,
+        timeout: Duration,
+    ) -> ImapResult<(Socket, u16, Client)> {
+        probe_candidates(ports, move |socket, port| async move {
+            let mut client = match socket {
+                Socket::STARTTLS => {
+                    Client::connect_insecure(host, port, socks5, timeout)
+                        .await?
+                        .secure(host, strict_tls, timeout)
+                        .await?
+                }
+                _ => Client::connect_secure(host, port, strict_tls, socks5, timeout).await?,
+            };
+            client.run_command_and_check_ok("CAPABILITY", None).await?;
+            Ok(client)
+        })
+        .await
+    }
+}
+
+async fn connect_tls(
+    host: &str,
+    port: u16,
+    strict_tls: bool,
+    socks5: Option<&Socks5Config>,
+) -> ImapResult<Box<dyn SessionStream>> {
+    let stream = connect_tcp(host, port, socks5).await?;
+    let tls = dc_build_tls(strict_tls);
+    let tls_stream: Box<dyn SessionStream> = Box::new(tls.connect(host, stream).await?);
+    Ok(tls_stream)
+}
+
+async fn read_greeting(
+    client: &mut ImapClient<Box<dyn SessionStream>>,
+    timeout: Duration,
+) -> ImapResult<()> {
+    let _greeting = future::timeout(timeout, client.read_response())
+        .await
+        .map_err(|_| ImapError::Bad("greeting read timed out".to_string()))?
+        .ok_or_else(|| ImapError::Bad("failed to read greeting".to_string()))?;
+    Ok(())
+}
+
+/// Calls `probe` for each port with `Socket::SSL` and `Socket::STARTTLS`
+/// and returns the first successful result.
+async fn probe_candidates<T, F, Fut>(ports: &[u16], mut probe: F) -> ImapResult<(Socket, u16, T)>
+where
+    F: FnMut(Socket, u16) -> Fut,
+    Fut: Future<Output = ImapResult<T>>,
+{
+    let mut last_err = ImapError::Bad("no ports to try".to_string());
+    for port in ports {
+        for socket in &[Socket::SSL, Socket::STARTTLS] {
+            match probe(*socket, *port).await {
+                Ok(res) => return Ok((*socket, *port, res)),
+                Err(err) => last_err = err,
+            }
+        }
+    }
+    Err(last_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_imap::Authenticator;
+    use async_std::net::TcpListener;
+    use async_std::prelude::*;
+    use async_std::task;
+
+    use crate::socks::serve_socks5;
+    use crate::test_utils::fake_imap_server;
+
+    #[test]
+    fn test_oauth2_response() {
+        let mut auth = OAuth2 {
+            user: "alice@example.com".to_string(),
+            access_token: "token".to_string(),
+        };
+        assert_eq!(
+            auth.process(b""),
+            "user=alice@example.com\x01auth=Bearer token\x01\x01"
+        );
+    }
+
+    /// Logs in with XOAUTH2 on a server accepting only `valid_token`,
+    /// where cached tokens are "old-token" and refreshed ones
+    /// "new-token". Returns whether login succeeded and the arguments
+    /// `get_token` was called with.
+    async fn login_oauth2_refreshing(refresh: bool, valid_token: &str) -> (bool, Vec<bool>) {
+        let valid = base64::encode(
+            OAuth2 {
+                user: "alice".to_string(),
+                access_token: valid_token.to_string(),
+            }
+            .process(b""),
+        );
+        let (port, server) = fake_imap_server(move |line| {
+            if line.contains(" AUTHENTICATE ") || line == valid {
+                String::new()
+            } else {
+                "NO invalid token".to_string()
+            }
+        })
+        .await;
+
+        let client = Client::connect_insecure("127.0.0.1", port, None, Duration::from_secs(10))
+            .await
+            .unwrap();
+        let mut calls = Vec::new();
+        let res = client
+            .login_oauth2_with_refresh("alice", refresh, |regenerate| {
+                calls.push(regenerate);
+                let token = if regenerate { "new-token" } else { "old-token" };
+                async move { Some(token.to_string()) }
+            })
+            .await;
+        let logged_in = match res {
+            Ok(_) => true,
+            Err((OAuth2Error::TokenExpired(_), _)) => false,
+            Err((err, _)) => panic!("unexpected error: {}", err),
+        };
+        server.cancel().await;
+        (logged_in, calls)
+    }
+
+    #[async_std::test]
+    async fn test_login_oauth2_with_refresh() {
+        // The rejected cached token is refreshed once.
+        assert_eq!(
+            login_oauth2_refreshing(false, "new-token").await,
+            (true, vec![false, true])
+        );
+        assert_eq!(
+            login_oauth2_refreshing(false, "other-token").await,
+            (false, vec![false, true])
+        );
+
+        // A token refreshed already is not refreshed again.
+        assert_eq!(
+            login_oauth2_refreshing(true, "new-token").await,
+            (true, vec![true])
+        );
+        assert_eq!(
+            login_oauth2_refreshing(true, "other-token").await,
+            (false, vec![true])
+        );
+    }
+
+    #[async_std::test]
+    async fn test_greeting_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = task::spawn(async move {
+            // Accept, but never send a greeting.
+            let (stream, _) = listener.accept().await.unwrap();
+            task::sleep(Duration::from_secs(5)).await;
+            drop(stream);
+        });
+
+        let res =
+            Client::connect_insecure("127.0.0.1", port, None, Duration::from_millis(100)).await;
+        match res {
+            Err(ImapError::Bad(msg)) => assert_eq!(msg, "greeting read timed out"),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        server.cancel().await;
+    }
+
+    #[async_std::test]
+    async fn test_starttls_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = task::spawn(async move {
+            // Accept STARTTLS, but never complete the TLS handshake.
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut writer = stream.clone();
+            writer.write_all(b"* OK IMAP4rev1 ready\r\n").await.unwrap();
+            let mut lines = async_std::io::BufReader::new(stream).lines();
+            let line = lines.next().await.unwrap().unwrap();
+            let tag = line.split(' ').next().unwrap_or_default().to_string();
+            writer
+                .write_all(format!("{} OK begin TLS\r\n", tag).as_bytes())
+                .await
+                .unwrap();
+            task::sleep(Duration::from_secs(5)).await;
+        });
+
+        let client = Client::connect_insecure("127.0.0.1", port, None, Duration::from_secs(10))
+            .await
+            .unwrap();
+        let res = client
+            .secure("127.0.0.1", false, Duration::from_millis(100))
+            .await;
+        match res {
+            Err(ImapError::Bad(msg)) => assert_eq!(msg, "STARTTLS timed out"),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        server.cancel().await;
+    }
+
+    #[async_std::test]
+    async fn test_connect_through_socks5() {
+        let imap_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let imap_addr = imap_listener.local_addr().unwrap();
+        task::spawn(async move {
+            let (mut stream, _) = imap_listener.accept().await.unwrap();
+            stream.write_all(b"* OK IMAP ready\r\n").await.unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.ok();
+        });
+
+        let proxy_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks5 = Socks5Config {
+            host: "127.0.0.1".to_string(),
+            port: proxy_listener.local_addr().unwrap().port(),
+            user_password: Some(("alice".to_string(), "secret".to_string())),
+        };
+        let proxy = task::spawn(serve_socks5(proxy_listener, imap_addr));
+
+        // The host name is resolved by the proxy only.
+        let client = Client::connect_insecure(
+            "imap.example.org",
+            143,
+            Some(&socks5),
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap();
+        drop(client);
+
+        assert_eq!(proxy.await, "imap.example.org:143");
+    }
+
+    #[async_std::test]
+    async fn test_auth_mechanisms() {
+        let (port, server) = fake_imap_server(|_| {
+            "* CAPABILITY IMAP4rev1 IDLE AUTH=PLAIN AUTH=CRAM-MD5 AUTH=XOAUTH2 auth=plain\r\n"
+                .to_string()
+        })
+        .await;
+
+        let mut client = Client::connect_insecure("127.0.0.1", port, None, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.auth_mechanisms().await.unwrap(),
+            vec!["PLAIN", "CRAM-MD5", "XOAUTH2"]
+        );
+
+        drop(client);
+        server.cancel().await;
+    }
+
+    #[async_std::test]
+    async fn test_probe_candidates_starttls_only() {
+        let mut tried = Vec::new();
+        let res = probe_candidates(&[993, 143], |socket, port| {
+            tried.push((socket, port));
+            async move {
+                if socket == Socket::STARTTLS && port == 143 {
+                    Ok(())
+                } else {
+                    Err(ImapError::Bad("connection refused".to_string()))
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(res, (Socket::STARTTLS, 143, ()));
+        assert_eq!(
+            tried,
+            vec![
+                (Socket::SSL, 993),
+                (Socket::STARTTLS, 993),
+                (Socket::SSL, 143),
+                (Socket::STARTTLS, 143)
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_probe_candidates_none() {
+        let res = probe_candidates(&[993], |_, _| async {
+            Err::<(), _>(ImapError::Bad("connection refused".to_string()))
+        })
+        .await;
+        assert!(res.is_err());
+
+        let res = probe_candidates(&[], |_, _| async { Ok(()) }).await;
+        assert!(res.is_err());
+    }
 }
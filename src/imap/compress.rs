@@ -0,0 +1,379 @@
+//! # IMAP COMPRESS=DEFLATE extension.
+//!
+//! The extension is defined in [RFC 4978](https://tools.ietf.org/html/rfc4978).
+//! Compression is negotiated after login, so the connection is wrapped
+//! into a `CompressStream` beforehand and switched to raw DEFLATE once
+//! the server accepted the `COMPRESS DEFLATE` command.
+
+use std::fmt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use async_std::io::{self, Read, Write};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+
+use super::session::SessionStream;
+
+/// Size of the chunks read from the underlying stream.
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Size of the compressed output buffered before it is written to the
+/// underlying stream, even if not flushed.
+const MAX_WRITE_BUFFER: usize = 65536;
+
+/// Handle to switch a `CompressStream` to DEFLATE compression.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CompressHandle(Arc<AtomicBool>);
+
+impl CompressHandle {
+    /// Compresses all data read and written from now on.
+    pub fn enable(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Stream which is switched to DEFLATE compression with its
+/// `CompressHandle`.
+#[derive(Debug)]
+pub(crate) struct CompressStream {
+    inner: Box<dyn SessionStream>,
+    handle: CompressHandle,
+    deflate: Option<Deflate>,
+}
+
+/// Compression state, created when compression is enabled.
+struct Deflate {
+    compress: Compress,
+    decompress: Decompress,
+    /// Compressed data read from the underlying stream, starting at
+    /// `read_pos`.
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    /// Compressed data not written to the underlying stream yet.
+    write_buf: Vec<u8>,
+    /// Whether data was compressed since the last sync flush.
+    needs_flush: bool,
+}
+
+impl fmt::Debug for Deflate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Deflate")
+            .field("read_buf", &(self.read_buf.len() - self.read_pos))
+            .field("write_buf", &self.write_buf.len())
+            .finish()
+    }
+}
+
+impl Default for Deflate {
+    fn default() -> Self {
+        Deflate {
+            // RFC 4978 uses raw DEFLATE without zlib header.
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            read_buf: Vec::new(),
+            read_pos: 0,
+            write_buf: Vec::new(),
+            needs_flush: false,
+        }
+    }
+}
+
+impl CompressStream {
+    pub fn new(inner: Box<dyn SessionStream>) -> (Self, CompressHandle) {
+        let handle = CompressHandle::default();
+        let stream = CompressStream {
+            inner,
+            handle: handle.clone(),
+            deflate: None,
+        };
+        (stream, handle)
+    }
+
+    /// Returns the compression state if compression is enabled.
+    fn deflate(&mut self) -> Option<&mut Deflate> {
+        if self.deflate.is_none() && self.handle.is_enabled() {
+            self.deflate = Some(Deflate::default());
+        }
+        self.deflate.as_mut()
+    }
+
+    /// Writes the buffered compressed data to the underlying stream.
+    fn poll_write_buffered(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let CompressStream { inner, deflate, .. } = self;
+        if let Some(deflate) = deflate {
+            while !deflate.write_buf.is_empty() {
+                match Pin::new(&mut *inner).poll_write(cx, &deflate.write_buf) {
+                    Poll::Ready(Ok(0)) => {
+                        return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                    }
+                    Poll::Ready(Ok(n)) => {
+                        deflate.write_buf.drain(..n);
+                    }
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Deflate {
+    /// Compresses `input` into `write_buf`.
+    fn compress(&mut self, mut input: &[u8], flush: FlushCompress) -> io::Result<()> {
+        loop {
+            self.write_buf.reserve(input.len() + 64);
+            let before_in = self.compress.total_in();
+            let before_out = self.compress.total_out();
+            self.compress
+                .compress_vec(input, &mut self.write_buf, flush)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            let consumed = (self.compress.total_in() - before_in) as usize;
+            let produced = self.compress.total_out() - before_out;
+            input = &input[consumed..];
+
+            // The output is complete once the compressor does not fill
+            // the buffer anymore.
+            if input.is_empty() && self.write_buf.len() < self.write_buf.capacity() {
+                return Ok(());
+            }
+            if consumed == 0 && produced == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Decompresses buffered input into `buf`, returning the number of
+    /// bytes produced.
+    fn decompress(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let before_in = self.decompress.total_in();
+        let before_out = self.decompress.total_out();
+        self.decompress
+            .decompress(&self.read_buf[self.read_pos..], buf, FlushDecompress::None)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        self.read_pos += (self.decompress.total_in() - before_in) as usize;
+        Ok((self.decompress.total_out() - before_out) as usize)
+    }
+}
+
+impl Read for CompressStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.deflate().is_none() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let CompressStream { inner, deflate, .. } = this;
+        let deflate = deflate.as_mut().expect("compression enabled");
+        loop {
+            if deflate.read_pos < deflate.read_buf.len() {
+                let produced = deflate.decompress(buf)?;
+                if produced > 0 {
+                    return Poll::Ready(Ok(produced));
+                }
+            }
+
+            // More compressed data is needed.
+            deflate.read_buf.drain(..deflate.read_pos);
+            deflate.read_pos = 0;
+            let mut chunk = [0u8; READ_CHUNK_SIZE];
+            match Pin::new(&mut *inner).poll_read(cx, &mut chunk) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(n)) => deflate.read_buf.extend_from_slice(&chunk[..n]),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Write for CompressStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let buffered = match this.deflate() {
+            Some(deflate) => deflate.write_buf.len(),
+            None => return Pin::new(&mut this.inner).poll_write(cx, buf),
+        };
+        if buffered >= MAX_WRITE_BUFFER {
+            if let Poll::Ready(Err(err)) = this.poll_write_buffered(cx) {
+                return Poll::Ready(Err(err));
+            }
+        }
+
+        let deflate = this.deflate.as_mut().expect("compression enabled");
+        if deflate.write_buf.len() >= MAX_WRITE_BUFFER {
+            return Poll::Pending;
+        }
+        deflate.compress(buf, FlushCompress::None)?;
+        deflate.needs_flush = true;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if let Some(deflate) = this.deflate() {
+            if deflate.needs_flush {
+                deflate.compress(&[], FlushCompress::Sync)?;
+                deflate.needs_flush = false;
+            }
+            match this.poll_write_buffered(cx) {
+                Poll::Ready(Ok(())) => {}
+                res => return res,
+            }
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.as_mut().poll_flush(cx) {
+            Poll::Ready(Ok(())) => {}
+            res => return res,
+        }
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+impl SessionStream for CompressStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::prelude::*;
+    use async_std::task;
+    use std::time::Duration;
+
+    use crate::imap::client::Client;
+
+    /// Reads a line terminated by CRLF, without the terminator.
+    async fn read_line(stream: &mut CompressStream) -> Option<String> {
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+        while !line.ends_with(b"\r\n") {
+            if stream.read(&mut byte).await.ok()? == 0 {
+                return None;
+            }
+            line.push(byte[0]);
+        }
+        line.truncate(line.len() - 2);
+        String::from_utf8(line).ok()
+    }
+
+    async fn write(stream: &mut CompressStream, response: String) {
+        stream.write_all(response.as_bytes()).await.unwrap();
+        stream.flush().await.unwrap();
+    }
+
+    /// Serves a single connection of a server supporting COMPRESS=DEFLATE.
+    async fn serve(stream: TcpStream) {
+        let (mut stream, handle) = CompressStream::new(Box::new(stream));
+        write(&mut stream, "* OK IMAP4rev1 ready\r\n".to_string()).await;
+        while let Some(line) = read_line(&mut stream).await {
+            let mut parts = line.splitn(2, ' ');
+            let tag = parts.next().unwrap_or_default().to_string();
+            let command = parts.next().unwrap_or_default().to_uppercase();
+            let response = if command == "CAPABILITY" {
+                format!(
+                    "* CAPABILITY IMAP4rev1 COMPRESS=DEFLATE\r\n{} OK done\r\n",
+                    tag
+                )
+            } else if command.starts_with("LOGIN") {
+                format!("{} OK logged in\r\n", tag)
+            } else if command == "COMPRESS DEFLATE" {
+                write(&mut stream, format!("{} OK compressing\r\n", tag)).await;
+                handle.enable();
+                continue;
+            } else if command.starts_with("SELECT") {
+                assert!(handle.is_enabled());
+                format!(
+                    "* 3 EXISTS\r\n\
+                     * OK [UIDVALIDITY 42] UIDs valid\r\n\
+                     {} OK [READ-WRITE] selected\r\n",
+                    tag
+                )
+            } else if command == "LOGOUT" {
+                write(&mut stream, format!("* BYE\r\n{} OK bye\r\n", tag)).await;
+                break;
+            } else {
+                format!("{} BAD unknown command\r\n", tag)
+            };
+            write(&mut stream, response).await;
+        }
+    }
+
+    #[async_std::test]
+    async fn test_compressed_select() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve(stream).await;
+        });
+
+        let client =
+            Client::connect_insecure("127.0.0.1", addr.port(), None, Duration::from_secs(10))
+                .await
+                .unwrap();
+        let (client, handle) = client.compressible();
+        let mut session = client
+            .login("alice", "secret")
+            .await
+            .map_err(|(err, _)| err)
+            .unwrap();
+        let caps = session.capabilities().await.unwrap();
+        assert!(caps.has_str("COMPRESS=DEFLATE"));
+        assert!(!handle.is_enabled());
+
+        session.compress(&handle).await.unwrap();
+        assert!(handle.is_enabled());
+
+        let mailbox = session.select("INBOX").await.unwrap();
+        assert_eq!(mailbox.exists, 3);
+        assert_eq!(mailbox.uid_validity, Some(42));
+
+        session.logout().await.unwrap();
+        server.await;
+    }
+
+    #[async_std::test]
+    async fn test_compress_roundtrip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+
+        let expected = data.clone();
+        let server = task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut stream, handle) = CompressStream::new(Box::new(stream));
+            handle.enable();
+            let mut received = vec![0u8; expected.len()];
+            stream.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, expected);
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut stream, handle) = CompressStream::new(Box::new(stream));
+        handle.enable();
+        stream.write_all(&data).await.unwrap();
+        stream.flush().await.unwrap();
+        server.await;
+    }
+}
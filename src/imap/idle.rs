@@ -1,15 +1,29 @@
 use super::Imap;
 
 use async_imap::extensions::idle::IdleResponse;
+use async_imap::imap_proto::types::{AttributeValue, Response};
 use async_imap::types::UnsolicitedResponse;
 use async_std::prelude::*;
-use std::time::{Duration, SystemTime};
+use std::cmp;
+use std::time::{Duration, Instant, SystemTime};
 
+use crate::config::Config;
 use crate::error::{bail, format_err, Result};
 use crate::{context::Context, scheduler::InterruptInfo};
 
 use super::session::Session;
 
+/// Lower bound for the IDLE timeout when it is adapted to the server.
+const MIN_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Upper bound for the IDLE timeout, servers may drop connections
+/// idling longer than 30 minutes according to RFC 2177.
+const MAX_IDLE_TIMEOUT: Duration = Duration::from_secs(29 * 60);
+
+/// Number of IDLEs in a row the server has to close early before the
+/// IDLE timeout is lowered, so a single network hiccup does not lower it.
+const IDLE_DROPS_BEFORE_LOWERING: u32 = 3;
+
 impl Imap {
     pub fn can_idle(&self) -> bool {
         self.config.can_idle
@@ -29,79 +43,246 @@ impl Imap {
 
         self.select_folder(context, watch_folder.clone()).await?;
 
-        let timeout = Duration::from_secs(23 * 60);
+        let timeout = self.idle_timeout(context).await;
         let mut info = Default::default();
 
-        if let Some(session) = self.session.take() {
-            // if we have unsolicited responses we directly return
-            let mut unsolicited_exists = false;
+        if self.session.is_some() {
+            let liveness_interval = Duration::from_secs(
+                context.get_config_int(Config::IdleLivenessInterval).await as u64,
+            );
+            let idle_start = Instant::now();
+            let deadline = idle_start + timeout;
+            let mut interrupted = false;
+            loop {
+                // if we have unsolicited responses we directly return,
+                // this includes responses received while ending the
+                // previous IDLE for the liveness check
+                if self
+                    .drain_unsolicited_responses(context, &watch_folder)
+                    .await
+                {
+                    return Ok(info);
+                }
+                let session = match self.session.take() {
+                    Some(session) => session,
+                    None => bail!("IMAP session lost before IDLE"),
+                };
+
+                let mut handle = session.idle();
+                if let Err(err) = handle.init().await {
+                    bail!("IMAP IDLE protocol failed to init/complete: {}", err);
+                }
+
+                let wait = idle_wait_timeout(deadline, liveness_interval, Instant::now());
+                let (idle_wait, interrupt) = handle.wait_with_timeout(wait);
+
+                enum Event {
+                    IdleResponse(IdleResponse),
+                    Interrupt(InterruptInfo),
+                }
+
+                info!(context, "Idle entering wait-on-remote state");
+                let fut = idle_wait.map(|ev| ev.map(Event::IdleResponse)).race(async {
+                    let probe_network = self.idle_interrupt.recv().await;
+
+                    // cancel imap idle connection properly
+                    drop(interrupt);
+
+                    Ok(Event::Interrupt(probe_network.unwrap_or_default()))
+                });
+
+                let mut check_liveness = false;
+                match fut.await {
+                    Ok(Event::IdleResponse(IdleResponse::NewData(x))) => {
+                        info!(context, "Idle has NewData {:?}", x);
+                    }
+                    Ok(Event::IdleResponse(IdleResponse::Timeout)) => {
+                        if Instant::now() < deadline {
+                            info!(context, "Idle checking that the connection is alive");
+                            check_liveness = true;
+                        } else {
+                            info!(context, "Idle-wait timeout or interruption");
+                            self.note_idle_completed();
+                        }
+                    }
+                    Ok(Event::IdleResponse(IdleResponse::ManualInterrupt)) => {
+                        info!(context, "Idle wait was interrupted");
+                    }
+                    Ok(Event::Interrupt(i)) => {
+                        info = i;
+                        interrupted = true;
+                        info!(context, "Idle wait was interrupted");
+                    }
+                    Err(err) => {
+                        warn!(context, "Idle wait errored: {:?}", err);
+                        self.note_idle_dropped(context, idle_start.elapsed(), timeout);
+                    }
+                }
+
+                // Ending IDLE needs a response from the server, so a dead
+                // connection results in an error and a reconnect.
+                let inner = handle
+                    .done()
+                    .timeout(Duration::from_secs(15))
+                    .await
+                    .map_err(|err| format_err!("IMAP IDLE protocol timed out: {}", err))??;
+                self.session = Some(Session { inner });
+
+                if !check_liveness {
+                    break;
+                }
+            }
+
+            if interrupted {
+                info = self.throttle_fetch(context, info).await;
+            }
+        } else {
+            warn!(context, "Attempted to idle without a session");
+        }
+
+        Ok(info)
+    }
+
+    /// Drains the unsolicited responses received so far.
+    ///
+    /// EXPUNGE responses and FETCH responses setting the `\\Seen` flag
+    /// trigger a reconciliation of the flags of `watch_folder`. Returns
+    /// true if an EXISTS response was received, i.e. there are new
+    /// messages to fetch.
+    async fn drain_unsolicited_responses(
+        &mut self,
+        context: &Context,
+        watch_folder: &Option<String>,
+    ) -> bool {
+        let mut unsolicited_exists = false;
+        let mut expunged = false;
+        let mut flags_changed = false;
+        if let Some(ref session) = self.session {
             while let Ok(response) = session.unsolicited_responses.try_recv() {
                 match response {
                     UnsolicitedResponse::Exists(_) => {
                         warn!(context, "skip idle, got unsolicited EXISTS {:?}", response);
                         unsolicited_exists = true;
                     }
+                    UnsolicitedResponse::Expunge(_) => {
+                        info!(context, "got unsolicited EXPUNGE {:?}", response);
+                        expunged = true;
+                    }
+                    UnsolicitedResponse::Other(ref data) if is_seen_fetch(data.parsed()) => {
+                        info!(context, "got unsolicited FETCH {:?}", response);
+                        flags_changed = true;
+                    }
                     _ => info!(context, "ignoring unsolicited response {:?}", response),
                 }
             }
+        }
 
-            if unsolicited_exists {
-                self.session = Some(session);
-                return Ok(info);
-            }
-
-            let mut handle = session.idle();
-            if let Err(err) = handle.init().await {
-                bail!("IMAP IDLE protocol failed to init/complete: {}", err);
+        if expunged || flags_changed {
+            if let Some(ref folder) = watch_folder {
+                self.reconcile_flags(context, folder, expunged).await;
             }
+        }
+        unsolicited_exists
+    }
 
-            let (idle_wait, interrupt) = handle.wait_with_timeout(timeout);
+    /// Returns how long to stay in IDLE before refreshing it.
+    ///
+    /// This is `Config::ImapIdleTimeout`, lowered if the server
+    /// advertised a shorter limit or closed the connection earlier.
+    async fn idle_timeout(&self, context: &Context) -> Duration {
+        let configured = Duration::from_secs(cmp::max(
+            context.get_config_int(Config::ImapIdleTimeout).await,
+            0,
+        ) as u64);
+        let timeout =
+            effective_idle_timeout(configured, self.config.idle_limit, self.idle_dropped_after);
+        info!(
+            context,
+            "IDLE timeout is {}s (configured {}s, server limit {:?}, dropped after {:?})",
+            timeout.as_secs(),
+            configured.as_secs(),
+            self.config.idle_limit,
+            self.idle_dropped_after
+        );
+        timeout
+    }
 
-            enum Event {
-                IdleResponse(IdleResponse),
-                Interrupt(InterruptInfo),
-            }
+    /// Remembers that the server closed the connection `elapsed` after
+    /// entering IDLE. After `IDLE_DROPS_BEFORE_LOWERING` such IDLEs in a
+    /// row, the next IDLE is refreshed earlier.
+    fn note_idle_dropped(&mut self, context: &Context, elapsed: Duration, timeout: Duration) {
+        if elapsed < MIN_IDLE_TIMEOUT || elapsed >= timeout {
+            return;
+        }
+        self.idle_early_drops += 1;
+        if self.idle_early_drops < IDLE_DROPS_BEFORE_LOWERING {
+            info!(
+                context,
+                "IDLE connection closed after {}s, {} times in a row",
+                elapsed.as_secs(),
+                self.idle_early_drops
+            );
+            return;
+        }
+        let dropped_after = match self.idle_dropped_after {
+            Some(previous) => cmp::min(previous, elapsed),
+            None => elapsed,
+        };
+        warn!(
+            context,
+            "IDLE connection closed after {}s, lowering IDLE timeout",
+            elapsed.as_secs()
+        );
+        self.idle_dropped_after = Some(dropped_after);
+    }
 
-            info!(context, "Idle entering wait-on-remote state");
-            let fut = idle_wait.map(|ev| ev.map(Event::IdleResponse)).race(async {
-                let probe_network = self.idle_interrupt.recv().await;
+    /// Remembers that an IDLE lasted until its timeout, so earlier
+    /// drops were not caused by the server closing IDLE connections.
+    fn note_idle_completed(&mut self) {
+        self.idle_early_drops = 0;
+    }
 
-                // cancel imap idle connection properly
-                drop(interrupt);
+    /// Defers the next fetch until `Config::MinFetchInterval` has passed
+    /// since the last fetch.
+    ///
+    /// Interrupts arriving in the meantime are coalesced into a single
+    /// fetch. Interrupts signalling that the network is back, asking
+    /// to process a specific message or triggered by the user end the
+    /// wait immediately.
+    pub(super) async fn throttle_fetch(
+        &mut self,
+        context: &Context,
+        info: InterruptInfo,
+    ) -> InterruptInfo {
+        if info.bypasses_throttle() {
+            return info;
+        }
 
-                Ok(Event::Interrupt(probe_network.unwrap_or_default()))
-            });
+        let min_interval =
+            Duration::from_secs(context.get_config_int(Config::MinFetchInterval).await as u64);
+        let delay = match fetch_delay(self.last_fetch, min_interval, Instant::now()) {
+            Some(delay) => delay,
+            None => return info,
+        };
+        info!(context, "Deferring fetch by {:?}", delay);
 
-            match fut.await {
-                Ok(Event::IdleResponse(IdleResponse::NewData(x))) => {
-                    info!(context, "Idle has NewData {:?}", x);
-                }
-                Ok(Event::IdleResponse(IdleResponse::Timeout)) => {
-                    info!(context, "Idle-wait timeout or interruption");
-                }
-                Ok(Event::IdleResponse(IdleResponse::ManualInterrupt)) => {
-                    info!(context, "Idle wait was interrupted");
-                }
-                Ok(Event::Interrupt(i)) => {
-                    info = i;
-                    info!(context, "Idle wait was interrupted");
-                }
-                Err(err) => {
-                    warn!(context, "Idle wait errored: {:?}", err);
+        let deadline = Instant::now() + delay;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                break;
+            }
+            match self.idle_interrupt.recv().timeout(remaining).await {
+                Ok(Ok(new_info)) => {
+                    if new_info.bypasses_throttle() {
+                        return new_info;
+                    }
+                    // Coalesce with the deferred fetch.
                 }
+                Ok(Err(_)) | Err(_) => break,
             }
-
-            let session = handle
-                .done()
-                .timeout(Duration::from_secs(15))
-                .await
-                .map_err(|err| format_err!("IMAP IDLE protocol timed out: {}", err))??;
-            self.session = Some(Session { inner: session });
-        } else {
-            warn!(context, "Attempted to idle without a session");
         }
-
-        Ok(info)
+        info
     }
 
     pub(crate) async fn fake_idle(
@@ -121,19 +302,20 @@ impl Imap {
         }
         info!(context, "IMAP-fake-IDLEing folder={:?}", watch_folder);
 
-        // check every minute if there are new messages
-        // TODO: grow sleep durations / make them more flexible
-        let mut interval = async_std::stream::interval(Duration::from_secs(60));
-
         enum Event {
             Tick,
             Interrupt(InterruptInfo),
         }
+        // Poll more and more rarely while nothing arrives, see
+        // `Config::FakeIdleMaxInterval`. The interval is reset when
+        // fake-IDLE is entered again after an interrupt or new messages.
+        let mut interval = context.fake_idle_interval().await;
+
         // loop until we are interrupted or if we fetched something
         let info = loop {
             use futures::future::FutureExt;
-            match interval
-                .next()
+
+            match async_std::task::sleep(interval)
                 .map(|_| Event::Tick)
                 .race(
                     self.idle_interrupt
@@ -143,6 +325,8 @@ impl Imap {
                 .await
             {
                 Event::Tick => {
+                    interval = context.next_fake_idle_interval(interval).await;
+
                     // try to connect with proper login params
                     // (setup_handle_if_needed might not know about them if we
                     // never successfully connected)
@@ -177,7 +361,7 @@ impl Imap {
                 }
                 Event::Interrupt(info) => {
                     // Interrupt
-                    break info;
+                    break self.throttle_fetch(context, info).await;
                 }
             }
         };
@@ -195,3 +379,404 @@ impl Imap {
         info
     }
 }
+
+/// Returns how long to wait in IDLE before ending it, either because
+/// `deadline` is reached or to check that the connection is alive
+/// every `liveness_interval`.
+///
+/// A zero `liveness_interval` disables the liveness check.
+fn idle_wait_timeout(deadline: Instant, liveness_interval: Duration, now: Instant) -> Duration {
+    let remaining = deadline.saturating_duration_since(now);
+    if liveness_interval == Duration::from_secs(0) {
+        remaining
+    } else {
+        cmp::min(remaining, liveness_interval)
+    }
+}
+
+/// Returns the IDLE timeout to use given the `configured` one, the
+/// `server_limit` advertised by the server and the IDLE duration after
+/// which the server was seen closing the connection.
+///
+/// Some margin is kept below the server's limits, but they never lower
+/// the timeout below `MIN_IDLE_TIMEOUT`. The configured timeout is kept
+/// between `MIN_IDLE_TIMEOUT` and `MAX_IDLE_TIMEOUT` as well.
+fn effective_idle_timeout(
+    configured: Duration,
+    server_limit: Option<Duration>,
+    dropped_after: Option<Duration>,
+) -> Duration {
+    let configured = cmp::min(cmp::max(configured, MIN_IDLE_TIMEOUT), MAX_IDLE_TIMEOUT);
+    server_limit
+        .into_iter()
+        .chain(dropped_after)
+        .map(|limit| cmp::max(limit * 4 / 5, MIN_IDLE_TIMEOUT))
+        .fold(configured, cmp::min)
+}
+
+/// Parses the non-standard `IDLE=<seconds>` capability some servers use
+/// to advertise how long they keep an idling connection open.
+pub(super) fn idle_limit_from_capabilities<'a>(
+    capabilities: impl Iterator<Item = &'a str>,
+) -> Option<Duration> {
+    capabilities
+        .filter_map(|cap| {
+            let (name, value) = cap.split_at(cap.find('=')?);
+            if name.eq_ignore_ascii_case("IDLE") {
+                value[1..].parse().ok().map(Duration::from_secs)
+            } else {
+                None
+            }
+        })
+        .min()
+}
+
+/// Returns true if `response` is a FETCH response setting the `\\Seen`
+/// flag, i.e. a message was read with another client.
+fn is_seen_fetch(response: &Response<'_>) -> bool {
+    match response {
+        Response::Fetch(_, attributes) => attributes.iter().any(|attribute| match attribute {
+            AttributeValue::Flags(flags) => flags.iter().any(|flag| *flag == "\\Seen"),
+            _ => false,
+        }),
+        _ => false,
+    }
+}
+
+/// Returns how long the next fetch has to be deferred to keep at least
+/// `min_interval` between fetches.
+fn fetch_delay(
+    last_fetch: Option<Instant>,
+    min_interval: Duration,
+    now: Instant,
+) -> Option<Duration> {
+    let last_fetch = last_fetch?;
+    let elapsed = now.saturating_duration_since(last_fetch);
+    if elapsed < min_interval {
+        Some(min_interval - elapsed)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_std::sync::{channel, Arc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use strum::EnumProperty;
+
+    use crate::imap::client::Client;
+    use crate::scheduler::InterruptReason;
+    use crate::test_utils::{fake_imap_server, TestContext};
+
+    #[test]
+    fn test_idle_wait_timeout() {
+        let now = Instant::now();
+        let deadline = now + Duration::from_secs(23 * 60);
+        let minute = Duration::from_secs(60);
+
+        // A dropped connection is noticed after the liveness interval
+        // instead of the full IDLE timeout.
+        assert_eq!(idle_wait_timeout(deadline, minute, now), minute);
+        assert_eq!(
+            idle_wait_timeout(deadline, minute, deadline - Duration::from_secs(10)),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            idle_wait_timeout(deadline, minute, deadline),
+            Duration::from_secs(0)
+        );
+
+        // The check can be disabled.
+        assert_eq!(
+            idle_wait_timeout(deadline, Duration::from_secs(0), now),
+            Duration::from_secs(23 * 60)
+        );
+    }
+
+    #[test]
+    fn test_effective_idle_timeout() {
+        let configured = Duration::from_secs(23 * 60);
+        assert_eq!(effective_idle_timeout(configured, None, None), configured);
+
+        // Server limits are respected with some margin.
+        assert_eq!(
+            effective_idle_timeout(configured, Some(Duration::from_secs(600)), None),
+            Duration::from_secs(480)
+        );
+        assert_eq!(
+            effective_idle_timeout(
+                configured,
+                Some(Duration::from_secs(600)),
+                Some(Duration::from_secs(300))
+            ),
+            Duration::from_secs(240)
+        );
+
+        // Higher limits and a lower configured value are kept as is.
+        assert_eq!(
+            effective_idle_timeout(configured, Some(Duration::from_secs(3600)), None),
+            configured
+        );
+        assert_eq!(
+            effective_idle_timeout(
+                Duration::from_secs(300),
+                Some(Duration::from_secs(600)),
+                None
+            ),
+            Duration::from_secs(300)
+        );
+
+        // The configured value is clamped as well.
+        assert_eq!(
+            effective_idle_timeout(Duration::from_secs(0), None, None),
+            MIN_IDLE_TIMEOUT
+        );
+        assert_eq!(
+            effective_idle_timeout(Duration::from_secs(u64::MAX), None, None),
+            MAX_IDLE_TIMEOUT
+        );
+
+        // Very short limits do not result in IDLE being refreshed all the time.
+        assert_eq!(
+            effective_idle_timeout(configured, None, Some(Duration::from_secs(10))),
+            MIN_IDLE_TIMEOUT
+        );
+    }
+
+    #[test]
+    fn test_idle_limit_from_capabilities() {
+        assert_eq!(
+            idle_limit_from_capabilities(vec!["IMAP4rev1", "IDLE", "MOVE"].into_iter()),
+            None
+        );
+        assert_eq!(
+            idle_limit_from_capabilities(vec!["IDLE", "IDLE=600"].into_iter()),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(
+            idle_limit_from_capabilities(vec!["idle=300", "COMPRESS=DEFLATE"].into_iter()),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(
+            idle_limit_from_capabilities(vec!["IDLE=forever"].into_iter()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_fetch_delay() {
+        let now = Instant::now();
+        let interval = Duration::from_secs(10);
+        assert_eq!(fetch_delay(None, interval, now), None);
+        assert_eq!(
+            fetch_delay(Some(now), interval, now + Duration::from_secs(4)),
+            Some(Duration::from_secs(6))
+        );
+        assert_eq!(
+            fetch_delay(Some(now), interval, now + Duration::from_secs(10)),
+            None
+        );
+        assert_eq!(fetch_delay(Some(now), Duration::from_secs(0), now), None);
+    }
+
+    #[async_std::test]
+    async fn test_note_idle_dropped() {
+        let t = TestContext::new().await;
+        let (_sender, receiver) = channel(1);
+        let mut imap = Imap::new(receiver);
+        let timeout = Duration::from_secs(23 * 60);
+        let elapsed = Duration::from_secs(5 * 60);
+
+        // Drops right after entering IDLE or at the timeout are ignored.
+        imap.note_idle_dropped(&t.ctx, Duration::from_secs(10), timeout);
+        imap.note_idle_dropped(&t.ctx, timeout, timeout);
+        assert_eq!(imap.idle_early_drops, 0);
+
+        // Single early drops do not lower the timeout.
+        for _ in 1..IDLE_DROPS_BEFORE_LOWERING {
+            imap.note_idle_dropped(&t.ctx, elapsed, timeout);
+        }
+        assert_eq!(imap.idle_dropped_after, None);
+
+        // The drops have to be in a row.
+        imap.note_idle_completed();
+        imap.note_idle_dropped(&t.ctx, elapsed, timeout);
+        assert_eq!(imap.idle_dropped_after, None);
+
+        for _ in 1..IDLE_DROPS_BEFORE_LOWERING {
+            imap.note_idle_dropped(&t.ctx, elapsed, timeout);
+        }
+        assert_eq!(imap.idle_dropped_after, Some(elapsed));
+    }
+
+    /// IDLEs for 3 seconds on a fake server with the given
+    /// `Config::IdleLivenessInterval` and returns the number of IDLE
+    /// commands sent.
+    async fn count_idle_commands(liveness_interval: Option<&str>) -> usize {
+        let t = TestContext::new().await;
+        t.ctx
+            .set_config(Config::IdleLivenessInterval, liveness_interval)
+            .await
+            .unwrap();
+
+        let idles = Arc::new(AtomicUsize::new(0));
+        let counter = idles.clone();
+        let (port, server) = fake_imap_server(move |line| {
+            if line.ends_with(" IDLE") {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            String::new()
+        })
+        .await;
+        let client = Client::connect_insecure("127.0.0.1", port, None, Duration::from_secs(10))
+            .await
+            .unwrap();
+        let session = client
+            .login("alice", "secret")
+            .await
+            .map_err(|(err, _)| err)
+            .unwrap();
+        let (sender, receiver) = channel(1);
+        let mut imap = Imap::new(receiver);
+        imap.session = Some(session);
+        imap.connected = true;
+        imap.config.lp.server = "127.0.0.1".to_string();
+        imap.config.can_idle = true;
+
+        let (res, _) = futures::join!(imap.idle(&t.ctx, Some("INBOX".to_string())), async {
+            async_std::task::sleep(Duration::from_secs(3)).await;
+            sender.send(InterruptInfo::default()).await;
+        });
+        res.unwrap();
+        drop(imap);
+        server.cancel().await;
+        idles.load(Ordering::SeqCst)
+    }
+
+    #[async_std::test]
+    async fn test_idle_liveness_check() {
+        // By default, the connection is checked once a minute.
+        assert_eq!(Config::IdleLivenessInterval.get_str("default"), Some("60"));
+        assert_eq!(count_idle_commands(None).await, 1);
+
+        // The check can be disabled.
+        assert_eq!(count_idle_commands(Some("0")).await, 1);
+
+        // IDLE is restarted every second to check the connection.
+        assert!(count_idle_commands(Some("1")).await >= 2);
+    }
+
+    #[async_std::test]
+    async fn test_idle_socket_closed() {
+        let t = TestContext::new().await;
+        t.ctx
+            .set_config(Config::ImapIdleTimeout, Some("600"))
+            .await
+            .unwrap();
+        t.ctx
+            .set_config(Config::IdleLivenessInterval, Some("2"))
+            .await
+            .unwrap();
+
+        let (port, server) = fake_imap_server(|_| String::new()).await;
+        let client = Client::connect_insecure("127.0.0.1", port, None, Duration::from_secs(10))
+            .await
+            .unwrap();
+        let session = client
+            .login("alice", "secret")
+            .await
+            .map_err(|(err, _)| err)
+            .unwrap();
+        let (_sender, receiver) = channel(1);
+        let mut imap = Imap::new(receiver);
+        imap.session = Some(session);
+        imap.connected = true;
+        imap.config.lp.server = "127.0.0.1".to_string();
+        imap.config.can_idle = true;
+
+        // The server closes the socket without BYE while idling, IDLE
+        // fails long before its timeout, so the caller reconnects.
+        let start = Instant::now();
+        let (res, _) = futures::join!(imap.idle(&t.ctx, Some("INBOX".to_string())), async {
+            async_std::task::sleep(Duration::from_secs(1)).await;
+            server.cancel().await;
+        });
+        assert!(res.is_err());
+        assert!(start.elapsed() < Duration::from_secs(20));
+    }
+
+    #[async_std::test]
+    async fn test_throttle_fetch() {
+        let t = TestContext::new().await;
+        t.ctx
+            .set_config(Config::MinFetchInterval, Some("1"))
+            .await
+            .unwrap();
+
+        let (sender, receiver) = channel(10);
+        let mut imap = Imap::new(receiver);
+
+        // No fetch happened yet, nothing to throttle.
+        let start = Instant::now();
+        imap.throttle_fetch(&t.ctx, InterruptInfo::default()).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Several rapid interrupts after a fetch result in a single
+        // deferred fetch.
+        imap.last_fetch = Some(Instant::now());
+        for _ in 0..5 {
+            sender.send(InterruptInfo::default()).await;
+        }
+        let start = Instant::now();
+        let mut fetches = 0;
+        while start.elapsed() < Duration::from_secs(1) {
+            let info = imap
+                .idle_interrupt
+                .recv()
+                .timeout(Duration::from_millis(100))
+                .await;
+            if let Ok(Ok(info)) = info {
+                imap.throttle_fetch(&t.ctx, info).await;
+                imap.last_fetch = Some(Instant::now());
+                fetches += 1;
+            }
+        }
+        assert_eq!(fetches, 1);
+        assert!(imap.idle_interrupt.is_empty());
+
+        // Network coming back bypasses the throttle.
+        imap.last_fetch = Some(Instant::now());
+        let start = Instant::now();
+        imap.throttle_fetch(&t.ctx, InterruptInfo::new(true, None))
+            .await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[async_std::test]
+    async fn test_interrupt_reason() {
+        let t = TestContext::new().await;
+        t.ctx
+            .set_config(Config::MinFetchInterval, Some("10"))
+            .await
+            .unwrap();
+
+        let (sender, receiver) = channel(10);
+        let mut imap = Imap::new(receiver);
+
+        sender
+            .send(InterruptInfo::new(false, None).with_reason(InterruptReason::UserRefresh))
+            .await;
+        let info = imap.fake_idle(&t.ctx, None).await;
+        assert_eq!(info.reason, InterruptReason::UserRefresh);
+
+        // Refreshing by the user is not throttled.
+        imap.last_fetch = Some(Instant::now());
+        let start = Instant::now();
+        let info = imap.throttle_fetch(&t.ctx, info).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(info.reason, InterruptReason::UserRefresh);
+    }
+}
@@ -3,7 +3,11 @@
 //! uses [async-email/async-imap](https://github.com/async-email/async-imap)
 //! to implement connect, fetch, delete functionality with standard IMAP servers.
 
-use std::{cmp, collections::BTreeMap};
+use std::{
+    cmp,
+    collections::{BTreeMap, BTreeSet},
+    time::{Duration, Instant},
+};
 
 use anyhow::Context as _;
 use async_imap::{
@@ -11,34 +15,41 @@ use async_imap::{
     types::{Capability, Fetch, Flag, Mailbox, Name, NameAttribute},
 };
 use async_std::prelude::*;
-use async_std::sync::Receiver;
+use async_std::sync::{Arc, Receiver, RwLock};
 use num_traits::FromPrimitive;
 
 use crate::constants::*;
-use crate::context::Context;
+use crate::context::{ConnectionStatus, Context};
 use crate::dc_receive_imf::{from_field_to_contact_id, is_msgrmsg_rfc724_mid_in_list};
+use crate::ephemeral;
 use crate::error::{bail, format_err, Result};
-use crate::events::EventType;
+use crate::events::{Connectivity, EventType};
 use crate::headerdef::{HeaderDef, HeaderDefMap};
 use crate::job::{self, Action};
-use crate::login_param::{CertificateChecks, LoginParam, ServerLoginParam};
-use crate::message::{self, update_server_uid, MessageState};
+use crate::login_param::{LoginParam, ServerLoginParam};
+use crate::message::{self, update_server_uid, MessageState, MsgId};
 use crate::mimeparser;
 use crate::oauth2::dc_get_oauth2_access_token;
 use crate::param::Params;
-use crate::provider::{get_provider_info, Socket};
+use crate::provider::Socket;
+use crate::socks::Socks5Config;
 use crate::{
-    chat, dc_tools::dc_extract_grpid_from_rfc724_mid, scheduler::InterruptInfo, stock::StockMessage,
+    chat,
+    dc_tools::{dc_extract_grpid_from_rfc724_mid, time, EmailAddress},
+    scheduler::InterruptInfo,
+    stock::StockMessage,
 };
 use crate::{config::*, dc_receive_imf::dc_receive_imf_inner};
 
+mod capture;
 mod client;
+mod compress;
 mod idle;
 pub mod select_folder;
 mod session;
 
 use chat::get_chat_id_by_grpid;
-use client::Client;
+use client::{Client, OAuth2Error};
 use mailparse::SingleInfo;
 use message::Message;
 use session::Session;
@@ -67,9 +78,21 @@ const PREFETCH_FLAGS: &str = "(UID BODY.PEEK[HEADER.FIELDS (\
 const DELETE_CHECK_FLAGS: &str = "(UID BODY.PEEK[HEADER.FIELDS (MESSAGE-ID)])";
 const RFC724MID_UID: &str = "(UID BODY.PEEK[HEADER.FIELDS (MESSAGE-ID)])";
 const JUST_UID: &str = "(UID)";
+const UID_FLAGS: &str = "(UID FLAGS)";
+
+/// Maximum number of messages pending deletion checked for existence
+/// on the server after each fetch.
+const VANISHED_CHECK_LIMIT: usize = 100;
+/// Maximum number of fresh messages whose flags are checked by
+/// `Imap::sync_seen_flags()`.
+const SEEN_SYNC_LIMIT: usize = 100;
 const BODY_FLAGS: &str = "(FLAGS BODY.PEEK[])";
 const CORE_FOLDER: &str = "MyJuttmy";
 
+/// Number of messages fetched with one command if
+/// `Config::FetchNewestFirst` is set.
+const FETCH_NEWEST_FIRST_CHUNK_SIZE: usize = 10;
+
 #[derive(Debug)]
 pub struct Imap {
     idle_interrupt: Receiver<InterruptInfo>,
@@ -79,29 +102,35 @@ pub struct Imap {
     interrupt: Option<stop_token::StopSource>,
     should_reconnect: bool,
     login_failed_once: bool,
-}
-
-#[derive(Debug)]
-struct OAuth2 {
-    user: String,
-    access_token: String,
-}
-
-impl async_imap::Authenticator for OAuth2 {
-    type Response = String;
-
-    fn process(&mut self, _data: &[u8]) -> Self::Response {
-        format!(
-            "user={}\x01auth=Bearer {}\x01\x01",
-            self.user, self.access_token
-        )
-    }
+    /// Time of the last fetch, used to throttle fetches.
+    last_fetch: Option<Instant>,
+    /// Number of failed connection attempts in a row.
+    connect_failures: u32,
+    /// Status shared with the scheduler, see `Context::scheduler_status()`.
+    status: Arc<RwLock<ConnectionStatus>>,
+    /// Connection cache generation at the time of connecting, see
+    /// `Context::clear_connection_caches()`.
+    cache_generation: u64,
+    /// Whether the OAuth2 access token was rejected and has to be
+    /// refreshed before the next login.
+    refresh_oauth2_token: bool,
+    /// Handle to enable compression of the current session.
+    compress: Option<CompressHandle>,
+    /// Shortest IDLE duration after which the server closed the
+    /// connection, see `Imap::idle_timeout()`.
+    idle_dropped_after: Option<Duration>,
+    /// Number of IDLEs in a row closed by the server before the timeout.
+    idle_early_drops: u32,
+    /// Folder watched by the connection, e.g. "inbox", used in capture
+    /// file names.
+    role: String,
 }
 
 #[derive(Debug, PartialEq)]
 enum FolderMeaning {
     Unknown,
     SentObjects,
+    Trash,
     Other,
 }
 
@@ -119,6 +148,14 @@ struct ImapConfig {
     /// True if the server has MOVE capability as defined in
     /// https://tools.ietf.org/html/rfc6851
     pub can_move: bool,
+
+    /// True if the server has COMPRESS=DEFLATE capability as defined in
+    /// https://tools.ietf.org/html/rfc4978
+    pub can_compress: bool,
+
+    /// Maximum IDLE duration advertised by the server with the
+    /// non-standard `IDLE=<seconds>` capability.
+    pub idle_limit: Option<Duration>,
 }
 
 impl Default for ImapConfig {
@@ -133,6 +170,8 @@ impl Default for ImapConfig {
             selected_folder_needs_expunge: false,
             can_idle: false,
             can_move: false,
+            can_compress: false,
+            idle_limit: None,
         }
     }
 }
@@ -147,6 +186,15 @@ impl Imap {
             interrupt: Default::default(),
             should_reconnect: Default::default(),
             login_failed_once: Default::default(),
+            last_fetch: None,
+            connect_failures: 0,
+            status: Default::default(),
+            cache_generation: 0,
+            refresh_oauth2_token: false,
+            compress: None,
+            idle_dropped_after: None,
+            idle_early_drops: 0,
+            role: "inbox".to_string(),
         }
     }
 
@@ -154,6 +202,22 @@ impl Imap {
         self.connected
     }
 
+    /// Sets the folder watched by the connection, see `Imap::role`.
+    pub(crate) fn set_role(&mut self, role: impl Into<String>) {
+        self.role = role.into();
+    }
+
+    /// Returns the file `Config::ImapCaptureFile` is written to by this
+    /// connection.
+    fn capture_path(&self, path: &str) -> String {
+        let role: String = self
+            .role
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        format!("{}.{}", path, role)
+    }
+
     pub fn should_reconnect(&self) -> bool {
         self.should_reconnect
     }
@@ -162,6 +226,66 @@ impl Imap {
         self.should_reconnect = true;
     }
 
+    /// Returns the number of failed `connect_configured()` calls since
+    /// the last successful one.
+    pub(crate) fn connect_failures(&self) -> u32 {
+        self.connect_failures
+    }
+
+    /// Forgets previous connection failures, so the next one is
+    /// retried without a long backoff.
+    pub(crate) fn reset_connect_failures(&mut self) {
+        self.connect_failures = 0;
+    }
+
+    /// Checks that `folder` can be selected before it is watched.
+    ///
+    /// Emits an error event and returns false if the folder is not
+    /// selectable, e.g. a container folder with the `\Noselect`
+    /// attribute. Returns true if the folder is selectable or the check
+    /// could not be done, in which case selecting the folder reports
+    /// errors as usual.
+    pub(crate) async fn verify_watch_folder(&mut self, context: &Context, folder: &str) -> bool {
+        let session = match self.session {
+            Some(ref mut session) => session,
+            None => return true,
+        };
+        match session.is_selectable(folder).await {
+            Ok(Some(false)) => {
+                emit_event!(
+                    context,
+                    EventType::Error(format!(
+                        "Cannot watch folder \"{}\": it is not selectable (\\Noselect), \
+                         please choose another folder",
+                        folder
+                    ))
+                );
+                false
+            }
+            Ok(Some(true)) => true,
+            Ok(None) => {
+                info!(context, "Folder {:?} to watch is not listed", folder);
+                true
+            }
+            Err(err) => {
+                warn!(
+                    context,
+                    "Cannot check if {:?} is selectable: {}", folder, err
+                );
+                true
+            }
+        }
+    }
+
+    /// Returns the status shared with the scheduler.
+    pub(crate) fn status(&self) -> Arc<RwLock<ConnectionStatus>> {
+        self.status.clone()
+    }
+
+    pub(crate) async fn set_status(&self, status: ConnectionStatus) {
+        *self.status.write().await = status;
+    }
+
     /// Connects or reconnects if needed.
     ///
     /// It is safe to call this function if already connected, actions
@@ -179,6 +303,8 @@ impl Imap {
         }
 
         let oauth2 = self.config.oauth2;
+        let timeout = connect_timeout(context).await;
+        let socks5 = Socks5Config::from_database(context).await?;
 
         let connection_res: ImapResult<Client> = if self.config.lp.security == Socket::STARTTLS
             || self.config.lp.security == Socket::Plain
@@ -187,10 +313,10 @@ impl Imap {
             let imap_server: &str = config.lp.server.as_ref();
             let imap_port = config.lp.port;
 
-            match Client::connect_insecure((imap_server, imap_port)).await {
+            match Client::connect_insecure(imap_server, imap_port, socks5.as_ref(), timeout).await {
                 Ok(client) => {
                     if config.lp.security == Socket::STARTTLS {
-                        client.secure(imap_server, config.strict_tls).await
+                        client.secure(imap_server, config.strict_tls, timeout).await
                     } else {
                         Ok(client)
                     }
@@ -202,31 +328,56 @@ impl Imap {
             let imap_server: &str = config.lp.server.as_ref();
             let imap_port = config.lp.port;
 
-            Client::connect_secure((imap_server, imap_port), imap_server, config.strict_tls).await
+            Client::connect_secure(
+                imap_server,
+                imap_port,
+                config.strict_tls,
+                socks5.as_ref(),
+                timeout,
+            )
+            .await
         };
 
-        let login_res = match connection_res {
+        let login_res: Result<Session> = match connection_res {
             Ok(client) => {
+                let (client, compress) = client.compressible();
+                self.compress = Some(compress);
+                let capture_path = context
+                    .get_config(Config::ImapCaptureFile)
+                    .await
+                    .map(|path| self.capture_path(&path));
+                let client = match capture_path {
+                    Some(path) => match async_std::fs::File::create(&path).await {
+                        Ok(file) => {
+                            warn!(context, "Capturing IMAP traffic to {}", path);
+                            client.capture(file)
+                        }
+                        Err(err) => {
+                            warn!(context, "Cannot capture IMAP traffic to {}: {}", path, err);
+                            client
+                        }
+                    },
+                    None => client,
+                };
                 let config = &self.config;
                 let imap_user: &str = config.lp.user.as_ref();
                 let imap_pw: &str = config.lp.password.as_ref();
 
                 if oauth2 {
                     let addr: &str = config.addr.as_ref();
-
-                    if let Some(token) =
-                        dc_get_oauth2_access_token(context, addr, imap_pw, true).await
-                    {
-                        let auth = OAuth2 {
-                            user: imap_user.into(),
-                            access_token: token,
-                        };
-                        client.authenticate("XOAUTH2", auth).await
-                    } else {
-                        bail!("IMAP Could not get OAUTH token");
-                    }
+                    let refresh = self.refresh_oauth2_token;
+
+                    client
+                        .login_oauth2_with_refresh(imap_user, refresh, |regenerate| {
+                            dc_get_oauth2_access_token(context, addr, imap_pw, regenerate)
+                        })
+                        .await
+                        .map_err(|(err, _)| err.into())
                 } else {
-                    client.login(imap_user, imap_pw).await
+                    client
+                        .login(imap_user, imap_pw)
+                        .await
+                        .map_err(|(err, _)| err.into())
                 }
             }
             Err(err) => {
@@ -242,10 +393,22 @@ impl Imap {
                 self.connected = true;
                 self.session = Some(session);
                 self.login_failed_once = false;
+                self.refresh_oauth2_token = false;
                 Ok(())
             }
 
-            Err((err, _)) => {
+            Err(err) => {
+                // The token was rejected even after refreshing it, this is
+                // reported as login failure. Still refresh it again before
+                // the next login.
+                let token_expired = matches!(
+                    err.downcast_ref::<OAuth2Error>(),
+                    Some(OAuth2Error::TokenExpired(_))
+                );
+                if token_expired {
+                    self.refresh_oauth2_token = true;
+                }
+
                 let imap_user = self.config.lp.user.to_owned();
                 let message = context
                     .stock_string_repl_str(StockMessage::CannotLogin, &imap_user)
@@ -275,6 +438,10 @@ impl Imap {
                 }
 
                 self.trigger_reconnect();
+                if token_expired {
+                    // Keep the error type, see `inner_connect_configured()`.
+                    return Err(err.context(message));
+                }
                 Err(format_err!("{}\n\n{}", message, err))
             }
         }
@@ -306,6 +473,7 @@ impl Imap {
             }
         }
         self.connected = false;
+        self.compress = None;
         self.config.selected_folder = None;
         self.config.selected_mailbox = None;
     }
@@ -318,15 +486,50 @@ impl Imap {
 
         cfg.can_idle = false;
         cfg.can_move = false;
+        cfg.can_compress = false;
+        cfg.idle_limit = None;
     }
 
     /// Connects to IMAP account using already-configured parameters.
     ///
-    /// Emits network error if connection fails.
+    /// Emits network error if connection fails. The result is reported
+    /// as connectivity, see `Context::get_connectivity()`.
     pub async fn connect_configured(&mut self, context: &Context) -> Result<()> {
+        let cache_generation = context.connection_cache_generation().await;
         if self.is_connected() && !self.should_reconnect() {
-            return Ok(());
+            if self.cache_generation == cache_generation {
+                context.count_connection(true).await;
+                return Ok(());
+            }
+            info!(context, "IMAP connection caches cleared, reconnecting");
+            self.trigger_reconnect();
+        }
+        self.cache_generation = cache_generation;
+        self.set_status(ConnectionStatus::Connecting).await;
+        let res = self.inner_connect_configured(context).await;
+        match res {
+            Ok(()) => {
+                self.connect_failures = 0;
+                context.count_connection(false).await;
+                self.set_status(ConnectionStatus::Connected).await;
+            }
+            Err(ref err) => {
+                self.connect_failures = self.connect_failures.saturating_add(1);
+                self.set_status(ConnectionStatus::Error(err.to_string()))
+                    .await;
+            }
         }
+        context
+            .set_connectivity(if res.is_ok() {
+                Connectivity::Connected
+            } else {
+                Connectivity::NotConnected
+            })
+            .await;
+        res
+    }
+
+    async fn inner_connect_configured(&mut self, context: &Context) -> Result<()> {
         if !context.is_configured().await {
             bail!("IMAP Connect without configured params");
         }
@@ -343,9 +546,22 @@ impl Imap {
             )
             .await
         {
+            if err.downcast_ref::<OAuth2Error>().is_some() {
+                // Keep the error type, so an expired token can be told
+                // apart from other failures.
+                return Err(err);
+            }
             bail!("IMAP Connection Failed with params {}: {}", param, err);
         } else {
-            self.ensure_configured_folders(context, true).await
+            self.ensure_configured_folders(context, true).await?;
+
+            // Folders may have been deleted by the user since configuration.
+            if let Some(ref mut session) = self.session {
+                if let Err(err) = session.ensure_configured_folders(context).await {
+                    warn!(context, "failed to recreate configured folders: {}", err);
+                }
+            }
+            Ok(())
         }
     }
 
@@ -370,15 +586,7 @@ impl Imap {
             let mut config = &mut self.config;
             config.addr = addr.to_string();
             config.lp = lp.clone();
-            let provider = get_provider_info(&addr);
-            config.strict_tls = match lp.certificate_checks {
-                CertificateChecks::Automatic => {
-                    provider.map_or(false, |provider| provider.strict_tls)
-                }
-                CertificateChecks::Strict => true,
-                CertificateChecks::AcceptInvalidCertificates
-                | CertificateChecks::AcceptInvalidCertificates2 => false,
-            };
+            config.strict_tls = lp.strict_tls(addr);
             config.oauth2 = oauth2;
         }
 
@@ -397,6 +605,14 @@ impl Imap {
                     } else {
                         let can_idle = caps.has_str("IDLE");
                         let can_move = caps.has_str("MOVE");
+                        let can_compress = caps.has_str("COMPRESS=DEFLATE");
+                        let idle_limit =
+                            idle::idle_limit_from_capabilities(caps.iter().filter_map(|cap| {
+                                match cap {
+                                    Capability::Atom(atom) => Some(&atom[..]),
+                                    _ => None,
+                                }
+                            }));
                         let caps_list = caps.iter().fold(String::new(), |s, c| {
                             if let Capability::Atom(x) = c {
                                 s + &format!(" {}", x)
@@ -407,6 +623,8 @@ impl Imap {
 
                         self.config.can_idle = can_idle;
                         self.config.can_move = can_move;
+                        self.config.can_compress = can_compress;
+                        self.config.idle_limit = idle_limit;
                         self.connected = true;
                         emit_event!(
                             context,
@@ -433,10 +651,26 @@ impl Imap {
                 context,
                 "IMAP disconnected immediately after connecting due to error"
             );
+        } else {
+            self.maybe_enable_compression(context).await;
         }
         Ok(())
     }
 
+    /// Enables compression if the server supports it, see
+    /// `Config::ImapCompression`.
+    async fn maybe_enable_compression(&mut self, context: &Context) {
+        if !self.config.can_compress || !context.get_config_bool(Config::ImapCompression).await {
+            return;
+        }
+        if let (Some(session), Some(compress)) = (&mut self.session, &self.compress) {
+            match session.compress(compress).await {
+                Ok(()) => info!(context, "IMAP compression enabled"),
+                Err(err) => warn!(context, "failed to enable IMAP compression: {}", err),
+            }
+        }
+    }
+
     pub async fn disconnect(&mut self, context: &Context) {
         self.unsetup_handle(context).await;
         self.free_connect_params().await;
@@ -448,6 +682,35 @@ impl Imap {
             bail!("IMAP operation attempted while it is torn down");
         }
         self.setup_handle(context).await?;
+        self.last_fetch = Some(Instant::now());
+
+        if let Some(ref mut session) = self.session {
+            let selected = match self.config.selected_folder {
+                Some(ref folder) if folder == watch_folder => self.config.selected_mailbox.as_ref(),
+                _ => None,
+            };
+            match session
+                .estimate_backlog(context, watch_folder, selected)
+                .await
+            {
+                Ok(0) => {}
+                Ok(backlog) => info!(
+                    context,
+                    "About {} new messages to fetch in {}.", backlog, watch_folder
+                ),
+                Err(err) => warn!(context, "Cannot estimate backlog: {}", err),
+            }
+        }
+
+        if let Some(last_fetch_time) = context.last_fetch_time(watch_folder).await {
+            let elapsed = time() - last_fetch_time;
+            if elapsed > STALE_FETCH_SECONDS {
+                warn!(
+                    context,
+                    "Folder {} was not fetched successfully for {} seconds.", watch_folder, elapsed
+                );
+            }
+        }
 
         while self
             .fetch_new_messages(context, &watch_folder, false)
@@ -455,9 +718,166 @@ impl Imap {
         {
             // We fetch until no more new messages are there.
         }
+        set_last_fetch_time(context, watch_folder).await;
+
+        if let Err(err) = self.forget_vanished_msgs(context, watch_folder).await {
+            warn!(
+                context,
+                "Cannot check for vanished messages in {}: {}", watch_folder, err
+            );
+        }
         Ok(())
     }
 
+    /// Forgets the UIDs of messages pending deletion in `folder` which
+    /// do not exist on the server anymore, e.g. because they were
+    /// deleted with another client, so no deletion job tries to delete
+    /// them again.
+    ///
+    /// The folder must be selected.
+    async fn forget_vanished_msgs(&mut self, context: &Context, folder: &str) -> Result<()> {
+        let uids =
+            ephemeral::load_imap_deletion_uids(context, folder, VANISHED_CHECK_LIMIT).await?;
+        if uids.is_empty() {
+            return Ok(());
+        }
+
+        let session = match self.session {
+            Some(ref mut session) => session,
+            None => bail!("not connected"),
+        };
+        let uid_set = uids
+            .iter()
+            .map(|uid| uid.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let mut existing = BTreeSet::new();
+        let mut list = session.uid_fetch(uid_set, JUST_UID).await?;
+        while let Some(fetch) = list.next().await {
+            if let Some(uid) = fetch?.uid {
+                existing.insert(uid);
+            }
+        }
+        drop(list);
+
+        let vanished: Vec<u32> = uids
+            .into_iter()
+            .filter(|uid| !existing.contains(uid))
+            .collect();
+        if !vanished.is_empty() {
+            info!(
+                context,
+                "{} messages pending deletion vanished from {}",
+                vanished.len(),
+                folder
+            );
+            ephemeral::forget_server_uids(context, folder, &vanished).await?;
+        }
+        Ok(())
+    }
+
+    /// Marks fresh messages in `folder` as seen if they were marked as
+    /// seen on the server, e.g. because they were read on another device.
+    ///
+    /// Only the flags of the newest fresh messages are fetched, so this
+    /// is cheap enough to run whenever the server notifies about
+    /// changed flags. The folder must be selected.
+    pub(crate) async fn sync_seen_flags(&mut self, context: &Context, folder: &str) -> Result<()> {
+        let fresh_msgs: BTreeMap<u32, (MsgId, chat::ChatId)> = context
+            .sql
+            .query_map(
+                "SELECT m.server_uid, m.id, m.chat_id \
+                 FROM msgs m LEFT JOIN chats c ON c.id=m.chat_id \
+                 WHERE m.server_folder=? AND m.server_uid!=0 AND m.state IN (?,?) \
+                 AND m.chat_id>? AND c.blocked=0 \
+                 ORDER BY m.server_uid DESC \
+                 LIMIT ?",
+                paramsv![
+                    folder,
+                    MessageState::InFresh,
+                    MessageState::InNoticed,
+                    DC_CHAT_ID_LAST_SPECIAL,
+                    SEEN_SYNC_LIMIT as i64
+                ],
+                |row| Ok((row.get::<_, u32>(0)?, (row.get(1)?, row.get(2)?))),
+                |rows| {
+                    rows.collect::<std::result::Result<BTreeMap<_, _>, _>>()
+                        .map_err(Into::into)
+                },
+            )
+            .await?;
+        if fresh_msgs.is_empty() {
+            return Ok(());
+        }
+
+        let session = match self.session {
+            Some(ref mut session) => session,
+            None => bail!("not connected"),
+        };
+        let uid_set = fresh_msgs
+            .keys()
+            .map(|uid| uid.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let mut seen = Vec::new();
+        let mut list = session.uid_fetch(uid_set, UID_FLAGS).await?;
+        while let Some(fetch) = list.next().await {
+            let fetch = fetch?;
+            if !fetch.flags().any(|flag| flag == Flag::Seen) {
+                continue;
+            }
+            if let Some(msg) = fetch.uid.and_then(|uid| fresh_msgs.get(&uid)) {
+                seen.push(*msg);
+            }
+        }
+        drop(list);
+
+        let mut updated_chat_ids = BTreeSet::new();
+        for (msg_id, chat_id) in seen {
+            if let Err(err) = msg_id.start_ephemeral_timer(context).await {
+                warn!(
+                    context,
+                    "Failed to start ephemeral timer for message {}: {}", msg_id, err
+                );
+            }
+            message::update_msg_state(context, msg_id, MessageState::InSeen).await;
+            updated_chat_ids.insert(chat_id);
+        }
+        if !updated_chat_ids.is_empty() {
+            info!(
+                context,
+                "Messages in {} were marked as seen on the server", folder
+            );
+        }
+        for chat_id in updated_chat_ids {
+            context.emit_event(EventType::MsgsNoticed(chat_id));
+        }
+        Ok(())
+    }
+
+    /// Reconciles the database with flag changes and expunges in
+    /// `folder` the server notified about outside of IDLE.
+    ///
+    /// The folder must be selected.
+    pub(crate) async fn reconcile_flags(
+        &mut self,
+        context: &Context,
+        folder: &str,
+        expunged: bool,
+    ) {
+        if expunged {
+            if let Err(err) = self.forget_vanished_msgs(context, folder).await {
+                warn!(
+                    context,
+                    "Cannot check for vanished messages in {}: {}", folder, err
+                );
+            }
+        }
+        if let Err(err) = self.sync_seen_flags(context, folder).await {
+            warn!(context, "Cannot sync seen flags in {}: {}", folder, err);
+        }
+    }
+
     /// Synchronizes UIDs in the database with UIDs on the server.
     ///
     /// It is assumed that no operations are taking place on the same
@@ -852,8 +1272,55 @@ impl Imap {
         server_uids: &[u32],
         fetching_existing_messages: bool,
     ) -> (Option<u32>, usize) {
+        let folder = folder.as_ref();
+        if !context.get_config_bool(Config::FetchNewestFirst).await {
+            let (last_uid, read_errors, _) = self
+                .fetch_msgs_chunk(
+                    context,
+                    folder,
+                    server_uids,
+                    fetching_existing_messages,
+                    false,
+                )
+                .await;
+            return (last_uid, read_errors);
+        }
+
+        let mut last_uid = None;
+        let mut read_errors = 0;
+        for chunk in newest_first_chunks(server_uids) {
+            let (chunk_last_uid, chunk_read_errors, complete) = self
+                .fetch_msgs_chunk(context, folder, chunk, fetching_existing_messages, true)
+                .await;
+            last_uid = last_uid.max(chunk_last_uid);
+            read_errors += chunk_read_errors;
+            if !complete {
+                // Messages with lower UIDs are not fetched yet, so the
+                // last seen UID must not be advanced past them.
+                return (None, read_errors);
+            }
+        }
+        (last_uid, read_errors)
+    }
+
+    /// Fetches and processes messages with the given sorted UIDs using
+    /// a single UID FETCH command.
+    ///
+    /// If `newest_first` is set, the messages are processed in reverse
+    /// order once all of them are downloaded.
+    ///
+    /// Returns the highest successfully processed UID, the number of
+    /// errors and whether all requested messages were received.
+    async fn fetch_msgs_chunk(
+        &mut self,
+        context: &Context,
+        folder: &str,
+        server_uids: &[u32],
+        fetching_existing_messages: bool,
+        newest_first: bool,
+    ) -> (Option<u32>, usize, bool) {
         let set = match server_uids {
-            [] => return (None, 0),
+            [] => return (None, 0, true),
             [server_uid] => server_uid.to_string(),
             [first_uid, .., last_uid] => {
                 // XXX: it is assumed that UIDs are sorted and
@@ -866,14 +1333,14 @@ impl Imap {
 
         if !self.is_connected() {
             warn!(context, "Not connected");
-            return (None, server_uids.len());
+            return (None, server_uids.len(), false);
         }
 
         if self.session.is_none() {
             // we could not get a valid imap session, this should be retried
             self.trigger_reconnect();
             warn!(context, "Could not get IMAP session");
-            return (None, server_uids.len());
+            return (None, server_uids.len(), false);
         }
 
         let session = self.session.as_mut().unwrap();
@@ -888,18 +1355,17 @@ impl Imap {
                     context,
                     "Error on fetching messages #{} from folder \"{}\"; error={}.",
                     &set,
-                    folder.as_ref(),
+                    folder,
                     err
                 );
-                return (None, server_uids.len());
+                return (None, server_uids.len(), false);
             }
         };
 
-        let folder = folder.as_ref().to_string();
-
         let mut read_errors = 0;
         let mut last_uid = None;
         let mut count = 0;
+        let mut fetched = Vec::new();
 
         while let Some(Ok(msg)) = msgs.next().await {
             let server_uid = msg.uid.unwrap_or_default();
@@ -910,39 +1376,34 @@ impl Imap {
             }
             count += 1;
 
-            let is_deleted = msg.flags().any(|flag| flag == Flag::Deleted);
-            if is_deleted || msg.body().is_none() {
-                // No need to process these.
+            if newest_first {
+                fetched.push(msg);
                 continue;
             }
+            match receive_fetched_msg(context, folder, &msg, fetching_existing_messages).await {
+                Ok(true) => last_uid = last_uid.max(Some(server_uid)),
+                Ok(false) => {}
+                Err(err) => {
+                    warn!(context, "dc_receive_imf error: {}", err);
+                    read_errors += 1;
+                }
+            }
+        }
 
-            // XXX put flags into a set and pass them to dc_receive_imf
-            let context = context.clone();
-            let folder = folder.clone();
-
-            // safe, as we checked above that there is a body.
-            let body = msg.body().unwrap();
-            let is_seen = msg.flags().any(|flag| flag == Flag::Seen);
-
-            match dc_receive_imf_inner(
-                &context,
-                &body,
-                &folder,
-                server_uid,
-                is_seen,
-                fetching_existing_messages,
-            )
-            .await
-            {
-                Ok(_) => last_uid = Some(server_uid),
+        for msg in fetched.iter().rev() {
+            let server_uid = msg.uid.unwrap_or_default();
+            match receive_fetched_msg(context, folder, msg, fetching_existing_messages).await {
+                Ok(true) => last_uid = last_uid.max(Some(server_uid)),
+                Ok(false) => {}
                 Err(err) => {
                     warn!(context, "dc_receive_imf error: {}", err);
                     read_errors += 1;
                 }
-            };
+            }
         }
 
-        if count != server_uids.len() {
+        let complete = count == server_uids.len();
+        if !complete {
             warn!(
                 context,
                 "failed to fetch all uids: got {}, requested {}",
@@ -951,7 +1412,7 @@ impl Imap {
             );
         }
 
-        (last_uid, read_errors)
+        (last_uid, read_errors, complete)
     }
 
     pub async fn can_move(&self) -> bool {
@@ -1160,6 +1621,51 @@ impl Imap {
         }
     }
 
+    /// Marks multiple messages in `folder` as seen.
+    ///
+    /// UIDs are sent in chunks to save round trips compared to
+    /// calling `set_seen()` for each message.
+    pub async fn set_seen_batch(
+        &mut self,
+        context: &Context,
+        folder: &str,
+        uids: &[u32],
+    ) -> ImapActionResult {
+        let first_uid = match uids.first() {
+            Some(uid) => *uid,
+            None => return ImapActionResult::AlreadyDone,
+        };
+        if let Some(imapresult) = self
+            .prepare_imap_operation_on_msg(context, folder, first_uid)
+            .await
+        {
+            return imapresult;
+        }
+        // we are connected, and the folder is selected
+        info!(
+            context,
+            "Marking {} messages in {} as seen...",
+            uids.len(),
+            folder
+        );
+
+        // See get_all_recipients() for the chunk size.
+        for uid_chunk in uids.chunks(100) {
+            let uid_set = uid_chunk
+                .iter()
+                .map(|uid| uid.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            if !self
+                .add_flag_finalized_with_set(context, &uid_set, "\\Seen")
+                .await
+            {
+                return ImapActionResult::RetryLater;
+            }
+        }
+        ImapActionResult::Success
+    }
+
     pub async fn delete_msg(
         &mut self,
         context: &Context,
@@ -1201,25 +1707,32 @@ impl Imap {
                         }
                     }
 
-                    if let Some(remote_message_id) = remote_message_id {
-                        if remote_message_id != message_id {
+                    match verify_delete_target(remote_message_id.as_deref(), message_id) {
+                        DeleteTarget::Matches => {}
+                        DeleteTarget::Mismatch => {
+                            // The stored UID points to another message,
+                            // e.g. because UIDVALIDITY has changed.
+                            // Resynchronize UIDs so the message is
+                            // deleted by its new UID on the next try.
                             warn!(
                                 context,
                                 "Cannot delete on IMAP, {}: remote message-id '{}' != '{}'",
                                 display_imap_id,
-                                remote_message_id,
+                                remote_message_id.unwrap_or_default(),
                                 message_id,
                             );
-                            return ImapActionResult::Failed;
+                            job::schedule_resync(context).await;
+                            return ImapActionResult::RetryLater;
+                        }
+                        DeleteTarget::Gone => {
+                            warn!(
+                                context,
+                                "Cannot delete on IMAP, {}: imap entry gone '{}'",
+                                display_imap_id,
+                                message_id,
+                            );
+                            return ImapActionResult::AlreadyDone;
                         }
-                    } else {
-                        warn!(
-                            context,
-                            "Cannot delete on IMAP, {}: imap entry gone '{}'",
-                            display_imap_id,
-                            message_id,
-                        );
-                        return ImapActionResult::AlreadyDone;
                     }
                 }
                 Err(err) => {
@@ -1252,6 +1765,145 @@ impl Imap {
         }
     }
 
+    /// Marks multiple messages in `folder` as deleted.
+    ///
+    /// `msgs` are pairs of server UID and Message-ID. As in
+    /// `delete_msg()`, the Message-IDs on the server are checked
+    /// first, but with a single UID FETCH and a single UID STORE per
+    /// chunk of UIDs.
+    ///
+    /// Returns the UIDs of the messages which are marked as deleted or
+    /// are already gone. Other messages should be retried later.
+    pub async fn delete_msgs(
+        &mut self,
+        context: &Context,
+        folder: &str,
+        msgs: &[(u32, String)],
+    ) -> Vec<u32> {
+        let mut done = Vec::new();
+        let first_uid = match msgs.first() {
+            Some((uid, _)) => *uid,
+            None => return done,
+        };
+        if self
+            .prepare_imap_operation_on_msg(context, folder, first_uid)
+            .await
+            .is_some()
+        {
+            return done;
+        }
+        // we are connected, and the folder is selected
+        info!(
+            context,
+            "Marking {} messages in {} as deleted...",
+            msgs.len(),
+            folder
+        );
+
+        // See get_all_recipients() for the chunk size.
+        for chunk in msgs.chunks(100) {
+            let uid_set = chunk
+                .iter()
+                .map(|(uid, _)| uid.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+
+            let mut remote_message_ids = BTreeMap::new();
+            if let Some(ref mut session) = &mut self.session {
+                match session.uid_fetch(&uid_set, DELETE_CHECK_FLAGS).await {
+                    Ok(mut responses) => {
+                        while let Some(response) = responses.next().await {
+                            match response {
+                                Ok(fetch) => {
+                                    let remote_message_id = get_fetch_headers(&fetch)
+                                        .and_then(|headers| prefetch_get_message_id(&headers));
+                                    if let (Some(uid), Ok(remote_message_id)) =
+                                        (fetch.uid, remote_message_id)
+                                    {
+                                        remote_message_ids.insert(uid, remote_message_id);
+                                    }
+                                }
+                                Err(err) => {
+                                    warn!(context, "IMAP fetch error {}", err);
+                                    return done;
+                                }
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        warn!(
+                            context,
+                            "Cannot delete on IMAP, {}/{}: {}", folder, uid_set, err
+                        );
+                        return done;
+                    }
+                }
+            }
+
+            let mut matching = Vec::new();
+            let mut mismatch = false;
+            for (uid, message_id) in chunk {
+                let remote_message_id = remote_message_ids.get(uid).map(|id| id.as_str());
+                match verify_delete_target(remote_message_id, message_id) {
+                    DeleteTarget::Matches => matching.push(*uid),
+                    DeleteTarget::Mismatch => {
+                        warn!(
+                            context,
+                            "Cannot delete on IMAP, {}/{}: remote message-id '{}' != '{}'",
+                            folder,
+                            uid,
+                            remote_message_id.unwrap_or_default(),
+                            message_id,
+                        );
+                        mismatch = true;
+                    }
+                    DeleteTarget::Gone => {
+                        warn!(
+                            context,
+                            "Cannot delete on IMAP, {}/{}: imap entry gone '{}'",
+                            folder,
+                            uid,
+                            message_id,
+                        );
+                        done.push(*uid);
+                    }
+                }
+            }
+            if mismatch {
+                job::schedule_resync(context).await;
+            }
+            if matching.is_empty() {
+                continue;
+            }
+
+            let uid_set = matching
+                .iter()
+                .map(|uid| uid.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            if !self
+                .add_flag_finalized_with_set(context, &uid_set, "\\Deleted")
+                .await
+            {
+                warn!(
+                    context,
+                    "Cannot mark messages {}/{} as \"Deleted\".", folder, uid_set
+                );
+                return done;
+            }
+            emit_event!(
+                context,
+                EventType::ImapMessageDeleted(format!(
+                    "IMAP Messages {}/{} marked as deleted",
+                    folder, uid_set
+                ))
+            );
+            self.config.selected_folder_needs_expunge = true;
+            done.extend(matching);
+        }
+        done
+    }
+
     pub async fn ensure_configured_folders(
         &mut self,
         context: &Context,
@@ -1284,6 +1936,7 @@ impl Imap {
             let mut delimiter = ".".to_string();
             let mut delimiter_is_default = true;
             let mut sentbox_folder = None;
+            let mut trash_folder = None;
             let mut mvbox_folder = None;
             let mut fallback_folder = get_fallback_folder(&delimiter);
 
@@ -1311,6 +1964,8 @@ impl Imap {
                 } else if let FolderMeaning::SentObjects = get_folder_meaning(&folder) {
                     // Always takes precedent
                     sentbox_folder = Some(folder.name().to_string());
+                } else if let FolderMeaning::Trash = get_folder_meaning(&folder) {
+                    trash_folder = Some(folder.name().to_string());
                 } else if let FolderMeaning::SentObjects =
                     get_folder_meaning_by_name(&folder.name())
                 {
@@ -1324,6 +1979,7 @@ impl Imap {
 
             info!(context, "Using \"{}\" as folder-delimiter.", delimiter);
             info!(context, "sentbox folder is {:?}", sentbox_folder);
+            info!(context, "trash folder is {:?}", trash_folder);
 
             if mvbox_folder.is_none() && create_mvbox {
                 info!(context, "Creating MVBOX-folder \"{}\"...", CORE_FOLDER);
@@ -1377,6 +2033,11 @@ impl Imap {
                     .set_config(Config::ConfiguredSentboxFolder, Some(sentbox_folder))
                     .await?;
             }
+            if let Some(ref trash_folder) = trash_folder {
+                context
+                    .set_config(Config::ConfiguredTrashFolder, Some(trash_folder))
+                    .await?;
+            }
             context
                 .sql
                 .set_raw_config_int(context, "folders_configured", DC_FOLDERS_CONFIGURED_VERSION)
@@ -1437,7 +2098,7 @@ fn get_folder_meaning_by_name(folder_name: &str) -> FolderMeaning {
 }
 
 fn get_folder_meaning(folder_name: &Name) -> FolderMeaning {
-    let special_names = vec!["\\Spam", "\\Trash", "\\Drafts", "\\Junk"];
+    let special_names = vec!["\\Spam", "\\Drafts", "\\Junk"];
 
     for attr in folder_name.attributes() {
         if let NameAttribute::Custom(ref label) = attr {
@@ -1445,6 +2106,8 @@ fn get_folder_meaning(folder_name: &Name) -> FolderMeaning {
                 return FolderMeaning::Other;
             } else if label == "\\Sent" {
                 return FolderMeaning::SentObjects;
+            } else if label == "\\Trash" {
+                return FolderMeaning::Trash;
             }
         }
     }
@@ -1691,6 +2354,156 @@ pub async fn set_config_last_seen_uid<S: AsRef<str>>(
         .ok();
 }
 
+/// Time in seconds after which a folder without successful fetch is
+/// reported as stale.
+const STALE_FETCH_SECONDS: i64 = 24 * 60 * 60;
+
+impl Context {
+    /// Connects to the IMAP server entered for configuration and
+    /// returns the SASL mechanisms it supports, e.g. `PLAIN`,
+    /// `CRAM-MD5` or `XOAUTH2`, so setup screens can hint which
+    /// authentication methods are available.
+    ///
+    /// If no server is entered, `imap.<domain>` is probed. The result is
+    /// cached per server, so this is cheap to call repeatedly.
+    pub async fn probe_auth_mechanisms(&self) -> Result<Vec<String>> {
+        let param = LoginParam::from_database(self, "").await;
+        let server = if param.imap.server.is_empty() {
+            let addr: EmailAddress = param.addr.parse().context("Bad email-address")?;
+            format!("imap.{}", addr.domain)
+        } else {
+            param.imap.server.clone()
+        };
+        let key = format!("{}:{}:{:?}", server, param.imap.port, param.imap.security);
+        if let Some(mechanisms) = self.auth_mechanisms.read().await.get(&key) {
+            return Ok(mechanisms.clone());
+        }
+
+        let strict_tls = param.imap.strict_tls(&param.addr);
+        let timeout = connect_timeout(self).await;
+        let socks5 = Socks5Config::from_database(self).await?;
+        let port = match (param.imap.port, param.imap.security) {
+            (0, Socket::SSL) => 993,
+            (0, Socket::STARTTLS) | (0, Socket::Plain) => 143,
+            (port, _) => port,
+        };
+        let mut client = match param.imap.security {
+            Socket::Automatic => {
+                let ports = if port == 0 {
+                    vec![993, 143]
+                } else {
+                    vec![port]
+                };
+                let (_, _, client) =
+                    Client::autoconfigure(&server, &ports, strict_tls, socks5.as_ref(), timeout)
+                        .await?;
+                client
+            }
+            Socket::SSL => {
+                Client::connect_secure(&server, port, strict_tls, socks5.as_ref(), timeout).await?
+            }
+            Socket::STARTTLS => {
+                Client::connect_insecure(&server, port, socks5.as_ref(), timeout)
+                    .await?
+                    .secure(&server, strict_tls, timeout)
+                    .await?
+            }
+            Socket::Plain => {
+                Client::connect_insecure(&server, port, socks5.as_ref(), timeout).await?
+            }
+        };
+        let mechanisms = client.auth_mechanisms().await?;
+        info!(self, "{} supports {:?}", server, mechanisms);
+
+        self.auth_mechanisms
+            .write()
+            .await
+            .insert(key, mechanisms.clone());
+        Ok(mechanisms)
+    }
+
+    /// Returns the time of the last successful fetch of `folder`.
+    pub async fn last_fetch_time(&self, folder: &str) -> Option<i64> {
+        self.sql
+            .get_raw_config_int64(self, format!("imap.last_fetch.{}", folder))
+            .await
+    }
+}
+
+async fn set_last_fetch_time(context: &Context, folder: &str) {
+    context
+        .sql
+        .set_raw_config_int64(context, format!("imap.last_fetch.{}", folder), time())
+        .await
+        .ok();
+}
+
+/// Passes a message downloaded by `fetch_msgs_chunk()` to `dc_receive_imf`.
+///
+/// Returns `Ok(false)` if the message is skipped because it is deleted
+/// or has no body.
+async fn receive_fetched_msg(
+    context: &Context,
+    folder: &str,
+    msg: &Fetch,
+    fetching_existing_messages: bool,
+) -> Result<bool> {
+    let is_deleted = msg.flags().any(|flag| flag == Flag::Deleted);
+    let body = match msg.body() {
+        Some(body) if !is_deleted => body,
+        _ => {
+            // No need to process these.
+            return Ok(false);
+        }
+    };
+
+    // XXX put flags into a set and pass them to dc_receive_imf
+    let is_seen = msg.flags().any(|flag| flag == Flag::Seen);
+    dc_receive_imf_inner(
+        context,
+        body,
+        folder,
+        msg.uid.unwrap_or_default(),
+        is_seen,
+        fetching_existing_messages,
+    )
+    .await?;
+    Ok(true)
+}
+
+/// Splits sorted UIDs into chunks for fetching newest messages
+/// first, starting with the chunk of the highest UIDs.
+fn newest_first_chunks(server_uids: &[u32]) -> impl Iterator<Item = &[u32]> {
+    server_uids.rchunks(FETCH_NEWEST_FIRST_CHUNK_SIZE)
+}
+
+/// Result of checking the message found at a UID before deleting it.
+#[derive(Debug, PartialEq, Eq)]
+enum DeleteTarget {
+    /// The UID points to the message that should be deleted.
+    Matches,
+    /// The UID points to another message.
+    Mismatch,
+    /// There is no message with this UID.
+    Gone,
+}
+
+/// Compares the Message-ID fetched for a UID to the expected one.
+fn verify_delete_target(remote_message_id: Option<&str>, message_id: &str) -> DeleteTarget {
+    match remote_message_id {
+        Some(remote_message_id) if remote_message_id == message_id => DeleteTarget::Matches,
+        Some(_) => DeleteTarget::Mismatch,
+        None => DeleteTarget::Gone,
+    }
+}
+
+/// Returns the timeout for connecting and reading the greeting,
+/// see `Config::ImapConnectTimeout`.
+async fn connect_timeout(context: &Context) -> Duration {
+    let secs = context.get_config_int(Config::ImapConnectTimeout).await;
+    Duration::from_secs(cmp::max(secs, 1) as u64)
+}
+
 async fn get_config_last_seen_uid<S: AsRef<str>>(context: &Context, folder: S) -> (u32, u32) {
     let key = format!("imap.mailbox.{}", folder.as_ref());
     if let Some(entry) = context.sql.get_raw_config(context, &key).await {
@@ -1708,6 +2521,256 @@ async fn get_config_last_seen_uid<S: AsRef<str>>(context: &Context, folder: S) -
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_utils::{fake_imap_server, TestContext};
+
+    #[async_std::test]
+    async fn test_clear_connection_caches() {
+        let t = TestContext::new().await;
+        let (_interrupt_sender, interrupt_receiver) = async_std::sync::channel(1);
+        let mut imap = Imap::new(interrupt_receiver);
+
+        // Pretend to be connected already.
+        imap.connected = true;
+        imap.connect_configured(&t.ctx).await.unwrap();
+        assert_eq!(t.ctx.connection_cache_stats().await.reused, 1);
+        assert_eq!(t.ctx.connection_cache_stats().await.connected, 0);
+
+        t.ctx.clear_connection_caches().await;
+        assert_eq!(t.ctx.connection_cache_stats().await.cleared, 1);
+
+        // The established connection is not reused, a new one is made,
+        // which fails as the context is not configured. The failed
+        // attempt is not counted.
+        assert!(imap.connect_configured(&t.ctx).await.is_err());
+        assert!(imap.should_reconnect());
+        let stats = t.ctx.connection_cache_stats().await;
+        assert_eq!(stats.reused, 1);
+        assert_eq!(stats.connected, 0);
+        assert_eq!(stats.cleared, 1);
+    }
+
+    #[test]
+    fn test_capture_file_per_connection() {
+        let (_sender, receiver) = async_std::sync::channel(1);
+        let inbox = Imap::new(receiver);
+        let (_sender, receiver) = async_std::sync::channel(1);
+        let mut sentbox = Imap::new(receiver);
+        sentbox.set_role("sentbox");
+        let (_sender, receiver) = async_std::sync::channel(1);
+        let mut custom = Imap::new(receiver);
+        custom.set_role("Archive/2020");
+
+        // The connections do not overwrite each other's capture files,
+        // and the names stay the same across restarts.
+        assert_eq!(inbox.capture_path("imap.capture"), "imap.capture.inbox");
+        assert_eq!(sentbox.capture_path("imap.capture"), "imap.capture.sentbox");
+        assert_eq!(
+            custom.capture_path("imap.capture"),
+            "imap.capture.Archive_2020"
+        );
+    }
+
+    #[async_std::test]
+    async fn test_verify_watch_folder() {
+        use std::time::Duration;
+
+        let (port, server) = fake_imap_server(|line| {
+            if line.contains(" LIST ") {
+                "* LIST (\\HasNoChildren) \"/\" INBOX\r\n\
+                 * LIST (\\Noselect \\HasChildren) \"/\" Archive\r\n\
+                 * LIST (\\HasNoChildren) \"/\" \"Archive/2020\"\r\n"
+                    .to_string()
+            } else {
+                String::new()
+            }
+        })
+        .await;
+
+        let t = TestContext::new().await;
+        let events = t.ctx.get_event_emitter();
+        let client = Client::connect_insecure("127.0.0.1", port, None, Duration::from_secs(10))
+            .await
+            .unwrap();
+        let session = client
+            .login("alice", "secret")
+            .await
+            .map_err(|(err, _)| err)
+            .unwrap();
+        let (_interrupt_sender, interrupt_receiver) = async_std::sync::channel(1);
+        let mut imap = Imap::new(interrupt_receiver);
+        imap.session = Some(session);
+        imap.connected = true;
+
+        assert!(imap.verify_watch_folder(&t.ctx, "INBOX").await);
+        assert!(imap.verify_watch_folder(&t.ctx, "Archive/2020").await);
+        assert!(!imap.verify_watch_folder(&t.ctx, "Archive").await);
+
+        let error = async_std::future::timeout(Duration::from_secs(10), async {
+            while let Some(event) = events.recv().await {
+                if let EventType::Error(msg) = event.typ {
+                    return msg;
+                }
+            }
+            String::new()
+        })
+        .await
+        .unwrap();
+        assert!(error.contains("Cannot watch folder \"Archive\""));
+
+        drop(imap);
+        server.cancel().await;
+    }
+
+    #[async_std::test]
+    async fn test_sync_seen_flags() {
+        use crate::contact::Contact;
+        use std::time::Duration;
+
+        let (port, server) = fake_imap_server(|line| {
+            if line.contains(" UID FETCH ") {
+                // Message 10 was read with another client.
+                "* 1 FETCH (UID 10 FLAGS (\\Seen))\r\n\
+                 * 2 FETCH (UID 11 FLAGS ())\r\n"
+                    .to_string()
+            } else {
+                String::new()
+            }
+        })
+        .await;
+
+        let t = TestContext::new().await;
+        let contact_id = Contact::create(&t.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        let chat_id = chat::create_by_contact_id(&t.ctx, contact_id)
+            .await
+            .unwrap();
+        for uid in &[10, 11] {
+            t.ctx
+                .sql
+                .execute(
+                    "INSERT INTO msgs (chat_id, from_id, server_folder, server_uid, state) \
+                     VALUES (?, ?, 'INBOX', ?, ?);",
+                    paramsv![chat_id, contact_id, *uid, MessageState::InFresh],
+                )
+                .await
+                .unwrap();
+        }
+
+        let client = Client::connect_insecure("127.0.0.1", port, None, Duration::from_secs(10))
+            .await
+            .unwrap();
+        let session = client
+            .login("alice", "secret")
+            .await
+            .map_err(|(err, _)| err)
+            .unwrap();
+        let (_interrupt_sender, interrupt_receiver) = async_std::sync::channel(1);
+        let mut imap = Imap::new(interrupt_receiver);
+        imap.session = Some(session);
+        imap.connected = true;
+
+        imap.sync_seen_flags(&t.ctx, "INBOX").await.unwrap();
+
+        let state = |uid: u32| {
+            let ctx = &t.ctx;
+            async move {
+                ctx.sql
+                    .query_get_value_result::<MessageState>(
+                        "SELECT state FROM msgs WHERE server_uid=?;",
+                        paramsv![uid],
+                    )
+                    .await
+                    .unwrap()
+                    .unwrap()
+            }
+        };
+        assert_eq!(state(10).await, MessageState::InSeen);
+        assert_eq!(state(11).await, MessageState::InFresh);
+
+        drop(imap);
+        server.cancel().await;
+    }
+
+    #[async_std::test]
+    async fn test_last_fetch_time() {
+        let t = TestContext::new().await;
+        assert_eq!(t.ctx.last_fetch_time("INBOX").await, None);
+
+        let before = time();
+        set_last_fetch_time(&t.ctx, "INBOX").await;
+        let last_fetch_time = t.ctx.last_fetch_time("INBOX").await.unwrap();
+        assert!(last_fetch_time >= before && last_fetch_time <= time());
+        assert_eq!(t.ctx.last_fetch_time("Sent").await, None);
+    }
+
+    #[async_std::test]
+    async fn test_connectivity_not_connected() {
+        use std::sync::{Arc, Mutex};
+
+        let t = TestContext::new().await;
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let changes1 = changes.clone();
+        t.ctx.on_event_type(
+            EventType::ConnectivityChanged(Connectivity::Connected).as_id(),
+            move |event| {
+                if let EventType::ConnectivityChanged(connectivity) = event.typ {
+                    changes1.lock().unwrap().push(connectivity);
+                }
+            },
+        );
+        assert_eq!(t.ctx.get_connectivity().await, Connectivity::Connecting);
+
+        // Connecting fails, because the context is not configured.
+        let (_sender, receiver) = async_std::sync::channel(1);
+        let mut imap = Imap::new(receiver);
+        assert!(imap.connect_configured(&t.ctx).await.is_err());
+        assert_eq!(t.ctx.get_connectivity().await, Connectivity::NotConnected);
+        assert_eq!(*changes.lock().unwrap(), vec![Connectivity::NotConnected]);
+
+        // Failing again does not emit another event.
+        assert!(imap.connect_configured(&t.ctx).await.is_err());
+        assert_eq!(*changes.lock().unwrap(), vec![Connectivity::NotConnected]);
+    }
+
+    #[test]
+    fn test_newest_first_chunks() {
+        let backlog: Vec<u32> = (1..=25).collect();
+        let chunks: Vec<&[u32]> = newest_first_chunks(&backlog).collect();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0], &backlog[15..]);
+        assert_eq!(chunks[2], &backlog[..5]);
+
+        // Each chunk is processed in reverse, so the whole backlog is
+        // processed starting with the newest message.
+        let order: Vec<u32> = chunks
+            .into_iter()
+            .flat_map(|chunk| chunk.iter().rev().copied())
+            .collect();
+        let mut expected = backlog.clone();
+        expected.reverse();
+        assert_eq!(order, expected);
+
+        assert_eq!(newest_first_chunks(&[]).count(), 0);
+    }
+
+    #[test]
+    fn test_verify_delete_target() {
+        assert_eq!(
+            verify_delete_target(Some("foo@example.org"), "foo@example.org"),
+            DeleteTarget::Matches
+        );
+        // UID is now used by a different message, it must not be deleted.
+        assert_eq!(
+            verify_delete_target(Some("bar@example.org"), "foo@example.org"),
+            DeleteTarget::Mismatch
+        );
+        assert_eq!(
+            verify_delete_target(None, "foo@example.org"),
+            DeleteTarget::Gone
+        );
+    }
+
     #[test]
     fn test_get_folder_meaning_by_name() {
         assert_eq!(
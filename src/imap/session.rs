@@ -1,8 +1,18 @@
+use std::cmp;
 use std::ops::{Deref, DerefMut};
 
+use async_imap::types::{Mailbox, NameAttribute};
 use async_imap::Session as ImapSession;
 use async_native_tls::TlsStream;
 use async_std::net::TcpStream;
+use async_std::prelude::*;
+use async_trait::async_trait;
+
+use super::compress::CompressHandle;
+use super::get_config_last_seen_uid;
+use crate::config::{Config, FolderKind};
+use crate::context::Context;
+use crate::error::Result;
 
 #[derive(Debug)]
 pub(crate) struct Session {
@@ -33,8 +43,474 @@ impl DerefMut for Session {
 }
 
 impl Session {
+    /// Enables DEFLATE compression with the `COMPRESS DEFLATE` command,
+    /// see `Client::compressible()`.
+    pub async fn compress(&mut self, handle: &CompressHandle) -> Result<()> {
+        self.run_command_and_check_ok("COMPRESS DEFLATE", None)
+            .await?;
+        handle.enable();
+        Ok(())
+    }
+
     pub fn idle(self) -> async_imap::extensions::idle::Handle<Box<dyn SessionStream>> {
         let Session { inner } = self;
         inner.idle()
     }
+
+    /// Returns whether `folder` can be selected according to the
+    /// attributes returned by `LIST`, `None` if the folder is not listed.
+    pub async fn is_selectable(&mut self, folder: &str) -> Result<Option<bool>> {
+        let mut selectable = None;
+        let mut names = self.list(Some(""), Some("*")).await?;
+        while let Some(name) = names.next().await {
+            let name = name?;
+            if name.name() == folder {
+                let noselect = name
+                    .attributes()
+                    .iter()
+                    .any(|attr| matches!(attr, NameAttribute::NoSelect));
+                selectable = Some(!noselect);
+            }
+        }
+        Ok(selectable)
+    }
+
+    /// Estimates the number of new messages in `folder` without fetching them.
+    ///
+    /// Uses the last seen UID stored for the folder and `selected`, the
+    /// `SELECT` response if `folder` is the selected folder, or `STATUS`
+    /// otherwise. `STATUS` should not be used on the selected folder, see
+    /// RFC 3501, section 6.3.10. The result is an upper bound, as UIDs are
+    /// not necessarily contiguous.
+    pub async fn estimate_backlog(
+        &mut self,
+        context: &Context,
+        folder: &str,
+        selected: Option<&Mailbox>,
+    ) -> Result<u32> {
+        let status;
+        let mailbox = match selected {
+            Some(mailbox) => mailbox,
+            None => {
+                status = self
+                    .status(folder, "(MESSAGES UIDNEXT UIDVALIDITY)")
+                    .await?;
+                &status
+            }
+        };
+        let (uid_validity, last_seen_uid) = get_config_last_seen_uid(context, folder).await;
+
+        Ok(estimate_backlog_from_status(
+            mailbox.exists,
+            mailbox.uid_next,
+            mailbox.uid_validity,
+            uid_validity,
+            last_seen_uid,
+        ))
+    }
+
+    /// Recreates the configured mvbox and sentbox if they were deleted
+    /// on the server, e.g. by the user in another client.
+    ///
+    /// Missing parent folders are created as well, using the hierarchy
+    /// delimiter reported by the server.
+    pub async fn ensure_configured_folders(&mut self, context: &Context) -> Result<()> {
+        let mut wanted = Vec::new();
+        if context.get_config_bool(Config::MvboxWatch).await
+            || context.get_config_bool(Config::MvboxMove).await
+        {
+            wanted.extend(context.get_folder(FolderKind::Mvbox).await);
+        }
+        if context.get_config_bool(Config::SentboxWatch).await {
+            wanted.extend(context.get_folder(FolderKind::Sentbox).await);
+        }
+        if wanted.is_empty() {
+            return Ok(());
+        }
+
+        let mut existing = Vec::new();
+        let mut delimiter = None;
+        let mut names = self.list(Some(""), Some("*")).await?;
+        while let Some(name) = names.next().await {
+            let name = name?;
+            if delimiter.is_none() {
+                delimiter = name
+                    .delimiter()
+                    .filter(|d| !d.is_empty())
+                    .map(|d| d.to_string());
+            }
+            existing.push(name.name().to_string());
+        }
+        drop(names);
+
+        for command in plan_folder_commands(&existing, &wanted, delimiter.as_deref()) {
+            match command {
+                FolderCommand::Create(folder) => {
+                    info!(context, "Creating missing folder {:?}", folder);
+                    self.create(&folder).await?;
+                }
+                FolderCommand::Subscribe(folder) => {
+                    // SUBSCRIBE makes the folder visible to LSUB used by other MUAs.
+                    if let Err(err) = self.subscribe(&folder).await {
+                        warn!(context, "could not subscribe to {:?}: {:?}", folder, err);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Searches the watched folders for the message with the given
+    /// Message-ID and returns the folder and UID where it is stored.
+    ///
+    /// This selects other folders, so the caller must reselect its
+    /// folder afterwards.
+    pub async fn find_message(
+        &mut self,
+        context: &Context,
+        rfc724_mid: &str,
+    ) -> Result<Option<(String, u32)>> {
+        let folders = search_folders(context).await;
+        find_message_in_folders(context, self, &folders, rfc724_mid).await
+    }
+}
+
+/// Search for a message in a single folder, see `Session::find_message()`.
+#[async_trait]
+trait MessageSearch {
+    /// Returns the UID of the message with `rfc724_mid` in `folder`.
+    async fn search_message_id(&mut self, folder: &str, rfc724_mid: &str) -> Result<Option<u32>>;
+}
+
+#[async_trait]
+impl MessageSearch for Session {
+    async fn search_message_id(&mut self, folder: &str, rfc724_mid: &str) -> Result<Option<u32>> {
+        // EXAMINE selects the folder read-only, so nothing is expunged.
+        self.examine(folder).await?;
+        let uids = self
+            .uid_search(format!(
+                "HEADER Message-ID \"<{}>\"",
+                rfc724_mid.replace('\\', "\\\\").replace('"', "\\\"")
+            ))
+            .await?;
+        Ok(uids.into_iter().min())
+    }
+}
+
+/// Returns the folders searched by `Session::find_message()`: the
+/// inbox, mvbox and sentbox, followed by additionally watched folders.
+async fn search_folders(context: &Context) -> Vec<String> {
+    let mut folders: Vec<String> = Vec::new();
+    for kind in &[FolderKind::Inbox, FolderKind::Mvbox, FolderKind::Sentbox] {
+        folders.extend(context.get_folder(*kind).await);
+    }
+    folders.extend(context.get_watched_folders().await);
+
+    let mut unique = Vec::new();
+    for folder in folders {
+        if !unique.contains(&folder) {
+            unique.push(folder);
+        }
+    }
+    unique
+}
+
+/// Searches `folders` in order and returns the first folder containing
+/// the message together with its UID.
+///
+/// Folders which can not be searched, e.g. because they were deleted,
+/// are skipped.
+async fn find_message_in_folders<S: MessageSearch + Send>(
+    context: &Context,
+    search: &mut S,
+    folders: &[String],
+    rfc724_mid: &str,
+) -> Result<Option<(String, u32)>> {
+    for folder in folders {
+        match search.search_message_id(folder, rfc724_mid).await {
+            Ok(Some(uid)) => return Ok(Some((folder.clone(), uid))),
+            Ok(None) => {}
+            Err(err) => {
+                warn!(
+                    context,
+                    "could not search {:?} for {}: {:#}", folder, rfc724_mid, err
+                );
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Command issued by `Session::ensure_configured_folders()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FolderCommand {
+    Create(String),
+    Subscribe(String),
+}
+
+/// Returns the commands creating and subscribing the `wanted` folders
+/// missing from `existing`, preceded by the creation of missing parents.
+fn plan_folder_commands(
+    existing: &[String],
+    wanted: &[String],
+    delimiter: Option<&str>,
+) -> Vec<FolderCommand> {
+    let mut known = existing.to_vec();
+    let mut commands = Vec::new();
+
+    for folder in wanted {
+        if known.contains(folder) {
+            continue;
+        }
+
+        if let Some(delimiter) = delimiter {
+            let parts: Vec<&str> = folder.split(delimiter).collect();
+            let mut parent = String::new();
+            for part in &parts[..parts.len() - 1] {
+                if !parent.is_empty() {
+                    parent.push_str(delimiter);
+                }
+                parent.push_str(part);
+                // INBOX always exists and its name is case-insensitive.
+                if !parent.is_empty()
+                    && !parent.eq_ignore_ascii_case("INBOX")
+                    && !known.contains(&parent)
+                {
+                    commands.push(FolderCommand::Create(parent.clone()));
+                    known.push(parent.clone());
+                }
+            }
+        }
+
+        commands.push(FolderCommand::Create(folder.clone()));
+        commands.push(FolderCommand::Subscribe(folder.clone()));
+        known.push(folder.clone());
+    }
+    commands
+}
+
+/// Estimates the number of new messages from `STATUS` response values
+/// and the stored sync marker.
+fn estimate_backlog_from_status(
+    messages: u32,
+    uid_next: Option<u32>,
+    uid_validity: Option<u32>,
+    stored_uid_validity: u32,
+    last_seen_uid: u32,
+) -> u32 {
+    if uid_validity != Some(stored_uid_validity) {
+        // Existing messages are skipped when UIDVALIDITY changes,
+        // see `Imap::select_with_uidvalidity()`.
+        return 0;
+    }
+    match uid_next {
+        Some(uid_next) => cmp::min(
+            uid_next.saturating_sub(1).saturating_sub(last_seen_uid),
+            messages,
+        ),
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::error::format_err;
+    use crate::test_utils::TestContext;
+
+    #[test]
+    fn test_estimate_backlog_from_status() {
+        // * STATUS INBOX (MESSAGES 520 UIDNEXT 1001 UIDVALIDITY 7)
+        assert_eq!(
+            estimate_backlog_from_status(520, Some(1001), Some(7), 7, 500),
+            500
+        );
+
+        // UIDs are not contiguous, at most MESSAGES are new.
+        assert_eq!(
+            estimate_backlog_from_status(20, Some(1001), Some(7), 7, 500),
+            20
+        );
+
+        // Everything is fetched already.
+        assert_eq!(
+            estimate_backlog_from_status(520, Some(1001), Some(7), 7, 1000),
+            0
+        );
+
+        // UIDVALIDITY changed or folder was never synced.
+        assert_eq!(
+            estimate_backlog_from_status(520, Some(1001), Some(8), 7, 500),
+            0
+        );
+        assert_eq!(
+            estimate_backlog_from_status(520, Some(1001), Some(7), 0, 0),
+            0
+        );
+
+        // No UIDNEXT in the response.
+        assert_eq!(estimate_backlog_from_status(520, None, Some(7), 7, 500), 0);
+    }
+
+    #[async_std::test]
+    async fn test_estimate_backlog() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        use crate::imap::client::Client;
+        use crate::imap::set_config_last_seen_uid;
+        use crate::test_utils::fake_imap_server;
+
+        let status_commands = Arc::new(AtomicUsize::new(0));
+        let counter = status_commands.clone();
+        let (port, server) = fake_imap_server(move |line| {
+            if line.contains(" SELECT ") {
+                "* 520 EXISTS\r\n\
+                 * OK [UIDVALIDITY 7] UIDs valid\r\n\
+                 * OK [UIDNEXT 1001] Predicted next UID\r\n"
+                    .to_string()
+            } else if line.contains(" STATUS ") {
+                counter.fetch_add(1, Ordering::SeqCst);
+                "* STATUS Sent (MESSAGES 20 UIDNEXT 1001 UIDVALIDITY 7)\r\n".to_string()
+            } else {
+                String::new()
+            }
+        })
+        .await;
+
+        let t = TestContext::new().await;
+        set_config_last_seen_uid(&t.ctx, "INBOX", 7, 500).await;
+        set_config_last_seen_uid(&t.ctx, "Sent", 7, 500).await;
+        let client = Client::connect_insecure("127.0.0.1", port, None, Duration::from_secs(10))
+            .await
+            .unwrap();
+        let mut session = client
+            .login("alice", "secret")
+            .await
+            .map_err(|(err, _)| err)
+            .unwrap();
+
+        // The selected folder is estimated from the SELECT response.
+        let mailbox = session.select("INBOX").await.unwrap();
+        let backlog = session
+            .estimate_backlog(&t.ctx, "INBOX", Some(&mailbox))
+            .await
+            .unwrap();
+        assert_eq!(backlog, 500);
+        assert_eq!(status_commands.load(Ordering::SeqCst), 0);
+
+        // Other folders are estimated with STATUS.
+        let backlog = session
+            .estimate_backlog(&t.ctx, "Sent", None)
+            .await
+            .unwrap();
+        assert_eq!(backlog, 20);
+        assert_eq!(status_commands.load(Ordering::SeqCst), 1);
+
+        drop(session);
+        server.cancel().await;
+    }
+
+    #[test]
+    fn test_plan_folder_commands() {
+        let existing = vec!["INBOX".to_string(), "Sent".to_string()];
+
+        // Nothing to do if all folders exist.
+        assert!(plan_folder_commands(&existing, &["Sent".to_string()], Some(".")).is_empty());
+
+        assert_eq!(
+            plan_folder_commands(
+                &existing,
+                &["Juttmy".to_string(), "Sent".to_string()],
+                Some(".")
+            ),
+            vec![
+                FolderCommand::Create("Juttmy".to_string()),
+                FolderCommand::Subscribe("Juttmy".to_string()),
+            ]
+        );
+
+        // INBOX is never created.
+        assert_eq!(
+            plan_folder_commands(&existing, &["INBOX/Juttmy".to_string()], Some("/")),
+            vec![
+                FolderCommand::Create("INBOX/Juttmy".to_string()),
+                FolderCommand::Subscribe("INBOX/Juttmy".to_string()),
+            ]
+        );
+
+        // Missing parents are created first.
+        assert_eq!(
+            plan_folder_commands(&existing, &["Archive.Juttmy".to_string()], Some(".")),
+            vec![
+                FolderCommand::Create("Archive".to_string()),
+                FolderCommand::Create("Archive.Juttmy".to_string()),
+                FolderCommand::Subscribe("Archive.Juttmy".to_string()),
+            ]
+        );
+    }
+
+    /// Folders with their messages, mapping Message-IDs to UIDs.
+    struct MockSearch(Vec<(String, Vec<(String, u32)>)>);
+
+    #[async_trait]
+    impl MessageSearch for MockSearch {
+        async fn search_message_id(
+            &mut self,
+            folder: &str,
+            rfc724_mid: &str,
+        ) -> Result<Option<u32>> {
+            let (_, msgs) = self
+                .0
+                .iter()
+                .find(|(name, _)| name == folder)
+                .ok_or_else(|| format_err!("no folder {}", folder))?;
+            Ok(msgs
+                .iter()
+                .find(|(mid, _)| mid == rfc724_mid)
+                .map(|(_, uid)| *uid))
+        }
+    }
+
+    #[async_std::test]
+    async fn test_find_message_in_folders() {
+        let t = TestContext::new_alice().await;
+        t.ctx
+            .set_config(Config::ConfiguredMvboxFolder, Some("DeltaChat"))
+            .await
+            .unwrap();
+        t.ctx
+            .set_config(Config::ConfiguredSentboxFolder, Some("Sent"))
+            .await
+            .unwrap();
+        let folders = search_folders(&t.ctx).await;
+        assert_eq!(folders, vec!["INBOX", "DeltaChat", "Sent"]);
+
+        let mut search = MockSearch(vec![
+            (
+                "INBOX".to_string(),
+                vec![("other@example.org".to_string(), 1)],
+            ),
+            (
+                "DeltaChat".to_string(),
+                vec![("moved@example.org".to_string(), 7)],
+            ),
+        ]);
+        assert_eq!(
+            find_message_in_folders(&t.ctx, &mut search, &folders, "moved@example.org")
+                .await
+                .unwrap(),
+            Some(("DeltaChat".to_string(), 7))
+        );
+
+        // "Sent" can not be searched, but this is not fatal.
+        assert_eq!(
+            find_message_in_folders(&t.ctx, &mut search, &folders, "unknown@example.org")
+                .await
+                .unwrap(),
+            None
+        );
+    }
 }
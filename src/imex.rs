@@ -87,6 +87,9 @@ pub async fn imex(context: &Context, what: ImexMode, param1: impl AsRef<Path>) -
         match success {
             Ok(()) => {
                 info!(context, "IMEX successfully completed");
+                if what == ImexMode::ImportBackup {
+                    context.emit_configured_changed(false).await;
+                }
                 context.emit_event(EventType::ImexProgress(1000));
                 Ok(())
             }
@@ -111,6 +114,33 @@ pub async fn imex(context: &Context, what: ImexMode, param1: impl AsRef<Path>) -
     res
 }
 
+impl Context {
+    /// Imports a backup into this account, replacing its data, so the
+    /// account keeps its id instead of a new one being created as by
+    /// `Accounts::import_account()`.
+    ///
+    /// Fails if the account is configured or already contains messages.
+    pub async fn import_backup_into_self(&self, file: impl AsRef<Path>) -> Result<()> {
+        ensure!(
+            !self.is_configured().await,
+            "Cannot import backups to accounts in use."
+        );
+        let has_msgs = self
+            .sql
+            .exists(
+                "SELECT id FROM msgs WHERE chat_id>? AND from_id!=?;",
+                paramsv![DC_CHAT_ID_LAST_SPECIAL, DC_CONTACT_ID_DEVICE],
+            )
+            .await?;
+        ensure!(
+            !has_msgs,
+            "Cannot import backups to accounts containing messages."
+        );
+
+        imex(self, ImexMode::ImportBackup, file).await
+    }
+}
+
 async fn cleanup_aborted_imex(context: &Context, what: ImexMode) {
     if what == ImexMode::ImportBackup {
         dc_delete_file(context, context.get_dbfile()).await;
@@ -994,6 +1024,39 @@ mod tests {
     use crate::test_utils::*;
     use ::pgp::armor::BlockType;
 
+    #[async_std::test]
+    async fn test_import_backup_into_self() {
+        let alice = TestContext::new_alice().await;
+        let backup_dir = tempfile::tempdir().unwrap();
+        imex(&alice.ctx, ImexMode::ExportBackup, backup_dir.path())
+            .await
+            .unwrap();
+        let backup = has_backup(&alice.ctx, backup_dir.path()).await.unwrap();
+
+        let t = TestContext::new().await;
+        let id = t.ctx.get_id();
+        t.ctx.import_backup_into_self(&backup).await.unwrap();
+        assert_eq!(t.ctx.get_id(), id);
+        assert!(t.ctx.is_configured().await);
+        assert_eq!(
+            t.ctx.get_config(Config::ConfiguredAddr).await,
+            Some("alice@example.com".to_string())
+        );
+
+        // Importing again is refused as the account is configured now.
+        assert!(t.ctx.import_backup_into_self(&backup).await.is_err());
+
+        // Accounts with messages are refused even if not configured.
+        let t = TestContext::new().await;
+        let bob = crate::contact::Contact::create(&t.ctx, "bob", "bob@example.net")
+            .await
+            .unwrap();
+        let chat_id = chat::create_by_contact_id(&t.ctx, bob).await.unwrap();
+        chat::add_info_msg(&t.ctx, chat_id, "hello").await;
+        assert!(t.ctx.import_backup_into_self(&backup).await.is_err());
+        assert!(!t.ctx.is_configured().await);
+    }
+
     #[async_std::test]
     async fn test_render_setup_file() {
         let t = TestContext::new().await;
@@ -3,6 +3,7 @@
 //! This module implements a job queue maintained in the SQLite database
 //! and job types.
 
+use std::collections::BTreeMap;
 use std::fmt;
 use std::future::Future;
 
@@ -15,11 +16,11 @@ use async_smtp::smtp::response::Code;
 use async_smtp::smtp::response::Detail;
 
 use crate::chat::{self, ChatId};
-use crate::config::Config;
+use crate::config::{Config, FolderKind};
 use crate::contact::Contact;
 use crate::context::Context;
 use crate::dc_tools::*;
-use crate::ephemeral::load_imap_deletion_msgid;
+use crate::ephemeral::load_imap_deletion_msgids;
 use crate::error::{bail, ensure, format_err, Error, Result};
 use crate::events::EventType;
 use crate::imap::*;
@@ -28,15 +29,24 @@ use crate::message::MsgId;
 use crate::message::{self, Message, MessageState};
 use crate::mimefactory::MimeFactory;
 use crate::param::*;
+use crate::scheduler::{InterruptInfo, InterruptReason};
 use crate::smtp::Smtp;
+use crate::sql;
 use crate::{blob::BlobObject, contact::normalize_name, contact::Modifier, contact::Origin};
-use crate::{scheduler::InterruptInfo, sql};
 
 // results in ~3 weeks for the last backoff timespan
 const JOB_RETRIES: u32 = 17;
 
+/// Number of moves remembered for `Context::recently_moved_messages()`.
+const MAX_RECENT_MOVES: usize = 100;
+
+/// Maximum number of messages deleted from the server by one job.
+const IMAP_DELETION_BATCH_SIZE: usize = 100;
+
 /// Thread IDs
-#[derive(Debug, Display, Copy, Clone, PartialEq, Eq, FromPrimitive, ToPrimitive, FromSql, ToSql)]
+#[derive(
+    Debug, Display, Copy, Clone, PartialEq, Eq, FromPrimitive, ToPrimitive, FromSql, ToSql,
+)]
 #[repr(i32)]
 pub(crate) enum Thread {
     Unknown = 0,
@@ -95,11 +105,20 @@ pub enum Action {
     FetchExistingMsgs = 110,
     MarkseenMsgOnImap = 130,
 
+    // Marks messages with started ephemeral timers as seen,
+    // see `Config::SyncSeenToServer`. All pending jobs of this
+    // kind are performed at once.
+    MarkseenEphemeralOnImap = 135,
+
     // Moving message is prioritized lower than deletion so we don't
     // bother moving message if it is already scheduled for deletion.
     MoveMsg = 200,
     DeleteMsgOnImap = 210,
 
+    // Deletes the messages listed in `Param::MsgIds`, created when
+    // many messages are due for deletion at once.
+    DeleteMsgsOnImap = 211,
+
     // UID synchronization is high-priority to make sure correct UIDs
     // are used by message moving/deletion.
     ResyncFolders = 300,
@@ -127,8 +146,10 @@ impl From<Action> for Thread {
             Housekeeping => Thread::Imap,
             FetchExistingMsgs => Thread::Imap,
             DeleteMsgOnImap => Thread::Imap,
+            DeleteMsgsOnImap => Thread::Imap,
             ResyncFolders => Thread::Imap,
             MarkseenMsgOnImap => Thread::Imap,
+            MarkseenEphemeralOnImap => Thread::Imap,
             MoveMsg => Thread::Imap,
 
             MaybeSendLocations => Thread::Smtp,
@@ -146,6 +167,7 @@ pub struct Job {
     pub foreign_id: u32,
     pub desired_timestamp: i64,
     pub added_timestamp: i64,
+    /// Number of failed attempts to run the job so far.
     pub tries: u32,
     pub param: Params,
     pub pending_error: Option<String>,
@@ -227,6 +249,16 @@ impl Job {
         Ok(())
     }
 
+    /// Postpones the job by `delay_seconds` without counting it as a try.
+    ///
+    /// The job is marked as deferred, so `resume_deferred_jobs()` can
+    /// make it due again earlier.
+    pub(crate) async fn postpone(mut self, context: &Context, delay_seconds: i64) -> Result<()> {
+        self.desired_timestamp = time() + delay_seconds;
+        self.param.set_int(Param::Deferred, 1);
+        self.save(context).await
+    }
+
     async fn smtp_send<F, Fut>(
         &mut self,
         context: &Context,
@@ -386,6 +418,7 @@ impl Job {
                 // smtp success, update db ASAP, then delete smtp file
                 if 0 != foreign_id {
                     set_delivered(context, MsgId::new(foreign_id)).await;
+                    context.boost_fetch_after_send().await;
                 }
                 // now also delete the generated file
                 dc_delete_file(context, filename).await;
@@ -508,7 +541,7 @@ impl Job {
         }
 
         let msg = job_try!(Message::load_from_db(context, MsgId::new(self.foreign_id)).await);
-        let dest_folder = context.get_config(Config::ConfiguredMvboxFolder).await;
+        let dest_folder = context.get_folder(FolderKind::Mvbox).await;
 
         if let Some(dest_folder) = dest_folder {
             let server_folder = msg.server_folder.as_ref().unwrap();
@@ -521,6 +554,7 @@ impl Job {
                 ImapActionResult::Success => {
                     // Rust-Imap provides no target uid on mv, so just set it to 0, update again when precheck_imf() is called for the moved message
                     message::update_server_uid(context, &msg.rfc724_mid, &dest_folder, 0).await;
+                    record_move(context, msg.id, server_folder, &dest_folder).await;
                     Status::Finished(Ok(()))
                 }
                 ImapActionResult::Failed => {
@@ -550,6 +584,37 @@ impl Job {
         let msg = job_try!(Message::load_from_db(context, MsgId::new(self.foreign_id)).await);
 
         if !msg.rfc724_mid.is_empty() {
+            // Delete the copy in the Sent folder first, if there is one.
+            let sentbox_uid: u32 = job_try!(
+                context
+                    .sql
+                    .query_get_value_result(
+                        "SELECT sentbox_uid FROM msgs WHERE id=?",
+                        paramsv![msg.id]
+                    )
+                    .await
+            )
+            .unwrap_or_default();
+            if sentbox_uid != 0 {
+                if let Some(sentbox) = context.get_folder(FolderKind::Sentbox).await {
+                    match imap
+                        .delete_msg(context, &msg.rfc724_mid, &sentbox, sentbox_uid)
+                        .await
+                    {
+                        ImapActionResult::AlreadyDone | ImapActionResult::Success => {}
+                        ImapActionResult::RetryLater | ImapActionResult::Failed => {
+                            return Status::RetryLater;
+                        }
+                    }
+                }
+                job_try!(
+                    context
+                        .sql
+                        .execute("UPDATE msgs SET sentbox_uid=0 WHERE id=?", paramsv![msg.id])
+                        .await
+                );
+            }
+
             let cnt = message::rfc724_mid_cnt(context, &msg.rfc724_mid).await;
             info!(
                 context,
@@ -570,13 +635,13 @@ impl Job {
             } else {
                 /* if this is the last existing part of the message,
                 we delete the message from the server */
-                let mid = msg.rfc724_mid;
+                let mid = &msg.rfc724_mid;
                 let server_folder = msg.server_folder.as_ref().unwrap();
                 let res = if msg.server_uid == 0 {
                     // Message is already deleted on IMAP server.
                     ImapActionResult::AlreadyDone
                 } else {
-                    imap.delete_msg(context, &mid, server_folder, msg.server_uid)
+                    imap.delete_msg(context, mid, server_folder, msg.server_uid)
                         .await
                 };
                 match res {
@@ -593,27 +658,7 @@ impl Job {
                     }
                 }
             }
-            if msg.chat_id.is_trash() || msg.hidden {
-                // Messages are stored in trash chat only to keep
-                // their server UID and Message-ID. Once message is
-                // deleted from the server, database record can be
-                // removed as well.
-                //
-                // Hidden messages are similar to trashed, but are
-                // related to some chat. We also delete their
-                // database records.
-                job_try!(msg.id.delete_from_db(context).await)
-            } else {
-                // Remove server UID from the database record.
-                //
-                // We have either just removed the message from the
-                // server, in which case UID is not valid anymore, or
-                // we have more refernces to the same server UID, so
-                // we remove UID to reduce the number of messages
-                // pointing to the corresponding UID. Once the counter
-                // reaches zero, we will remove the message.
-                job_try!(msg.id.unlink(context).await);
-            }
+            job_try!(finish_imap_deletion(context, &msg).await);
             Status::Finished(Ok(()))
         } else {
             /* eg. device messages have no Message-ID */
@@ -621,6 +666,92 @@ impl Job {
         }
     }
 
+    /// Deletes the messages listed in `Param::MsgIds` from the server.
+    ///
+    /// Messages with a single server UID are deleted with one command
+    /// per folder. Messages which also have a copy in the Sent folder
+    /// or share their UID with other database entries are deleted one
+    /// by one, as in `delete_msg_on_imap()`.
+    async fn delete_msgs_on_imap(&mut self, context: &Context, imap: &mut Imap) -> Status {
+        if let Err(err) = imap.connect_configured(context).await {
+            warn!(context, "could not connect: {:?}", err);
+            return Status::RetryLater;
+        }
+
+        let msg_ids: Vec<MsgId> = self
+            .param
+            .get(Param::MsgIds)
+            .unwrap_or_default()
+            .split_whitespace()
+            .filter_map(|msg_id| msg_id.parse().ok())
+            .map(MsgId::new)
+            .collect();
+
+        let mut retry = false;
+        let mut batches: BTreeMap<String, Vec<Message>> = BTreeMap::new();
+        for msg_id in msg_ids {
+            let msg = match Message::load_from_db(context, msg_id).await {
+                Ok(msg) => msg,
+                Err(err) => {
+                    // Already deleted by a previous try.
+                    info!(context, "Not deleting {} on IMAP: {}", msg_id, err);
+                    continue;
+                }
+            };
+            let sentbox_uid: u32 = job_try!(
+                context
+                    .sql
+                    .query_get_value_result(
+                        "SELECT sentbox_uid FROM msgs WHERE id=?",
+                        paramsv![msg.id]
+                    )
+                    .await
+            )
+            .unwrap_or_default();
+
+            let server_folder = msg.server_folder.clone().unwrap_or_default();
+            if msg.server_uid != 0
+                && sentbox_uid == 0
+                && !server_folder.is_empty()
+                && !msg.rfc724_mid.is_empty()
+                && message::rfc724_mid_cnt(context, &msg.rfc724_mid).await == 1
+            {
+                batches.entry(server_folder).or_default().push(msg);
+                continue;
+            }
+
+            let mut job = Job::new(Action::DeleteMsgOnImap, msg_id.to_u32(), Params::new(), 0);
+            match job.delete_msg_on_imap(context, imap).await {
+                Status::Finished(Ok(())) => {}
+                Status::Finished(Err(err)) => {
+                    warn!(context, "Failed to delete {} on IMAP: {}", msg_id, err);
+                }
+                Status::RetryNow | Status::RetryLater => retry = true,
+            }
+        }
+
+        for (folder, msgs) in batches {
+            let targets: Vec<(u32, String)> = msgs
+                .iter()
+                .map(|msg| (msg.server_uid, msg.rfc724_mid.clone()))
+                .collect();
+            let deleted = imap.delete_msgs(context, &folder, &targets).await;
+            for msg in msgs {
+                if deleted.contains(&msg.server_uid) {
+                    job_try!(finish_imap_deletion(context, &msg).await);
+                } else {
+                    retry = true;
+                }
+            }
+        }
+
+        if retry {
+            Status::RetryLater
+        } else {
+            Status::Finished(Ok(()))
+        }
+    }
+
     /// Read the recipients from old emails sent by the user and add them as contacts.
     /// This way, we can already offer them some email addresses they can write to.
     ///
@@ -635,17 +766,13 @@ impl Job {
             return Status::RetryLater;
         }
 
-        add_all_recipients_as_contacts(context, imap, Config::ConfiguredSentboxFolder).await;
-        add_all_recipients_as_contacts(context, imap, Config::ConfiguredMvboxFolder).await;
-        add_all_recipients_as_contacts(context, imap, Config::ConfiguredInboxFolder).await;
+        add_all_recipients_as_contacts(context, imap, FolderKind::Sentbox).await;
+        add_all_recipients_as_contacts(context, imap, FolderKind::Mvbox).await;
+        add_all_recipients_as_contacts(context, imap, FolderKind::Inbox).await;
 
         if context.get_config_bool(Config::FetchExistingMsgs).await {
-            for config in &[
-                Config::ConfiguredMvboxFolder,
-                Config::ConfiguredInboxFolder,
-                Config::ConfiguredSentboxFolder,
-            ] {
-                if let Some(folder) = context.get_config(*config).await {
+            for kind in &[FolderKind::Mvbox, FolderKind::Inbox, FolderKind::Sentbox] {
+                if let Some(folder) = context.get_folder(*kind).await {
                     if let Err(e) = imap.fetch_new_messages(context, folder, true).await {
                         // We are using Anyhow's .context() and to show the inner error, too, we need the {:#}:
                         warn!(context, "Could not fetch messages, retrying: {:#}", e);
@@ -673,21 +800,21 @@ impl Job {
             return Status::RetryLater;
         }
 
-        if let Some(sentbox_folder) = &context.get_config(Config::ConfiguredSentboxFolder).await {
+        if let Some(sentbox_folder) = &context.get_folder(FolderKind::Sentbox).await {
             job_try!(
                 imap.resync_folder_uids(context, sentbox_folder.to_string())
                     .await
             );
         }
 
-        if let Some(inbox_folder) = &context.get_config(Config::ConfiguredInboxFolder).await {
+        if let Some(inbox_folder) = &context.get_folder(FolderKind::Inbox).await {
             job_try!(
                 imap.resync_folder_uids(context, inbox_folder.to_string())
                     .await
             );
         }
 
-        if let Some(mvbox_folder) = &context.get_config(Config::ConfiguredMvboxFolder).await {
+        if let Some(mvbox_folder) = &context.get_folder(FolderKind::Mvbox).await {
             job_try!(
                 imap.resync_folder_uids(context, mvbox_folder.to_string())
                     .await
@@ -750,6 +877,201 @@ impl Job {
             }
         }
     }
+
+    /// Marks messages of all pending `MarkseenEphemeralOnImap` jobs
+    /// as seen, using one IMAP command per folder.
+    async fn markseen_ephemeral_on_imap(&mut self, context: &Context, imap: &mut Imap) -> Status {
+        if let Err(err) = imap.connect_configured(context).await {
+            warn!(context, "could not connect: {:?}", err);
+            return Status::RetryLater;
+        }
+
+        let (job_ids, batches) = job_try!(load_seen_sync_batches(context).await);
+        for (folder, uids) in batches.iter() {
+            match imap.set_seen_batch(context, folder, uids).await {
+                ImapActionResult::RetryLater => return Status::RetryLater,
+                ImapActionResult::AlreadyDone
+                | ImapActionResult::Success
+                | ImapActionResult::Failed => {}
+            }
+        }
+
+        // The current job is deleted by the caller.
+        let other_job_ids: Vec<u32> = job_ids
+            .into_iter()
+            .filter(|job_id| *job_id != self.job_id)
+            .collect();
+        if !other_job_ids.is_empty() {
+            job_try!(kill_ids(context, &other_job_ids).await);
+        }
+        Status::Finished(Ok(()))
+    }
+}
+
+/// Message moved to the mvbox, see `Context::recently_moved_messages()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MovedMsg {
+    pub msg_id: MsgId,
+    pub from_folder: String,
+    pub to_folder: String,
+    pub timestamp: i64,
+}
+
+impl Context {
+    /// Returns up to `limit` messages most recently moved to the
+    /// mvbox, newest first.
+    ///
+    /// Only the last `MAX_RECENT_MOVES` moves since the context was
+    /// opened are remembered.
+    pub async fn recently_moved_messages(&self, limit: usize) -> Vec<MovedMsg> {
+        self.recent_moves
+            .read()
+            .await
+            .iter()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Remembers a move to the mvbox and emits `MsgMovedToMvbox`.
+async fn record_move(context: &Context, msg_id: MsgId, from_folder: &str, to_folder: &str) {
+    info!(
+        context,
+        "Moved {} from {} to {}", msg_id, from_folder, to_folder
+    );
+    {
+        let recent_moves = &mut *context.recent_moves.write().await;
+        recent_moves.push_front(MovedMsg {
+            msg_id,
+            from_folder: from_folder.to_string(),
+            to_folder: to_folder.to_string(),
+            timestamp: time(),
+        });
+        recent_moves.truncate(MAX_RECENT_MOVES);
+    }
+    context.emit_event(EventType::MsgMovedToMvbox {
+        msg_id,
+        from_folder: from_folder.to_string(),
+    });
+}
+
+/// Updates the database after `msg` is deleted from the server.
+async fn finish_imap_deletion(context: &Context, msg: &Message) -> Result<()> {
+    if msg.chat_id.is_trash() || msg.hidden {
+        // Messages are stored in trash chat only to keep
+        // their server UID and Message-ID. Once message is
+        // deleted from the server, database record can be
+        // removed as well.
+        //
+        // Hidden messages are similar to trashed, but are
+        // related to some chat. We also delete their
+        // database records.
+        msg.id.delete_from_db(context).await?;
+    } else {
+        // Remove server UID from the database record.
+        //
+        // We have either just removed the message from the
+        // server, in which case UID is not valid anymore, or
+        // we have more refernces to the same server UID, so
+        // we remove UID to reduce the number of messages
+        // pointing to the corresponding UID. Once the counter
+        // reaches zero, we will remove the message.
+        msg.id.unlink(context).await?;
+    }
+    Ok(())
+}
+
+/// Loads pending `MarkseenEphemeralOnImap` jobs.
+///
+/// Returns the IDs of the jobs and the server UIDs of their messages
+/// grouped by folder. Messages which are not on the server anymore
+/// are skipped.
+async fn load_seen_sync_batches(
+    context: &Context,
+) -> sql::Result<(Vec<u32>, BTreeMap<String, Vec<u32>>)> {
+    let rows = context
+        .sql
+        .query_map(
+            "SELECT j.id, m.server_folder, m.server_uid \
+             FROM jobs j LEFT JOIN msgs m ON m.id=j.foreign_id \
+             WHERE j.action=?",
+            paramsv![Action::MarkseenEphemeralOnImap],
+            |row| {
+                Ok((
+                    row.get::<_, u32>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, Option<u32>>(2)?,
+                ))
+            },
+            |rows| {
+                rows.collect::<std::result::Result<Vec<_>, _>>()
+                    .map_err(Into::into)
+            },
+        )
+        .await?;
+
+    let mut job_ids = Vec::new();
+    let mut batches: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    for (job_id, folder, uid) in rows {
+        job_ids.push(job_id);
+        if let (Some(folder), Some(uid)) = (folder, uid) {
+            if !folder.is_empty() && uid != 0 {
+                let uids = batches.entry(folder).or_default();
+                if !uids.contains(&uid) {
+                    uids.push(uid);
+                }
+            }
+        }
+    }
+    Ok((job_ids, batches))
+}
+
+/// Snapshot of the job queue, see `Context::job_counts()`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct JobCounts {
+    /// Number of pending jobs for the IMAP thread.
+    pub imap: usize,
+    /// Number of pending jobs for the SMTP thread.
+    pub smtp: usize,
+    /// Number of jobs which are performed right now.
+    ///
+    /// Running jobs are also included into the pending counts, as
+    /// they are removed from the queue only when finished.
+    pub running: usize,
+}
+
+impl Context {
+    /// Returns the number of pending jobs per thread.
+    ///
+    /// This is meant for debugging, e.g. to display in a debug panel.
+    pub async fn job_counts(&self) -> Result<JobCounts> {
+        let rows = self
+            .sql
+            .query_map(
+                "SELECT thread, COUNT(*) FROM jobs GROUP BY thread",
+                paramsv![],
+                |row| Ok((row.get::<_, Thread>(0)?, row.get::<_, isize>(1)?)),
+                |rows| {
+                    rows.collect::<std::result::Result<Vec<_>, _>>()
+                        .map_err(Into::into)
+                },
+            )
+            .await?;
+
+        let mut counts = JobCounts {
+            running: *self.running_jobs.read().await,
+            ..Default::default()
+        };
+        for (thread, count) in rows {
+            match thread {
+                Thread::Imap => counts.imap = count as usize,
+                Thread::Smtp => counts.smtp = count as usize,
+                Thread::Unknown => {}
+            }
+        }
+        Ok(counts)
+    }
 }
 
 /// Delete all pending jobs with the given action.
@@ -798,8 +1120,8 @@ async fn set_delivered(context: &Context, msg_id: MsgId) {
     context.emit_event(EventType::MsgDelivered { chat_id, msg_id });
 }
 
-async fn add_all_recipients_as_contacts(context: &Context, imap: &mut Imap, folder: Config) {
-    let mailbox = if let Some(m) = context.get_config(folder).await {
+async fn add_all_recipients_as_contacts(context: &Context, imap: &mut Imap, folder: FolderKind) {
+    let mailbox = if let Some(m) = context.get_folder(folder).await {
         m
     } else {
         return;
@@ -965,15 +1287,28 @@ pub(crate) enum Connection<'a> {
 }
 
 async fn load_imap_deletion_job(context: &Context) -> sql::Result<Option<Job>> {
-    let res = if let Some(msg_id) = load_imap_deletion_msgid(context).await? {
-        Some(Job::new(
+    let msg_ids = load_imap_deletion_msgids(context, IMAP_DELETION_BATCH_SIZE).await?;
+    let res = match msg_ids.as_slice() {
+        [] => None,
+        [msg_id] => Some(Job::new(
             Action::DeleteMsgOnImap,
             msg_id.to_u32(),
             Params::new(),
             0,
-        ))
-    } else {
-        None
+        )),
+        [first_msg_id, ..] => {
+            let mut param = Params::new();
+            param.set(
+                Param::MsgIds,
+                msg_ids.iter().map(|msg_id| msg_id.to_u32()).join(" "),
+            );
+            Some(Job::new(
+                Action::DeleteMsgsOnImap,
+                first_msg_id.to_u32(),
+                param,
+                0,
+            ))
+        }
     };
     Ok(res)
 }
@@ -1003,7 +1338,13 @@ impl<'a> Connection<'a> {
     }
 }
 
-pub(crate) async fn perform_job(context: &Context, mut connection: Connection<'_>, mut job: Job) {
+pub(crate) async fn perform_job(context: &Context, connection: Connection<'_>, job: Job) {
+    *context.running_jobs.write().await += 1;
+    inner_perform_job(context, connection, job).await;
+    *context.running_jobs.write().await -= 1;
+}
+
+async fn inner_perform_job(context: &Context, mut connection: Connection<'_>, mut job: Job) {
     info!(context, "{}-job {} started...", &connection, &job);
 
     let try_res = match perform_job_action(context, &mut job, &mut connection, 0).await {
@@ -1014,8 +1355,9 @@ pub(crate) async fn perform_job(context: &Context, mut connection: Connection<'_
     match try_res {
         Status::RetryNow | Status::RetryLater => {
             let tries = job.tries + 1;
+            let max_tries = max_job_tries(context, &job).await;
 
-            if tries < JOB_RETRIES {
+            if tries < max_tries {
                 info!(
                     context,
                     "{} thread increases job {} tries to {}", &connection, job, tries
@@ -1040,8 +1382,9 @@ pub(crate) async fn perform_job(context: &Context, mut connection: Connection<'_
                     "{} thread removes job {} as it exhausted {} retries",
                     &connection,
                     job,
-                    JOB_RETRIES
+                    max_tries
                 );
+                give_up_job(context, &mut job).await;
                 job.delete(context).await.unwrap_or_else(|err| {
                     error!(context, "failed to delete job: {}", err);
                 });
@@ -1067,6 +1410,30 @@ pub(crate) async fn perform_job(context: &Context, mut connection: Connection<'_
     }
 }
 
+/// Returns the number of tries after which `job` is given up,
+/// see `Config::SmtpMaxRetries`.
+async fn max_job_tries(context: &Context, job: &Job) -> u32 {
+    match Thread::from(job.action) {
+        Thread::Smtp => {
+            let retries = context.get_config_int(Config::SmtpMaxRetries).await;
+            retries.max(0) as u32 + 1
+        }
+        Thread::Imap | Thread::Unknown => JOB_RETRIES,
+    }
+}
+
+/// Marks the message of a send job which exhausted its retries as
+/// failed, so the user sees it was not sent.
+async fn give_up_job(context: &Context, job: &mut Job) {
+    if job.action == Action::SendMsgToSmtp {
+        let error = job
+            .pending_error
+            .take()
+            .unwrap_or_else(|| format!("Giving up after {} attempts", job.tries + 1));
+        message::set_msg_failed(context, MsgId::new(job.foreign_id), Some(error)).await;
+    }
+}
+
 async fn perform_job_action(
     context: &Context,
     job: &mut Job,
@@ -1087,8 +1454,13 @@ async fn perform_job_action(
             location::job_maybe_send_locations_ended(context, job).await
         }
         Action::DeleteMsgOnImap => job.delete_msg_on_imap(context, connection.inbox()).await,
+        Action::DeleteMsgsOnImap => job.delete_msgs_on_imap(context, connection.inbox()).await,
         Action::ResyncFolders => job.resync_folders(context, connection.inbox()).await,
         Action::MarkseenMsgOnImap => job.markseen_msg_on_imap(context, connection.inbox()).await,
+        Action::MarkseenEphemeralOnImap => {
+            job.markseen_ephemeral_on_imap(context, connection.inbox())
+                .await
+        }
         Action::MoveMsg => job.move_msg(context, connection.inbox()).await,
         Action::FetchExistingMsgs => job.fetch_existing_msgs(context, connection.inbox()).await,
         Action::Housekeeping => {
@@ -1154,13 +1526,17 @@ pub async fn add(context: &Context, job: Job) {
             Action::Unknown => unreachable!(),
             Action::Housekeeping
             | Action::DeleteMsgOnImap
+            | Action::DeleteMsgsOnImap
             | Action::ResyncFolders
             | Action::MarkseenMsgOnImap
+            | Action::MarkseenEphemeralOnImap
             | Action::FetchExistingMsgs
             | Action::MoveMsg => {
                 info!(context, "interrupt: imap");
                 context
-                    .interrupt_inbox(InterruptInfo::new(false, None))
+                    .interrupt_imap_jobs(
+                        InterruptInfo::new(false, None).with_reason(InterruptReason::Job),
+                    )
                     .await;
             }
             Action::MaybeSendLocations
@@ -1168,8 +1544,13 @@ pub async fn add(context: &Context, job: Job) {
             | Action::SendMdn
             | Action::SendMsgToSmtp => {
                 info!(context, "interrupt: smtp");
+                let reason = if action == Action::SendMsgToSmtp {
+                    InterruptReason::OutgoingMsg
+                } else {
+                    InterruptReason::Job
+                };
                 context
-                    .interrupt_smtp(InterruptInfo::new(false, None))
+                    .interrupt_smtp(InterruptInfo::new(false, None).with_reason(reason))
                     .await;
             }
         }
@@ -1182,6 +1563,43 @@ pub async fn add(context: &Context, job: Job) {
 /// IMAP jobs.  The `probe_network` parameter decides how to query
 /// jobs, this is tricky and probably wrong currently. Look at the
 /// SQL queries for details.
+/// Makes the jobs postponed with `Job::postpone()` due right away,
+/// e.g. when the network is not metered anymore.
+///
+/// Returns the number of resumed jobs.
+pub(crate) async fn resume_deferred_jobs(context: &Context) -> sql::Result<usize> {
+    let jobs = context
+        .sql
+        .query_map(
+            "SELECT id, param FROM jobs",
+            paramsv![],
+            |row| Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?)),
+            |rows| {
+                rows.collect::<std::result::Result<Vec<_>, _>>()
+                    .map_err(Into::into)
+            },
+        )
+        .await?;
+
+    let mut resumed = 0;
+    for (job_id, param) in jobs {
+        let mut param: Params = param.parse().unwrap_or_default();
+        if !param.exists(Param::Deferred) {
+            continue;
+        }
+        param.remove(Param::Deferred);
+        context
+            .sql
+            .execute(
+                "UPDATE jobs SET desired_timestamp=?, param=? WHERE id=?",
+                paramsv![time(), param.to_string(), job_id],
+            )
+            .await?;
+        resumed += 1;
+    }
+    Ok(resumed)
+}
+
 pub(crate) async fn load_next(
     context: &Context,
     thread: Thread,
@@ -1366,4 +1784,143 @@ mod tests {
         .await;
         assert!(jobs.is_some());
     }
+
+    #[async_std::test]
+    async fn test_sync_seen_to_server() {
+        let t = TestContext::new_alice().await;
+
+        let first_id = receive_simple_msg_in(&t.ctx, "first@example.com", "INBOX", 7).await;
+        let second_id = receive_simple_msg_in(&t.ctx, "second@example.com", "INBOX", 8).await;
+        t.ctx
+            .sql
+            .execute("UPDATE msgs SET ephemeral_timer=60", paramsv![])
+            .await
+            .unwrap();
+
+        // Option is disabled by default.
+        first_id.start_ephemeral_timer(&t.ctx).await.unwrap();
+        let (job_ids, batches) = load_seen_sync_batches(&t.ctx).await.unwrap();
+        assert!(job_ids.is_empty());
+        assert!(batches.is_empty());
+
+        t.ctx
+            .set_config(Config::SyncSeenToServer, Some("1"))
+            .await
+            .unwrap();
+        second_id.start_ephemeral_timer(&t.ctx).await.unwrap();
+        let (job_ids, batches) = load_seen_sync_batches(&t.ctx).await.unwrap();
+        assert_eq!(job_ids.len(), 1);
+        assert_eq!(batches.get("INBOX"), Some(&vec![8]));
+
+        // Timer is already running, no store is queued again.
+        second_id.start_ephemeral_timer(&t.ctx).await.unwrap();
+        let (job_ids, _) = load_seen_sync_batches(&t.ctx).await.unwrap();
+        assert_eq!(job_ids.len(), 1);
+    }
+
+    #[async_std::test]
+    async fn test_job_counts() {
+        let t = TestContext::new().await;
+        assert_eq!(t.ctx.job_counts().await.unwrap(), JobCounts::default());
+
+        insert_job(&t.ctx, 1).await;
+        insert_job(&t.ctx, 2).await;
+        add(&t.ctx, Job::new(Action::SendMdn, 1, Params::new(), 0)).await;
+
+        assert_eq!(
+            t.ctx.job_counts().await.unwrap(),
+            JobCounts {
+                imap: 2,
+                smtp: 1,
+                running: 0
+            }
+        );
+    }
+
+    #[async_std::test]
+    async fn test_give_up_smtp_job() {
+        let t = TestContext::new_alice().await;
+        let bob_id = crate::contact::Contact::create(&t.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        let chat_id = chat::create_by_contact_id(&t.ctx, bob_id).await.unwrap();
+        let msg_id = chat::send_text_msg(&t.ctx, chat_id, "hi".to_string())
+            .await
+            .unwrap();
+        assert_eq!(message::get_send_attempts(&t.ctx, msg_id).await.unwrap(), 0);
+
+        let mut job = load_next(&t.ctx, Thread::Smtp, &InterruptInfo::new(false, None))
+            .await
+            .unwrap();
+        assert_eq!(job.action, Action::SendMsgToSmtp);
+        assert_eq!(max_job_tries(&t.ctx, &job).await, 17);
+        t.ctx
+            .set_config(Config::SmtpMaxRetries, Some("2"))
+            .await
+            .unwrap();
+        assert_eq!(max_job_tries(&t.ctx, &job).await, 3);
+        assert_eq!(
+            max_job_tries(&t.ctx, &Job::new(Action::MoveMsg, 1, Params::new(), 0)).await,
+            JOB_RETRIES
+        );
+
+        job.tries = 2;
+        job.save(&t.ctx).await.unwrap();
+        assert_eq!(message::get_send_attempts(&t.ctx, msg_id).await.unwrap(), 2);
+
+        job.pending_error = Some("550 rejected".to_string());
+        give_up_job(&t.ctx, &mut job).await;
+        let msg = Message::load_from_db(&t.ctx, msg_id).await.unwrap();
+        assert_eq!(msg.state, MessageState::OutFailed);
+        assert_eq!(msg.error(), Some("550 rejected".to_string()));
+    }
+
+    #[async_std::test]
+    async fn test_record_move() {
+        use std::sync::{Arc, Mutex};
+
+        let t = TestContext::new().await;
+        let moved_events = Arc::new(Mutex::new(Vec::new()));
+        let events = moved_events.clone();
+        let event_id = EventType::MsgMovedToMvbox {
+            msg_id: MsgId::new(0),
+            from_folder: String::new(),
+        }
+        .as_id();
+        t.ctx.on_event_type(event_id, move |event| {
+            if let EventType::MsgMovedToMvbox {
+                msg_id,
+                from_folder,
+            } = event.typ
+            {
+                events.lock().unwrap().push((msg_id, from_folder));
+            }
+        });
+
+        assert!(t.ctx.recently_moved_messages(10).await.is_empty());
+        record_move(&t.ctx, MsgId::new(10), "INBOX", "DeltaChat").await;
+        record_move(&t.ctx, MsgId::new(11), "Spam", "DeltaChat").await;
+        assert_eq!(
+            *moved_events.lock().unwrap(),
+            vec![
+                (MsgId::new(10), "INBOX".to_string()),
+                (MsgId::new(11), "Spam".to_string())
+            ]
+        );
+
+        let moves = t.ctx.recently_moved_messages(10).await;
+        assert_eq!(moves.len(), 2);
+        assert_eq!(moves[0].msg_id, MsgId::new(11));
+        assert_eq!(moves[0].from_folder, "Spam");
+        assert_eq!(moves[1].msg_id, MsgId::new(10));
+        assert_eq!(moves[1].to_folder, "DeltaChat");
+        assert_eq!(t.ctx.recently_moved_messages(1).await.len(), 1);
+
+        for i in 0..MAX_RECENT_MOVES as u32 {
+            record_move(&t.ctx, MsgId::new(100 + i), "INBOX", "DeltaChat").await;
+        }
+        let moves = t.ctx.recently_moved_messages(usize::MAX).await;
+        assert_eq!(moves.len(), MAX_RECENT_MOVES);
+        assert_eq!(moves[0].msg_id, MsgId::new(99 + MAX_RECENT_MOVES as u32));
+    }
 }
@@ -72,7 +72,9 @@ pub mod qr;
 pub mod securejoin;
 mod simplify;
 mod smtp;
+mod socks;
 pub mod stock;
+pub mod storage;
 mod token;
 #[macro_use]
 mod dehtml;
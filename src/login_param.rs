@@ -3,7 +3,9 @@
 use std::borrow::Cow;
 use std::fmt;
 
-use crate::{context::Context, provider::Socket};
+use crate::constants::DC_LP_AUTH_OAUTH2;
+use crate::context::Context;
+use crate::provider::{get_provider_info, Socket};
 
 #[derive(Copy, Clone, Debug, Display, FromPrimitive, PartialEq, Eq)]
 #[repr(i32)]
@@ -42,6 +44,34 @@ pub struct ServerLoginParam {
     pub certificate_checks: CertificateChecks,
 }
 
+impl ServerLoginParam {
+    /// Returns whether certificates and hostnames are checked strictly
+    /// when connecting to this server.
+    ///
+    /// `addr` is used to look up the provider default if
+    /// `certificate_checks` is `Automatic`.
+    pub fn strict_tls(&self, addr: &str) -> bool {
+        match self.certificate_checks {
+            CertificateChecks::Automatic => {
+                get_provider_info(addr).map_or(false, |provider| provider.strict_tls)
+            }
+            CertificateChecks::Strict => true,
+            CertificateChecks::AcceptInvalidCertificates
+            | CertificateChecks::AcceptInvalidCertificates2 => false,
+        }
+    }
+
+    fn summary(&self, addr: &str) -> ServerSummary {
+        ServerSummary {
+            server: self.server.clone(),
+            user: self.user.clone(),
+            port: self.port,
+            security: self.security,
+            strict_tls: self.strict_tls(addr),
+        }
+    }
+}
+
 #[derive(Default, Debug, Clone)]
 pub struct LoginParam {
     pub addr: String,
@@ -220,6 +250,64 @@ impl LoginParam {
     }
 }
 
+/// Authentication method used for both IMAP and SMTP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMethod {
+    Password,
+    OAuth2,
+}
+
+/// Settings actually used to connect to a single server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerSummary {
+    pub server: String,
+    pub user: String,
+    pub port: u16,
+    pub security: Socket,
+
+    /// Whether invalid certificates and hostnames are rejected,
+    /// with the provider default already applied.
+    pub strict_tls: bool,
+}
+
+/// Summary of the configured login parameters, without any secrets.
+///
+/// Unlike [LoginParam] this is safe to show in the UI or to log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginParamsSummary {
+    pub addr: String,
+    pub imap: ServerSummary,
+    pub smtp: ServerSummary,
+    pub auth: AuthMethod,
+}
+
+impl From<&LoginParam> for LoginParamsSummary {
+    fn from(lp: &LoginParam) -> Self {
+        LoginParamsSummary {
+            addr: lp.addr.clone(),
+            imap: lp.imap.summary(&lp.addr),
+            smtp: lp.smtp.summary(&lp.addr),
+            auth: if lp.server_flags & DC_LP_AUTH_OAUTH2 != 0 {
+                AuthMethod::OAuth2
+            } else {
+                AuthMethod::Password
+            },
+        }
+    }
+}
+
+impl Context {
+    /// Returns the login parameters IMAP and SMTP connections use,
+    /// with passwords left out.
+    ///
+    /// These are the `configured_` values written by a successful
+    /// configure, not the values entered by the user.
+    pub async fn effective_login_params(&self) -> LoginParamsSummary {
+        let lp = LoginParam::from_database(self, "configured_").await;
+        LoginParamsSummary::from(&lp)
+    }
+}
+
 impl fmt::Display for LoginParam {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let unset = "0";
@@ -305,6 +393,9 @@ pub fn dc_build_tls(strict_tls: bool) -> async_native_tls::TlsConnector {
 mod tests {
     use super::*;
 
+    use crate::config::Config;
+    use crate::test_utils::TestContext;
+
     #[test]
     fn test_certificate_checks_display() {
         use std::string::ToString;
@@ -314,4 +405,48 @@ mod tests {
             CertificateChecks::AcceptInvalidCertificates.to_string()
         );
     }
+
+    #[async_std::test]
+    async fn test_effective_login_params() {
+        let t = TestContext::new().await;
+        let lp = LoginParam {
+            addr: "alice@example.org".to_string(),
+            imap: ServerLoginParam {
+                server: "imap.example.org".to_string(),
+                user: "alice".to_string(),
+                password: "imap-secret".to_string(),
+                port: 993,
+                security: Socket::SSL,
+                certificate_checks: CertificateChecks::Strict,
+            },
+            smtp: ServerLoginParam {
+                server: "smtp.example.org".to_string(),
+                user: "alice@example.org".to_string(),
+                password: "smtp-secret".to_string(),
+                port: 587,
+                security: Socket::STARTTLS,
+                certificate_checks: CertificateChecks::AcceptInvalidCertificates,
+            },
+            server_flags: DC_LP_AUTH_OAUTH2,
+        };
+        lp.save_to_database(&t.ctx, "configured_").await.unwrap();
+        t.ctx
+            .set_config(Config::Configured, Some("1"))
+            .await
+            .unwrap();
+
+        let summary = t.ctx.effective_login_params().await;
+        assert_eq!(summary.addr, "alice@example.org");
+        assert_eq!(summary.imap.server, "imap.example.org");
+        assert_eq!(summary.imap.user, "alice");
+        assert_eq!(summary.imap.port, 993);
+        assert_eq!(summary.imap.security, Socket::SSL);
+        assert!(summary.imap.strict_tls);
+        assert_eq!(summary.smtp.server, "smtp.example.org");
+        assert_eq!(summary.smtp.port, 587);
+        assert_eq!(summary.smtp.security, Socket::STARTTLS);
+        assert!(!summary.smtp.strict_tls);
+        assert_eq!(summary.auth, AuthMethod::OAuth2);
+        assert!(!format!("{:?}", summary).contains("secret"));
+    }
 }
@@ -6,11 +6,12 @@ use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
 use crate::chat::{self, Chat, ChatId};
-use crate::config::Config;
+use crate::config::{Config, FolderKind};
 use crate::constants::*;
 use crate::contact::*;
 use crate::context::*;
 use crate::dc_tools::*;
+use crate::ephemeral::Timer as EphemeralTimer;
 use crate::error::{ensure, Error};
 use crate::events::EventType;
 use crate::job::{self, Action};
@@ -150,6 +151,39 @@ impl MsgId {
         Ok(())
     }
 
+    /// Deletes an own message for everyone in the chat.
+    ///
+    /// Sends a hidden delete request referencing the message, so
+    /// recipients trash their copies on receipt, and then deletes the
+    /// message locally and from the server.
+    pub async fn request_delete_for_everyone(self, context: &Context) -> Result<(), Error> {
+        let msg = Message::load_from_db(context, self).await?;
+        ensure!(
+            msg.from_id == DC_CONTACT_ID_SELF,
+            "Only own messages can be deleted for everyone"
+        );
+        ensure!(
+            !msg.chat_id.is_special(),
+            "Message {} is not in a chat",
+            self
+        );
+        ensure!(
+            !msg.rfc724_mid.is_empty(),
+            "Message {} has no Message-ID",
+            self
+        );
+
+        let mut request = Message::new(Viewtype::Text);
+        request.text = Some("Delete request".to_string());
+        request.hidden = true;
+        request.param.set_cmd(SystemMessage::DeleteRequest);
+        request.param.set(Param::Arg, &msg.rfc724_mid);
+        chat::send_msg(context, msg.chat_id, &mut request).await?;
+
+        delete_msgs(context, &[self]).await;
+        Ok(())
+    }
+
     /// Bad evil escape hatch.
     ///
     /// Avoid using this, eventually types should be cleaned up enough
@@ -523,10 +557,34 @@ impl Message {
         self.param.get_int(Param::GuaranteeE2ee).unwrap_or_default() != 0
     }
 
+    /// Returns the ephemeral timer of the message in seconds.
+    ///
+    /// This is the timer of the chat when the message was sent or
+    /// received, unless it was overridden for this message.
     pub fn get_ephemeral_timer(&self) -> u32 {
         self.ephemeral_timer
     }
 
+    /// Sets an ephemeral timer for this message only, taking precedence
+    /// over the timer of the chat.
+    ///
+    /// Must be called before the message is sent. The timer is sent
+    /// along, so recipients apply it to the message, but not to the chat.
+    /// Sending fails if the timer is longer than `MAX_TIMER_DURATION` or
+    /// not shorter than the timer of the chat.
+    pub fn set_ephemeral_timer(&mut self, timer: EphemeralTimer) {
+        self.param
+            .set(Param::EphemeralTimerOverride, timer.to_u32().to_string());
+        self.ephemeral_timer = timer.to_u32();
+    }
+
+    /// Returns the timer set with `Message::set_ephemeral_timer()`.
+    pub fn get_ephemeral_timer_override(&self) -> Option<EphemeralTimer> {
+        self.param
+            .get(Param::EphemeralTimerOverride)
+            .and_then(|timer| timer.parse().ok())
+    }
+
     pub fn get_ephemeral_timestamp(&self) -> i64 {
         self.ephemeral_timestamp
     }
@@ -1492,6 +1550,19 @@ pub async fn exists(context: &Context, msg_id: MsgId) -> bool {
     }
 }
 
+/// Returns the number of failed attempts to send the message,
+/// 0 if it is not waiting to be sent.
+pub async fn get_send_attempts(context: &Context, msg_id: MsgId) -> crate::sql::Result<u32> {
+    let tries: Option<u32> = context
+        .sql
+        .query_get_value_result(
+            "SELECT tries FROM jobs WHERE action=? AND foreign_id=? ORDER BY tries DESC LIMIT 1",
+            paramsv![Action::SendMsgToSmtp, msg_id],
+        )
+        .await?;
+    Ok(tries.unwrap_or_default())
+}
+
 pub async fn set_msg_failed(context: &Context, msg_id: MsgId, error: Option<impl AsRef<str>>) {
     if let Ok(mut msg) = Message::load_from_db(context, msg_id).await {
         let error = error.map(|e| e.as_ref().to_string()).unwrap_or_default();
@@ -1863,6 +1934,55 @@ pub async fn update_server_uid(
     }
 }
 
+/// Updates the server location of an already known message.
+///
+/// A copy in the Sent folder is tracked in addition to the copy in
+/// another folder, e.g. the BCC-self copy in the inbox, so both
+/// copies can be deleted from the server. In all other cases the
+/// message is assumed to be moved and the location is replaced.
+pub(crate) async fn add_server_copy(
+    context: &Context,
+    rfc724_mid: &str,
+    old_server_folder: &str,
+    old_server_uid: u32,
+    server_folder: &str,
+    server_uid: u32,
+) {
+    let sentbox = context.get_folder(FolderKind::Sentbox).await;
+    let is_sentbox = |folder: &str| sentbox.as_deref() == Some(folder);
+
+    let new_sentbox_copy =
+        old_server_uid != 0 && is_sentbox(server_folder) && !is_sentbox(old_server_folder);
+    let old_sentbox_copy =
+        old_server_uid != 0 && is_sentbox(old_server_folder) && !is_sentbox(server_folder);
+
+    let res = if new_sentbox_copy {
+        context
+            .sql
+            .execute(
+                "UPDATE msgs SET sentbox_uid=? WHERE rfc724_mid=?",
+                paramsv![server_uid, rfc724_mid],
+            )
+            .await
+    } else if old_sentbox_copy {
+        context
+            .sql
+            .execute(
+                "UPDATE msgs SET server_folder=?, server_uid=?, sentbox_uid=? \
+                 WHERE rfc724_mid=?",
+                paramsv![server_folder, server_uid, old_server_uid, rfc724_mid],
+            )
+            .await
+    } else {
+        update_server_uid(context, rfc724_mid, server_folder, server_uid).await;
+        return;
+    };
+
+    if let Err(err) = res {
+        warn!(context, "msg: failed to add server copy: {}", err);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -2130,4 +2250,43 @@ mod tests {
             .expect("quoted message not found");
         assert!(quoted_msg.get_text() == msg2.quoted_text());
     }
+
+    #[async_std::test]
+    async fn test_request_delete_for_everyone() {
+        let alice = test::TestContext::new_alice().await;
+        let bob = test::TestContext::new_bob().await;
+
+        let contact_id = Contact::create(&alice.ctx, "Bob", "bob@example.net")
+            .await
+            .unwrap();
+        let chat_id = chat::create_by_contact_id(&alice.ctx, contact_id)
+            .await
+            .unwrap();
+        let msg_id = chat::send_text_msg(&alice.ctx, chat_id, "hi".to_string())
+            .await
+            .unwrap();
+        let rfc724_mid = Message::load_from_db(&alice.ctx, msg_id)
+            .await
+            .unwrap()
+            .rfc724_mid;
+        bob.recv_msg(&alice.pop_sent_msg().await).await;
+
+        let (_, _, bob_msg_id) = rfc724_mid_exists(&bob.ctx, &rfc724_mid)
+            .await
+            .unwrap()
+            .expect("bob did not receive the message");
+        let bob_msg = Message::load_from_db(&bob.ctx, bob_msg_id).await.unwrap();
+        assert!(!bob_msg.chat_id.is_trash());
+
+        msg_id
+            .request_delete_for_everyone(&alice.ctx)
+            .await
+            .unwrap();
+        let alice_msg = Message::load_from_db(&alice.ctx, msg_id).await.unwrap();
+        assert!(alice_msg.chat_id.is_trash());
+
+        bob.recv_msg(&alice.pop_sent_msg().await).await;
+        let bob_msg = Message::load_from_db(&bob.ctx, bob_msg_id).await.unwrap();
+        assert!(bob_msg.chat_id.is_trash());
+    }
 }
@@ -523,8 +523,18 @@ impl<'a, 'b> MimeFactory<'a, 'b> {
             Loaded::MDN { .. } => dc_create_outgoing_rfc724_mid(None, &self.from_addr),
         };
 
-        let ephemeral_timer = self.msg.chat_id.get_ephemeral_timer(self.context).await?;
-        if let EphemeralTimer::Enabled { duration } = ephemeral_timer {
+        let ephemeral_timer = match self.msg.get_ephemeral_timer_override() {
+            Some(timer) => {
+                // Let the recipients apply the timer to this message only.
+                protected_headers.push(Header::new(
+                    "Chat-Ephemeral-Timer-Override".to_string(),
+                    "1".to_string(),
+                ));
+                timer
+            }
+            None => self.msg.chat_id.get_ephemeral_timer(self.context).await?,
+        };
+        if let EphemeralTimer::Enabled { duration, .. } = ephemeral_timer {
             protected_headers.push(Header::new(
                 "Ephemeral-Timer".to_string(),
                 duration.to_string(),
@@ -858,6 +868,18 @@ impl<'a, 'b> MimeFactory<'a, 'b> {
                     "protection-disabled".to_string(),
                 ));
             }
+            SystemMessage::DeleteRequest => {
+                protected_headers.push(Header::new(
+                    "Chat-Content".to_string(),
+                    "delete-request".to_string(),
+                ));
+                if let Some(rfc724_mid) = self.msg.param.get(Param::Arg) {
+                    protected_headers.push(Header::new(
+                        "Chat-Delete-Request".to_string(),
+                        render_rfc724_mid(rfc724_mid),
+                    ));
+                }
+            }
             _ => {}
         }
 
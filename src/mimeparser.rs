@@ -15,6 +15,7 @@ use crate::context::Context;
 use crate::dc_tools::*;
 use crate::dehtml::dehtml;
 use crate::e2ee;
+use crate::ephemeral::Timer as EphemeralTimer;
 use crate::error::{bail, Result};
 use crate::events::EventType;
 use crate::format_flowed::unformat_flowed;
@@ -27,6 +28,9 @@ use crate::peerstate::Peerstate;
 use crate::simplify::*;
 use crate::stock::StockMessage;
 
+/// Error of messages which could not be decrypted.
+pub(crate) const DECRYPTION_FAILED_ERROR: &str = "Decryption failed";
+
 /// A parsed MIME message.
 ///
 /// This represents the relevant information of a parsed MIME message
@@ -90,6 +94,9 @@ pub enum SystemMessage {
     // Chat protection state changed
     ChatProtectionEnabled = 11,
     ChatProtectionDisabled = 12,
+
+    /// Sender requests deletion of one of their messages for everyone.
+    DeleteRequest = 13,
 }
 
 impl Default for SystemMessage {
@@ -263,6 +270,8 @@ impl MimeMessage {
                 self.is_system_message = SystemMessage::ChatProtectionEnabled;
             } else if value == "protection-disabled" {
                 self.is_system_message = SystemMessage::ChatProtectionDisabled;
+            } else if value == "delete-request" {
+                self.is_system_message = SystemMessage::DeleteRequest;
             }
         }
         Ok(())
@@ -603,7 +612,7 @@ impl MimeMessage {
                 part.typ = Viewtype::Text;
                 part.msg_raw = Some(txt.clone());
                 part.msg = txt;
-                part.error = Some("Decryption failed".to_string());
+                part.error = Some(DECRYPTION_FAILED_ERROR.to_string());
 
                 self.parts.push(part);
 
@@ -902,6 +911,20 @@ impl MimeMessage {
                         }
                         Err(e) => warn!(context, "Could not read {} address: {}", key, e),
                     }
+                } else if key == HeaderDef::EphemeralTimer.get_headername() {
+                    let values = fields.get_all_values(&key);
+                    if values.len() > 1 {
+                        warn!(
+                            context,
+                            "Message has {} {} headers, using the first valid one.",
+                            values.len(),
+                            field.get_key()
+                        );
+                    }
+                    let value = EphemeralTimer::from_header_values(values.iter().map(|v| &v[..]))
+                        .map(|timer| timer.to_string())
+                        .unwrap_or_else(|| field.get_value());
+                    headers.insert(key.to_string(), value);
                 } else {
                     let value = field.get_value();
                     headers.insert(key.to_string(), value);
@@ -1463,6 +1486,27 @@ mod tests {
         );
     }
 
+    #[async_std::test]
+    async fn test_duplicate_ephemeral_timer_headers() {
+        let context = TestContext::new().await;
+        let raw = b"From: hello@one.org\n\
+                    Ephemeral-Timer: forever\n\
+                    Ephemeral-Timer: 60\n\
+                    Ephemeral-Timer: 3600\n\
+                    Content-Type: text/plain\n\
+                    Chat-Version: 1.0\n\
+                    \n\
+                    test1\n\
+                    ";
+        let mimeparser = MimeMessage::from_bytes(&context.ctx, &raw[..])
+            .await
+            .unwrap();
+        assert_eq!(
+            mimeparser.get(HeaderDef::EphemeralTimer),
+            Some(&"60".to_string())
+        );
+    }
+
     #[async_std::test]
     async fn test_parse_first_addr() {
         let context = TestContext::new().await;
@@ -132,6 +132,39 @@ pub enum Param {
 
     /// For MDN-sending job
     MsgId = b'I',
+
+    /// For batch jobs: space-separated message IDs
+    MsgIds = b'X',
+
+    /// For Messages: remaining seconds of a paused ephemeral timer
+    EphemeralRemaining = b'T',
+
+    /// For Messages: ephemeral timer overriding the timer of the chat
+    EphemeralTimerOverride = b'O',
+
+    /// For Chats: timestamp of the last applied ephemeral timer change
+    EphemeralTimerTimestamp = b'j',
+
+    /// For Chats: sender of the last applied ephemeral timer change
+    EphemeralTimerSender = b'J',
+
+    /// For Chats: timestamp of the last own ephemeral timer change
+    /// which was re-sent to a member not knowing it
+    EphemeralTimerResent = b'C',
+
+    /// For Messages: delete the message right after it is seen
+    BurnAfterReading = b'B',
+
+    /// For Chats: ephemeral timer replaced by the last own ephemeral
+    /// timer change
+    EphemeralTimerPrevious = b'p',
+
+    /// For Chats: seconds of the unit the ephemeral timer was chosen in
+    EphemeralTimerUnit = b'N',
+
+    /// For Jobs: the job is deferred while the network is metered or
+    /// during quiet hours
+    Deferred = b'Z',
 }
 
 /// An object for handling key=value parameter lists.
@@ -1,12 +1,23 @@
+use std::cmp;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use async_std::future;
 use async_std::prelude::*;
-use async_std::sync::{channel, Receiver, Sender};
+use async_std::sync::{channel, Arc, MutexGuard, Receiver, RwLock, Sender};
 use async_std::task;
+use chrono::{Local, NaiveTime, Timelike};
+use rand::Rng;
 
-use crate::context::Context;
+use crate::config::{Config, FolderKind};
+use crate::context::{ConnectionCacheStats, ConnectionStatus, Context, SchedulerStatus};
 use crate::dc_tools::maybe_add_time_based_warnings;
+use crate::error::{ensure, Result};
+use crate::events::{Connectivity, EventType};
 use crate::imap::Imap;
-use crate::job::{self, Thread};
-use crate::{config::Config, message::MsgId, smtp::Smtp};
+use crate::job::{self, Action, Thread};
+use crate::param::Param;
+use crate::{message::MsgId, smtp::Smtp};
 
 pub(crate) struct StopToken;
 
@@ -24,22 +35,579 @@ pub(crate) enum Scheduler {
         sentbox_handle: Option<task::JoinHandle<()>>,
         smtp: SmtpConnectionState,
         smtp_handle: Option<task::JoinHandle<()>>,
+        /// Loops for folders added with `Context::add_watched_folder`.
+        watched: Vec<WatchedFolderLoop>,
+        /// Coalesces calls to `Context::maybe_network()`.
+        network_debounce: std::sync::Mutex<Debounce>,
     },
 }
 
+/// Raw config key storing the list of additionally watched folders,
+/// separated by newlines.
+const WATCHED_FOLDERS_KEY: &str = "imap.watched_folders";
+
+/// Raw config key storing the quiet hours as local times,
+/// e.g. "22:00-07:00", see `Context::set_quiet_hours`.
+const QUIET_HOURS_KEY: &str = "quiet_hours";
+
+/// Format of the times stored in `QUIET_HOURS_KEY`.
+const QUIET_HOURS_FORMAT: &str = "%H:%M";
+
+/// Interval between fetches of folders sharing the inbox connection,
+/// see `Config::MaxConnections`.
+const ROTATION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Interval between fetches while the network is metered,
+/// see `Context::set_metered`.
+const METERED_POLL_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Initial interval between fetches while fake-IDLEing. It doubles after
+/// each fetch without new messages, up to `Config::FakeIdleMaxInterval`.
+const FAKE_IDLE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Interval between fetches while fake-IDLEing shortly after sending
+/// a message, see `Config::BoostAfterSend`.
+const BOOSTED_FAKE_IDLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Delay before waiting for SMTP interrupts again if the interrupt
+/// channel is closed. A random jitter of up to the same duration is
+/// added.
+const SMTP_INTERRUPT_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Delay before reconnecting after the first failed connection
+/// attempt. It is doubled with each further failure.
+const RECONNECT_BACKOFF_BASE: Duration = Duration::from_secs(1);
+
+/// Upper limit for the delay between reconnection attempts.
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5 * 60);
+
+/// Calls to `Context::maybe_network()` within this time after the loops
+/// were interrupted are coalesced into one call at the end of the
+/// window, as some platforms report network changes several times in
+/// a row.
+const MAYBE_NETWORK_DEBOUNCE: Duration = Duration::from_secs(1);
+
+/// Upper limit for `Config::InboxJobBatchSize`.
+const MAX_INBOX_JOB_BATCH_SIZE: i32 = 200;
+
+/// Trailing-edge debouncing of events carrying a `metered` flag.
+#[derive(Debug, Default)]
+pub(crate) struct Debounce {
+    /// Time and `metered` flag of the last handled event.
+    last: Option<(Instant, bool)>,
+    /// Whether an event is scheduled at the end of the window.
+    trailing: bool,
+}
+
+/// What to do with an event, see `Debounce::on_event()`.
+#[derive(Debug, PartialEq, Eq)]
+enum Debounced {
+    /// Handle the event now.
+    Now,
+    /// Handle the event after the delay, at the end of the window.
+    Later(Duration),
+    /// The event is covered by an already scheduled one.
+    Pending,
+}
+
+impl Debounce {
+    /// Returns what to do with an event at `now`.
+    ///
+    /// Events within `window` after the last handled one are coalesced
+    /// into one event at the end of the window, so the last event is
+    /// never lost. Events changing `metered` are handled right away.
+    fn on_event(&mut self, now: Instant, metered: bool, window: Duration) -> Debounced {
+        if let Some((last, last_metered)) = self.last {
+            let elapsed = now.saturating_duration_since(last);
+            if elapsed < window && last_metered == metered {
+                if self.trailing {
+                    return Debounced::Pending;
+                }
+                self.trailing = true;
+                return Debounced::Later(window - elapsed);
+            }
+        }
+        self.last = Some((now, metered));
+        Debounced::Now
+    }
+
+    /// Records that the scheduled event is handled at `now`.
+    fn fire_trailing(&mut self, now: Instant, metered: bool) {
+        self.trailing = false;
+        self.last = Some((now, metered));
+    }
+}
+
+/// Folder watched by a `simple_imap_loop`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum WatchFolder {
+    /// Logical folder, resolved with `Context::get_folder()`.
+    Configured(FolderKind),
+    /// Arbitrary folder added by the user.
+    Custom(String),
+}
+
+impl std::fmt::Display for WatchFolder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatchFolder::Configured(kind) => write!(f, "{}", kind),
+            WatchFolder::Custom(folder) => write!(f, "{}", folder),
+        }
+    }
+}
+
+/// Connection state of a loop watching an additional folder.
+#[derive(Debug)]
+pub(crate) struct WatchedFolderLoop {
+    folder: String,
+    state: ImapConnectionState,
+    handle: Option<task::JoinHandle<()>>,
+}
+
 impl Context {
     /// Indicate that the network likely has come back.
+    ///
+    /// While the network is metered or during quiet hours, only due
+    /// messages are sent, folders are not fetched.
     pub async fn maybe_network(&self) {
-        self.scheduler.read().await.maybe_network().await;
+        let metered = self.is_metered().await || self.quiet_hours_remaining().await.is_some();
+        let debounced = match &*self.scheduler.read().await {
+            Scheduler::Running {
+                network_debounce, ..
+            } => network_debounce.lock().unwrap().on_event(
+                Instant::now(),
+                metered,
+                MAYBE_NETWORK_DEBOUNCE,
+            ),
+            Scheduler::Stopped => return,
+        };
+        match debounced {
+            Debounced::Now => self.scheduler.read().await.maybe_network(metered).await,
+            Debounced::Later(delay) => {
+                let ctx = self.clone();
+                self.spawn_task("maybe_network", async move {
+                    task::sleep(delay).await;
+                    let metered =
+                        ctx.is_metered().await || ctx.quiet_hours_remaining().await.is_some();
+                    let scheduler = ctx.scheduler.read().await;
+                    if let Scheduler::Running {
+                        network_debounce, ..
+                    } = &*scheduler
+                    {
+                        network_debounce
+                            .lock()
+                            .unwrap()
+                            .fire_trailing(Instant::now(), metered);
+                    }
+                    scheduler.maybe_network(metered).await;
+                });
+            }
+            Debounced::Pending => {}
+        }
+    }
+
+    /// Sets whether the network connection is metered.
+    ///
+    /// While metered, IMAP connections do not IDLE but only poll every
+    /// 15 minutes or when interrupted, and SMTP jobs other than sending
+    /// messages are deferred. Normal operation resumes when unmetered.
+    pub async fn set_metered(&self, metered: bool) {
+        if self.metered.swap(metered, Ordering::SeqCst) == metered {
+            return;
+        }
+        info!(self, "network metered: {}", metered);
+
+        if !metered {
+            // Do not wait for the end of the deferral.
+            match job::resume_deferred_jobs(self).await {
+                Ok(resumed) => info!(self, "resumed {} deferred jobs", resumed),
+                Err(err) => warn!(self, "failed to resume deferred jobs: {}", err),
+            }
+        }
+
+        // Wake up the loops so they switch between IDLE and polling.
+        self.scheduler.read().await.config_changed().await;
+    }
+
+    /// Returns whether the network connection is metered,
+    /// see `Context::set_metered`.
+    pub async fn is_metered(&self) -> bool {
+        self.metered.load(Ordering::SeqCst)
+    }
+
+    /// Pauses IO, e.g. when the app goes to background.
+    ///
+    /// The loops stop IDLE, fetching and executing jobs, but keep their
+    /// connections, so `resume_io()` does not need to reconnect. Has no
+    /// effect on the loops if IO is not running, but they start paused
+    /// until `resume_io()` is called.
+    pub async fn pause_io(&self) {
+        {
+            let paused = &mut *self.io_paused.write().await;
+            if *paused {
+                return;
+            }
+            *paused = true;
+        }
+        info!(self, "pausing IO");
+
+        // Wake up the loops so they leave IDLE and park.
+        self.scheduler.read().await.config_changed().await;
+    }
+
+    /// Resumes IO after `pause_io()`.
+    pub async fn resume_io(&self) {
+        {
+            let paused = &mut *self.io_paused.write().await;
+            if !*paused {
+                return;
+            }
+            *paused = false;
+        }
+        info!(self, "resuming IO");
+
+        self.scheduler.read().await.config_changed().await;
+    }
+
+    /// Returns whether IO is paused, see `Context::pause_io`.
+    pub async fn is_io_paused(&self) -> bool {
+        *self.io_paused.read().await
+    }
+
+    /// Returns whether the SMTP loop is executing a job right now,
+    /// e.g. sending a message.
+    ///
+    /// Unlike job counts, this is only true while a job is actually
+    /// in flight, so it can be used for a send indicator or to check
+    /// whether it is safe to shut down.
+    pub fn smtp_is_sending(&self) -> bool {
+        self.smtp_sending.load(Ordering::SeqCst)
+    }
+
+    /// Sets quiet hours from `start` to `end` local time, e.g. from
+    /// 22:00 to 07:00 during the night.
+    ///
+    /// During quiet hours the IMAP loops are parked and SMTP jobs other
+    /// than sending messages are deferred, to save battery. IO resumes
+    /// at the end of the window. Equal `start` and `end` disable quiet
+    /// hours.
+    pub async fn set_quiet_hours(&self, start: NaiveTime, end: NaiveTime) -> Result<()> {
+        let value = if start == end {
+            None
+        } else {
+            Some(format!(
+                "{}-{}",
+                start.format(QUIET_HOURS_FORMAT),
+                end.format(QUIET_HOURS_FORMAT)
+            ))
+        };
+        self.sql
+            .set_raw_config(self, QUIET_HOURS_KEY, value.as_deref())
+            .await?;
+
+        // Wake up the loops so they park or resume.
+        self.scheduler.read().await.config_changed().await;
+        Ok(())
+    }
+
+    /// Returns the quiet hours set with `set_quiet_hours()` as start
+    /// and end local time, `None` if they are disabled.
+    pub async fn get_quiet_hours(&self) -> Option<(NaiveTime, NaiveTime)> {
+        let value = self.sql.get_raw_config(self, QUIET_HOURS_KEY).await?;
+        let mut parts = value.splitn(2, '-');
+        let start = NaiveTime::parse_from_str(parts.next()?, QUIET_HOURS_FORMAT).ok()?;
+        let end = NaiveTime::parse_from_str(parts.next()?, QUIET_HOURS_FORMAT).ok()?;
+        Some((start, end))
+    }
+
+    /// Returns the time until the quiet hours end, `None` outside of
+    /// quiet hours.
+    pub(crate) async fn quiet_hours_remaining(&self) -> Option<Duration> {
+        self.quiet_hours_remaining_at(Local::now().time()).await
+    }
+
+    /// Same as `quiet_hours_remaining()`, but at local time `now`.
+    async fn quiet_hours_remaining_at(&self, now: NaiveTime) -> Option<Duration> {
+        let (start, end) = self.get_quiet_hours().await?;
+        quiet_hours_remaining(start, end, now)
+    }
+
+    /// Checks all watched folders for new messages now, e.g. when the
+    /// user asks to refresh.
+    pub async fn fetch_now(&self) {
+        self.scheduler.read().await.fetch_now().await;
     }
 
-    pub(crate) async fn interrupt_inbox(&self, info: InterruptInfo) {
-        self.scheduler.read().await.interrupt_inbox(info).await;
+    /// Returns how long the IMAP connections currently wait before
+    /// trying to reconnect, zero if all connections are connected.
+    ///
+    /// If several connections fail, the longest delay is returned.
+    pub async fn get_reconnect_backoff(&self) -> Duration {
+        self.reconnect_backoff
+            .read()
+            .await
+            .values()
+            .max()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    async fn clear_reconnect_backoff(&self, folder: &str) {
+        self.reconnect_backoff.write().await.remove(folder);
+    }
+
+    /// Lets fake-IDLE poll more often for a while after a message was
+    /// sent, see `Config::BoostAfterSend`.
+    pub(crate) async fn boost_fetch_after_send(&self) {
+        let window = self.get_config_int(Config::BoostAfterSend).await;
+        if window <= 0 {
+            return;
+        }
+        let until = Instant::now() + Duration::from_secs(window as u64);
+        *self.fetch_boost_until.write().await = Some(until);
+        info!(self, "boosting fetch for {}s after sending", window);
+
+        // Wake up the inbox loop so it starts polling with the shorter
+        // interval right away.
+        self.scheduler
+            .read()
+            .await
+            .interrupt_inbox(InterruptInfo::new(false, None))
+            .await;
+    }
+
+    /// Drops established IMAP and SMTP connections, e.g. after the
+    /// server certificate was rotated.
+    ///
+    /// The next connection attempts connect and log in again instead of
+    /// reusing the established connections.
+    pub async fn clear_connection_caches(&self) {
+        self.connection_cache.write().await.cleared += 1;
+        info!(self, "connection caches cleared");
+
+        // Wake up the loops so they reconnect right away.
+        self.scheduler.read().await.config_changed().await;
+    }
+
+    /// Returns statistics about reused IMAP and SMTP connections.
+    pub async fn connection_cache_stats(&self) -> ConnectionCacheStats {
+        *self.connection_cache.read().await
+    }
+
+    /// Returns the number of times the connection caches were cleared,
+    /// connections established before must not be reused.
+    pub(crate) async fn connection_cache_generation(&self) -> u64 {
+        self.connection_cache.read().await.cleared
+    }
+
+    /// Counts a reused or a newly established connection for
+    /// `connection_cache_stats()`. Failed connection attempts are not
+    /// counted.
+    pub(crate) async fn count_connection(&self, reused: bool) {
+        let stats = &mut *self.connection_cache.write().await;
+        if reused {
+            stats.reused += 1;
+        } else {
+            stats.connected += 1;
+        }
+    }
+
+    /// Returns the interval before the first fetch while fake-IDLEing.
+    pub(crate) async fn fake_idle_interval(&self) -> Duration {
+        let interval = fake_idle_interval(*self.fetch_boost_until.read().await, Instant::now());
+        cmp::min(interval, self.fake_idle_max_interval().await)
+    }
+
+    /// Returns the interval before the next fetch while fake-IDLEing
+    /// after a fetch without new messages, `current` being the interval
+    /// before that fetch.
+    pub(crate) async fn next_fake_idle_interval(&self, current: Duration) -> Duration {
+        let boosted = self.fake_idle_interval().await;
+        if boosted < FAKE_IDLE_INTERVAL {
+            return boosted;
+        }
+        next_fake_idle_interval(current, self.fake_idle_max_interval().await)
+    }
+
+    async fn fake_idle_max_interval(&self) -> Duration {
+        let secs = self.get_config_int(Config::FakeIdleMaxInterval).await;
+        Duration::from_secs(cmp::max(secs, 1) as u64)
+    }
+
+    /// Returns the status of the inbox, mvbox, sentbox and SMTP
+    /// connections, e.g. to show a connectivity indicator.
+    pub async fn scheduler_status(&self) -> SchedulerStatus {
+        self.scheduler.read().await.status().await
+    }
+
+    /// Returns whether the last IMAP connection attempt succeeded.
+    ///
+    /// `Connectivity::NotConnected` means IO is waiting for the
+    /// network, while `Connectivity::Connected` means it is connected
+    /// and idle if there is nothing to do.
+    pub async fn get_connectivity(&self) -> Connectivity {
+        *self.connectivity.read().await
+    }
+
+    /// Updates the connectivity, emitting `EventType::ConnectivityChanged`
+    /// if it changed.
+    pub(crate) async fn set_connectivity(&self, connectivity: Connectivity) {
+        let changed = {
+            let current = &mut *self.connectivity.write().await;
+            let changed = *current != connectivity;
+            *current = connectivity;
+            changed
+        };
+        if changed {
+            self.emit_event(EventType::ConnectivityChanged(connectivity));
+        }
     }
 
     pub(crate) async fn interrupt_smtp(&self, info: InterruptInfo) {
         self.scheduler.read().await.interrupt_smtp(info).await;
     }
+
+    /// Interrupts the IMAP connection executing IMAP jobs.
+    pub(crate) async fn interrupt_imap_jobs(&self, info: InterruptInfo) {
+        let kind = self.get_job_imap_connection().await;
+        self.scheduler
+            .read()
+            .await
+            .interrupt_folder(kind, info)
+            .await;
+    }
+
+    /// Returns the IMAP connection executing IMAP jobs,
+    /// see `Config::JobImapConnection`.
+    pub async fn get_job_imap_connection(&self) -> FolderKind {
+        let kind = self
+            .get_config(Config::JobImapConnection)
+            .await
+            .and_then(|kind| kind.parse().ok())
+            .unwrap_or(FolderKind::Inbox);
+        let (dedicated, _) = self.plan_imap_connections().await;
+        if dedicated.contains(&WatchFolder::Configured(kind)) {
+            kind
+        } else {
+            FolderKind::Inbox
+        }
+    }
+
+    /// Splits the folders watched in addition to the inbox into folders
+    /// watched on a connection of their own and folders fetched in turn
+    /// on the inbox connection, see `Config::MaxConnections`.
+    async fn plan_imap_connections(&self) -> (Vec<WatchFolder>, Vec<WatchFolder>) {
+        let mut folders = Vec::new();
+        if self.get_config_bool(Config::MvboxWatch).await {
+            folders.push(WatchFolder::Configured(FolderKind::Mvbox));
+        }
+        if self.get_config_bool(Config::SentboxWatch).await {
+            folders.push(WatchFolder::Configured(FolderKind::Sentbox));
+        }
+        folders.extend(
+            self.get_watched_folders()
+                .await
+                .into_iter()
+                .map(WatchFolder::Custom),
+        );
+
+        let max_connections = self.get_config_int(Config::MaxConnections).await;
+        if max_connections > 0 {
+            // The inbox always has a connection of its own.
+            let dedicated = cmp::min(folders.len(), max_connections as usize - 1);
+            let rotated = folders.split_off(dedicated);
+            (folders, rotated)
+        } else {
+            (folders, Vec::new())
+        }
+    }
+
+    /// Returns the list of folders watched in addition to inbox,
+    /// mvbox and sentbox.
+    pub async fn get_watched_folders(&self) -> Vec<String> {
+        self.sql
+            .get_raw_config(self, WATCHED_FOLDERS_KEY)
+            .await
+            .unwrap_or_default()
+            .lines()
+            .filter(|folder| !folder.is_empty())
+            .map(|folder| folder.to_string())
+            .collect()
+    }
+
+    /// Starts watching an additional IMAP folder.
+    ///
+    /// The folder is persisted, so it is watched again on the next
+    /// `start_io()`. If IO is already running, the folder loop is
+    /// started immediately.
+    pub async fn add_watched_folder(&self, folder: impl AsRef<str>) -> Result<()> {
+        let folder = folder.as_ref().trim();
+        ensure!(!folder.is_empty(), "folder name must not be empty");
+        ensure!(
+            !folder.contains('\n'),
+            "folder name must not contain newlines"
+        );
+        ensure!(
+            !self.is_trash(folder).await,
+            "the trash folder can not be watched, deleted messages would reappear"
+        );
+
+        let mut folders = self.get_watched_folders().await;
+        if folders.iter().any(|f| f == folder) {
+            return Ok(());
+        }
+        folders.push(folder.to_string());
+        self.sql
+            .set_raw_config(self, WATCHED_FOLDERS_KEY, Some(&folders.join("\n")))
+            .await?;
+
+        let (dedicated, _) = self.plan_imap_connections().await;
+        if dedicated.contains(&WatchFolder::Custom(folder.to_string())) {
+            self.scheduler
+                .write()
+                .await
+                .start_watched_folder(self, folder.to_string())
+                .await;
+        } else {
+            // The inbox connection fetches the folder in turn.
+            self.scheduler
+                .read()
+                .await
+                .interrupt_inbox(
+                    InterruptInfo::new(false, None).with_reason(InterruptReason::ConfigChanged),
+                )
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Stops watching a folder added with `add_watched_folder()`.
+    pub async fn remove_watched_folder(&self, folder: impl AsRef<str>) -> Result<()> {
+        let folder = folder.as_ref().trim();
+
+        let mut folders = self.get_watched_folders().await;
+        folders.retain(|f| f != folder);
+        let value = if folders.is_empty() {
+            None
+        } else {
+            Some(folders.join("\n"))
+        };
+        self.sql
+            .set_raw_config(self, WATCHED_FOLDERS_KEY, value.as_deref())
+            .await?;
+
+        self.scheduler
+            .read()
+            .await
+            .pre_stop_watched_folder(folder)
+            .await;
+        self.scheduler
+            .write()
+            .await
+            .stop_watched_folder(folder)
+            .await;
+        Ok(())
+    }
 }
 
 async fn inbox_loop(ctx: Context, started: Sender<()>, inbox_handlers: ImapConnectionHandlers) {
@@ -61,14 +629,29 @@ async fn inbox_loop(ctx: Context, started: Sender<()>, inbox_handlers: ImapConne
         let mut jobs_loaded = 0;
         let mut info = InterruptInfo::default();
         loop {
-            match job::load_next(&ctx, Thread::Imap, &info).await {
-                Some(job) if jobs_loaded <= 20 => {
+            if ctx.is_io_paused().await {
+                info!(ctx, "inbox loop is paused");
+                jobs_loaded = 0;
+                info = connection.fake_idle(&ctx, None).await;
+                continue;
+            }
+            if let Some(remaining) = ctx.quiet_hours_remaining().await {
+                info!(ctx, "inbox loop is parked for quiet hours");
+                jobs_loaded = 0;
+                info = park_quiet_hours(&ctx, &mut connection, remaining).await;
+                continue;
+            }
+
+            let job_batch_size = inbox_job_batch_size(&ctx).await;
+            match load_imap_job(&ctx, FolderKind::Inbox, &info).await {
+                Some((job, _guard)) if jobs_loaded < job_batch_size => {
                     jobs_loaded += 1;
                     job::perform_job(&ctx, job::Connection::Inbox(&mut connection), job).await;
                     info = Default::default();
                 }
-                Some(job) => {
+                Some((job, guard)) => {
                     // Let the fetch run, but return back to the job afterwards.
+                    drop(guard);
                     jobs_loaded = 0;
                     if ctx.get_config_bool(Config::InboxWatch).await {
                         info!(ctx, "postponing imap-job {} to run fetch...", job);
@@ -86,8 +669,11 @@ async fn inbox_loop(ctx: Context, started: Sender<()>, inbox_handlers: ImapConne
 
                     maybe_add_time_based_warnings(&ctx).await;
 
-                    info = if ctx.get_config_bool(Config::InboxWatch).await {
-                        fetch_idle(&ctx, &mut connection, Config::ConfiguredInboxFolder).await
+                    let (_, rotated) = ctx.plan_imap_connections().await;
+                    info = if !rotated.is_empty() {
+                        fetch_rotation(&ctx, &mut connection, rotated).await
+                    } else if ctx.get_config_bool(Config::InboxWatch).await {
+                        fetch_idle(&ctx, &mut connection, FolderKind::Inbox).await
                     } else {
                         connection.fake_idle(&ctx, None).await
                     };
@@ -106,19 +692,46 @@ async fn inbox_loop(ctx: Context, started: Sender<()>, inbox_handlers: ImapConne
     shutdown_sender.send(()).await;
 }
 
+/// Loads the next IMAP job if the connection watching `kind` executes
+/// IMAP jobs, see `Config::JobImapConnection`.
+///
+/// The returned guard must be held while the job is performed, so
+/// that no other connection executes IMAP jobs at the same time,
+/// e.g. right after the setting was changed.
+async fn load_imap_job<'a>(
+    ctx: &'a Context,
+    kind: FolderKind,
+    info: &InterruptInfo,
+) -> Option<(job::Job, MutexGuard<'a, ()>)> {
+    if ctx.get_job_imap_connection().await != kind {
+        return None;
+    }
+    let guard = ctx.imap_jobs_mutex.try_lock()?;
+    let job = job::load_next(ctx, Thread::Imap, info).await?;
+    Some((job, guard))
+}
+
+/// Returns the number of IMAP jobs to execute before fetching, see
+/// `Config::InboxJobBatchSize`.
+async fn inbox_job_batch_size(ctx: &Context) -> usize {
+    clamp_job_batch_size(ctx.get_config_int(Config::InboxJobBatchSize).await)
+}
+
+fn clamp_job_batch_size(job_batch_size: i32) -> usize {
+    job_batch_size.max(1).min(MAX_INBOX_JOB_BATCH_SIZE) as usize
+}
+
 async fn fetch(ctx: &Context, connection: &mut Imap) {
-    match ctx.get_config(Config::ConfiguredInboxFolder).await {
+    match ctx.get_folder(FolderKind::Inbox).await {
         Some(watch_folder) => {
             if let Err(err) = connection.connect_configured(&ctx).await {
                 error_network!(ctx, "{}", err);
+                wait_reconnect_backoff(ctx, connection, &watch_folder).await;
                 return;
             }
+            ctx.clear_reconnect_backoff(&watch_folder).await;
 
-            // fetch
-            if let Err(err) = connection.fetch(&ctx, &watch_folder).await {
-                connection.trigger_reconnect();
-                warn!(ctx, "{}", err);
-            }
+            fetch_folder(ctx, connection, &watch_folder).await;
         }
         None => {
             warn!(ctx, "Can not fetch inbox folder, not set");
@@ -127,35 +740,146 @@ async fn fetch(ctx: &Context, connection: &mut Imap) {
     }
 }
 
-async fn fetch_idle(ctx: &Context, connection: &mut Imap, folder: Config) -> InterruptInfo {
-    match ctx.get_config(folder).await {
-        Some(watch_folder) => {
-            // connect and fake idle if unable to connect
-            if let Err(err) = connection.connect_configured(&ctx).await {
-                warn!(ctx, "imap connection failed: {}", err);
-                return connection.fake_idle(&ctx, Some(watch_folder)).await;
-            }
+/// Fetches the inbox and `folders` in turn on a single connection,
+/// then waits for an interrupt or until they are due to be fetched again.
+async fn fetch_rotation(
+    ctx: &Context,
+    connection: &mut Imap,
+    mut folders: Vec<WatchFolder>,
+) -> InterruptInfo {
+    if ctx.get_config_bool(Config::InboxWatch).await {
+        folders.insert(0, WatchFolder::Configured(FolderKind::Inbox));
+    }
 
-            // fetch
-            if let Err(err) = connection.fetch(&ctx, &watch_folder).await {
-                connection.trigger_reconnect();
-                warn!(ctx, "{}", err);
+    for folder in folders {
+        let watch_folder = match &folder {
+            WatchFolder::Configured(kind) => ctx.get_folder(*kind).await,
+            WatchFolder::Custom(watch_folder) => Some(watch_folder.clone()),
+        };
+        let watch_folder = match watch_folder {
+            Some(watch_folder) => watch_folder,
+            None => {
+                warn!(ctx, "Can not fetch {} folder, not set", folder);
+                continue;
             }
+        };
 
-            // idle
-            if connection.can_idle() {
-                connection
-                    .idle(&ctx, Some(watch_folder))
-                    .await
-                    .unwrap_or_else(|err| {
-                        connection.trigger_reconnect();
-                        warn!(ctx, "{}", err);
-                        InterruptInfo::new(false, None)
-                    })
-            } else {
-                connection.fake_idle(&ctx, Some(watch_folder)).await
-            }
+        if let Err(err) = connection.connect_configured(&ctx).await {
+            warn!(ctx, "imap connection failed: {}", err);
+            break;
         }
+        fetch_folder(ctx, connection, &watch_folder).await;
+    }
+
+    let interval = if ctx.is_metered().await {
+        METERED_POLL_INTERVAL
+    } else {
+        ROTATION_INTERVAL
+    };
+    set_idle_status(connection).await;
+    let info = future::timeout(interval, connection.fake_idle(&ctx, None))
+        .await
+        .unwrap_or_default();
+    set_idle_done_status(connection).await;
+    info
+}
+
+/// Fetches `watch_folder`, reporting `ConnectionStatus::Fetching`
+/// meanwhile.
+async fn fetch_folder(ctx: &Context, connection: &mut Imap, watch_folder: &str) {
+    connection.set_status(ConnectionStatus::Fetching).await;
+    match connection.fetch(&ctx, watch_folder).await {
+        Ok(()) => connection.set_status(ConnectionStatus::Connected).await,
+        Err(err) => {
+            connection.trigger_reconnect();
+            warn!(ctx, "{}", err);
+            connection
+                .set_status(ConnectionStatus::Error(err.to_string()))
+                .await;
+        }
+    }
+}
+
+/// Reports `ConnectionStatus::Idle` unless the last connection attempt
+/// or fetch failed.
+async fn set_idle_status(connection: &Imap) {
+    let status = connection.status();
+    let status = &mut *status.write().await;
+    if !matches!(status, ConnectionStatus::Error(_)) {
+        *status = ConnectionStatus::Idle;
+    }
+}
+
+/// Reports whether the connection is still connected after IDLE ended.
+async fn set_idle_done_status(connection: &Imap) {
+    let status = connection.status();
+    let status = &mut *status.write().await;
+    if *status == ConnectionStatus::Idle {
+        *status = if connection.is_connected() {
+            ConnectionStatus::Connected
+        } else {
+            ConnectionStatus::NotConnected
+        };
+    }
+}
+
+/// Returns `BOOSTED_FAKE_IDLE_INTERVAL` until `boost_until` and
+/// `FAKE_IDLE_INTERVAL` afterwards.
+fn fake_idle_interval(boost_until: Option<Instant>, now: Instant) -> Duration {
+    match boost_until {
+        Some(boost_until) if now < boost_until => BOOSTED_FAKE_IDLE_INTERVAL,
+        _ => FAKE_IDLE_INTERVAL,
+    }
+}
+
+/// Doubles `current` up to `max`.
+fn next_fake_idle_interval(current: Duration, max: Duration) -> Duration {
+    cmp::min(current * 2, max)
+}
+
+/// Waits for an interrupt, but at most until the quiet hours end in
+/// `remaining`, without fetching.
+async fn park_quiet_hours(
+    ctx: &Context,
+    connection: &mut Imap,
+    remaining: Duration,
+) -> InterruptInfo {
+    future::timeout(remaining, connection.fake_idle(&ctx, None))
+        .await
+        .unwrap_or_default()
+}
+
+/// Returns the time from `now` until `end` if `now` is within the
+/// quiet hours from `start` to `end`, which may span midnight.
+fn quiet_hours_remaining(start: NaiveTime, end: NaiveTime, now: NaiveTime) -> Option<Duration> {
+    const DAY: u32 = 24 * 60 * 60;
+    let start = start.num_seconds_from_midnight();
+    let end = end.num_seconds_from_midnight();
+    let now = now.num_seconds_from_midnight();
+    let quiet = if start <= end {
+        start <= now && now < end
+    } else {
+        now >= start || now < end
+    };
+    if quiet {
+        Some(Duration::from_secs(u64::from((end + DAY - now) % DAY)))
+    } else {
+        None
+    }
+}
+
+/// Waits for an interrupt, but at most `METERED_POLL_INTERVAL`,
+/// instead of keeping the connection busy with IDLE.
+async fn idle_metered(ctx: &Context, connection: &mut Imap) -> InterruptInfo {
+    info!(ctx, "network is metered, polling instead of IDLE");
+    future::timeout(METERED_POLL_INTERVAL, connection.fake_idle(&ctx, None))
+        .await
+        .unwrap_or_default()
+}
+
+async fn fetch_idle(ctx: &Context, connection: &mut Imap, folder: FolderKind) -> InterruptInfo {
+    match ctx.get_folder(folder).await {
+        Some(watch_folder) => fetch_idle_folder(ctx, connection, watch_folder).await,
         None => {
             warn!(ctx, "Can not watch {} folder, not set", folder);
             connection.fake_idle(&ctx, None).await
@@ -163,20 +887,95 @@ async fn fetch_idle(ctx: &Context, connection: &mut Imap, folder: Config) -> Int
     }
 }
 
+async fn fetch_idle_folder(
+    ctx: &Context,
+    connection: &mut Imap,
+    watch_folder: String,
+) -> InterruptInfo {
+    // connect and wait before retrying if unable to connect
+    if let Err(err) = connection.connect_configured(&ctx).await {
+        warn!(ctx, "imap connection failed: {}", err);
+        return wait_reconnect_backoff(ctx, connection, &watch_folder).await;
+    }
+    ctx.clear_reconnect_backoff(&watch_folder).await;
+
+    fetch_folder(ctx, connection, &watch_folder).await;
+
+    // idle
+    set_idle_status(connection).await;
+    let info = if ctx.is_metered().await {
+        idle_metered(ctx, connection).await
+    } else if connection.can_idle() {
+        connection
+            .idle(&ctx, Some(watch_folder))
+            .await
+            .unwrap_or_else(|err| {
+                connection.trigger_reconnect();
+                warn!(ctx, "{}", err);
+                InterruptInfo::new(false, None)
+            })
+    } else {
+        connection.fake_idle(&ctx, Some(watch_folder)).await
+    };
+    set_idle_done_status(connection).await;
+    info
+}
+
+/// Returns the delay before reconnecting after `failures` failed
+/// connection attempts in a row, without jitter.
+fn reconnect_backoff(failures: u32) -> Duration {
+    if failures == 0 {
+        return Duration::from_secs(0);
+    }
+    let factor = 2u32.pow(cmp::min(failures - 1, 16));
+    cmp::min(RECONNECT_BACKOFF_BASE * factor, RECONNECT_BACKOFF_MAX)
+}
+
+/// Waits before the next attempt to connect `connection`, which is
+/// used to watch `folder`, according to the number of failures so far.
+///
+/// A random jitter of up to half the delay is added. The wait ends
+/// early on an interrupt. If the interrupt comes from
+/// `Context::maybe_network()`, the backoff is reset, so the connection
+/// is retried right away.
+async fn wait_reconnect_backoff(
+    ctx: &Context,
+    connection: &mut Imap,
+    folder: &str,
+) -> InterruptInfo {
+    let base = reconnect_backoff(connection.connect_failures()).as_millis() as u64;
+    let delay = Duration::from_millis(base + rand::thread_rng().gen_range(0, base / 2 + 1));
+    info!(ctx, "reconnecting for {} in {:?}", folder, delay);
+    ctx.reconnect_backoff
+        .write()
+        .await
+        .insert(folder.to_string(), delay);
+
+    let info = future::timeout(delay, connection.fake_idle(ctx, None))
+        .await
+        .unwrap_or_default();
+    if info.probe_network {
+        connection.reset_connect_failures();
+        ctx.clear_reconnect_backoff(folder).await;
+    }
+    info
+}
+
 async fn simple_imap_loop(
     ctx: Context,
     started: Sender<()>,
     inbox_handlers: ImapConnectionHandlers,
-    folder: Config,
+    folder: WatchFolder,
 ) {
     use futures::future::FutureExt;
 
-    info!(ctx, "starting simple loop for {}", folder.as_ref());
+    info!(ctx, "starting simple loop for {}", folder);
     let ImapConnectionHandlers {
         mut connection,
         stop_receiver,
         shutdown_sender,
     } = inbox_handlers;
+    connection.set_role(folder.to_string());
 
     let ctx1 = ctx.clone();
 
@@ -184,8 +983,71 @@ async fn simple_imap_loop(
         started.send(()).await;
         let ctx = ctx1;
 
+        // track number of continously executed jobs
+        let mut jobs_loaded = 0;
+        let mut info = InterruptInfo::default();
+        let mut verified_folder = None;
         loop {
-            fetch_idle(&ctx, &mut connection, folder).await;
+            if ctx.is_io_paused().await {
+                info!(ctx, "simple loop for {} is paused", folder);
+                jobs_loaded = 0;
+                info = connection.fake_idle(&ctx, None).await;
+                continue;
+            }
+            if let Some(remaining) = ctx.quiet_hours_remaining().await {
+                info!(ctx, "simple loop for {} is parked for quiet hours", folder);
+                jobs_loaded = 0;
+                info = park_quiet_hours(&ctx, &mut connection, remaining).await;
+                continue;
+            }
+
+            match &folder {
+                WatchFolder::Configured(kind) => {
+                    if jobs_loaded < inbox_job_batch_size(&ctx).await {
+                        if let Some((job, _guard)) = load_imap_job(&ctx, *kind, &info).await {
+                            jobs_loaded += 1;
+                            job::perform_job(&ctx, job::Connection::Inbox(&mut connection), job)
+                                .await;
+                            info = Default::default();
+                            continue;
+                        }
+                    }
+                    if jobs_loaded > 0 {
+                        jobs_loaded = 0;
+                        if let Err(err) = connection.maybe_close_folder(&ctx).await {
+                            warn!(ctx, "failed to close folder: {:?}", err);
+                        }
+                    }
+                    if let Some(watch_folder) = ctx.get_folder(*kind).await {
+                        if !verify_watch_folder(
+                            &ctx,
+                            &mut connection,
+                            &watch_folder,
+                            &mut verified_folder,
+                        )
+                        .await
+                        {
+                            info = connection.fake_idle(&ctx, None).await;
+                            continue;
+                        }
+                    }
+                    info = fetch_idle(&ctx, &mut connection, *kind).await;
+                }
+                WatchFolder::Custom(watch_folder) => {
+                    if !verify_watch_folder(
+                        &ctx,
+                        &mut connection,
+                        watch_folder,
+                        &mut verified_folder,
+                    )
+                    .await
+                    {
+                        info = connection.fake_idle(&ctx, None).await;
+                        continue;
+                    }
+                    info = fetch_idle_folder(&ctx, &mut connection, watch_folder.clone()).await;
+                }
+            }
         }
     };
 
@@ -199,6 +1061,34 @@ async fn simple_imap_loop(
     shutdown_sender.send(()).await;
 }
 
+/// Checks that `folder` is selectable before a simple loop watches it,
+/// see `Imap::verify_watch_folder()`.
+///
+/// The check is done once per folder name, `verified` is the last
+/// folder which passed it. If the folder is not selectable, the loop
+/// waits for an interrupt, e.g. a configuration change, before it
+/// checks again.
+async fn verify_watch_folder(
+    ctx: &Context,
+    connection: &mut Imap,
+    folder: &str,
+    verified: &mut Option<String>,
+) -> bool {
+    if verified.as_deref() == Some(folder) {
+        return true;
+    }
+    if connection.connect_configured(ctx).await.is_err() {
+        // Connection errors are handled when fetching.
+        return true;
+    }
+    if !connection.verify_watch_folder(ctx, folder).await {
+        warn!(ctx, "not watching {:?}, it is not selectable", folder);
+        return false;
+    }
+    *verified = Some(folder.to_string());
+    true
+}
+
 async fn smtp_loop(ctx: Context, started: Sender<()>, smtp_handlers: SmtpConnectionHandlers) {
     use futures::future::FutureExt;
 
@@ -217,16 +1107,40 @@ async fn smtp_loop(ctx: Context, started: Sender<()>, smtp_handlers: SmtpConnect
 
         let mut interrupt_info = Default::default();
         loop {
+            if ctx.is_io_paused().await {
+                info!(ctx, "smtp loop is paused");
+                interrupt_info = smtp_fake_idle(&ctx, &idle_interrupt_receiver).await;
+                continue;
+            }
+
+            let deferral = smtp_job_deferral(&ctx).await;
             match job::load_next(&ctx, Thread::Smtp, &interrupt_info).await {
-                Some(job) => {
+                Some(job) if deferral.is_some() && job.action != Action::SendMsgToSmtp => {
+                    let delay = deferral.unwrap_or_default();
+                    info!(ctx, "deferring smtp job {} for {:?}", job, delay);
+                    if let Err(err) = job.postpone(&ctx, delay.as_secs().max(1) as i64).await {
+                        warn!(ctx, "failed to defer smtp job: {}", err);
+                    }
+                    interrupt_info = Default::default();
+                }
+                Some(mut job) => {
                     info!(ctx, "executing smtp job");
-                    job::perform_job(&ctx, job::Connection::Smtp(&mut connection), job).await;
+                    job.param.remove(Param::Deferred);
+                    let conn = job::Connection::Smtp(&mut connection);
+                    while_sending(&ctx, job::perform_job(&ctx, conn, job)).await;
                     interrupt_info = Default::default();
                 }
                 None => {
                     // Fake Idle
                     info!(ctx, "smtp fake idle - started");
-                    interrupt_info = idle_interrupt_receiver.recv().await.unwrap_or_default();
+                    let connected = connection.is_connected().await;
+                    if connected {
+                        connection.set_status(ConnectionStatus::Idle).await;
+                    }
+                    interrupt_info = smtp_fake_idle(&ctx, &idle_interrupt_receiver).await;
+                    if connected {
+                        connection.set_status(ConnectionStatus::Connected).await;
+                    }
                     info!(ctx, "smtp fake idle - interrupted")
                 }
             }
@@ -243,6 +1157,64 @@ async fn smtp_loop(ctx: Context, started: Sender<()>, smtp_handlers: SmtpConnect
     shutdown_sender.send(()).await;
 }
 
+/// Sets `Context::smtp_is_sending` while the guard is alive.
+///
+/// The flag is reset on drop, so it is not left set if the SMTP loop
+/// is stopped in the middle of a job.
+struct SendingGuard<'a>(&'a AtomicBool);
+
+impl<'a> SendingGuard<'a> {
+    fn new(flag: &'a AtomicBool) -> Self {
+        flag.store(true, Ordering::SeqCst);
+        SendingGuard(flag)
+    }
+}
+
+impl Drop for SendingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Runs `fut` with `Context::smtp_is_sending` set.
+async fn while_sending<T>(ctx: &Context, fut: impl Future<Output = T>) -> T {
+    let _guard = SendingGuard::new(&ctx.smtp_sending);
+    fut.await
+}
+
+/// Returns how long SMTP jobs other than sending messages are
+/// deferred during quiet hours or while the network is metered,
+/// `None` if they are not deferred.
+async fn smtp_job_deferral(ctx: &Context) -> Option<Duration> {
+    if let Some(remaining) = ctx.quiet_hours_remaining().await {
+        Some(remaining)
+    } else if ctx.is_metered().await {
+        Some(METERED_POLL_INTERVAL)
+    } else {
+        None
+    }
+}
+
+/// Waits for an interrupt of the SMTP loop.
+///
+/// If the interrupt channel is closed, this sleeps for a jittered
+/// delay instead of returning at once, so the loop does not spin.
+async fn smtp_fake_idle(ctx: &Context, receiver: &Receiver<InterruptInfo>) -> InterruptInfo {
+    match receiver.recv().await {
+        Ok(info) => info,
+        Err(_) => {
+            let base = SMTP_INTERRUPT_RETRY_DELAY.as_millis() as u64;
+            let delay = Duration::from_millis(base + rand::thread_rng().gen_range(0, base));
+            warn!(
+                ctx,
+                "smtp interrupt channel is closed, waiting {:?} before retrying", delay
+            );
+            task::sleep(delay).await;
+            InterruptInfo::default()
+        }
+    }
+}
+
 impl Scheduler {
     /// Start the scheduler, panics if it is already running.
     pub async fn start(&mut self, ctx: Context) {
@@ -259,18 +1231,19 @@ impl Scheduler {
         let (smtp_start_send, smtp_start_recv) = channel(1);
 
         let ctx1 = ctx.clone();
-        let inbox_handle = Some(task::spawn(async move {
+        let inbox_handle = Some(ctx.spawn_task("inbox_loop", async move {
             inbox_loop(ctx1, inbox_start_send, inbox_handlers).await
         }));
 
-        if ctx.get_config_bool(Config::MvboxWatch).await {
+        let (dedicated, _) = ctx.plan_imap_connections().await;
+        if dedicated.contains(&WatchFolder::Configured(FolderKind::Mvbox)) {
             let ctx1 = ctx.clone();
-            mvbox_handle = Some(task::spawn(async move {
+            mvbox_handle = Some(ctx.spawn_task("simple_loop", async move {
                 simple_imap_loop(
                     ctx1,
                     mvbox_start_send,
                     mvbox_handlers,
-                    Config::ConfiguredMvboxFolder,
+                    WatchFolder::Configured(FolderKind::Mvbox),
                 )
                 .await
             }));
@@ -278,14 +1251,14 @@ impl Scheduler {
             mvbox_start_send.send(()).await;
         }
 
-        if ctx.get_config_bool(Config::SentboxWatch).await {
+        if dedicated.contains(&WatchFolder::Configured(FolderKind::Sentbox)) {
             let ctx1 = ctx.clone();
-            sentbox_handle = Some(task::spawn(async move {
+            sentbox_handle = Some(ctx.spawn_task("simple_loop", async move {
                 simple_imap_loop(
                     ctx1,
                     sentbox_start_send,
                     sentbox_handlers,
-                    Config::ConfiguredSentboxFolder,
+                    WatchFolder::Configured(FolderKind::Sentbox),
                 )
                 .await
             }));
@@ -294,7 +1267,7 @@ impl Scheduler {
         }
 
         let ctx1 = ctx.clone();
-        let smtp_handle = Some(task::spawn(async move {
+        let smtp_handle = Some(ctx.spawn_task("smtp_loop", async move {
             smtp_loop(ctx1, smtp_start_send, smtp_handlers).await
         }));
 
@@ -307,6 +1280,8 @@ impl Scheduler {
             mvbox_handle,
             sentbox_handle,
             smtp_handle,
+            watched: Vec::new(),
+            network_debounce: Default::default(),
         };
 
         // wait for all loops to be started
@@ -320,19 +1295,130 @@ impl Scheduler {
             error!(ctx, "failed to start scheduler: {}", err);
         }
 
+        for folder in dedicated {
+            if let WatchFolder::Custom(folder) = folder {
+                self.start_watched_folder(&ctx, folder).await;
+            }
+        }
+
         info!(ctx, "scheduler is running");
     }
 
-    async fn maybe_network(&self) {
+    /// Starts a loop watching `folder` if the scheduler is running
+    /// and the folder is not watched yet.
+    async fn start_watched_folder(&mut self, ctx: &Context, folder: String) {
+        if let Scheduler::Running { watched, .. } = self {
+            if watched.iter().any(|w| w.folder == folder) {
+                return;
+            }
+
+            let (state, handlers) = ImapConnectionState::new();
+            let (start_send, start_recv) = channel(1);
+            let ctx1 = ctx.clone();
+            let watch_folder = WatchFolder::Custom(folder.clone());
+            let handle = Some(ctx.spawn_task("simple_loop", async move {
+                simple_imap_loop(ctx1, start_send, handlers, watch_folder).await
+            }));
+
+            if let Err(err) = start_recv.recv().await {
+                error!(ctx, "failed to start loop for {}: {}", folder, err);
+            }
+
+            watched.push(WatchedFolderLoop {
+                folder,
+                state,
+                handle,
+            });
+        }
+    }
+
+    /// Shuts down the loop watching `folder`, if any.
+    ///
+    /// Only needs a read lock, so the loop can still interrupt
+    /// other connections while shutting down.
+    async fn pre_stop_watched_folder(&self, folder: &str) {
+        if let Scheduler::Running { watched, .. } = self {
+            if let Some(watched_loop) = watched.iter().find(|w| w.folder == folder) {
+                watched_loop.state.stop().await;
+            }
+        }
+    }
+
+    /// Removes the loop watching `folder` after `pre_stop_watched_folder()`.
+    async fn stop_watched_folder(&mut self, folder: &str) {
+        if let Scheduler::Running { watched, .. } = self {
+            if let Some(idx) = watched.iter().position(|w| w.folder == folder) {
+                let mut watched_loop = watched.remove(idx);
+                if let Some(handle) = watched_loop.handle.take() {
+                    handle.await;
+                }
+            }
+        }
+    }
+
+    async fn maybe_network(&self, metered: bool) {
         if !self.is_running() {
             return;
         }
 
-        self.interrupt_inbox(InterruptInfo::new(true, None))
-            .join(self.interrupt_mvbox(InterruptInfo::new(true, None)))
-            .join(self.interrupt_sentbox(InterruptInfo::new(true, None)))
-            .join(self.interrupt_smtp(InterruptInfo::new(true, None)))
+        if metered {
+            // Do not force fetching all folders on a metered network,
+            // only let due messages be sent.
+            let info = InterruptInfo::new(false, None).with_reason(InterruptReason::NetworkBack);
+            self.interrupt_smtp(info).await;
+            return;
+        }
+
+        let info = || InterruptInfo::new(true, None).with_reason(InterruptReason::NetworkBack);
+        self.interrupt_inbox(info())
+            .join(self.interrupt_mvbox(info()))
+            .join(self.interrupt_sentbox(info()))
+            .join(self.interrupt_smtp(info()))
             .await;
+
+        if let Scheduler::Running { watched, .. } = self {
+            for watched_loop in watched {
+                watched_loop.state.interrupt(info()).await;
+            }
+        }
+    }
+
+    async fn fetch_now(&self) {
+        if !self.is_running() {
+            return;
+        }
+
+        let info = || InterruptInfo::new(false, None).with_reason(InterruptReason::UserRefresh);
+        self.interrupt_inbox(info())
+            .join(self.interrupt_mvbox(info()))
+            .join(self.interrupt_sentbox(info()))
+            .await;
+
+        if let Scheduler::Running { watched, .. } = self {
+            for watched_loop in watched {
+                watched_loop.state.interrupt(info()).await;
+            }
+        }
+    }
+
+    /// Interrupts all loops after a setting affecting them was changed.
+    async fn config_changed(&self) {
+        if !self.is_running() {
+            return;
+        }
+
+        let info = || InterruptInfo::new(false, None).with_reason(InterruptReason::ConfigChanged);
+        self.interrupt_inbox(info())
+            .join(self.interrupt_mvbox(info()))
+            .join(self.interrupt_sentbox(info()))
+            .join(self.interrupt_smtp(info()))
+            .await;
+
+        if let Scheduler::Running { watched, .. } = self {
+            for watched_loop in watched {
+                watched_loop.state.interrupt(info()).await;
+            }
+        }
     }
 
     async fn interrupt_inbox(&self, info: InterruptInfo) {
@@ -347,6 +1433,16 @@ impl Scheduler {
         }
     }
 
+    async fn interrupt_folder(&self, kind: FolderKind, info: InterruptInfo) {
+        match kind {
+            FolderKind::Inbox => self.interrupt_inbox(info).await,
+            FolderKind::Mvbox => self.interrupt_mvbox(info).await,
+            FolderKind::Sentbox => self.interrupt_sentbox(info).await,
+            // The trash folder is never watched.
+            FolderKind::Trash => {}
+        }
+    }
+
     async fn interrupt_sentbox(&self, info: InterruptInfo) {
         if let Scheduler::Running { ref sentbox, .. } = self {
             sentbox.interrupt(info).await;
@@ -370,6 +1466,7 @@ impl Scheduler {
                 mvbox,
                 sentbox,
                 smtp,
+                watched,
                 ..
             } => {
                 inbox
@@ -378,6 +1475,9 @@ impl Scheduler {
                     .join(sentbox.stop())
                     .join(smtp.stop())
                     .await;
+                for watched_loop in watched {
+                    watched_loop.state.stop().await;
+                }
 
                 StopToken
             }
@@ -395,6 +1495,7 @@ impl Scheduler {
                 mvbox_handle,
                 sentbox_handle,
                 smtp_handle,
+                watched,
                 ..
             } => {
                 if let Some(handle) = inbox_handle.take() {
@@ -409,12 +1510,35 @@ impl Scheduler {
                 if let Some(handle) = smtp_handle.take() {
                     handle.await;
                 }
+                for watched_loop in watched.iter_mut() {
+                    if let Some(handle) = watched_loop.handle.take() {
+                        handle.await;
+                    }
+                }
 
                 *self = Scheduler::Stopped;
             }
         }
     }
 
+    async fn status(&self) -> SchedulerStatus {
+        match self {
+            Scheduler::Stopped => SchedulerStatus::default(),
+            Scheduler::Running {
+                inbox,
+                mvbox,
+                sentbox,
+                smtp,
+                ..
+            } => SchedulerStatus {
+                inbox: inbox.state.status().await,
+                mvbox: mvbox.state.status().await,
+                sentbox: sentbox.state.status().await,
+                smtp: smtp.state.status().await,
+            },
+        }
+    }
+
     /// Check if the scheduler is running.
     pub fn is_running(&self) -> bool {
         matches!(self, Scheduler::Running { .. })
@@ -430,6 +1554,8 @@ struct ConnectionState {
     stop_sender: Sender<()>,
     /// Channel to interrupt idle.
     idle_interrupt_sender: Sender<InterruptInfo>,
+    /// Status updated by the loop, see `Context::scheduler_status()`.
+    status: Arc<RwLock<ConnectionStatus>>,
 }
 
 impl ConnectionState {
@@ -445,6 +1571,10 @@ impl ConnectionState {
         // Use try_send to avoid blocking on interrupts.
         self.idle_interrupt_sender.try_send(info).ok();
     }
+
+    async fn status(&self) -> ConnectionStatus {
+        self.status.read().await.clone()
+    }
 }
 
 #[derive(Debug)]
@@ -469,6 +1599,7 @@ impl SmtpConnectionState {
             idle_interrupt_sender,
             shutdown_receiver,
             stop_sender,
+            status: handlers.connection.status(),
         };
 
         let conn = SmtpConnectionState { state };
@@ -516,6 +1647,7 @@ impl ImapConnectionState {
             idle_interrupt_sender,
             shutdown_receiver,
             stop_sender,
+            status: handlers.connection.status(),
         };
 
         let conn = ImapConnectionState { state };
@@ -541,10 +1673,34 @@ struct ImapConnectionHandlers {
     shutdown_sender: Sender<()>,
 }
 
+/// Why a loop was interrupted, see `InterruptInfo`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptReason {
+    /// No specific reason, e.g. the loop woke up by itself.
+    Other,
+    /// A job was scheduled.
+    Job,
+    /// A message was queued for sending.
+    OutgoingMsg,
+    /// The network likely came back, see `Context::maybe_network()`.
+    NetworkBack,
+    /// The user asked to check for new messages, see `Context::fetch_now()`.
+    UserRefresh,
+    /// A setting affecting the loops changed.
+    ConfigChanged,
+}
+
+impl Default for InterruptReason {
+    fn default() -> Self {
+        InterruptReason::Other
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct InterruptInfo {
     pub probe_network: bool,
     pub msg_id: Option<MsgId>,
+    pub reason: InterruptReason,
 }
 
 impl InterruptInfo {
@@ -552,6 +1708,552 @@ impl InterruptInfo {
         Self {
             probe_network,
             msg_id,
+            reason: InterruptReason::Other,
+        }
+    }
+
+    pub fn with_reason(mut self, reason: InterruptReason) -> Self {
+        self.reason = reason;
+        self
+    }
+
+    /// Returns true if the interrupt should not wait for
+    /// `Config::MinFetchInterval`.
+    pub(crate) fn bypasses_throttle(&self) -> bool {
+        self.probe_network || self.msg_id.is_some() || self.reason == InterruptReason::UserRefresh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::context::SchedulerState;
+    use crate::param::Params;
+    use crate::test_utils::TestContext;
+
+    #[async_std::test]
+    async fn test_smtp_is_sending() {
+        let t = TestContext::new().await;
+        assert!(!t.ctx.smtp_is_sending());
+
+        // The job signals that it started and waits until the flag is
+        // checked.
+        let (started_sender, started_receiver) = channel(1);
+        let (done_sender, done_receiver) = channel::<()>(1);
+        let send = while_sending(&t.ctx, async {
+            started_sender.send(()).await;
+            done_receiver.recv().await.unwrap();
+        });
+        let check = async {
+            started_receiver.recv().await.unwrap();
+            let sending = t.ctx.smtp_is_sending();
+            done_sender.send(()).await;
+            sending
+        };
+        let ((), sending) = send.join(check).await;
+        assert!(sending);
+        assert!(!t.ctx.smtp_is_sending());
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        assert_eq!(reconnect_backoff(0), Duration::from_secs(0));
+        assert_eq!(reconnect_backoff(1), Duration::from_secs(1));
+        assert_eq!(reconnect_backoff(2), Duration::from_secs(2));
+        assert_eq!(reconnect_backoff(3), Duration::from_secs(4));
+        assert_eq!(reconnect_backoff(9), Duration::from_secs(256));
+        assert_eq!(reconnect_backoff(10), RECONNECT_BACKOFF_MAX);
+        assert_eq!(reconnect_backoff(u32::MAX), RECONNECT_BACKOFF_MAX);
+    }
+
+    #[async_std::test]
+    async fn test_boost_fetch_after_send() {
+        let t = TestContext::new().await;
+        assert_eq!(t.ctx.fake_idle_interval().await, FAKE_IDLE_INTERVAL);
+
+        // Disabled by default.
+        t.ctx.boost_fetch_after_send().await;
+        assert_eq!(t.ctx.fake_idle_interval().await, FAKE_IDLE_INTERVAL);
+
+        t.ctx
+            .set_config(Config::BoostAfterSend, Some("60"))
+            .await
+            .unwrap();
+        t.ctx.boost_fetch_after_send().await;
+        assert_eq!(t.ctx.fake_idle_interval().await, BOOSTED_FAKE_IDLE_INTERVAL);
+
+        // Let the boost window end.
+        *t.ctx.fetch_boost_until.write().await = Some(Instant::now());
+        assert_eq!(t.ctx.fake_idle_interval().await, FAKE_IDLE_INTERVAL);
+
+        let now = Instant::now();
+        let until = now + Duration::from_secs(120);
+        assert_eq!(fake_idle_interval(None, now), FAKE_IDLE_INTERVAL);
+        assert_eq!(
+            fake_idle_interval(Some(until), now),
+            BOOSTED_FAKE_IDLE_INTERVAL
+        );
+        assert_eq!(fake_idle_interval(Some(until), until), FAKE_IDLE_INTERVAL);
+    }
+
+    #[async_std::test]
+    async fn test_fake_idle_backoff() {
+        let t = TestContext::new().await;
+        let mut interval = t.ctx.fake_idle_interval().await;
+        assert_eq!(interval, FAKE_IDLE_INTERVAL);
+
+        let mut intervals = Vec::new();
+        for _ in 0..6 {
+            interval = t.ctx.next_fake_idle_interval(interval).await;
+            intervals.push(interval.as_secs());
         }
+        assert_eq!(intervals, vec![30, 60, 120, 240, 300, 300]);
+
+        t.ctx
+            .set_config(Config::FakeIdleMaxInterval, Some("10"))
+            .await
+            .unwrap();
+        assert_eq!(t.ctx.fake_idle_interval().await, Duration::from_secs(10));
+        assert_eq!(
+            t.ctx.next_fake_idle_interval(interval).await,
+            Duration::from_secs(10)
+        );
+
+        // Polling does not back off while boosted.
+        t.ctx
+            .set_config(Config::FakeIdleMaxInterval, None)
+            .await
+            .unwrap();
+        t.ctx
+            .set_config(Config::BoostAfterSend, Some("60"))
+            .await
+            .unwrap();
+        t.ctx.boost_fetch_after_send().await;
+        assert_eq!(
+            t.ctx.next_fake_idle_interval(interval).await,
+            BOOSTED_FAKE_IDLE_INTERVAL
+        );
+    }
+
+    #[async_std::test]
+    async fn test_get_reconnect_backoff() {
+        let t = TestContext::new().await;
+        assert_eq!(t.ctx.get_reconnect_backoff().await, Duration::from_secs(0));
+
+        let (_interrupt_sender, interrupt_receiver) = channel(1);
+        let mut connection = Imap::new(interrupt_receiver);
+        let info = wait_reconnect_backoff(&t.ctx, &mut connection, "INBOX").await;
+        assert!(!info.probe_network);
+        assert_eq!(t.ctx.get_reconnect_backoff().await, Duration::from_secs(0));
+
+        {
+            let mut backoff = t.ctx.reconnect_backoff.write().await;
+            backoff.insert("INBOX".to_string(), Duration::from_secs(4));
+            backoff.insert("DeltaChat".to_string(), Duration::from_secs(2));
+        }
+        assert_eq!(t.ctx.get_reconnect_backoff().await, Duration::from_secs(4));
+
+        t.ctx.clear_reconnect_backoff("INBOX").await;
+        assert_eq!(t.ctx.get_reconnect_backoff().await, Duration::from_secs(2));
+    }
+
+    #[async_std::test]
+    async fn test_inbox_job_batch_size() {
+        let t = TestContext::new().await;
+        assert_eq!(inbox_job_batch_size(&t.ctx).await, 20);
+
+        t.ctx
+            .set_config(Config::InboxJobBatchSize, Some("1"))
+            .await
+            .unwrap();
+        assert_eq!(inbox_job_batch_size(&t.ctx).await, 1);
+
+        assert_eq!(clamp_job_batch_size(0), 1);
+        assert_eq!(clamp_job_batch_size(-5), 1);
+        assert_eq!(clamp_job_batch_size(150), 150);
+        assert_eq!(clamp_job_batch_size(1000), 200);
+    }
+
+    #[async_std::test]
+    async fn test_scheduler_status() {
+        let t = TestContext::new().await;
+        assert_eq!(t.ctx.scheduler_status().await, SchedulerStatus::default());
+
+        // The unconfigured context fails to connect to IMAP, while SMTP
+        // does not connect without anything to send.
+        t.ctx.start_io().await;
+        let status = async_std::future::timeout(Duration::from_secs(10), async {
+            loop {
+                let status = t.ctx.scheduler_status().await;
+                if let ConnectionStatus::Error(_) = status.inbox {
+                    return status;
+                }
+                task::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(status.smtp, ConnectionStatus::NotConnected);
+
+        t.ctx.stop_io().await;
+        assert_eq!(t.ctx.scheduler_status().await, SchedulerStatus::default());
+    }
+
+    #[async_std::test]
+    async fn test_pause_io() {
+        let t = TestContext::new().await;
+        let events = t.ctx.get_event_emitter();
+        assert_eq!(t.ctx.scheduler_state().await, SchedulerState::Stopped);
+
+        t.ctx.start_io().await;
+        assert_eq!(t.ctx.scheduler_state().await, SchedulerState::Running);
+
+        t.ctx.pause_io().await;
+        assert!(t.ctx.is_io_paused().await);
+        assert_eq!(t.ctx.scheduler_state().await, SchedulerState::Paused);
+
+        let parked = async_std::future::timeout(Duration::from_secs(10), async {
+            while let Some(event) = events.recv().await {
+                if let EventType::Info(msg) = event.typ {
+                    if msg.contains("smtp loop is paused") {
+                        return true;
+                    }
+                }
+            }
+            false
+        })
+        .await;
+        assert_eq!(parked, Ok(true));
+
+        t.ctx.resume_io().await;
+        assert_eq!(t.ctx.scheduler_state().await, SchedulerState::Running);
+
+        // Stopping IO ends the pause.
+        t.ctx.pause_io().await;
+        t.ctx.stop_io().await;
+        assert!(!t.ctx.is_io_paused().await);
+        assert_eq!(t.ctx.scheduler_state().await, SchedulerState::Stopped);
+    }
+
+    #[test]
+    fn test_quiet_hours_remaining() {
+        let time = |h, m| NaiveTime::from_hms(h, m, 0);
+        let night = (time(22, 0), time(7, 0));
+        assert_eq!(quiet_hours_remaining(night.0, night.1, time(21, 59)), None);
+        assert_eq!(
+            quiet_hours_remaining(night.0, night.1, time(22, 0)),
+            Some(Duration::from_secs(9 * 60 * 60))
+        );
+        assert_eq!(
+            quiet_hours_remaining(night.0, night.1, time(6, 30)),
+            Some(Duration::from_secs(30 * 60))
+        );
+        assert_eq!(quiet_hours_remaining(night.0, night.1, time(7, 0)), None);
+
+        let noon = (time(12, 0), time(13, 0));
+        assert_eq!(quiet_hours_remaining(noon.0, noon.1, time(11, 0)), None);
+        assert_eq!(
+            quiet_hours_remaining(noon.0, noon.1, time(12, 15)),
+            Some(Duration::from_secs(45 * 60))
+        );
+        assert_eq!(quiet_hours_remaining(noon.0, noon.1, time(13, 0)), None);
+
+        assert_eq!(
+            quiet_hours_remaining(time(8, 0), time(8, 0), time(8, 0)),
+            None
+        );
+    }
+
+    #[async_std::test]
+    async fn test_quiet_hours() {
+        let t = TestContext::new().await;
+        let events = t.ctx.get_event_emitter();
+        assert_eq!(t.ctx.get_quiet_hours().await, None);
+        assert_eq!(t.ctx.quiet_hours_remaining().await, None);
+
+        let time = |h, m| NaiveTime::from_hms(h, m, 0);
+        t.ctx
+            .set_quiet_hours(time(22, 0), time(7, 0))
+            .await
+            .unwrap();
+        assert_eq!(
+            t.ctx.get_quiet_hours().await,
+            Some((time(22, 0), time(7, 0)))
+        );
+        assert_eq!(
+            t.ctx.quiet_hours_remaining_at(time(6, 30)).await,
+            Some(Duration::from_secs(30 * 60))
+        );
+        assert_eq!(t.ctx.quiet_hours_remaining_at(time(12, 0)).await, None);
+
+        // The loops are parked by quiet hours spanning the whole day
+        // except one minute twelve hours from now, whatever the local
+        // time is.
+        let now = Local::now().time();
+        let start = NaiveTime::from_hms(now.hour(), now.minute(), 0) + chrono::Duration::hours(12);
+        let end = start - chrono::Duration::minutes(1);
+        t.ctx.set_quiet_hours(start, end).await.unwrap();
+
+        t.ctx.start_io().await;
+        let parked = async_std::future::timeout(Duration::from_secs(10), async {
+            while let Some(event) = events.recv().await {
+                if let EventType::Info(msg) = event.typ {
+                    if msg.contains("inbox loop is parked for quiet hours") {
+                        return true;
+                    }
+                }
+            }
+            false
+        })
+        .await;
+        assert_eq!(parked, Ok(true));
+        t.ctx.stop_io().await;
+
+        t.ctx.set_quiet_hours(start, start).await.unwrap();
+        assert_eq!(t.ctx.get_quiet_hours().await, None);
+        assert_eq!(t.ctx.quiet_hours_remaining().await, None);
+    }
+
+    #[async_std::test]
+    async fn test_watched_folder() {
+        let t = TestContext::new().await;
+        let events = t.ctx.get_event_emitter();
+
+        t.ctx.start_io().await;
+        t.ctx.add_watched_folder("Custom").await.unwrap();
+        assert_eq!(
+            t.ctx.get_watched_folders().await,
+            vec!["Custom".to_string()]
+        );
+
+        // Adding the same folder twice does not start another loop.
+        t.ctx.add_watched_folder(" Custom ").await.unwrap();
+        assert_eq!(t.ctx.get_watched_folders().await.len(), 1);
+        assert!(t.ctx.add_watched_folder("").await.is_err());
+
+        let started = async_std::future::timeout(Duration::from_secs(10), async {
+            while let Some(event) = events.recv().await {
+                if let EventType::Info(msg) = event.typ {
+                    if msg.contains("starting simple loop for Custom") {
+                        return true;
+                    }
+                }
+            }
+            false
+        })
+        .await;
+        assert_eq!(started, Ok(true));
+
+        t.ctx.remove_watched_folder("Custom").await.unwrap();
+        assert!(t.ctx.get_watched_folders().await.is_empty());
+
+        t.ctx.stop_io().await;
+    }
+
+    #[async_std::test]
+    async fn test_job_imap_connection() {
+        let t = TestContext::new().await;
+        assert_eq!(t.ctx.get_job_imap_connection().await, FolderKind::Inbox);
+
+        t.ctx
+            .set_config(Config::JobImapConnection, Some("mvbox"))
+            .await
+            .unwrap();
+        assert_eq!(t.ctx.get_job_imap_connection().await, FolderKind::Mvbox);
+
+        job::add(
+            &t.ctx,
+            job::Job::new(job::Action::Housekeeping, 0, Params::new(), 0),
+        )
+        .await;
+        let info = InterruptInfo::default();
+        assert!(load_imap_job(&t.ctx, FolderKind::Inbox, &info)
+            .await
+            .is_none());
+
+        // No job is loaded while another connection executes one.
+        let guard = t.ctx.imap_jobs_mutex.lock().await;
+        assert!(load_imap_job(&t.ctx, FolderKind::Mvbox, &info)
+            .await
+            .is_none());
+        drop(guard);
+
+        let (_sender, receiver) = channel(1);
+        let mut connection = Imap::new(receiver);
+        let (job, _guard) = load_imap_job(&t.ctx, FolderKind::Mvbox, &info)
+            .await
+            .unwrap();
+        assert_eq!(job.action, job::Action::Housekeeping);
+        let job_id = job.job_id;
+        job::perform_job(&t.ctx, job::Connection::Inbox(&mut connection), job).await;
+        let count: i32 = t
+            .ctx
+            .sql
+            .query_get_value_result("SELECT COUNT(*) FROM jobs WHERE id=?", paramsv![job_id])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(count, 0);
+
+        // Without mvbox loop, jobs are executed by the inbox connection.
+        t.ctx
+            .set_config(Config::MvboxWatch, Some("0"))
+            .await
+            .unwrap();
+        assert_eq!(t.ctx.get_job_imap_connection().await, FolderKind::Inbox);
+    }
+
+    #[async_std::test]
+    async fn test_watched_folder_restored_on_start() {
+        let t = TestContext::new().await;
+        t.ctx.start_io().await;
+        let loops = t.ctx.debug_task_counts()["simple_loop"];
+        t.ctx.stop_io().await;
+
+        // Folders added while IO is stopped are watched on the next start.
+        t.ctx.add_watched_folder("Custom").await.unwrap();
+        t.ctx.start_io().await;
+        assert_eq!(t.ctx.debug_task_counts()["simple_loop"], loops + 1);
+
+        // Stopping joins the loop of the custom folder, too.
+        t.ctx.stop_io().await;
+        assert!(t.ctx.debug_task_counts().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_max_connections() {
+        let t = TestContext::new().await;
+        t.ctx.add_watched_folder("Custom").await.unwrap();
+
+        let (dedicated, rotated) = t.ctx.plan_imap_connections().await;
+        assert_eq!(dedicated.len(), 3);
+        assert!(rotated.is_empty());
+
+        t.ctx
+            .set_config(Config::MaxConnections, Some("2"))
+            .await
+            .unwrap();
+        let (dedicated, rotated) = t.ctx.plan_imap_connections().await;
+        assert_eq!(dedicated, vec![WatchFolder::Configured(FolderKind::Mvbox)]);
+        assert_eq!(rotated.len(), 2);
+
+        // With a single connection, the inbox connection serves all folders.
+        t.ctx
+            .set_config(Config::MaxConnections, Some("1"))
+            .await
+            .unwrap();
+        let (dedicated, rotated) = t.ctx.plan_imap_connections().await;
+        assert!(dedicated.is_empty());
+        assert_eq!(
+            rotated,
+            vec![
+                WatchFolder::Configured(FolderKind::Mvbox),
+                WatchFolder::Configured(FolderKind::Sentbox),
+                WatchFolder::Custom("Custom".to_string()),
+            ]
+        );
+        assert_eq!(t.ctx.get_job_imap_connection().await, FolderKind::Inbox);
+    }
+
+    #[async_std::test]
+    async fn test_metered() {
+        let t = TestContext::new().await;
+        assert!(!t.ctx.is_metered().await);
+        t.ctx.set_metered(true).await;
+        assert!(t.ctx.is_metered().await);
+
+        // Metered connections are parked until interrupted
+        // instead of fetching again.
+        let (sender, receiver) = channel(1);
+        let mut connection = Imap::new(receiver);
+        let res = future::timeout(
+            Duration::from_secs(1),
+            idle_metered(&t.ctx, &mut connection),
+        )
+        .await;
+        assert!(res.is_err());
+
+        sender
+            .send(InterruptInfo::new(false, None).with_reason(InterruptReason::UserRefresh))
+            .await;
+        let info = idle_metered(&t.ctx, &mut connection).await;
+        assert_eq!(info.reason, InterruptReason::UserRefresh);
+
+        // Deferred jobs are not loaded before they are due.
+        job::Job::new(Action::SendMdn, 1, Params::new(), 0)
+            .save(&t.ctx)
+            .await
+            .unwrap();
+        let job = job::load_next(&t.ctx, Thread::Smtp, &Default::default())
+            .await
+            .unwrap();
+        let delay = METERED_POLL_INTERVAL.as_secs() as i64;
+        job.postpone(&t.ctx, delay).await.unwrap();
+        assert!(job::load_next(&t.ctx, Thread::Smtp, &Default::default())
+            .await
+            .is_none());
+
+        // They are due again as soon as the network is not metered.
+        t.ctx.set_metered(false).await;
+        assert!(!t.ctx.is_metered().await);
+        let job = job::load_next(&t.ctx, Thread::Smtp, &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(job.action, Action::SendMdn);
+    }
+
+    #[test]
+    fn test_debounce() {
+        let window = Duration::from_secs(1);
+        let start = Instant::now();
+        let mut debounce = Debounce::default();
+        assert_eq!(debounce.on_event(start, false, window), Debounced::Now);
+
+        // Further events are coalesced into one at the end of the window.
+        assert_eq!(
+            debounce.on_event(start + Duration::from_millis(300), false, window),
+            Debounced::Later(Duration::from_millis(700))
+        );
+        assert_eq!(
+            debounce.on_event(start + Duration::from_millis(999), false, window),
+            Debounced::Pending
+        );
+
+        // A change of `metered` is handled right away.
+        assert_eq!(
+            debounce.on_event(start + Duration::from_millis(999), true, window),
+            Debounced::Now
+        );
+
+        // Once the scheduled event is handled, the next window starts.
+        let end = start + window;
+        debounce.fire_trailing(end, true);
+        assert_eq!(
+            debounce.on_event(end, true, window),
+            Debounced::Later(window)
+        );
+        assert_eq!(
+            debounce.on_event(end + window, true, window),
+            Debounced::Now
+        );
+    }
+
+    #[async_std::test]
+    async fn test_smtp_fake_idle_closed_channel() {
+        let t = TestContext::new().await;
+        let (sender, receiver) = channel(1);
+        drop(sender);
+
+        // The loop waits before retrying instead of spinning.
+        let mut loops = 0;
+        let _ = future::timeout(Duration::from_secs(1), async {
+            loop {
+                smtp_fake_idle(&t.ctx, &receiver).await;
+                loops += 1;
+            }
+        })
+        .await;
+        assert_eq!(loops, 0);
     }
 }
@@ -6,13 +6,15 @@ use std::time::{Duration, SystemTime};
 
 use async_smtp::smtp::client::net::*;
 use async_smtp::*;
+use async_std::sync::{Arc, RwLock};
 
 use crate::constants::*;
-use crate::context::Context;
+use crate::context::{ConnectionStatus, Context};
 use crate::events::EventType;
-use crate::login_param::{dc_build_tls, CertificateChecks, LoginParam, ServerLoginParam};
+use crate::login_param::{dc_build_tls, LoginParam, ServerLoginParam};
 use crate::oauth2::*;
-use crate::provider::{get_provider_info, Socket};
+use crate::provider::Socket;
+use crate::socks::{Socks5Config, Socks5Relay};
 use crate::stock::StockMessage;
 
 /// SMTP write and read timeout in seconds.
@@ -41,6 +43,9 @@ pub enum Error {
 
     #[error("TLS error")]
     Tls(#[from] async_native_tls::Error),
+
+    #[error("SMTP: SOCKS5 relay error: {0}")]
+    Socks5(#[source] std::io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -56,6 +61,16 @@ pub(crate) struct Smtp {
     /// (eg connect or send succeeded). On initialization and disconnect
     /// it is set to None.
     last_success: Option<SystemTime>,
+
+    /// Status shared with the scheduler, see `Context::scheduler_status()`.
+    status: Arc<RwLock<ConnectionStatus>>,
+
+    /// Connection cache generation at the time of connecting, see
+    /// `Context::clear_connection_caches()`.
+    cache_generation: u64,
+
+    /// Relay the transport connects to if a SOCKS5 proxy is configured.
+    socks5_relay: Option<Socks5Relay>,
 }
 
 impl Smtp {
@@ -69,7 +84,18 @@ impl Smtp {
         if let Some(mut transport) = self.transport.take() {
             transport.close().await.ok();
         }
+        self.socks5_relay = None;
         self.last_success = None;
+        self.set_status(ConnectionStatus::NotConnected).await;
+    }
+
+    /// Returns the status shared with the scheduler.
+    pub(crate) fn status(&self) -> Arc<RwLock<ConnectionStatus>> {
+        self.status.clone()
+    }
+
+    pub(crate) async fn set_status(&self, status: ConnectionStatus) {
+        *self.status.write().await = status;
     }
 
     /// Return true if smtp was connected but is not known to
@@ -96,10 +122,18 @@ impl Smtp {
 
     /// Connect using configured parameters.
     pub async fn connect_configured(&mut self, context: &Context) -> Result<()> {
+        let cache_generation = context.connection_cache_generation().await;
         if self.is_connected().await {
-            return Ok(());
+            if self.cache_generation == cache_generation {
+                context.count_connection(true).await;
+                return Ok(());
+            }
+            info!(context, "SMTP connection caches cleared, reconnecting");
+            self.disconnect().await;
         }
+        self.cache_generation = cache_generation;
 
+        self.set_status(ConnectionStatus::Connecting).await;
         let lp = LoginParam::from_database(context, "configured_").await;
         let res = self
             .connect(
@@ -109,6 +143,14 @@ impl Smtp {
                 lp.server_flags & DC_LP_AUTH_OAUTH2 != 0,
             )
             .await;
+        self.set_status(match res {
+            Ok(()) => ConnectionStatus::Connected,
+            Err(ref err) => ConnectionStatus::Error(err.to_string()),
+        })
+        .await;
+        if res.is_ok() {
+            context.count_connection(false).await;
+        }
         if let Err(ref err) = res {
             let message = context
                 .stock_string_repl_str2(
@@ -151,14 +193,7 @@ impl Smtp {
         let domain = &lp.server;
         let port = lp.port;
 
-        let provider = get_provider_info(addr);
-        let strict_tls = match lp.certificate_checks {
-            CertificateChecks::Automatic => provider.map_or(false, |provider| provider.strict_tls),
-            CertificateChecks::Strict => true,
-            CertificateChecks::AcceptInvalidCertificates
-            | CertificateChecks::AcceptInvalidCertificates2 => false,
-        };
-        let tls_config = dc_build_tls(strict_tls);
+        let tls_config = dc_build_tls(lp.strict_tls(addr));
         let tls_parameters = ClientTlsParameters::new(domain.to_string(), tls_config);
 
         let (creds, mechanism) = if oauth2 {
@@ -197,7 +232,29 @@ impl Smtp {
             _ => smtp::ClientSecurity::Wrapper(tls_parameters),
         };
 
-        let client = smtp::SmtpClient::with_security((domain.as_str(), port), security)
+        // The SMTP library cannot connect through a proxy itself, so it
+        // connects to a local relay instead. TLS parameters still use
+        // `domain`, so the certificate is verified for the real server.
+        let socks5_relay = match Socks5Config::from_database(context)
+            .await
+            .map_err(Error::Socks5)?
+        {
+            Some(socks5) => Some(
+                Socks5Relay::start(context, socks5, domain.to_string(), port)
+                    .await
+                    .map_err(Error::Socks5)?,
+            ),
+            None => None,
+        };
+        let (server_host, server_port) = match &socks5_relay {
+            Some(relay) => (
+                relay.local_addr().ip().to_string(),
+                relay.local_addr().port(),
+            ),
+            None => (domain.to_string(), port),
+        };
+
+        let client = smtp::SmtpClient::with_security((server_host.as_str(), server_port), security)
             .await
             .map_err(Error::ConnectionSetupFailure)?;
 
@@ -214,6 +271,7 @@ impl Smtp {
         }
 
         self.transport = Some(trans);
+        self.socks5_relay = socks5_relay;
         self.last_success = Some(SystemTime::now());
 
         context.emit_event(EventType::SmtpConnected(format!(
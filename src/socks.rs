@@ -0,0 +1,457 @@
+//! # SOCKS5 proxy support.
+//!
+//! Implements the client side of SOCKS5 as defined in
+//! https://tools.ietf.org/html/rfc1928 with username/password
+//! authentication as defined in https://tools.ietf.org/html/rfc1929,
+//! so IMAP and SMTP connections can be routed e.g. through Tor.
+
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+
+use async_std::io;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::sync::{channel, Sender};
+
+use crate::config::Config;
+use crate::context::Context;
+
+const SOCKS_VERSION: u8 = 5;
+const AUTH_NONE: u8 = 0;
+const AUTH_USER_PASSWORD: u8 = 2;
+const AUTH_NO_ACCEPTABLE: u8 = 0xff;
+const USER_PASSWORD_VERSION: u8 = 1;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// SOCKS5 proxy configuration, see `Config::Socks5Host`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Socks5Config {
+    pub host: String,
+    pub port: u16,
+    pub user_password: Option<(String, String)>,
+}
+
+impl Socks5Config {
+    /// Loads the proxy configuration, `None` if no proxy is configured.
+    ///
+    /// Fails if the configured port is invalid, so connections are not
+    /// made without the proxy by mistake.
+    pub async fn from_database(context: &Context) -> io::Result<Option<Self>> {
+        let host = match context
+            .get_config(Config::Socks5Host)
+            .await
+            .filter(|host| !host.is_empty())
+        {
+            Some(host) => host,
+            None => return Ok(None),
+        };
+        let port = context.get_config_int(Config::Socks5Port).await;
+        let port = match u16::try_from(port) {
+            Ok(port) if port > 0 => port,
+            _ => return Err(socks_error(&format!("invalid proxy port {}", port))),
+        };
+        let user_password = match context.get_config(Config::Socks5User).await {
+            Some(user) if !user.is_empty() => {
+                let password = context
+                    .get_config(Config::Socks5Password)
+                    .await
+                    .unwrap_or_default();
+                Some((user, password))
+            }
+            _ => None,
+        };
+        Ok(Some(Socks5Config {
+            host,
+            port,
+            user_password,
+        }))
+    }
+
+    /// Connects to `target_host:target_port` through the proxy.
+    ///
+    /// Host names are resolved by the proxy, so no DNS requests are
+    /// made outside of it.
+    pub async fn connect(&self, target_host: &str, target_port: u16) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        self.authenticate(&mut stream).await?;
+
+        let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+        match target_host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(ATYP_IPV4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(ATYP_IPV6);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                request.push(ATYP_DOMAIN);
+                push_with_len(&mut request, target_host)?;
+            }
+        }
+        request.extend_from_slice(&target_port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != SOCKS_VERSION {
+            return Err(socks_error("invalid reply version"));
+        }
+        if reply[1] != 0 {
+            return Err(socks_error(&format!(
+                "connect to {}:{} failed: {}",
+                target_host,
+                target_port,
+                reply_description(reply[1])
+            )));
+        }
+
+        // Skip the address the proxy bound to, it is not needed.
+        let addr_len = match reply[3] {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len).await?;
+                usize::from(len[0])
+            }
+            _ => return Err(socks_error("invalid address type in reply")),
+        };
+        let mut bound_addr = vec![0u8; addr_len + 2];
+        stream.read_exact(&mut bound_addr).await?;
+
+        Ok(stream)
+    }
+
+    async fn authenticate(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let greeting: &[u8] = if self.user_password.is_some() {
+            &[SOCKS_VERSION, 2, AUTH_NONE, AUTH_USER_PASSWORD]
+        } else {
+            &[SOCKS_VERSION, 1, AUTH_NONE]
+        };
+        stream.write_all(greeting).await?;
+
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await?;
+        if choice[0] != SOCKS_VERSION {
+            return Err(socks_error("invalid greeting version"));
+        }
+        match (choice[1], &self.user_password) {
+            (AUTH_NONE, _) => Ok(()),
+            (AUTH_USER_PASSWORD, Some((user, password))) => {
+                let mut request = vec![USER_PASSWORD_VERSION];
+                push_with_len(&mut request, user)?;
+                push_with_len(&mut request, password)?;
+                stream.write_all(&request).await?;
+
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status).await?;
+                if status[1] != 0 {
+                    return Err(socks_error("authentication failed"));
+                }
+                Ok(())
+            }
+            (AUTH_NO_ACCEPTABLE, _) => Err(socks_error("no acceptable authentication method")),
+            (method, _) => Err(socks_error(&format!(
+                "unsupported authentication method {}",
+                method
+            ))),
+        }
+    }
+}
+
+/// Connects to `host:port`, through the proxy if `socks5` is set.
+pub(crate) async fn connect_tcp(
+    host: &str,
+    port: u16,
+    socks5: Option<&Socks5Config>,
+) -> io::Result<TcpStream> {
+    match socks5 {
+        Some(socks5) => socks5.connect(host, port).await,
+        None => TcpStream::connect((host, port)).await,
+    }
+}
+
+/// Local TCP relay forwarding a single connection through a SOCKS5
+/// proxy to a fixed target.
+///
+/// This is used for SMTP, as the SMTP library only connects to socket
+/// addresses itself. TLS is still negotiated end-to-end with the
+/// target, only the TCP connection is relayed.
+///
+/// Only the first connection is accepted, the listening socket is
+/// closed right after, so other local processes cannot use the relay
+/// to connect through the proxy. A new relay is started for each
+/// connection.
+///
+/// The relayed connection is closed when the relay is dropped.
+#[derive(Debug)]
+pub(crate) struct Socks5Relay {
+    local_addr: SocketAddr,
+
+    /// Closed when the relay is dropped, which stops the relay task.
+    _stop_sender: Sender<()>,
+}
+
+impl Socks5Relay {
+    /// Starts relaying a connection to `host:port` on a random
+    /// localhost port.
+    pub async fn start(
+        context: &Context,
+        socks5: Socks5Config,
+        host: String,
+        port: u16,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let local_addr = listener.local_addr()?;
+        let (stop_sender, stop_receiver) = channel::<()>(1);
+
+        let ctx = context.clone();
+        let relay = async move {
+            let client = match listener.accept().await {
+                Ok((client, _)) => client,
+                Err(err) => {
+                    warn!(ctx, "SOCKS5 relay cannot accept connection: {}", err);
+                    return;
+                }
+            };
+            drop(listener);
+            relay_connection(&ctx, &socks5, &host, port, client).await
+        };
+        context.spawn_task("socks5_relay", async move {
+            relay
+                .race(async move {
+                    stop_receiver.recv().await.ok();
+                })
+                .await
+        });
+
+        Ok(Socks5Relay {
+            local_addr,
+            _stop_sender: stop_sender,
+        })
+    }
+
+    /// Returns the local address to connect to instead of the target.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+async fn relay_connection(
+    context: &Context,
+    socks5: &Socks5Config,
+    host: &str,
+    port: u16,
+    client: TcpStream,
+) {
+    let remote = match socks5.connect(host, port).await {
+        Ok(remote) => remote,
+        Err(err) => {
+            warn!(
+                context,
+                "SOCKS5 relay cannot connect to {}:{}: {}", host, port, err
+            );
+            return;
+        }
+    };
+
+    let (mut client_reader, mut client_writer) = (client.clone(), client);
+    let (mut remote_reader, mut remote_writer) = (remote.clone(), remote);
+    let upstream = io::copy(&mut client_reader, &mut remote_writer);
+    let downstream = io::copy(&mut remote_reader, &mut client_writer);
+    if let Err(err) = upstream.race(downstream).await {
+        info!(context, "SOCKS5 relay connection closed: {}", err);
+    }
+}
+
+fn push_with_len(buf: &mut Vec<u8>, s: &str) -> io::Result<()> {
+    if s.len() > usize::from(u8::MAX) {
+        return Err(socks_error("value too long"));
+    }
+    buf.push(s.len() as u8);
+    buf.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+fn reply_description(reply: u8) -> &'static str {
+    match reply {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn socks_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("SOCKS5: {}", msg))
+}
+
+/// Minimal SOCKS5 server for tests.
+///
+/// Accepts one connection, requires `alice`/`secret` as credentials
+/// and connects it to `upstream` regardless of the requested target.
+/// Returns the requested target as "host:port" after the client
+/// closed the connection.
+#[cfg(test)]
+pub(crate) async fn serve_socks5(listener: TcpListener, upstream: SocketAddr) -> String {
+    let (mut stream, _) = listener.accept().await.unwrap();
+
+    let mut greeting = [0u8; 2];
+    stream.read_exact(&mut greeting).await.unwrap();
+    assert_eq!(greeting[0], SOCKS_VERSION);
+    let mut methods = vec![0u8; usize::from(greeting[1])];
+    stream.read_exact(&mut methods).await.unwrap();
+    assert!(methods.contains(&AUTH_USER_PASSWORD));
+    stream
+        .write_all(&[SOCKS_VERSION, AUTH_USER_PASSWORD])
+        .await
+        .unwrap();
+
+    let mut credentials = Vec::new();
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).await.unwrap();
+    assert_eq!(head[0], USER_PASSWORD_VERSION);
+    let mut user = vec![0u8; usize::from(head[1])];
+    stream.read_exact(&mut user).await.unwrap();
+    credentials.push(user);
+    let mut len = [0u8; 1];
+    stream.read_exact(&mut len).await.unwrap();
+    let mut password = vec![0u8; usize::from(len[0])];
+    stream.read_exact(&mut password).await.unwrap();
+    credentials.push(password);
+    assert_eq!(credentials, vec![b"alice".to_vec(), b"secret".to_vec()]);
+    stream.write_all(&[USER_PASSWORD_VERSION, 0]).await.unwrap();
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await.unwrap();
+    assert_eq!(request, [SOCKS_VERSION, CMD_CONNECT, 0, ATYP_DOMAIN]);
+    stream.read_exact(&mut len).await.unwrap();
+    let mut host = vec![0u8; usize::from(len[0])];
+    stream.read_exact(&mut host).await.unwrap();
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port).await.unwrap();
+    let target = format!(
+        "{}:{}",
+        String::from_utf8(host).unwrap(),
+        u16::from_be_bytes(port)
+    );
+
+    let remote = TcpStream::connect(upstream).await.unwrap();
+    stream
+        .write_all(&[SOCKS_VERSION, 0, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await
+        .unwrap();
+
+    let (mut client_reader, mut client_writer) = (stream.clone(), stream);
+    let (mut remote_reader, mut remote_writer) = (remote.clone(), remote);
+    io::copy(&mut client_reader, &mut remote_writer)
+        .race(io::copy(&mut remote_reader, &mut client_writer))
+        .await
+        .ok();
+    target
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_std::task;
+
+    use crate::test_utils::TestContext;
+
+    #[async_std::test]
+    async fn test_socks5_relay() {
+        let t = TestContext::new().await;
+
+        // SMTP server sending a greeting.
+        let smtp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let smtp_addr = smtp_listener.local_addr().unwrap();
+        task::spawn(async move {
+            let (mut stream, _) = smtp_listener.accept().await.unwrap();
+            stream.write_all(b"220 smtp.example.org\r\n").await.unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.ok();
+        });
+
+        let proxy_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks5 = Socks5Config {
+            host: "127.0.0.1".to_string(),
+            port: proxy_listener.local_addr().unwrap().port(),
+            user_password: Some(("alice".to_string(), "secret".to_string())),
+        };
+        let proxy = task::spawn(serve_socks5(proxy_listener, smtp_addr));
+
+        let relay = Socks5Relay::start(&t.ctx, socks5, "smtp.example.org".to_string(), 25)
+            .await
+            .unwrap();
+        let mut stream = TcpStream::connect(relay.local_addr()).await.unwrap();
+        let mut greeting = [0u8; 22];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"220 smtp.example.org\r\n");
+
+        // Other connections are not accepted.
+        assert!(TcpStream::connect(relay.local_addr()).await.is_err());
+
+        drop(stream);
+        assert_eq!(proxy.await, "smtp.example.org:25");
+    }
+
+    #[async_std::test]
+    async fn test_socks5_config_from_database() {
+        let t = TestContext::new().await;
+        assert_eq!(Socks5Config::from_database(&t.ctx).await.unwrap(), None);
+
+        t.ctx
+            .set_config(Config::Socks5Host, Some("127.0.0.1"))
+            .await
+            .unwrap();
+        assert_eq!(
+            Socks5Config::from_database(&t.ctx).await.unwrap(),
+            Some(Socks5Config {
+                host: "127.0.0.1".to_string(),
+                port: 1080,
+                user_password: None,
+            })
+        );
+
+        t.ctx
+            .set_config(Config::Socks5User, Some("alice"))
+            .await
+            .unwrap();
+        t.ctx
+            .set_config(Config::Socks5Password, Some("secret"))
+            .await
+            .unwrap();
+        assert_eq!(
+            Socks5Config::from_database(&t.ctx)
+                .await
+                .unwrap()
+                .unwrap()
+                .user_password,
+            Some(("alice".to_string(), "secret".to_string()))
+        );
+
+        // Invalid ports are rejected instead of connecting without proxy.
+        assert!(t
+            .ctx
+            .set_config(Config::Socks5Port, Some("70000"))
+            .await
+            .is_err());
+        t.ctx
+            .sql
+            .set_raw_config(&t.ctx, Config::Socks5Port, Some("0"))
+            .await
+            .unwrap();
+        assert!(Socks5Config::from_database(&t.ctx).await.is_err());
+    }
+}
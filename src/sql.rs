@@ -9,12 +9,14 @@ use std::time::Duration;
 
 use rusqlite::{Connection, Error as SqlError, OpenFlags};
 
-use crate::chat::{update_device_icon, update_saved_messages_icon};
-use crate::constants::{ShowEmails, DC_CHAT_ID_TRASH};
-use crate::context::Context;
+use crate::chat::{update_device_icon, update_saved_messages_icon, ChatId};
+use crate::constants::ShowEmails;
+use crate::context::{Context, HousekeepingReport};
 use crate::dc_tools::*;
-use crate::ephemeral::start_ephemeral_timers;
+use crate::ephemeral::{delete_expired_messages, prune_trashed_messages, start_ephemeral_timers};
 use crate::error::format_err;
+use crate::events::EventType;
+use crate::message::MsgId;
 use crate::param::*;
 use crate::peerstate::*;
 
@@ -464,7 +466,8 @@ pub fn get_rowid2(
     )
 }
 
-pub async fn housekeeping(context: &Context) {
+pub async fn housekeeping(context: &Context) -> HousekeepingReport {
+    let mut report = HousekeepingReport::default();
     let mut files_in_use = HashSet::new();
     let mut unreferenced_count = 0;
 
@@ -568,7 +571,9 @@ pub async fn housekeeping(context: &Context) {
                     entry.file_name()
                 );
                 let path = entry.path();
-                dc_delete_file(context, path).await;
+                if dc_delete_file(context, path).await {
+                    report.deleted_files += 1;
+                }
             }
         }
         Err(err) => {
@@ -581,21 +586,41 @@ pub async fn housekeeping(context: &Context) {
         }
     }
 
-    if let Err(err) = start_ephemeral_timers(context).await {
-        warn!(
+    match start_ephemeral_timers(context).await {
+        Ok(started) => report.started_timers = started,
+        Err(err) => warn!(
             context,
             "Housekeeping: cannot start ephemeral timers: {}", err
-        );
+        ),
     }
 
-    if let Err(err) = prune_tombstones(context).await {
-        warn!(
+    // This also reschedules the ephemeral task.
+    match delete_expired_messages(context).await {
+        Ok(deleted) => {
+            report.deleted_expired_msgs = deleted;
+            if deleted {
+                context.emit_event(EventType::MsgsChanged {
+                    chat_id: ChatId::new(0),
+                    msg_id: MsgId::new(0),
+                });
+            }
+        }
+        Err(err) => warn!(
             context,
-            "Housekeeping: Cannot prune message tombstones: {}", err
-        );
+            "Housekeeping: cannot delete expired messages: {}", err
+        ),
+    }
+
+    match prune_trashed_messages(context).await {
+        Ok(pruned) => report.pruned_msgs = pruned,
+        Err(err) => warn!(
+            context,
+            "Housekeeping: cannot prune trashed messages: {}", err
+        ),
     }
 
     info!(context, "Housekeeping done.",);
+    report
 }
 
 #[allow(clippy::indexing_slicing)]
@@ -1382,6 +1407,16 @@ CREATE INDEX devmsglabels_index1 ON devmsglabels (label);
             .await?;
             sql.set_raw_config_int(context, "dbversion", 69).await?;
         }
+        if dbversion < 70 {
+            info!(context, "[migration] v70");
+            // UID of the message copy in the Sent folder, if any.
+            sql.execute(
+                "ALTER TABLE msgs ADD COLUMN sentbox_uid INTEGER DEFAULT 0;",
+                paramsv![],
+            )
+            .await?;
+            sql.set_raw_config_int(context, "dbversion", 70).await?;
+        }
 
         // (2) updates that require high-level objects
         // (the structure is complete now and all objects are usable)
@@ -1419,21 +1454,6 @@ CREATE INDEX devmsglabels_index1 ON devmsglabels (label);
     Ok(())
 }
 
-/// Removes from the database locally deleted messages that also don't
-/// have a server UID.
-async fn prune_tombstones(context: &Context) -> Result<()> {
-    context
-        .sql
-        .execute(
-            "DELETE FROM msgs \
-         WHERE (chat_id = ? OR hidden) \
-         AND server_uid = 0",
-            paramsv![DC_CHAT_ID_TRASH],
-        )
-        .await?;
-    Ok(())
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
@@ -0,0 +1,186 @@
+//! # Storage usage.
+//!
+//! Breaks down the disk space used by an account so that UIs can show
+//! what takes up space before offering to delete old messages.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use async_std::fs;
+use async_std::prelude::*;
+
+use crate::constants::{Viewtype, DC_CHAT_ID_TRASH};
+use crate::context::Context;
+use crate::error::Result;
+use crate::param::{Param, Params};
+
+/// How long a computed breakdown is returned from the cache.
+const STORAGE_BREAKDOWN_TTL: Duration = Duration::from_secs(30);
+
+/// Disk space used by an account in bytes, see `Context::storage_breakdown()`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StorageBreakdown {
+    /// Size of the database file.
+    pub database: u64,
+    /// Blobs of images, GIFs and stickers.
+    pub images: u64,
+    /// Blobs of videos.
+    pub videos: u64,
+    /// Blobs of audio and voice messages.
+    pub audio: u64,
+    /// Blobs of files and all other message types.
+    pub files: u64,
+    /// Blobs not referenced by any message, e.g. avatars and outgoing
+    /// messages waiting for SMTP.
+    pub other: u64,
+    /// Database space used by trashed message rows, which are kept
+    /// to not download deleted messages again.
+    pub trash: u64,
+}
+
+impl StorageBreakdown {
+    /// Total size of all blobs.
+    pub fn blobs(&self) -> u64 {
+        self.images + self.videos + self.audio + self.files + self.other
+    }
+
+    /// Total disk space used.
+    pub fn total(&self) -> u64 {
+        self.database + self.blobs()
+    }
+
+    fn add_blob(&mut self, viewtype: Option<Viewtype>, bytes: u64) {
+        let bucket = match viewtype {
+            Some(Viewtype::Image) | Some(Viewtype::Gif) | Some(Viewtype::Sticker) => {
+                &mut self.images
+            }
+            Some(Viewtype::Video) => &mut self.videos,
+            Some(Viewtype::Audio) | Some(Viewtype::Voice) => &mut self.audio,
+            Some(_) => &mut self.files,
+            None => &mut self.other,
+        };
+        *bucket += bytes;
+    }
+}
+
+impl Context {
+    /// Returns the disk space used by this account.
+    ///
+    /// The result is cached for a short time, so this can be called
+    /// whenever a "free up space" screen is shown.
+    pub async fn storage_breakdown(&self) -> Result<StorageBreakdown> {
+        if let Some((computed_at, breakdown)) = *self.storage_breakdown.read().await {
+            if computed_at.elapsed() < STORAGE_BREAKDOWN_TTL {
+                return Ok(breakdown);
+            }
+        }
+
+        let breakdown = compute_storage_breakdown(self).await?;
+        *self.storage_breakdown.write().await = Some((Instant::now(), breakdown));
+        Ok(breakdown)
+    }
+}
+
+async fn compute_storage_breakdown(context: &Context) -> Result<StorageBreakdown> {
+    let mut breakdown = StorageBreakdown::default();
+
+    // If a blob is referenced by messages of different types,
+    // the first one wins.
+    let blob_types: HashMap<String, Viewtype> = context
+        .sql
+        .query_map(
+            "SELECT type, param FROM msgs WHERE chat_id!=? AND type!=?;",
+            paramsv![DC_CHAT_ID_TRASH, Viewtype::Text],
+            |row| Ok((row.get::<_, Viewtype>(0)?, row.get::<_, String>(1)?)),
+            |rows| {
+                let mut blob_types = HashMap::new();
+                for row in rows {
+                    let (viewtype, param) = row?;
+                    let param: Params = param.parse().unwrap_or_default();
+                    if let Some(name) = param
+                        .get(Param::File)
+                        .and_then(|file| file.strip_prefix("$BLOBDIR/"))
+                    {
+                        blob_types.entry(name.to_string()).or_insert(viewtype);
+                    }
+                }
+                Ok(blob_types)
+            },
+        )
+        .await?;
+
+    let mut dir_handle = fs::read_dir(context.get_blobdir()).await?;
+    while let Some(entry) = dir_handle.next().await {
+        let entry = entry?;
+        let meta = entry.metadata().await?;
+        if !meta.is_file() {
+            continue;
+        }
+        let name = entry.file_name();
+        let viewtype = name.to_str().and_then(|name| blob_types.get(name));
+        breakdown.add_blob(viewtype.copied(), meta.len());
+    }
+
+    breakdown.database = fs::metadata(context.get_dbfile())
+        .await
+        .map(|meta| meta.len())
+        .unwrap_or_default();
+
+    breakdown.trash = context
+        .sql
+        .query_get_value_result::<i64>(
+            "SELECT IFNULL(SUM(LENGTH(txt)+LENGTH(param)+IFNULL(LENGTH(mime_headers),0)),0)
+             FROM msgs WHERE chat_id=?;",
+            paramsv![DC_CHAT_ID_TRASH],
+        )
+        .await?
+        .unwrap_or_default() as u64;
+
+    Ok(breakdown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::blob::BlobObject;
+    use crate::chat::ChatId;
+    use crate::test_utils::{add_blob_msg, TestContext};
+
+    #[async_std::test]
+    async fn test_storage_breakdown() {
+        let t = TestContext::new().await;
+        let chat_id = ChatId::new(10);
+
+        add_blob_msg(&t.ctx, chat_id, Viewtype::Image, "a.jpg", 1000).await;
+        add_blob_msg(&t.ctx, chat_id, Viewtype::Sticker, "b.png", 200).await;
+        add_blob_msg(&t.ctx, chat_id, Viewtype::Video, "c.mp4", 3000).await;
+        add_blob_msg(&t.ctx, chat_id, Viewtype::Voice, "d.opus", 400).await;
+        add_blob_msg(&t.ctx, chat_id, Viewtype::File, "e.pdf", 500).await;
+        BlobObject::create(&t.ctx, "unreferenced.bin", &[0u8; 60])
+            .await
+            .unwrap();
+        t.ctx
+            .sql
+            .execute(
+                "INSERT INTO msgs (chat_id, txt, param) VALUES (?, ?, ?);",
+                paramsv![DC_CHAT_ID_TRASH, "trashed", ""],
+            )
+            .await
+            .unwrap();
+
+        let breakdown = t.ctx.storage_breakdown().await.unwrap();
+        assert_eq!(breakdown.images, 1200);
+        assert_eq!(breakdown.videos, 3000);
+        assert_eq!(breakdown.audio, 400);
+        assert_eq!(breakdown.files, 500);
+        assert_eq!(breakdown.other, 60);
+        assert_eq!(breakdown.trash, "trashed".len() as u64);
+        assert!(breakdown.database > 0);
+        assert_eq!(breakdown.blobs(), 5160);
+
+        // The breakdown is cached.
+        add_blob_msg(&t.ctx, chat_id, Viewtype::Video, "f.mp4", 7000).await;
+        assert_eq!(t.ctx.storage_breakdown().await.unwrap(), breakdown);
+    }
+}
@@ -5,19 +5,25 @@
 use std::str::FromStr;
 use std::time::{Duration, Instant};
 
+use async_std::io::BufReader;
+use async_std::net::TcpListener;
 use async_std::path::PathBuf;
+use async_std::prelude::*;
 use async_std::sync::RwLock;
+use async_std::task;
 use tempfile::{tempdir, TempDir};
 
+use crate::blob::BlobObject;
 use crate::chat;
 use crate::chat::{ChatId, ChatItem};
 use crate::config::Config;
+use crate::constants::Viewtype;
 use crate::context::Context;
 use crate::dc_receive_imf::dc_receive_imf;
 use crate::dc_tools::EmailAddress;
 use crate::job::Action;
 use crate::key::{self, DcKey};
-use crate::message::Message;
+use crate::message::{self, Message, MsgId};
 use crate::mimeparser::MimeMessage;
 use crate::param::{Param, Params};
 
@@ -239,6 +245,69 @@ impl SentMessage {
     }
 }
 
+/// Receives a plain text message from bob@example.com with the Message-ID
+/// `rfc724_mid` into INBOX with server UID 1 and returns its id.
+pub(crate) async fn receive_simple_msg(ctx: &Context, rfc724_mid: &str) -> MsgId {
+    receive_simple_msg_in(ctx, rfc724_mid, "INBOX", 1).await
+}
+
+/// Like [receive_simple_msg], but receives the message into `folder`
+/// with the server UID `uid`.
+pub(crate) async fn receive_simple_msg_in(
+    ctx: &Context,
+    rfc724_mid: &str,
+    folder: &str,
+    uid: u32,
+) -> MsgId {
+    let imf_raw = format!(
+        "From: bob@example.com\n\
+         To: alice@example.com\n\
+         Subject: foo\n\
+         Message-ID: <{}>\n\
+         Date: Sun, 22 Mar 2020 22:37:57 +0000\n\
+         \n\
+         hello\n",
+        rfc724_mid
+    );
+    dc_receive_imf(ctx, imf_raw.as_bytes(), folder, uid, false)
+        .await
+        .unwrap();
+    let (_, _, msg_id) = message::rfc724_mid_exists(ctx, rfc724_mid)
+        .await
+        .unwrap()
+        .unwrap();
+    msg_id
+}
+
+/// Adds a message of type `viewtype` to `chat_id` referencing a new blob
+/// named `name` with `bytes` zeros and returns its id.
+pub(crate) async fn add_blob_msg(
+    ctx: &Context,
+    chat_id: ChatId,
+    viewtype: Viewtype,
+    name: &str,
+    bytes: usize,
+) -> MsgId {
+    let blob = BlobObject::create(ctx, name, &vec![0u8; bytes])
+        .await
+        .unwrap();
+    let mut param = Params::new();
+    param.set(Param::File, blob.as_name());
+    ctx.sql
+        .execute(
+            "INSERT INTO msgs (chat_id, type, param) VALUES (?, ?, ?);",
+            paramsv![chat_id, viewtype, param.to_string()],
+        )
+        .await
+        .unwrap();
+    let id = ctx
+        .sql
+        .get_rowid(ctx, "msgs", "param", param.to_string())
+        .await
+        .unwrap();
+    MsgId::new(id)
+}
+
 /// Load a pre-generated keypair for alice@example.com from disk.
 ///
 /// This saves CPU cycles by avoiding having to generate a key.
@@ -274,3 +343,57 @@ pub(crate) fn bob_keypair() -> key::KeyPair {
         secret,
     }
 }
+
+/// Starts a fake IMAP server accepting a single connection on localhost.
+///
+/// Every command is answered with the untagged responses returned by
+/// `respond` for the command line, followed by a tagged OK. IDLE is
+/// answered with a continuation and completed when the client sends
+/// DONE. AUTHENTICATE is answered with a continuation as well; the
+/// client response is passed to `respond`, which returns the status
+/// completing the command, e.g. "NO invalid token", or an empty string
+/// for OK. Returns the port of the server and its task.
+pub(crate) async fn fake_imap_server<F>(respond: F) -> (u16, task::JoinHandle<()>)
+where
+    F: Fn(&str) -> String + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = task::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut writer = stream.clone();
+        let mut lines = BufReader::new(stream).lines();
+        writer.write_all(b"* OK IMAP4rev1 ready\r\n").await.unwrap();
+        let mut idle_tag = None;
+        let mut auth_tag: Option<String> = None;
+        while let Some(line) = lines.next().await {
+            let line = line.unwrap();
+            let mut response = respond(&line);
+            if let Some(tag) = auth_tag.take() {
+                if response.is_empty() {
+                    response = "OK done".to_string();
+                }
+                response = format!("{} {}\r\n", tag, response);
+            } else if line == "DONE" {
+                if let Some(tag) = idle_tag.take() {
+                    response += &format!("{} OK IDLE terminated\r\n", tag);
+                }
+            } else {
+                let tag = line.split(' ').next().unwrap_or_default().to_string();
+                if line.ends_with(" IDLE") {
+                    response += "+ idling\r\n";
+                    idle_tag = Some(tag);
+                } else if line.contains(" AUTHENTICATE ") {
+                    response += "+ \r\n";
+                    auth_tag = Some(tag);
+                } else {
+                    response += &format!("{} OK done\r\n", tag);
+                }
+            }
+            if writer.write_all(response.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    });
+    (port, server)
+}